@@ -0,0 +1,88 @@
+//! How long `detach-rs` takes to get a short job going.
+//!
+//! Scheduled jobs are often shorter than the tool that detaches them is slow to start,
+//! so the launch path is measured end to end as well as piece by piece:
+//!
+//! - `logging/build`: building the log4rs configuration, file appender included.
+//! - `runtime/*`: building a tokio runtime, running nothing on it and dropping it, for
+//!   both flavors.
+//! - `detach-rs/detach`: `detach-rs --detach --command true` until the launching process
+//!   returns, which is the fork, `setsid` and redirect path as a caller sees it.
+//! - `detach-rs/foreground`: `detach-rs --no-detach --command true` to completion.
+//!
+//! Run with `cargo bench --bench startup`. The binary is run with `XDG_RUNTIME_DIR` and
+//! `XDG_STATE_HOME` in a scratch directory, so running instances are not disturbed.
+use criterion::{Criterion, criterion_group, criterion_main};
+use detach::LoggingConfig;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+fn scratch() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("detach-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("create the scratch directory");
+    dir
+}
+
+fn logging(c: &mut Criterion) {
+    let log = scratch().join("build.log");
+    c.bench_function("logging/build", |b| {
+        b.iter(|| {
+            LoggingConfig::new(&log, log::LevelFilter::Info)
+                .build()
+                .unwrap()
+        })
+    });
+}
+
+fn runtime(c: &mut Criterion) {
+    let mut group = c.benchmark_group("runtime");
+    group.bench_function("multi_thread", |b| {
+        b.iter(|| {
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(async {});
+        })
+    });
+    group.bench_function("current_thread", |b| {
+        b.iter(|| {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(async {});
+        })
+    });
+    group.finish();
+}
+
+fn detach_rs(dir: &Path, args: &[&str]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_detach-rs"))
+        .args(args)
+        .arg("--log-file")
+        .arg(dir.join("detach-rs.log"))
+        .args(["--command", "true"])
+        .env("XDG_RUNTIME_DIR", dir)
+        .env("XDG_STATE_HOME", dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .expect("run detach-rs");
+    assert!(status.success(), "detach-rs {:?} failed: {}", args, status);
+}
+
+fn launch(c: &mut Criterion) {
+    let dir = scratch();
+    let mut group = c.benchmark_group("detach-rs");
+    group.bench_function("detach", |b| b.iter(|| detach_rs(&dir, &["--detach"])));
+    group.bench_function("foreground", |b| {
+        b.iter(|| detach_rs(&dir, &["--no-detach"]))
+    });
+    group.finish();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, logging, runtime, launch);
+criterion_main!(benches);
@@ -1,5 +1,4 @@
 use log::{error, info, warn};
-use log4rs;
 
 fn main() {
     // 1. Define a configuration programmatically (highly portable)
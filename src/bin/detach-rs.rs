@@ -1,111 +1,1623 @@
-#![allow(unused)]
+use clap::{CommandFactory, FromArgMatches};
+use log::{debug, info, trace, warn};
 
-use chrono::Local;
-use clap::Parser;
-#[cfg(unix)]
-use clap::ValueEnum;
-#[cfg(unix)]
-use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO, dup2, fork, setsid};
-use log::{LevelFilter, debug, info, trace, warn};
-use std::fs::File as StdFile;
-#[cfg(unix)]
-use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
-// REMOVED: use std::process::Command;
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, BufReader};
-
-use detach::Args;
-use detach::daemonize;
-use detach::run_command_and_exit;
-use detach::run_service_async;
-use detach::setup_logging;
+use detach::prelude::*;
 
 fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-
-    // Define the default log file path
-    let default_log_file = PathBuf::from("./detach.log");
-
-    let log_file_path = if args.log_file == default_log_file {
-        // If the default log file is used, append a timestamp
-        let now = Local::now();
-        let timestamp_str = now.format("%Y%m%d-%H%M%S").to_string();
-        let timestamped_filename = format!("detach-{}.log", timestamp_str);
-        std::env::current_dir()?.join(timestamped_filename)
-    } else if args.log_file.is_relative() {
-        // If a custom relative path is provided, resolve it
-        std::env::current_dir()?.join(&args.log_file)
-    } else {
-        // If an absolute path is provided, use it as-is
-        args.log_file.clone()
-    };
+    // Parsed in two steps so the launch record can tell where each value came from
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)
+        .map_err(|e| e.format(&mut Args::command()))
+        .unwrap_or_else(|e| e.exit());
+    // One of the --instances: it runs under its own name and logs to its own file
+    if let (Some(index), Some(name)) = (args.instance, &args.name) {
+        args.name = Some(detach::instances::instance_name(name, index));
+        let log_file = resolve_log_path(args.daemon.log_file.as_deref())?;
+        args.daemon.log_file = Some(detach::instances::log_file(&log_file, index));
+    }
 
-    let log_level = args.logging.unwrap_or(log::LevelFilter::Info);
+    // Before anything uses the process-wide context, whose buffers it sizes
+    detach::profile::set_profile(args.profile);
 
-    let should_detach_initial = args.detach && !args.no_detach && !args.tail; // Determine this earlier
+    if let Some(shell) = args.completions {
+        print_completions(shell, "detach-rs");
+        return Ok(());
+    }
 
-    // Determine `to_console` based on command, tail, or detach status
-    let to_console = args.command.is_some() || args.tail || !should_detach_initial; // Log to console if command, tail, or not detaching
+    if let Some(Commands::Init {
+        name,
+        force,
+        command,
+    }) = &args.subcommand
+    {
+        let path = detach::config::scaffold_service(name, command, *force)?;
+        println!("Created {}", path.display());
+        return Ok(());
+    }
 
-    setup_logging(&log_file_path, log_level, to_console)?; // SINGLE setup_logging call
+    if let Some(Commands::Events { follow, name }) = &args.subcommand {
+        #[cfg(unix)]
+        return print_events(*follow, name.as_deref());
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({}, {:?}).",
+            follow,
+            name
+        ));
+    }
 
-    // Build the tokio runtime once
-    let rt = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap();
-
-    let result = rt.block_on(async {
-        // Wrap the main logic in an async block
-        // --- NEW LOGIC FOR --command FLAG ---
-        if let Some(cmd_str) = args.command {
-            return match run_command_and_exit(cmd_str, &log_file_path, log_level, args.timeout).await {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e),
-            };
+    if let Some(Commands::Enqueue { command }) = &args.subcommand {
+        #[cfg(unix)]
+        {
+            let job = detach::queue::enqueue(command)?;
+            println!("{}", job.id);
+            return Ok(());
         }
-        // --- END NEW LOGIC ---
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "The job queue is not supported on this operating system ({:?}).",
+            command
+        ));
+    }
 
-        // These debug/info/trace/warn calls should be after setup_logging
-        debug!("debug");
-        info!("info");
-        trace!("trace");
-        warn!("warn");
+    if let Some(Commands::Jobs) = &args.subcommand {
+        #[cfg(unix)]
+        return print_jobs();
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "The job queue is not supported on this operating system."
+        ));
+    }
 
-        let mut should_detach = should_detach_initial; // Use the initial determination
+    if let Some(Commands::Logs {
+        follow,
+        lines,
+        file,
+    }) = &args.subcommand
+    {
+        let path = match file {
+            Some(file) => file.clone(),
+            None => detach::logs::newest_log(&detach::config::log_dir()?)?,
+        };
+        return detach::logs::print_log(&path, *lines, *follow, &mut std::io::stdout().lock());
+    }
 
+    if let Some(Commands::Dump { name }) = &args.subcommand {
+        #[cfg(unix)]
+        {
+            let path = detach::control::socket_path(name)?;
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            let report = rt.block_on(detach::control::query(&path, "dump"))?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            return Ok(());
+        }
         #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({}).",
+            name
+        ));
+    }
+
+    if let Some(Commands::Status { name }) = &args.subcommand {
+        #[cfg(unix)]
+        return print_status(name.as_deref());
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({:?}).",
+            name
+        ));
+    }
+
+    if let Some(Commands::List { pattern, tags }) = &args.subcommand {
+        #[cfg(unix)]
+        return print_list(pattern.as_deref(), tags);
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({:?}, {:?}).",
+            pattern,
+            tags
+        ));
+    }
+
+    if let Some(Commands::Healthz { listen, require }) = &args.subcommand {
+        #[cfg(unix)]
+        return check_health(*listen, require);
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({:?}, {:?}).",
+            listen,
+            require
+        ));
+    }
+
+    if let Some(
+        Commands::Stop {
+            instances, force, ..
+        }
+        | Commands::Restart {
+            instances, force, ..
+        },
+    ) = &args.subcommand
+    {
+        let (request, ready_timeout, wait) = match &args.subcommand {
+            Some(Commands::Restart { ready_timeout, .. }) => ("restart", *ready_timeout, false),
+            Some(Commands::Stop { wait, .. }) => ("stop", 0, *wait),
+            _ => ("stop", 0, false),
+        };
+        #[cfg(unix)]
+        return control_instances(
+            instances,
+            request,
+            *force,
+            std::time::Duration::from_secs(ready_timeout),
+            wait,
+        );
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({:?}, {}, {}, {}, {}).",
+            instances,
+            request,
+            force,
+            ready_timeout,
+            wait
+        ));
+    }
+
+    if let Some(Commands::Deploy {
+        name,
+        new_cmd,
+        ready_timeout,
+    }) = &args.subcommand
+    {
+        #[cfg(unix)]
         {
-            if should_detach {
-                eprintln!("Daemonization is not supported on this operating system.");
-                should_detach = false;
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            let timeout = std::time::Duration::from_secs(*ready_timeout);
+            let new = rt.block_on(detach::deploy::deploy(name, new_cmd, timeout, |step| {
+                println!("{}", step)
+            }))?;
+            println!("Deployed: {} runs the new version.", new);
+            return Ok(());
+        }
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({}, {}, {}).",
+            name,
+            new_cmd,
+            ready_timeout
+        ));
+    }
+
+    if let Some(Commands::Exec { name, command }) = &args.subcommand {
+        #[cfg(unix)]
+        {
+            let status = exec_in(name, command)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({}, {:?}).",
+            name,
+            command
+        ));
+    }
+
+    if let Some(Commands::Tree { name }) = &args.subcommand {
+        #[cfg(unix)]
+        return print_tree(name);
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({}).",
+            name
+        ));
+    }
+
+    if let Some(Commands::Fds { name }) = &args.subcommand {
+        #[cfg(unix)]
+        return print_fds(name);
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({}).",
+            name
+        ));
+    }
+
+    if let Some(Commands::Doctor { name }) = &args.subcommand {
+        #[cfg(unix)]
+        return doctor(name);
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({}).",
+            name
+        ));
+    }
+
+    if let Some(Commands::Inspect { name, json }) = &args.subcommand {
+        #[cfg(unix)]
+        return print_inspect(name, *json);
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({}, {}).",
+            name,
+            json
+        ));
+    }
+
+    if let Some(Commands::History { name, since, json }) = &args.subcommand {
+        return print_history(name, *since, *json);
+    }
+
+    if let Some(Commands::Clean { force }) = &args.subcommand {
+        #[cfg(unix)]
+        return clean(*force);
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "Control sockets are not supported on this operating system ({}).",
+            force
+        ));
+    }
+
+    if let Some(Commands::Remote {
+        action,
+        host,
+        command,
+    }) = &args.subcommand
+    {
+        match action {
+            Some(RemoteAction::Logs { host, pid, follow }) => {
+                detach::remote::logs(host, *pid, *follow)?
+            }
+            Some(RemoteAction::Stop { host, pid }) => detach::remote::stop(host, *pid)?,
+            None => {
+                let host = host.as_deref().unwrap_or_default();
+                let pid = detach::remote::start(host, command)?;
+                println!("{}", pid);
             }
         }
+        return Ok(());
+    }
+
+    // Report every mistake in the options now, while there is a terminal to show them
+    detach::validate::validate(&args)?;
+
+    if let Some(count) = args.instances
+        && args.instance.is_none()
+    {
+        return detach::instances::launch(count);
+    }
+
+    let log_file_path = resolve_log_path(args.daemon.log_file.as_deref())?;
+
+    let log_level = args.daemon.level();
+    let console_level = resolve_console_level(args.console_level, args.daemon.quiet);
+
+    // --tail runs the service as a daemon and follows its log from here, so Ctrl-C only
+    // ends the tail; --kill-on-interrupt keeps the service in the foreground instead
+    let tail_detached = args.tail
+        && !args.kill_on_interrupt
+        && !args.daemon.no_detach
+        && cfg!(unix)
+        && args.log_to.resolve() == LogDestination::File;
+    let should_detach_initial = args.daemon.should_detach() || tail_detached;
+
+    // A service manager supervises the process it started; detaching would orphan the
+    // daemon from it.
+    let manager = ServiceManager::detect().filter(|_| should_detach_initial);
+    if let Some(manager) = manager
+        && args.strict
+    {
+        return Err(anyhow::anyhow!(
+            "Refusing to detach: started by {}, which supervises this process. Remove \
+             --detach, or use a unit type that expects forking.",
+            manager
+        ));
+    }
+    let should_detach_initial = should_detach_initial && manager.is_none();
+
+    // A detached --tail shows the log file instead of the console of the daemon
+    let to_console = !should_detach_initial; // Log to console if not detaching
+
+    let redactor = if args.redact_env.is_empty() && args.redact_regex.is_empty() {
+        None
+    } else {
+        Some(std::sync::Arc::new(Redactor::new(
+            &args.redact_env,
+            &args.redact_regex,
+        )?))
+    };
 
-        // Create the service future (heartbeat loop)
-        let service_future = run_service_async();
+    // Create a missing log directory here, while still privileged, so it gets the
+    // requested owner and mode and failures name the directory.
+    #[cfg(unix)]
+    if args.log_to.resolve() != LogDestination::Journald
+        && let Some(dir) = log_file_path.parent()
+    {
+        detach::compat::create_directory(dir, args.log_dir_mode, args.log_dir_owner.as_ref())?;
+    }
 
+    let logging = LoggingConfig {
+        to_console,
+        console_level,
+        console_stream: args.console_stream,
+        format: args.log_format,
+        fields: args.log_fields.clone(),
+        service: args.name.clone(),
+        redactor: redactor.clone(),
+        os_log: args.os_log.clone(),
+        destination: args.log_to,
+        on_error: args.on_log_error,
+        ..LoggingConfig::new(&log_file_path, log_level)
+    };
+    logging.init()?; // SINGLE setup_logging call
+    if args.log_to.resolve() == LogDestination::File {
+        detach::diagnostics::set_log_file(log_file_path.clone());
+    }
+
+    info!("Run ID {}", detach::run_id());
+
+    if let Some(manager) = manager {
+        info!(
+            "Started by {}; staying in the foreground instead of detaching so it keeps \
+             supervising this process.",
+            manager
+        );
+    }
+
+    // Take the lock before forking so a skipped run is reported to the launching terminal
+    let lock = match &args.exclusive_lock {
+        Some(path) => match LockFile::try_acquire(path)? {
+            Some(lock) => Some(lock),
+            None => {
+                info!(
+                    "Another instance holds the lock {}, skipping.",
+                    path.display()
+                );
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    let should_detach = should_detach_initial; // Use the initial determination
+
+    #[cfg(not(unix))]
+    let should_detach = {
+        if should_detach {
+            eprintln!("Daemonization is not supported on this operating system.");
+        }
+        false
+    };
+
+    if let Some(lines) = args.output_tail {
+        ServiceContext::current().keep_output(lines);
+    }
+    ServiceContext::current().set_tags(args.tags.clone());
+    let ports = detach::ports::resolve(&args.ports)?;
+    for (spec, port) in args.ports.iter().zip(&ports) {
+        if *spec == detach::ports::PortSpec::Auto {
+            info!("Allocated port {} for the command.", port);
+        }
+    }
+    ServiceContext::current().set_ports(ports.clone());
+    let vars = detach::template::Vars {
+        name: args.name.clone(),
+        instance: args.instance.unwrap_or(1).into(),
+        ports: ports.clone(),
+        log_dir: log_file_path
+            .parent()
+            .map(|dir| dir.to_string_lossy().into_owned()),
+    };
+    let command = args
+        .command
+        .as_deref()
+        .map(|command| detach::template::expand(command, &vars))
+        .transpose()?;
+    #[cfg(unix)]
+    if args.name.is_some() {
+        let mut launch =
+            detach::launch::capture(&matches, Some(&log_file_path), redactor.as_deref());
+        launch["ports"] = ports.clone().into();
+        launch["command"] = command.clone().into();
+        ServiceContext::current().set_launch(launch);
+    }
+    let crash_report = args.crash_report.map(|kb| CrashReport {
+        log_tail_kb: kb,
+        output: ServiceContext::current().output_tail(),
+        redactor: redactor.clone(),
+        ..CrashReport::new(&log_file_path)
+    });
+
+    #[cfg(unix)]
+    if let Some(mode) = args.fork_audit {
+        detach::forkcheck::set_fork_audit(mode);
+    }
+    detach::stdin::set_on_stdin_eof(args.daemon.on_stdin_eof);
+
+    let sandbox = FsSandbox {
+        read: args.allow_read.clone(),
+        write: args.allow_write.clone(),
+        mode: args.sandbox,
+    };
+    let isolation = Isolation {
+        private_tmp: args.private_tmp,
+        no_new_privileges: args.no_new_privileges,
+        network: args.network,
+    };
+
+    let container = args.backend.container(args.name.as_deref());
+
+    let run_logs = match (args.keep_runs, &args.command, args.name.as_deref()) {
+        (Some(keep), Some(_), Some(name)) => Some(RunLogs::for_instance(name, keep.into())?),
+        _ => None,
+    };
+
+    // Sampled once so the timeouts below agree with the actual wait
+    let start_delay = args.start_delay();
+
+    #[cfg(unix)]
+    let control_socket = args
+        .name
+        .as_deref()
+        .filter(|_| args.profile != Profile::Minimal)
+        .map(detach::control::socket_path)
+        .transpose()?;
+
+    // The launching process stays after the fork to follow the log with --tail, or to
+    // watch the instance start with --wait
+    #[cfg(unix)]
+    if args.tail && should_detach {
+        let tail = detach::wait::Wait::follow(args.name.as_deref(), &log_file_path);
+        detach::wait::set_wait(Some(tail));
+    }
+    #[cfg(unix)]
+    if let (Some(seconds), Some(name)) = (args.wait, args.name.as_deref()) {
+        if control_socket.is_none() {
+            return Err(anyhow::anyhow!(
+                "--wait needs the control socket, which --profile minimal leaves out"
+            ));
+        }
+        let timeout = std::time::Duration::from_secs(seconds);
+        let wait = detach::wait::Wait::new(name, &log_file_path, timeout)?;
+        detach::wait::set_wait(Some(wait));
+    }
+
+    #[cfg(all(unix, feature = "grpc"))]
+    let grpc_token = args
+        .grpc_token_file
+        .as_deref()
+        .map(detach::grpc::read_token)
+        .transpose()?;
+
+    // --- NEW LOGIC FOR --command FLAG ---
+    if let Some(cmd_str) = command.clone() {
+        #[cfg(unix)]
+        let proxy_signals = args.forward_signals.is_some() || args.signal_group;
+        #[cfg(not(unix))]
+        let proxy_signals = false;
+        #[cfg(unix)]
+        let budgeted = args.cpu_limit.is_some() || args.max_output.is_some();
+        #[cfg(not(unix))]
+        let budgeted = args.max_output.is_some();
+        // SIGUSR2 dumps diagnostics unless the supervisor forwards it to the command
+        #[allow(unused_mut)]
+        let mut dump_on_usr2 = true;
+        let supervised = args.restart_at.is_some()
+            || args.cores.is_some()
+            || args.seccomp.is_some()
+            || !sandbox.is_empty()
+            || !isolation.is_default()
+            || proxy_signals
+            || budgeted
+            || args.subreaper
+            || args.audit_log.is_some()
+            || args.name.is_some()
+            || container.is_some()
+            || run_logs.is_some()
+            || !ports.is_empty()
+            || dbus_enabled(&args)
+            || grpc_enabled(&args);
+        let command_future: std::pin::Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>> =
+            if supervised {
+                // Supervise the command so it can be restarted, sandboxed, its crashes
+                // handled or signals forwarded to it; the daemon timeout bounds the
+                // supervisor as a whole.
+                #[allow(unused_mut)]
+                let mut opts = SupervisorOptions {
+                    run_timeout: args.run_timeout().map(std::time::Duration::from_secs),
+                    lifetime: args
+                        .service_lifetime(start_delay)
+                        .map(std::time::Duration::from_secs),
+                    restart_at: args.restart_at.clone(),
+                    calendar: args.calendar(),
+                    missed: args.missed,
+                    start_jitter: args.start_jitter,
+                    cores: args.cores.clone(),
+                    isolation: (!isolation.is_default()).then_some(isolation),
+                    sandbox: (!sandbox.is_empty()).then(|| sandbox.clone()),
+                    seccomp: args.seccomp.clone(),
+                    audit: args.audit_log.as_deref().map(AuditLog::open).transpose()?,
+                    container,
+                    run_logs,
+                    sanitize: args.sanitize(),
+                    redactor: redactor.clone(),
+                    max_output: args.max_output,
+                    stop_timeout: args.stop_timeout,
+                    subreaper: args.subreaper,
+                    detached: should_detach,
+                    env: detach::ports::environment(&ports),
+                    ..SupervisorOptions::default()
+                };
+                #[cfg(unix)]
+                {
+                    opts.cpu_limit = args.cpu_limit;
+                    if let Some(signals) = args.forward_signals.clone() {
+                        opts.forward_signals = signals;
+                    }
+                    opts.process_group = args.signal_group;
+                    dump_on_usr2 = !opts.forward_signals.contains(&detach::signal::SIGUSR2);
+                }
+                if let Some(name) = args.name.as_deref() {
+                    opts.adopt = detach::adopt::find(name, &cmd_str);
+                }
+                Box::pin(delayed_start(start_delay, supervise_command(cmd_str, opts)))
+            } else {
+                // A single command run ends the process, so the daemon timeout simply caps
+                // the run timeout instead of racing it.
+                let timeout = args.command_timeout(start_delay);
+                let command_future = run_command_and_exit(cmd_str, None, timeout);
+                Box::pin(delayed_start(start_delay, command_future))
+            };
+        let command_future = with_memory_stats(
+            args.stats_interval,
+            args.metrics_listen.is_some(),
+            ServiceContext::current(),
+            command_future,
+        );
+        let command_future = with_metrics_endpoint(
+            args.metrics_listen,
+            ServiceContext::current(),
+            command_future,
+        );
+        // Boxed so the nested futures do not all end up in the stack frame of main
+        let command_future = Box::pin(with_history(
+            args.name.clone(),
+            args.history_retention,
+            ServiceContext::current(),
+            command_future,
+        ));
+        let command_future = Box::pin(with_status_file(
+            args.name.clone(),
+            args.status_interval,
+            ServiceContext::current(),
+            command_future,
+        ));
+        let command_future = Box::pin(with_alerts(
+            args.name.clone(),
+            args.alert_restarts,
+            detach::alert::Targets {
+                webhooks: args.alert_webhook.clone(),
+                emails: args.alert_email.clone(),
+            },
+            ServiceContext::current(),
+            command_future,
+        ));
+        #[cfg(unix)]
+        let command_future = with_control_socket(
+            control_socket.clone(),
+            args.name.clone().unwrap_or_default(),
+            ServiceContext::current(),
+            command_future,
+        );
+        #[cfg(all(unix, feature = "dbus"))]
+        let command_future = detach::dbus::with_dbus_service(
+            args.dbus,
+            args.name.clone(),
+            ServiceContext::current(),
+            command_future,
+        );
+        #[cfg(all(unix, feature = "grpc"))]
+        let command_future = detach::grpc::with_grpc_service(
+            args.grpc.clone(),
+            grpc_token.clone(),
+            args.name.clone(),
+            ServiceContext::current(),
+            command_future,
+        );
+        let command_future = with_sighup_reload(ServiceContext::current(), command_future);
+        let command_future = with_watchdog(args.watchdog(), command_future);
+        #[cfg(feature = "tokio-console")]
+        let command_future =
+            detach::console::with_tokio_console(args.tokio_console, command_future);
+        let command_future =
+            with_diagnostics_signal(dump_on_usr2, ServiceContext::current(), command_future);
+        let command_future = with_keep_awake(args.keep_awake, "detach-rs command", command_future);
+        let command_future = Box::pin(hold_lock(
+            lock,
+            with_crash_report(crash_report, command_future),
+        ));
+        // A single run has nothing to spread over worker threads, and a current-thread
+        // runtime starts several times faster, which short scheduled jobs notice
+        let single_threaded = !supervised || args.profile == Profile::Minimal;
         if should_detach {
-            debug!("Detaching process... Check logs at {:?}", log_file_path);
-            // daemonize will now handle tokio runtime, logging, and timeout
-            daemonize(
+            debug!("Detaching command... Check logs at {:?}", log_file_path);
+            if single_threaded {
+                return daemonize_local(&log_file_path, log_level, None, command_future);
+            }
+            return daemonize(&log_file_path, log_level, None, command_future);
+        }
+        let rt = if single_threaded {
+            Profile::Minimal.runtime().build().unwrap()
+        } else {
+            args.profile.runtime().build().unwrap()
+        };
+        return rt.block_on(command_future);
+    }
+    // --- END NEW LOGIC ---
+
+    // These debug/info/trace/warn calls should be after setup_logging
+    debug!("debug");
+    info!("info");
+    trace!("trace");
+    warn!("warn");
+
+    // Without a command the daemon sandboxes itself, keeping its own files writable. No
+    // other thread exists yet, and the restriction is inherited across the fork.
+    if !sandbox.is_empty() {
+        let mut own = sandbox.clone();
+        own.write
+            .extend(log_file_path.parent().map(std::path::Path::to_path_buf));
+        #[cfg(unix)]
+        own.write.extend(
+            control_socket
+                .as_deref()
+                .and_then(std::path::Path::parent)
+                .map(std::path::Path::to_path_buf),
+        );
+        #[cfg(all(unix, feature = "grpc"))]
+        if let Some(detach::grpc::Endpoint::Unix(path)) = &args.grpc {
+            own.write
+                .extend(path.parent().map(std::path::Path::to_path_buf));
+        }
+        #[cfg(unix)]
+        if args.queue_worker {
+            own.write
+                .push(detach::queue::Queue::open()?.dir().to_path_buf());
+        }
+        if crash_report.is_some() {
+            let dir = detach::config::state_dir()?.join("crash-reports");
+            std::fs::create_dir_all(&dir)?;
+            own.write.push(dir);
+        }
+        if own.restrict_current_process()? {
+            info!("Filesystem sandbox is in effect.");
+        }
+    }
+
+    // Create the service future: the queue worker, or the built-in heartbeat loop
+    let service: std::pin::Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>> =
+        queue_worker(&args, redactor.clone()).unwrap_or_else(|| Box::pin(run_service_async()));
+    let service = Box::pin(with_state(ServiceContext::current(), service));
+    let service_future = with_metrics_endpoint(
+        args.metrics_listen,
+        ServiceContext::current(),
+        with_sighup_reload(
+            ServiceContext::current(),
+            with_keep_awake(
+                args.keep_awake,
+                "detach-rs service",
+                delayed_start(start_delay, service),
+            ),
+        ),
+    );
+    let service_future = with_memory_stats(
+        args.stats_interval,
+        args.metrics_listen.is_some(),
+        ServiceContext::current(),
+        service_future,
+    );
+    // Boxed so the nested futures do not all end up in the stack frame of main
+    let service_future = Box::pin(with_history(
+        args.name.clone(),
+        args.history_retention,
+        ServiceContext::current(),
+        service_future,
+    ));
+    let service_future = Box::pin(with_status_file(
+        args.name.clone(),
+        args.status_interval,
+        ServiceContext::current(),
+        service_future,
+    ));
+    #[cfg(unix)]
+    let service_future = with_control_socket(
+        control_socket,
+        args.name.clone().unwrap_or_default(),
+        ServiceContext::current(),
+        service_future,
+    );
+    #[cfg(all(unix, feature = "dbus"))]
+    let service_future = detach::dbus::with_dbus_service(
+        args.dbus,
+        args.name.clone(),
+        ServiceContext::current(),
+        service_future,
+    );
+    #[cfg(all(unix, feature = "grpc"))]
+    let service_future = detach::grpc::with_grpc_service(
+        args.grpc.clone(),
+        grpc_token,
+        args.name.clone(),
+        ServiceContext::current(),
+        service_future,
+    );
+    let service_future = with_watchdog(args.watchdog(), service_future);
+    #[cfg(feature = "tokio-console")]
+    let service_future = detach::console::with_tokio_console(args.tokio_console, service_future);
+    let service_future = with_diagnostics_signal(true, ServiceContext::current(), service_future);
+    let service_future = hold_lock(lock, with_crash_report(crash_report, service_future));
+
+    if should_detach {
+        debug!("Detaching process... Check logs at {:?}", log_file_path);
+        // daemonize builds its own tokio runtime after forking, so it has to be called
+        // before any runtime exists in this process.
+        if args.profile == Profile::Minimal {
+            return daemonize_local(
                 &log_file_path,
                 log_level,
-                args.timeout,
+                args.daemon_timeout(),
                 service_future,
-            )?;
-            Ok(())
-        } else {
-            // All setup_logging calls removed from here
-            debug!("Service started. PID: {}", std::process::id());
+            );
+        }
+        return daemonize(
+            &log_file_path,
+            log_level,
+            args.daemon_timeout(),
+            service_future,
+        );
+    }
 
-            // Run the async service directly
+    // Build the tokio runtime for the foreground service
+    let rt = args.profile.runtime().build().unwrap();
+
+    rt.block_on(async {
+        debug!("Service started. PID: {}", std::process::id());
+
+        // Run the async service directly, bounded by the daemon timeout if one is set
+        if let Some(seconds) = args.daemon_timeout() {
+            let limit = std::time::Duration::from_secs(seconds);
+            if tokio::time::timeout(limit, service_future).await.is_err() {
+                debug!(
+                    "Timeout reached after {} seconds. Terminating service.",
+                    seconds
+                );
+            }
+        } else {
             service_future.await?;
+        }
+
+        info!("Service shutting down.");
+        Ok(())
+    })
+}
+
+/// Returns `true` if `--dbus` was given (only available with the `dbus` feature).
+fn dbus_enabled(args: &Args) -> bool {
+    #[cfg(all(unix, feature = "dbus"))]
+    return args.dbus.is_some();
+    #[cfg(not(all(unix, feature = "dbus")))]
+    {
+        let _ = args;
+        false
+    }
+}
 
-            info!("Service shutting down.");
-            Ok(())
+/// Returns `true` if `--grpc` was given (only available with the `grpc` feature).
+fn grpc_enabled(args: &Args) -> bool {
+    #[cfg(all(unix, feature = "grpc"))]
+    return args.grpc.is_some();
+    #[cfg(not(all(unix, feature = "grpc")))]
+    {
+        let _ = args;
+        false
+    }
+}
+
+/// Returns the queue worker future if `--queue-worker` was given (Unix only).
+fn queue_worker(
+    args: &Args,
+    redactor: Option<std::sync::Arc<Redactor>>,
+) -> Option<std::pin::Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>> {
+    #[cfg(unix)]
+    return args.queue_worker.then(|| {
+        let keep_runs = args.keep_runs.map(usize::from);
+        let sanitize = args.sanitize();
+        Box::pin(detach::queue::run_worker(
+            args.max_parallel.into(),
+            keep_runs,
+            sanitize,
+            redactor,
+        )) as _
+    });
+    #[cfg(not(unix))]
+    {
+        let _ = (args, redactor);
+        None
+    }
+}
+
+/// Prints the jobs in the queue as a table.
+#[cfg(unix)]
+fn print_jobs() -> anyhow::Result<()> {
+    let queue = detach::queue::Queue::open()?;
+    let jobs = queue.jobs()?;
+    if jobs.is_empty() {
+        println!("The queue is empty; add jobs with detach-rs enqueue.");
+        return Ok(());
+    }
+    println!(
+        "{:<6} {:<10} {:<5} {:<19} COMMAND",
+        "ID", "STATE", "EXIT", "ENQUEUED"
+    );
+    for job in &jobs {
+        println!(
+            "{:<6} {:<10} {:<5} {:<19} {}",
+            job.id,
+            job.state,
+            job.exit_code
+                .map(|code| code.to_string())
+                .unwrap_or_default(),
+            job.enqueued.format("%Y-%m-%d %H:%M:%S"),
+            job.command_line()
+        );
+    }
+    println!(
+        "Logs: {}",
+        queue.dir().join("logs").join("<ID>.log").display()
+    );
+    Ok(())
+}
+
+/// Prints the status of the instance `name`, or of all running instances, one JSON
+/// object per line.
+#[cfg(unix)]
+fn print_status(name: Option<&str>) -> anyhow::Result<()> {
+    let sockets = match name {
+        Some(name) => vec![(name.to_string(), detach::control::socket_path(name)?)],
+        None => detach::control::list_sockets()?,
+    };
+    if sockets.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No running instances found in {}; start one with --name.",
+            detach::config::runtime_dir()?.display()
+        ));
+    }
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async {
+        for (instance, path) in sockets {
+            match detach::control::query(&path, "status").await {
+                Ok(mut status) => {
+                    if let Some(stats) = history_stats(&instance, true) {
+                        status["stats"] = stats;
+                    }
+                    println!("{}", status);
+                }
+                Err(e) if name.is_some() => {
+                    let Some((state, since)) = ended_state(&instance) else {
+                        return Err(e);
+                    };
+                    let mut status = serde_json::json!({
+                        "name": instance,
+                        "state": state,
+                        "state_since": since,
+                        "running": false,
+                    });
+                    if let Some(stats) = history_stats(&instance, false) {
+                        status["stats"] = stats;
+                    }
+                    println!("{}", status);
+                }
+                Err(e) => eprintln!("Skipping {}: {}", instance, e),
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Prints whether all running instances, or those of the `required` services, are
+/// healthy, failing if they are not; with `listen`, serves that at `/healthz` instead.
+#[cfg(unix)]
+fn check_health(listen: Option<std::net::SocketAddr>, required: &[String]) -> anyhow::Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    if let Some(addr) = listen {
+        return rt.block_on(detach::healthz::serve(addr, required.to_vec()));
+    }
+    let report = rt.block_on(detach::healthz::check(required))?;
+    println!("{}", report);
+    if report["services"].as_array().is_none_or(Vec::is_empty) {
+        return Err(anyhow::anyhow!(
+            "No running instances found in {}; start one with --name.",
+            detach::config::runtime_dir()?.display()
+        ));
+    }
+    if report["healthy"] != true {
+        return Err(anyhow::anyhow!("Not all required instances are healthy."));
+    }
+    Ok(())
+}
+
+/// Returns the running instances `selector` picks, failing if there are none.
+#[cfg(unix)]
+async fn select_instances(
+    selector: &InstanceSelector,
+) -> anyhow::Result<Vec<(String, std::path::PathBuf)>> {
+    let sockets = detach::control::list_sockets()?;
+    if let Some(name) = &selector.name {
+        // A service started with --instances is all of its instances
+        let instances: Vec<_> = sockets
+            .into_iter()
+            .filter(|(instance, _)| detach::instances::belongs_to(instance, name))
+            .collect();
+        if instances.is_empty() {
+            return Ok(vec![(name.clone(), detach::control::socket_path(name)?)]);
+        }
+        return Ok(instances);
+    }
+    let pattern = selector.pattern.as_deref().unwrap_or("*");
+    let mut instances = Vec::new();
+    for (name, path) in sockets {
+        let service = detach::instances::split(&name).0;
+        if !detach::control::glob_match(pattern, &name)
+            && !detach::control::glob_match(pattern, service)
+        {
+            continue;
+        }
+        // Sockets of killed instances are left to `clean`
+        let selected = match detach::control::query(&path, "status").await {
+            Ok(status) => has_tags(&status, &selector.tags),
+            Err(_) => false,
+        };
+        if selected {
+            instances.push((name, path));
+        }
+    }
+    if instances.is_empty() {
+        return Err(match &selector.pattern {
+            Some(pattern) => anyhow::anyhow!("No running instance matches \"{}\".", pattern),
+            None if !selector.tags.is_empty() => {
+                anyhow::anyhow!("No running instance has all the tags given.")
+            }
+            None => anyhow::anyhow!(
+                "No running instances found in {}; start one with --name.",
+                detach::config::runtime_dir()?.display()
+            ),
+        });
+    }
+    Ok(instances)
+}
+
+/// Whether the `status` of an instance has every one of `tags`.
+#[cfg(unix)]
+fn has_tags(status: &serde_json::Value, tags: &[(String, String)]) -> bool {
+    tags.iter()
+        .all(|(key, value)| status["tags"][key].as_str() == Some(value))
+}
+
+/// Sends `request` (`stop` or `restart`) to the instances `selector` picks, after asking
+/// when it is all of them, and reports the result per instance. Fails if any of them
+/// could not be reached or refused.
+///
+/// Several instances are restarted one at a time, each after the one before it is ready
+/// again; one that is not ready within `ready_timeout` ends the rolling restart. With
+/// `wait`, each instance is reported only once its process has exited.
+#[cfg(unix)]
+fn control_instances(
+    selector: &InstanceSelector,
+    request: &str,
+    force: bool,
+    ready_timeout: std::time::Duration,
+    wait: bool,
+) -> anyhow::Result<()> {
+    let done = if request == "restart" {
+        "restarted"
+    } else {
+        "stopped"
+    };
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let instances = rt.block_on(select_instances(selector))?;
+    if selector.all {
+        let names: Vec<&str> = instances.iter().map(|(name, _)| name.as_str()).collect();
+        let action = format!(
+            "{} {} instances ({})",
+            request,
+            names.len(),
+            names.join(", ")
+        );
+        if !detach::confirm::confirm(&action, force)? {
+            println!("Nothing done.");
+            return Ok(());
+        }
+    }
+    let rolling = request == "restart" && instances.len() > 1;
+    let (failed, skipped) = rt.block_on(async {
+        let mut failed = 0;
+        for (done_so_far, (instance, path)) in instances.iter().enumerate() {
+            let previous_child = if rolling {
+                detach::control::query(path, "status")
+                    .await
+                    .ok()
+                    .and_then(|status| status["child_pid"].as_u64())
+            } else {
+                None
+            };
+            // Opened before the request, so the instance cannot exit and leave its PID
+            // to another process before it is held
+            let supervisor = if wait {
+                detach::control::query(path, "status")
+                    .await
+                    .ok()
+                    .and_then(|status| status["pid"].as_u64())
+                    .map(|pid| detach::pidfd::ProcessHandle::open(pid as u32))
+            } else {
+                None
+            };
+            match detach::control::query(path, request).await {
+                Ok(answer) => match answer.get("error").and_then(|e| e.as_str()) {
+                    None if rolling => {
+                        let ready =
+                            detach::control::wait_ready(path, previous_child, ready_timeout);
+                        if let Err(e) = ready.await {
+                            eprintln!("{}: {}", instance, e);
+                            return (failed + 1, instances.len() - done_so_far - 1);
+                        }
+                        println!("{}: {}, ready", instance, done);
+                    }
+                    None => {
+                        if let Some(supervisor) = &supervisor {
+                            supervisor.exited().await;
+                        }
+                        println!("{}: {}", instance, done);
+                    }
+                    Some(error) => {
+                        eprintln!("{}: {}", instance, error);
+                        failed += 1;
+                    }
+                },
+                Err(e) => {
+                    eprintln!("{}: {}", instance, e);
+                    failed += 1;
+                }
+            }
+        }
+        (failed, 0)
+    });
+    if skipped > 0 {
+        return Err(anyhow::anyhow!(
+            "Stopped the rolling restart; {} instances were not restarted",
+            skipped
+        ));
+    }
+    if failed > 0 {
+        return Err(anyhow::anyhow!(
+            "{} of {} instances failed to {}",
+            failed,
+            instances.len(),
+            request
+        ));
+    }
+    Ok(())
+}
+
+/// Prints a table of the instances whose name matches `pattern` (all without one) and
+/// that have all of `tags`, followed by those that have stopped, failed or were killed.
+#[cfg(unix)]
+fn print_list(pattern: Option<&str>, tags: &[(String, String)]) -> anyhow::Result<()> {
+    let sockets: Vec<_> = detach::control::list_sockets()?
+        .into_iter()
+        .filter(|(name, _)| {
+            pattern.is_none_or(|pattern| detach::control::glob_match(pattern, name))
+        })
+        .collect();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    // Instances that have ended have no tags to match
+    let ended: Vec<String> = detach::state::recorded()?
+        .into_iter()
+        .filter(|name| tags.is_empty() && !sockets.iter().any(|(socket, _)| socket == name))
+        .filter(|name| pattern.is_none_or(|pattern| detach::control::glob_match(pattern, name)))
+        .collect();
+    let mut rows = rt.block_on(async {
+        let mut rows = Vec::new();
+        for (name, path) in sockets {
+            let Ok(status) = detach::control::query(&path, "status").await else {
+                if tags.is_empty() {
+                    let state = ended_state(&name).map_or("failed".into(), |(state, _)| state);
+                    let hint = "(run `detach-rs clean`)".to_string();
+                    rows.push([name, "-".into(), state, "-".into(), String::new(), hint]);
+                }
+                continue;
+            };
+            if !has_tags(&status, tags) {
+                continue;
+            }
+            let labels = status["tags"]
+                .as_object()
+                .map(|labels| {
+                    labels
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value.as_str().unwrap_or("")))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                })
+                .unwrap_or_default();
+            let event = &status["last_event"];
+            let last_event = match event["action"].as_str() {
+                Some(action) => format!(
+                    "{} ({}) {}",
+                    action,
+                    event["trigger"].as_str().unwrap_or("?"),
+                    event["time"].as_str().unwrap_or("")
+                ),
+                None => String::new(),
+            };
+            let state = status["state"].as_str().unwrap_or("?").to_string();
+            let health = status["health"].as_str().unwrap_or("?").to_string();
+            let pid = status["pid"].to_string();
+            rows.push([name, pid, state, health, labels, last_event]);
+        }
+        rows
+    });
+    for name in ended {
+        if let Some((state, since)) = ended_state(&name) {
+            let since = format!("since {}", since);
+            rows.push([name, "-".into(), state, "-".into(), String::new(), since]);
+        }
+    }
+    if rows.is_empty() {
+        println!("No instances; start one with --name.");
+        return Ok(());
+    }
+    let last_runs: Vec<String> = rows
+        .iter()
+        .map(|row| match detach::state::read_last_run(&row[0]) {
+            Ok(Some(run)) => run.summary(),
+            _ => "-".to_string(),
+        })
+        .collect();
+    let tags_width = rows
+        .iter()
+        .map(|row| row[4].len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    let run_width = last_runs.iter().map(String::len).max().unwrap_or(0).max(8);
+    println!(
+        "{:<20} {:<8} {:<9} {:<12} {:<tags_width$} {:<run_width$} LAST EVENT",
+        "NAME", "PID", "STATE", "HEALTH", "TAGS", "LAST RUN"
+    );
+    for (row, last_run) in rows.into_iter().zip(last_runs) {
+        let [name, pid, state, health, labels, last_event] = row;
+        println!(
+            "{:<20} {:<8} {:<9} {:<12} {:<tags_width$} {:<run_width$} {}",
+            name, pid, state, health, labels, last_run, last_event
+        );
+    }
+    Ok(())
+}
+
+/// Prints the history of the service `name` from `since` on, as a table with a summary
+/// or as JSON lines.
+fn print_history(
+    name: &str,
+    since: Option<chrono::DateTime<chrono::Local>>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let history = detach::history::read(name, since)?;
+    if json {
+        for entry in &history {
+            println!("{}", entry);
+        }
+        return Ok(());
+    }
+    if history.is_empty() {
+        println!("No recorded events for {}.", name);
+        return Ok(());
+    }
+    println!(
+        "{:<29} {:<12} {:<8} {:<9} {:<8} DETAIL",
+        "TIME", "NAME", "ACTION", "TRIGGER", "STATE"
+    );
+    let text = |entry: &serde_json::Value, key: &str| entry[key].as_str().unwrap_or("").to_string();
+    for entry in &history {
+        let action = if detach::history::is_crash(entry) {
+            "crash".to_string()
+        } else {
+            text(entry, "action")
+        };
+        println!(
+            "{:<29} {:<12} {:<8} {:<9} {:<8} {}",
+            text(entry, "time"),
+            text(entry, "name"),
+            action,
+            text(entry, "trigger"),
+            text(entry, "state"),
+            text(entry, "detail")
+        );
+    }
+    let count = |action: &str| {
+        history
+            .iter()
+            .filter(|entry| entry["action"] == action)
+            .count()
+    };
+    let crashes = history
+        .iter()
+        .filter(|entry| detach::history::is_crash(entry))
+        .count();
+    println!(
+        "\n{} starts, {} crashes, {} restarts, {} stops since {}",
+        count("start"),
+        crashes,
+        count("restart"),
+        count("stop"),
+        since.map_or_else(
+            || text(&history[0], "time"),
+            |since| since.to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+        )
+    );
+    Ok(())
+}
+
+/// Returns the reliability statistics of the instance `name` from its history (see
+/// `history::stats`), counting its last run up to now if it is `running`; `None` without
+/// a history.
+#[cfg(unix)]
+fn history_stats(name: &str, running: bool) -> Option<serde_json::Value> {
+    let history: Vec<serde_json::Value> = detach::history::read(name, None)
+        .ok()?
+        .into_iter()
+        .filter(|entry| entry["name"] == name)
+        .collect();
+    if history.is_empty() {
+        return None;
+    }
+    let now = chrono::Local::now();
+    let stats = detach::history::stats(&history, now, running.then_some(now));
+    Some(stats.to_json())
+}
+
+/// Returns the state the instance `name`, which is not running, ended in and since when,
+/// from the registry. One that never recorded `stopped` or `failed` was killed, which
+/// counts as `failed`.
+#[cfg(unix)]
+fn ended_state(name: &str) -> Option<(String, String)> {
+    let (state, since) = detach::state::read(name).ok()??;
+    let state = if state.is_final() {
+        state
+    } else {
+        detach::ServiceState::Failed
+    };
+    Some((state.to_string(), since))
+}
+
+/// Runs `command` in the context of the instance `name`: its supervised command's while
+/// there is one, its own otherwise.
+#[cfg(unix)]
+fn exec_in(name: &str, command: &[String]) -> anyhow::Result<std::process::ExitStatus> {
+    let path = detach::control::socket_path(name)?;
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let status = rt.block_on(detach::control::query(&path, "status"))?;
+    let pid = status["child_pid"]
+        .as_u64()
+        .or(status["pid"].as_u64())
+        .ok_or_else(|| anyhow::anyhow!("{} did not report its PID", name))?;
+    let context = detach::exec::ProcessContext::read(pid as u32)?;
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("No command given"))?;
+    context.run(program, args)
+}
+
+/// Asks the running instance `name` for its PID.
+#[cfg(unix)]
+fn instance_pid(name: &str) -> anyhow::Result<u32> {
+    let path = detach::control::socket_path(name)?;
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let status = rt.block_on(detach::control::query(&path, "status"))?;
+    status["pid"]
+        .as_u64()
+        .map(|pid| pid as u32)
+        .ok_or_else(|| anyhow::anyhow!("{} did not report its PID", name))
+}
+
+/// Prints the processes below the instance `name`, indented under their parents.
+#[cfg(unix)]
+fn print_tree(name: &str) -> anyhow::Result<()> {
+    let pid = instance_pid(name)?;
+    let tree = detach::proctree::tree(pid, std::time::Duration::from_millis(250))?;
+    println!("{:<8} {:>10} {:>6}  COMMAND", "PID", "RSS", "CPU%");
+    for process in tree {
+        println!(
+            "{:<8} {:>10} {:>6.1}  {}{}",
+            process.pid,
+            format!("{:.1} MiB", process.rss as f64 / (1024.0 * 1024.0)),
+            process.cpu,
+            "  ".repeat(process.depth),
+            process.command
+        );
+    }
+    Ok(())
+}
+
+/// Prints the open descriptors of the instance `name` and the processes below it.
+#[cfg(unix)]
+fn print_fds(name: &str) -> anyhow::Result<()> {
+    let pid = instance_pid(name)?;
+    let pids: Vec<u32> = detach::proctree::tree(pid, std::time::Duration::ZERO)?
+        .iter()
+        .map(|process| process.pid)
+        .collect();
+    let files = detach::fds::open_files(&pids);
+    if files.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Cannot read the descriptors of process {}; reading another user's needs root",
+            pid
+        ));
+    }
+    println!("{:<8} {:>4}  {:<6} DESCRIPTION", "PID", "FD", "TYPE");
+    for file in files {
+        println!(
+            "{:<8} {:>4}  {:<6} {}",
+            file.pid, file.fd, file.kind, file.description
+        );
+    }
+    Ok(())
+}
+
+/// Prints the processes below the instance `name` that hold a terminal it does not, with
+/// advice, failing if there are any.
+#[cfg(unix)]
+fn doctor(name: &str) -> anyhow::Result<()> {
+    let pid = instance_pid(name)?;
+    let found = detach::tty::find(pid)?;
+    if found.is_empty() {
+        println!("No process of {} (PID {}) uses a terminal.", name, pid);
+        return Ok(());
+    }
+    for process in &found {
+        println!("{}.", process);
+    }
+    println!();
+    println!("{}", detach::tty::ADVICE);
+    Err(anyhow::anyhow!(
+        "{} process(es) of {} may block on a terminal",
+        found.len(),
+        name
+    ))
+}
+
+/// Prints how the instance `name` was launched, and whether it is still running, as text
+/// or as the JSON record with `running` added.
+#[cfg(unix)]
+fn print_inspect(name: &str, json: bool) -> anyhow::Result<()> {
+    let mut snapshot = detach::launch::read(name)?;
+    let running = detach::control::is_listening(&detach::control::socket_path(name)?);
+    if json {
+        snapshot["running"] = running.into();
+        println!("{}", serde_json::to_string_pretty(&snapshot)?);
+        return Ok(());
+    }
+    let text = |key: &str| snapshot[key].as_str().unwrap_or("?").to_string();
+    let command_line: Vec<String> = snapshot["command_line"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|arg| shell_quote(arg.as_str().unwrap_or_default()))
+        .collect();
+    println!("Name:         {}", name);
+    println!(
+        "State:        {}",
+        if running { "running" } else { "not running" }
+    );
+    println!("PID:          {}", snapshot["pid"]);
+    println!("Run ID:       {}", text("run_id"));
+    println!("Started:      {}", text("started"));
+    println!("Version:      {}", text("version"));
+    println!(
+        "User:         uid {}, gid {}",
+        snapshot["uid"], snapshot["gid"]
+    );
+    println!("Directory:    {}", text("cwd"));
+    println!("Log file:     {}", text("log_file"));
+    if let Some(ports) = snapshot["ports"]
+        .as_array()
+        .filter(|ports| !ports.is_empty())
+    {
+        let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+        println!("Ports:        {}", ports.join(", "));
+    }
+    println!("Command line: {}", command_line.join(" "));
+    println!("Configuration:");
+    if let Some(configuration) = snapshot["configuration"].as_object() {
+        let options: Vec<(String, String, &str)> = configuration
+            .iter()
+            .filter(|(_, option)| !option["value"].is_null())
+            .map(|(key, option)| {
+                let value = match &option["value"] {
+                    serde_json::Value::String(value) => shell_quote(value),
+                    serde_json::Value::Array(values) => values
+                        .iter()
+                        .map(|value| shell_quote(value.as_str().unwrap_or_default()))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    value => value.to_string(),
+                };
+                let source = option["source"].as_str().unwrap_or("?");
+                (format!("--{}", key), value, source)
+            })
+            .collect();
+        let width = options.iter().map(|(key, ..)| key.len()).max().unwrap_or(0);
+        for (key, value, source) in options {
+            println!("  {:<width$} {} ({})", key, value, source);
+        }
+    }
+    println!("Environment:");
+    if let Some(environment) = snapshot["environment"].as_object() {
+        let mut vars: Vec<_> = environment.iter().collect();
+        vars.sort_by_key(|(key, _)| key.as_str());
+        for (key, value) in vars {
+            println!("  {}={}", key, value.as_str().unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+/// Quotes `arg` for a POSIX shell, leaving plain words as they are.
+#[cfg(unix)]
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Removes the control sockets of killed instances, and the launch records and run logs
+/// of names that have no running instance, after listing them and asking.
+#[cfg(unix)]
+fn clean(force: bool) -> anyhow::Result<()> {
+    let mut running = Vec::new();
+    let mut leftovers = Vec::new();
+    for (name, path) in detach::control::list_sockets()? {
+        if detach::control::is_listening(&path) {
+            running.push(name);
+        } else {
+            leftovers.push(path);
+        }
+    }
+    let is_running = |name: &std::ffi::OsStr| {
+        running
+            .iter()
+            .any(|running| *running == name.to_string_lossy())
+    };
+    if let Ok(entries) = std::fs::read_dir(detach::config::runtime_dir()?) {
+        let mut records: Vec<_> = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| match path.extension() {
+                Some(ext) if ext == "json" || ext == "state" => {
+                    path.file_stem().is_some_and(|name| !is_running(name))
+                }
+                // A `deploy` link to a socket that is gone
+                Some(ext) if ext == "latest" => !path.exists(),
+                _ => false,
+            })
+            .collect();
+        records.sort();
+        leftovers.extend(records);
+    }
+    let runs = detach::config::state_dir()?.join("runs");
+    if let Ok(entries) = std::fs::read_dir(&runs) {
+        let mut logs: Vec<_> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| !is_running(&entry.file_name()))
+            .map(|entry| entry.path())
+            .collect();
+        logs.sort();
+        leftovers.extend(logs);
+    }
+    if leftovers.is_empty() {
+        println!("Nothing to clean.");
+        return Ok(());
+    }
+    if !force {
+        for path in &leftovers {
+            eprintln!("  {}", path.display());
+        }
+    }
+    let action = match leftovers.len() {
+        1 => "remove this path".to_string(),
+        n => format!("remove these {} paths", n),
+    };
+    if !detach::confirm::confirm(&action, force)? {
+        println!("Nothing removed.");
+        return Ok(());
+    }
+    let mut failed = 0;
+    for path in &leftovers {
+        let removed = if path.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        };
+        match removed {
+            Ok(()) => println!("Removed {}", path.display()),
+            Err(e) => {
+                eprintln!("Failed to remove {}: {}", path.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} paths could not be removed", failed));
+    }
+    Ok(())
+}
+
+/// Prints the lifecycle events of the instance `name`, or of all running instances.
+#[cfg(unix)]
+fn print_events(follow: bool, name: Option<&str>) -> anyhow::Result<()> {
+    let sockets = match name {
+        Some(name) => vec![(name.to_string(), detach::control::socket_path(name)?)],
+        None => detach::control::list_sockets()?,
+    };
+    if sockets.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No running instances found in {}; start one with --name.",
+            detach::config::runtime_dir()?.display()
+        ));
+    }
+    let single = name.is_some();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async {
+        let mut readers = tokio::task::JoinSet::new();
+        for (name, path) in sockets {
+            readers.spawn(async move {
+                let result = detach::control::read_events(&path, follow, |line| {
+                    println!("{}", line);
+                })
+                .await;
+                (name, result)
+            });
+        }
+        while let Some(joined) = readers.join_next().await {
+            let (name, result) = joined?;
+            match result {
+                Err(e) if single => return Err(e),
+                Err(e) => eprintln!("Skipping {}: {}", name, e),
+                Ok(()) => {}
+            }
         }
-    }); // End of rt.block_on(async { ... })
-    result // Main function returns the result of the async block
+        Ok(())
+    })
 }
@@ -0,0 +1,268 @@
+//! Taking over a command that outlived its supervisor.
+//!
+//! When the supervisor of an instance goes away without stopping its command (it crashed,
+//! was killed, or its binary was replaced and restarted), the command keeps running, now
+//! a child of init. Starting the instance again would run the command twice. So the
+//! registry record of every instance (`NAME.state`, see `state`) also holds the PID of its
+//! command and when that process started, and a new instance of the same name checks it
+//! before starting anything: if the old supervisor is gone but the recorded process still
+//! runs, with the same start time so that a reused PID is never mistaken for it, and the
+//! command string is unchanged, the supervisor adopts that process as its first run
+//! instead of starting a duplicate.
+//!
+//! An adopted process is not a child of its new supervisor, so its exit status cannot be
+//! collected: the supervisor waits for it to end through a pidfd (polling on kernels
+//! without `pidfd_open`, see `pidfd`) and reports the exit as "exit status unknown".
+//! Forwarded signals, reloads, stop and restart requests and the timeouts reach it as
+//! usual; once it has exited or was restarted, later runs are ordinary children again.
+//! Output that went through the old supervisor (with `--output-tail` or `--max-output`) has
+//! nowhere to go, so such a command may die of SIGPIPE on its next write. The start time
+//! comes from `/proc`, so adoption is only available on Linux.
+use crate::command::RunOptions;
+use crate::context::ServiceContext;
+use crate::pidfd::ProcessHandle;
+#[cfg(unix)]
+use crate::signal::{SIGHUP, SIGINT, SIGKILL};
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Without Unix signals nothing is delivered; these only keep `watch` the same
+#[cfg(not(unix))]
+const SIGHUP: i32 = 1;
+#[cfg(not(unix))]
+const SIGINT: i32 = 2;
+#[cfg(not(unix))]
+const SIGKILL: i32 = 9;
+
+/// A running command left behind by an earlier supervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Orphan {
+    /// Its PID
+    pub pid: u32,
+    /// When it started, in clock ticks after boot (see `start_time`)
+    pub start_time: u64,
+    /// The PID of the supervisor that started it
+    pub supervisor: u32,
+}
+
+/// How the run of an adopted command ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adopted {
+    /// How long it was watched, from adoption until it exited
+    pub duration: Duration,
+    /// Whether it was stopped because `RunOptions::timeout` expired, or was interrupted
+    /// through `RunOptions::interrupt`
+    pub timed_out: bool,
+}
+
+/// Returns when the process `pid` started, in clock ticks after boot, which together with
+/// the PID identifies a process across PID reuse.
+///
+/// # Returns
+/// - `Some(u64)`: The `starttime` field of `/proc/PID/stat`.
+/// - `None`: If the process does not exist, or there is no `/proc` to read it from.
+#[cfg(target_os = "linux")]
+pub fn start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The fields after the name, which may contain anything, start with field 3
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+/// Start times come from `/proc`, which only Linux provides.
+#[cfg(not(target_os = "linux"))]
+pub fn start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+/// Looks for a command of the instance `name` that is still running although the
+/// supervisor that started it is gone.
+///
+/// # Arguments
+/// - `name`: The instance about to be started.
+/// - `command`: Its command string; a process running another command is left alone.
+///
+/// # Returns
+/// - `Some(Orphan)`: The process to adopt.
+/// - `None`: If the registry records no running command, its supervisor is alive, the
+///   process has exited (or its PID was reused), or the command has changed.
+pub fn find(name: &str, command: &str) -> Option<Orphan> {
+    let path = crate::state::registry_path(name).ok()?;
+    let record: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+    let orphan = Orphan {
+        pid: u32::try_from(record["child_pid"].as_u64()?).ok()?,
+        start_time: record["child_start_time"].as_u64()?,
+        supervisor: u32::try_from(record["pid"].as_u64()?).ok()?,
+    };
+    if orphan.supervisor == std::process::id()
+        || is_alive(orphan.supervisor)
+        || !is_running(&orphan)
+    {
+        return None;
+    }
+    let launched = crate::launch::read(name).ok();
+    let previous = launched
+        .as_ref()
+        .and_then(|launch| launch["command"].as_str());
+    if previous != Some(command) {
+        warn!(
+            "The command of an earlier {} (PID {}) is still running, but it is not \"{}\". \
+             Leaving it alone.",
+            name, orphan.pid, command
+        );
+        return None;
+    }
+    Some(orphan)
+}
+
+/// Returns `true` while the process of `orphan` exists: its PID is alive and started
+/// at the recorded time.
+pub fn is_running(orphan: &Orphan) -> bool {
+    is_alive(orphan.pid) && start_time(orphan.pid) == Some(orphan.start_time)
+}
+
+/// Returns `true` if the process `pid` exists and has not exited; a supervisor that was
+/// killed stays a zombie until init reaps it, which may take a moment.
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    crate::signal::is_alive(pid) && !is_zombie(pid)
+}
+
+#[cfg(target_os = "linux")]
+fn is_zombie(pid: u32) -> bool {
+    let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+        return false;
+    };
+    // The state is field 3, right after the name
+    let state = stat
+        .rfind(')')
+        .and_then(|close| stat[close + 1..].split_whitespace().next());
+    state == Some("Z")
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn is_zombie(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    false
+}
+
+/// Supervises `orphan` until it exits, like `run_command` supervises a command it has
+/// started.
+///
+/// Of `opts`, the timeout and interrupt, grace period, reload requests, signals and process group
+/// apply; the process is already running, so everything about starting it does not.
+pub async fn watch(orphan: &Orphan, mut opts: RunOptions) -> Adopted {
+    let clock = &*opts.clock;
+    let started = clock.monotonic();
+    let ctx = ServiceContext::current();
+    let child = Arc::new(ProcessHandle::open(orphan.pid));
+    ctx.set_child(Some(child.clone()));
+    let mut signals = opts.signals.as_ref().map(|signals| signals.subscribe());
+    let mut timed_out = false;
+    let mut expired = std::pin::pin!(crate::command::expired(
+        clock,
+        opts.timeout,
+        opts.interrupt.clone()
+    ));
+    let mut exited = std::pin::pin!(exited(orphan, &child));
+    loop {
+        tokio::select! {
+            () = &mut exited => break,
+            limit = &mut expired, if !timed_out => {
+                timed_out = true;
+                warn!(
+                    "{}. Attempting graceful shutdown (SIGINT).",
+                    crate::command::cut_short(limit)
+                );
+                deliver(orphan, &child, opts.process_group, SIGINT);
+                if crate::clock::timeout(clock, opts.grace_period, &mut exited).await.is_ok() {
+                    break;
+                }
+                warn!("Process did not exit after SIGINT. Sending SIGKILL.");
+                deliver(orphan, &child, opts.process_group, SIGKILL);
+            }
+            changed = changed(&mut opts.reload) => {
+                if changed.is_err() {
+                    opts.reload = None;
+                    continue;
+                }
+                info!("Forwarding reload to command as SIGHUP.");
+                deliver(orphan, &child, opts.process_group, SIGHUP);
+            }
+            received = recv(&mut signals) => match received {
+                Ok(signal) => deliver(orphan, &child, opts.process_group, signal),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => signals = None,
+            },
+        }
+    }
+    let duration = clock.monotonic().saturating_sub(started);
+    ctx.set_child(None);
+    info!(
+        "Adopted command (PID {}) exited after {:?}; its exit status is unknown.",
+        orphan.pid, duration
+    );
+    Adopted {
+        duration,
+        timed_out,
+    }
+}
+
+/// Sends `signal` to `orphan`, or to its process group, unless it has exited.
+fn deliver(orphan: &Orphan, child: &ProcessHandle, process_group: bool, signal: i32) {
+    if !child.has_pidfd() && !is_running(orphan) {
+        return;
+    }
+    #[cfg(unix)]
+    {
+        let result = if process_group {
+            crate::signal::send_signal_group(orphan.pid, signal)
+        } else {
+            child.signal(signal)
+        };
+        if let Err(e) = result {
+            warn!("Failed to send signal {} to command: {}", signal, e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (process_group, signal);
+}
+
+/// Returns once `orphan`, held by `child`, has exited.
+async fn exited(orphan: &Orphan, child: &ProcessHandle) {
+    // The PID may have been reused before `child` was opened
+    if !is_running(orphan) {
+        return;
+    }
+    #[cfg(unix)]
+    if child.has_pidfd() {
+        return child.exited().await;
+    }
+    while is_running(orphan) {
+        tokio::time::sleep(crate::pidfd::POLL_INTERVAL).await;
+    }
+}
+
+async fn changed(
+    reload: &mut Option<crate::context::ReloadReceiver>,
+) -> Result<(), tokio::sync::watch::error::RecvError> {
+    match reload {
+        Some(rx) => rx.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn recv(
+    signals: &mut Option<tokio::sync::broadcast::Receiver<i32>>,
+) -> Result<i32, tokio::sync::broadcast::error::RecvError> {
+    match signals {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
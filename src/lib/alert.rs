@@ -0,0 +1,455 @@
+//! Alerts when a service restarts more often than it should.
+//!
+//! Some services restart now and then and are fine; others are stuck in a crash loop. A
+//! threshold such as `--alert-restarts 5/10m` tells them apart: the supervisor alerts when
+//! the command was started again more than 5 times within 10 minutes. Restarts are
+//! counted from the event history (see `history`), so those of earlier runs of the
+//! daemon count as well, and then from the lifecycle events as they happen.
+//!
+//! An alert is sent once when the threshold is crossed, logged, and delivered to every
+//! `--alert-webhook` as a JSON `POST` and to every `--alert-email` address through the
+//! system `sendmail`:
+//!
+//! ```text
+//! {"alert":"restarts","name":"web","host":"db1","restarts":6,"threshold":5,"window_secs":600,"time":"2026-10-16T03:00:01.204+02:00","last_event":{...}}
+//! ```
+//!
+//! Webhooks are posted with the system `curl`, like `remote` uses `ssh`, so HTTPS works
+//! without TLS support in detach-rs; they need the `http` feature, mail does not. A
+//! delivery that fails is tried again, up to `DELIVERY_ATTEMPTS` times in all, after the
+//! delays of `DELIVERY_BACKOFF`. The alert is sent again only after the restarts in the
+//! window have dropped back to the threshold.
+//!
+//! The targets are also alerted, with or without a threshold, when the log file cannot be
+//! written (see `logfile`): once when writes start failing, with `"alert":"log-error"` and
+//! the `detail` of the `log-error` event, as nothing else may ever read about it.
+use crate::audit::{AuditAction, LifecycleEvent};
+use crate::backoff::BackoffPolicy;
+use crate::context::ServiceContext;
+use crate::tasks::TaskGroup;
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::error::RecvError;
+
+/// How long one attempt at delivering an alert may take before it is given up.
+pub const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many times delivering an alert to one target is tried before it is given up.
+pub const DELIVERY_ATTEMPTS: u32 = 3;
+
+/// The delays between attempts at delivering an alert.
+pub const DELIVERY_BACKOFF: BackoffPolicy = BackoffPolicy::Exponential {
+    initial: Duration::from_secs(2),
+    max: Duration::from_secs(30),
+};
+
+/// "More than `restarts` restarts within `window`", as given to `--alert-restarts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Threshold {
+    /// How many restarts are still fine
+    pub restarts: usize,
+    /// How far back restarts are counted
+    pub window: Duration,
+}
+
+impl Threshold {
+    /// Parses a threshold such as `5/10m`: more than 5 restarts in 10 minutes.
+    ///
+    /// ```
+    /// use detach::alert::Threshold;
+    /// use std::time::Duration;
+    ///
+    /// let threshold = Threshold::parse("5/10m").unwrap();
+    /// assert_eq!(threshold.restarts, 5);
+    /// assert_eq!(threshold.window, Duration::from_secs(600));
+    /// assert!(Threshold::parse("5").is_err());
+    /// ```
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let (restarts, window) = input.trim().split_once('/').ok_or_else(|| {
+            anyhow::anyhow!(
+                "Invalid alert threshold \"{}\": expected RESTARTS/WINDOW, e.g. \"5/10m\"",
+                input
+            )
+        })?;
+        let restarts = restarts.trim().parse().map_err(|e| {
+            anyhow::anyhow!("Invalid number of restarts \"{}\": {}", restarts.trim(), e)
+        })?;
+        let window = crate::schedule::parse_duration(window)?;
+        if window.is_zero() {
+            return Err(anyhow::anyhow!(
+                "Invalid alert threshold \"{}\": the window must not be zero",
+                input
+            ));
+        }
+        Ok(Threshold { restarts, window })
+    }
+}
+
+/// Where alerts are delivered.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Targets {
+    /// URLs a JSON alert is posted to
+    pub webhooks: Vec<String>,
+    /// Addresses an alert is mailed to
+    pub emails: Vec<String>,
+}
+
+impl Targets {
+    /// Returns `true` if alerts go nowhere but the log.
+    pub fn is_empty(&self) -> bool {
+        self.webhooks.is_empty() && self.emails.is_empty()
+    }
+}
+
+/// Runs `future` while watching the restarts of the instance `name` against `threshold`,
+/// alerting `targets` when it is crossed or when the log file cannot be written.
+///
+/// Without a `threshold` or `targets`, this is a plain `future.await`. Alerts are delivered in the
+/// background; a failed delivery is logged and never stops the service. Deliveries still
+/// under way when `future` completes are waited for, so the alert about a service that
+/// gave up is not lost.
+pub async fn with_alerts<F>(
+    name: Option<String>,
+    threshold: Option<Threshold>,
+    targets: Targets,
+    ctx: ServiceContext,
+    future: F,
+) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    // `future` is awaited in one place only, so it is stored once in this future
+    let name = name.unwrap_or_default();
+    let mut restarts = threshold.map(|threshold| Restarts::from_history(&name, threshold));
+    let mut watching = restarts.is_some() || !targets.is_empty();
+    let mut events = ctx.subscribe_events();
+    let deliveries = TaskGroup::new();
+    tokio::pin!(future);
+    loop {
+        tokio::select! {
+            result = &mut future => {
+                deliveries.join().await;
+                return result;
+            }
+            event = events.recv(), if watching => match event {
+                Ok(event) if event.action == AuditAction::LogError => {
+                    if !targets.is_empty() {
+                        alert_log_error(&name, &event, &targets, &deliveries);
+                    }
+                }
+                Ok(event) => {
+                    let Some(restarts) = &mut restarts else {
+                        continue;
+                    };
+                    if let Some(count) = restarts.observe(&event) {
+                        let threshold = restarts.threshold;
+                        alert_restarts(&name, count, threshold, &event, &targets, &deliveries);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("{} lifecycle events were not checked for alerts.", missed);
+                }
+                Err(RecvError::Closed) => watching = false,
+            },
+        }
+    }
+}
+
+/// The restarts within the window of a threshold.
+struct Restarts {
+    threshold: Threshold,
+    /// When the command was started again, oldest first
+    times: VecDeque<DateTime<Local>>,
+    /// Whether the command has been started before, so the next start is a restart
+    started: bool,
+    /// Whether the threshold is crossed and has been alerted about
+    alerted: bool,
+}
+
+impl Restarts {
+    /// Starts counting with the restarts of `name` recorded in its history.
+    fn from_history(name: &str, threshold: Threshold) -> Self {
+        let mut restarts = Restarts {
+            threshold,
+            times: VecDeque::new(),
+            started: false,
+            alerted: false,
+        };
+        let history = crate::history::read(name, None).unwrap_or_else(|e| {
+            warn!("Cannot read the event history of {}: {}", name, e);
+            Vec::new()
+        });
+        for entry in history.iter().filter(|entry| entry["name"] == name) {
+            let time = entry["time"]
+                .as_str()
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok());
+            if let (Some(time), true) = (time, entry["action"] == "start") {
+                restarts.start(time.with_timezone(&Local));
+            }
+        }
+        restarts
+    }
+
+    /// Counts a start at `time`.
+    fn start(&mut self, time: DateTime<Local>) {
+        if std::mem::replace(&mut self.started, true) {
+            self.times.push_back(time);
+        }
+    }
+
+    /// Counts `event` and returns the number of restarts in the window if it has just
+    /// crossed the threshold.
+    fn observe(&mut self, event: &LifecycleEvent) -> Option<usize> {
+        if event.action != AuditAction::Start {
+            return None;
+        }
+        self.start(event.time);
+        let window = chrono::Duration::from_std(self.threshold.window).ok();
+        if let Some(cutoff) = window.and_then(|window| event.time.checked_sub_signed(window)) {
+            while self.times.front().is_some_and(|&time| time <= cutoff) {
+                self.times.pop_front();
+            }
+        }
+        let count = self.times.len();
+        if count <= self.threshold.restarts {
+            self.alerted = false;
+            return None;
+        }
+        (!std::mem::replace(&mut self.alerted, true)).then_some(count)
+    }
+}
+
+/// Alerts about `count` restarts of `name`.
+fn alert_restarts(
+    name: &str,
+    count: usize,
+    threshold: Threshold,
+    event: &LifecycleEvent,
+    targets: &Targets,
+    deliveries: &TaskGroup,
+) {
+    let window = humantime::format_duration(threshold.window);
+    let summary = format!(
+        "{} restarted {} times within {} (alert threshold: {})",
+        name, count, window, threshold.restarts
+    );
+    let payload = serde_json::json!({
+        "alert": "restarts",
+        "name": name,
+        "host": crate::kv::hostname(),
+        "restarts": count,
+        "threshold": threshold.restarts,
+        "window_secs": threshold.window.as_secs(),
+        "time": event.time.to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+        "last_event": event.to_json(),
+    });
+    alert(&summary, &payload, targets, deliveries);
+}
+
+/// Alerts about the log file of `name` failing, as the `log-error` `event` says.
+fn alert_log_error(name: &str, event: &LifecycleEvent, targets: &Targets, deliveries: &TaskGroup) {
+    let who = if name.is_empty() { "detach-rs" } else { name };
+    let summary = format!("{}: {}", who, event.detail);
+    let payload = serde_json::json!({
+        "alert": "log-error",
+        "name": name,
+        "host": crate::kv::hostname(),
+        "detail": event.detail,
+        "time": event.time.to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+        "last_event": event.to_json(),
+    });
+    alert(&summary, &payload, targets, deliveries);
+}
+
+/// Logs an alert and delivers `payload` to `targets` in the background, as tasks of
+/// `deliveries`; mail has `summary` as its subject.
+fn alert(summary: &str, payload: &serde_json::Value, targets: &Targets, deliveries: &TaskGroup) {
+    warn!("Alert: {}", summary);
+    for url in &targets.webhooks {
+        let (url, payload) = (url.clone(), payload.to_string());
+        deliveries.spawn("alert webhook", async move {
+            match deliver(|| post(&url, &payload)).await {
+                Ok(()) => info!("Alert posted to {}", url),
+                Err(e) => warn!("Failed to post the alert to {}: {}", url, e),
+            }
+        });
+    }
+    for address in &targets.emails {
+        let message = format!(
+            "To: {}\nSubject: [detach-rs] {}\nContent-Type: application/json\n\n{:#}\n",
+            address, summary, payload
+        );
+        let address = address.clone();
+        deliveries.spawn("alert email", async move {
+            match deliver(|| mail(&message)).await {
+                Ok(()) => info!("Alert mailed to {}", address),
+                Err(e) => warn!("Failed to mail the alert to {}: {}", address, e),
+            }
+        });
+    }
+}
+
+/// Runs a delivery until it succeeds or `DELIVERY_ATTEMPTS` attempts failed, giving each
+/// attempt up after `DELIVERY_TIMEOUT`.
+async fn deliver<F, Fut>(mut delivery: F) -> Result<(), anyhow::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<(), anyhow::Error>>,
+{
+    crate::backoff::retry(&DELIVERY_BACKOFF, DELIVERY_ATTEMPTS, || {
+        let attempt = delivery();
+        async {
+            tokio::time::timeout(DELIVERY_TIMEOUT, attempt)
+                .await
+                .map_err(|_| anyhow::anyhow!("timed out after {:?}", DELIVERY_TIMEOUT))?
+        }
+    })
+    .await
+}
+
+/// Posts `payload` as JSON to `url` with `curl`.
+#[cfg(feature = "http")]
+async fn post(url: &str, payload: &str) -> Result<(), anyhow::Error> {
+    let mut command = tokio::process::Command::new("curl");
+    command.args([
+        "--silent",
+        "--show-error",
+        "--fail",
+        "--max-time",
+        &DELIVERY_TIMEOUT.as_secs().to_string(),
+        "--header",
+        "Content-Type: application/json",
+        "--data-binary",
+        "@-",
+        "--",
+        url,
+    ]);
+    pipe(command, payload).await
+}
+
+#[cfg(not(feature = "http"))]
+async fn post(url: &str, payload: &str) -> Result<(), anyhow::Error> {
+    let _ = (url, payload);
+    Err(anyhow::anyhow!(
+        "webhooks need the http feature, which this build lacks"
+    ))
+}
+
+/// Hands `message`, with its headers, to `sendmail -t`.
+async fn mail(message: &str) -> Result<(), anyhow::Error> {
+    let mut command = tokio::process::Command::new("sendmail");
+    command.arg("-t");
+    pipe(command, message).await
+}
+
+/// Runs `command` with `input` on its stdin and fails unless it exits successfully.
+async fn pipe(mut command: tokio::process::Command, input: &str) -> Result<(), anyhow::Error> {
+    let program = command
+        .as_std()
+        .get_program()
+        .to_string_lossy()
+        .into_owned();
+    command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = crate::reaper::spawn(&mut command)
+        .map_err(|e| anyhow::anyhow!("cannot run {}: {}", program, e))?;
+    let pid = child.id();
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes()).await?;
+    }
+    let output = child.wait_with_output().await;
+    crate::reaper::release(pid);
+    let output = output?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "{} {}: {}",
+            program,
+            crate::command::ExitReason::from_status(output.status),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditTrigger;
+    use crate::state::ServiceState;
+
+    /// Counts restarts against "more than 2 within a minute", with no history.
+    fn restarts() -> Restarts {
+        Restarts {
+            threshold: Threshold::parse("2/1m").unwrap(),
+            times: VecDeque::new(),
+            started: false,
+            alerted: false,
+        }
+    }
+
+    /// A lifecycle event with `action`, `secs` seconds into the day.
+    fn event(action: AuditAction, secs: i64) -> LifecycleEvent {
+        use chrono::TimeZone;
+        let midnight = Local.with_ymd_and_hms(2026, 10, 16, 0, 0, 0).unwrap();
+        LifecycleEvent {
+            time: midnight + chrono::Duration::seconds(secs),
+            action,
+            trigger: AuditTrigger::Command,
+            detail: String::new(),
+            state: ServiceState::Starting,
+        }
+    }
+
+    /// Starts at `secs`, and what `observe` returns for each.
+    fn observe(restarts: &mut Restarts, secs: &[i64]) -> Vec<Option<usize>> {
+        secs.iter()
+            .map(|&secs| restarts.observe(&event(AuditAction::Start, secs)))
+            .collect()
+    }
+
+    #[test]
+    fn parse_reads_restarts_and_window() {
+        let threshold = Threshold::parse(" 3 / 1h ").unwrap();
+        assert_eq!(threshold.restarts, 3);
+        assert_eq!(threshold.window, Duration::from_secs(3600));
+        for invalid in ["3", "x/1h", "-1/1h", "3/soon", "3/0s"] {
+            assert!(Threshold::parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn alerts_once_when_restarts_cross_the_threshold() {
+        let mut restarts = restarts();
+        // The first start is not a restart; the third restart crosses "more than 2"
+        let seen = observe(&mut restarts, &[0, 10, 20, 30, 40]);
+        assert_eq!(seen, [None, None, None, Some(3), None]);
+    }
+
+    #[test]
+    fn restarts_age_out_of_the_window_and_rearm_the_alert() {
+        let mut restarts = restarts();
+        assert_eq!(
+            observe(&mut restarts, &[0, 10, 20, 30]).last(),
+            Some(&Some(3))
+        );
+        // More than a minute after them, the earlier restarts no longer count
+        let seen = observe(&mut restarts, &[100, 110, 120]);
+        assert_eq!(seen, [None, None, Some(3)]);
+    }
+
+    #[test]
+    fn only_starts_count() {
+        let mut restarts = restarts();
+        for secs in 0..10 {
+            let stop = event(AuditAction::Stop, secs);
+            assert_eq!(restarts.observe(&stop), None);
+        }
+        assert!(restarts.times.is_empty() && !restarts.started);
+    }
+}
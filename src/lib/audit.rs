@@ -0,0 +1,222 @@
+//! An append-only audit trail of what the supervisor did and why.
+//!
+//! The service's own log says what the service did; after an incident the question is
+//! often what happened *to* it: who started it, which restarts were scheduled, which
+//! signals were passed on, when a reload was requested. `AuditLog` writes one JSON object
+//! per line to a separate file, opened in append mode so earlier entries are never
+//! rewritten:
+//!
+//! ```text
+//! {"time":"2025-01-01T03:00:00.000+01:00","action":"restart","trigger":"schedule","detail":"run #2","pid":4242,"run_id":"5f0c…","user":"deploy"}
+//! ```
+//!
+//! The same entries are published as `LifecycleEvent`s on the process-wide
+//! `ServiceContext`, where `detach-rs events` picks them up through the control socket.
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// What the supervisor did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    /// The command was started
+    Start,
+    /// A command left running by an earlier supervisor was taken over (see `adopt`)
+    Adopt,
+    /// The command has kept running long enough to count as ready
+    Ready,
+    /// The command was stopped and will not be started again
+    Stop,
+    /// The command was stopped to be started again
+    Restart,
+    /// The command exited by itself
+    Exit,
+    /// A signal was sent to the command
+    Signal,
+    /// A configuration reload was requested
+    Reload,
+    /// The log file could not be written (see `logfile`)
+    LogError,
+}
+
+/// What caused an action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditTrigger {
+    /// The command line `detach-rs` was started with
+    Cli,
+    /// A signal sent to the supervisor
+    Signal,
+    /// The restart schedule
+    Schedule,
+    /// The run timeout
+    Timeout,
+    /// The end of the supervisor lifetime
+    Lifetime,
+    /// A resource limit, such as the command's output budget or a full disk
+    Limit,
+    /// The command itself
+    Command,
+    /// A management interface such as D-Bus
+    Control,
+}
+
+/// One thing the supervisor did, as published on `ServiceContext::subscribe_events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LifecycleEvent {
+    /// When it happened
+    pub time: chrono::DateTime<chrono::Local>,
+    /// What was done
+    pub action: AuditAction,
+    /// What caused it
+    pub trigger: AuditTrigger,
+    /// Free-form context such as the run number, signal or exit status
+    pub detail: String,
+    /// The state the instance was in right after it
+    pub state: crate::state::ServiceState,
+}
+
+impl LifecycleEvent {
+    /// Creates an event happening now, in the current state of the process-wide
+    /// `ServiceContext`.
+    pub fn new(action: AuditAction, trigger: AuditTrigger, detail: &str) -> Self {
+        LifecycleEvent {
+            time: chrono::Local::now(),
+            action,
+            trigger,
+            detail: detail.to_string(),
+            state: crate::context::ServiceContext::current().state().get(),
+        }
+    }
+
+    /// Renders the event as a JSON object with `time`, `action`, `trigger`, `detail`,
+    /// `state`, the supervisor's `pid` and the `run_id` of this start of the daemon.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "time": self.time.to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            "action": self.action.as_str(),
+            "trigger": self.trigger.as_str(),
+            "detail": self.detail,
+            "state": self.state.as_str(),
+            "pid": std::process::id(),
+            "run_id": crate::context::run_id(),
+        })
+    }
+}
+
+impl AuditAction {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::Start => "start",
+            AuditAction::Adopt => "adopt",
+            AuditAction::Ready => "ready",
+            AuditAction::Stop => "stop",
+            AuditAction::Restart => "restart",
+            AuditAction::Exit => "exit",
+            AuditAction::Signal => "signal",
+            AuditAction::Reload => "reload",
+            AuditAction::LogError => "log-error",
+        }
+    }
+}
+
+impl AuditTrigger {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            AuditTrigger::Cli => "cli",
+            AuditTrigger::Signal => "signal",
+            AuditTrigger::Schedule => "schedule",
+            AuditTrigger::Timeout => "timeout",
+            AuditTrigger::Lifetime => "lifetime",
+            AuditTrigger::Limit => "limit",
+            AuditTrigger::Command => "command",
+            AuditTrigger::Control => "control",
+        }
+    }
+}
+
+/// An open audit file. Clones share the file.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    file: Arc<Mutex<File>>,
+    path: PathBuf,
+    user: String,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, creating it (mode `0640` on Unix) if needed.
+    ///
+    /// The user who started `detach-rs` (`SUDO_USER`, else `USER`, else the numeric uid)
+    /// is recorded with every entry.
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o640);
+        let file = options
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open audit log {}: {}", path.display(), e))?;
+        Ok(AuditLog {
+            file: Arc::new(Mutex::new(file)),
+            path: path.to_path_buf(),
+            user: invoking_user(),
+        })
+    }
+
+    /// Returns the path of the audit file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends one entry. Write errors are logged and otherwise ignored, so a full disk
+    /// does not take the supervised command down with it.
+    ///
+    /// # Arguments
+    /// - `action`: What was done.
+    /// - `trigger`: What caused it.
+    /// - `detail`: Free-form context such as the run number, signal or exit status.
+    pub fn record(&self, action: AuditAction, trigger: AuditTrigger, detail: &str) {
+        self.write(&LifecycleEvent::new(action, trigger, detail));
+    }
+
+    /// Appends `event`, like `record`.
+    pub fn write(&self, event: &LifecycleEvent) {
+        let mut entry = event.to_json();
+        entry["user"] = self.user.clone().into();
+        let mut line = entry.to_string();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Failed to write audit log {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Publishes an event on the current `ServiceContext` and records it in `audit` if set.
+pub(crate) fn record(
+    audit: Option<&AuditLog>,
+    action: AuditAction,
+    trigger: AuditTrigger,
+    detail: &str,
+) {
+    let event = LifecycleEvent::new(action, trigger, detail);
+    if let Some(audit) = audit {
+        audit.write(&event);
+    }
+    crate::context::ServiceContext::current().publish_event(event);
+}
+
+fn invoking_user() -> String {
+    for var in ["SUDO_USER", "USER", "USERNAME"] {
+        if let Ok(user) = std::env::var(var)
+            && !user.is_empty()
+        {
+            return user;
+        }
+    }
+    #[cfg(unix)]
+    return format!("uid {}", unsafe { libc::getuid() });
+    #[cfg(not(unix))]
+    return "unknown".to_string();
+}
@@ -0,0 +1,329 @@
+//! Delays between retries of something that failed.
+//!
+//! A `BackoffPolicy` says how long to wait before each retry; `BackoffPolicy::backoff`
+//! turns it into a `Backoff`, which yields the successive delays and starts over after
+//! `reset`. Three policies are offered:
+//!
+//! - `Fixed`: the same delay every time.
+//! - `Exponential`: the delay doubles from `initial` up to `max`, and each delay is drawn
+//!   from its upper half so that clients failing together do not retry together.
+//! - `Decorrelated`: each delay is drawn between `base` and three times the previous one,
+//!   capped at `max` ("decorrelated jitter"), which spreads retries out the most.
+//!
+//! `retry` runs a fallible future until it succeeds or runs out of attempts; alert
+//! delivery (see `alert`) retries with it:
+//!
+//! ```
+//! use detach::backoff::{BackoffPolicy, retry};
+//! use std::time::Duration;
+//!
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let policy = BackoffPolicy::exponential(Duration::from_millis(1), Duration::from_millis(10));
+//! let mut calls = 0;
+//! let answer = retry(&policy, 5, || {
+//!     calls += 1;
+//!     let failing = calls < 3;
+//!     async move { if failing { Err("not yet") } else { Ok(42) } }
+//! })
+//! .await;
+//! assert_eq!(answer, Ok(42));
+//! assert_eq!(calls, 3);
+//! # });
+//! ```
+use crate::clock::{Clock, SystemClock};
+use crate::schedule::parse_duration;
+use log::debug;
+use std::time::Duration;
+
+/// How long to wait before each retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackoffPolicy {
+    /// Wait the same time before every retry
+    Fixed(Duration),
+    /// Double the delay after every failure, from `initial` up to `max`, with jitter
+    Exponential {
+        /// The delay before the first retry
+        initial: Duration,
+        /// The longest delay
+        max: Duration,
+    },
+    /// Draw each delay between `base` and three times the previous delay, up to `max`
+    Decorrelated {
+        /// The shortest delay, and the delay the first retry is based on
+        base: Duration,
+        /// The longest delay
+        max: Duration,
+    },
+}
+
+impl BackoffPolicy {
+    /// A policy that doubles the delay from `initial` up to `max`.
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        BackoffPolicy::Exponential { initial, max }
+    }
+
+    /// A policy with decorrelated jitter between `base` and `max`.
+    pub fn decorrelated(base: Duration, max: Duration) -> Self {
+        BackoffPolicy::Decorrelated { base, max }
+    }
+
+    /// Parses a policy such as `fixed:5s`, `exponential:1s-5m` or `decorrelated:1s-5m`.
+    ///
+    /// Each duration is parsed with `parse_duration`, so bare numbers are seconds. A range
+    /// gives the first and the longest delay.
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let input = input.trim();
+        let invalid = || {
+            anyhow::anyhow!(
+                "Invalid backoff \"{}\": expected e.g. \"fixed:5s\", \"exponential:1s-5m\" or \"decorrelated:1s-5m\"",
+                input
+            )
+        };
+        let (kind, delays) = input.split_once(':').ok_or_else(invalid)?;
+        if kind == "fixed" {
+            return Ok(BackoffPolicy::Fixed(parse_duration(delays)?));
+        }
+        let (first, max) = delays.split_once('-').ok_or_else(invalid)?;
+        let (first, max) = (parse_duration(first)?, parse_duration(max)?);
+        if first > max {
+            return Err(anyhow::anyhow!(
+                "Invalid backoff \"{}\": the first delay is longer than the longest",
+                input
+            ));
+        }
+        match kind {
+            "exponential" => Ok(BackoffPolicy::exponential(first, max)),
+            "decorrelated" => Ok(BackoffPolicy::decorrelated(first, max)),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Returns the delays of this policy, starting with the one before the first retry.
+    pub fn backoff(&self) -> Backoff {
+        Backoff {
+            policy: *self,
+            attempt: 0,
+            previous: Duration::ZERO,
+        }
+    }
+}
+
+/// The delays of a `BackoffPolicy` for one run of failures.
+///
+/// It is an endless iterator; callers decide when to give up.
+///
+/// ```
+/// use detach::backoff::BackoffPolicy;
+/// use std::time::Duration;
+///
+/// let policy = BackoffPolicy::parse("exponential:1s-1m").unwrap();
+/// for (attempt, delay) in policy.backoff().take(10).enumerate() {
+///     // Each delay lies in the upper half of 1s, 2s, 4s, ... capped at 1m
+///     let ceiling = Duration::from_secs(1 << attempt).min(Duration::from_secs(60));
+///     assert!(delay >= ceiling / 2 && delay <= ceiling);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    policy: BackoffPolicy,
+    attempt: u32,
+    previous: Duration,
+}
+
+impl Backoff {
+    /// Returns the delay before the next retry.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = match self.policy {
+            BackoffPolicy::Fixed(delay) => delay,
+            BackoffPolicy::Exponential { initial, max } => {
+                let ceiling = initial
+                    .checked_mul(1u32.checked_shl(self.attempt).unwrap_or(u32::MAX))
+                    .unwrap_or(max)
+                    .min(max);
+                random_between(ceiling / 2, ceiling)
+            }
+            BackoffPolicy::Decorrelated { base, max } => {
+                let upper = self.previous.max(base).saturating_mul(3).min(max);
+                random_between(base.min(upper), upper)
+            }
+        };
+        self.attempt = self.attempt.saturating_add(1);
+        self.previous = delay;
+        delay
+    }
+
+    /// The number of delays handed out since the start or the last `reset`.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Starts over after a success, so the next failure waits the shortest delay again.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+        self.previous = Duration::ZERO;
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        Some(self.next_delay())
+    }
+}
+
+/// Calls `operation` until its future succeeds, at most `max_attempts` times, sleeping
+/// the delays of `policy` in between.
+///
+/// # Returns
+/// - `Ok(T)`: The first success.
+/// - `Err(E)`: The error of the last attempt once `max_attempts` attempts failed.
+pub async fn retry<T, E, F, Fut>(
+    policy: &BackoffPolicy,
+    max_attempts: u32,
+    operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    retry_on(&SystemClock, policy, max_attempts, operation).await
+}
+
+/// `retry`, sleeping on `clock` instead of the system clock.
+pub async fn retry_on<T, E, F, Fut>(
+    clock: &dyn Clock,
+    policy: &BackoffPolicy,
+    max_attempts: u32,
+    mut operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    let mut backoff = policy.backoff();
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if backoff.attempt() + 1 >= max_attempts.max(1) => return Err(e),
+            Err(e) => {
+                let delay = backoff.next_delay();
+                debug!(
+                    "Attempt {} failed: {}; retrying in {:?}.",
+                    backoff.attempt(),
+                    e,
+                    delay
+                );
+                clock.sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Draws a delay uniformly from `min..=max`, at millisecond resolution.
+fn random_between(min: Duration, max: Duration) -> Duration {
+    let min = min.as_millis() as u64;
+    let max = max.as_millis() as u64;
+    Duration::from_millis(rand::random_range(min..=max.max(min)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, Waker};
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn exponential_delays_double_up_to_the_cap_in_their_upper_half() {
+        let mut backoff = BackoffPolicy::exponential(SECOND, 10 * SECOND).backoff();
+        for ceiling in [1, 2, 4, 8, 10, 10, 10].map(|secs| secs * SECOND) {
+            let delay = backoff.next_delay();
+            assert!(
+                delay >= ceiling / 2 && delay <= ceiling,
+                "{:?} outside {:?}..={:?}",
+                delay,
+                ceiling / 2,
+                ceiling
+            );
+        }
+        assert_eq!(backoff.attempt(), 7);
+    }
+
+    #[test]
+    fn exponential_delays_stay_capped_after_many_attempts() {
+        let max = Duration::from_secs(300);
+        let backoff = BackoffPolicy::exponential(SECOND, max).backoff();
+        assert!(backoff.skip(40).take(20).all(|delay| delay <= max));
+    }
+
+    #[test]
+    fn decorrelated_delays_stay_between_base_and_three_times_the_last() {
+        let (base, max) = (SECOND, 20 * SECOND);
+        let mut backoff = BackoffPolicy::decorrelated(base, max).backoff();
+        let mut previous = Duration::ZERO;
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay >= base && delay <= max);
+            assert!(delay <= previous.max(base) * 3);
+            previous = delay;
+        }
+    }
+
+    #[test]
+    fn reset_starts_over_from_the_first_delay() {
+        let mut backoff = BackoffPolicy::exponential(SECOND, 60 * SECOND).backoff();
+        backoff.by_ref().take(5).for_each(drop);
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.next_delay() <= SECOND);
+    }
+
+    #[test]
+    fn fixed_delays_do_not_change() {
+        let backoff = BackoffPolicy::parse("fixed:5s").unwrap().backoff();
+        assert!(backoff.take(5).all(|delay| delay == 5 * SECOND));
+    }
+
+    #[test]
+    fn parse_reads_the_three_policies() {
+        assert_eq!(
+            BackoffPolicy::parse("exponential:1s-5m").unwrap(),
+            BackoffPolicy::exponential(SECOND, 300 * SECOND)
+        );
+        assert_eq!(
+            BackoffPolicy::parse(" decorrelated:2-10 ").unwrap(),
+            BackoffPolicy::decorrelated(2 * SECOND, 10 * SECOND)
+        );
+        for invalid in [
+            "",
+            "fixed",
+            "exponential:1s",
+            "linear:1s-5s",
+            "exponential:5m-1s",
+        ] {
+            assert!(BackoffPolicy::parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn retry_sleeps_between_attempts_and_gives_up_after_the_last() {
+        let clock = MockClock::new(chrono::Local::now());
+        let mut calls = 0;
+        let mut retrying = Box::pin(retry_on(&clock, &BackoffPolicy::Fixed(SECOND), 3, || {
+            calls += 1;
+            std::future::ready(Err::<(), _>(calls))
+        }));
+        let mut poll = || Pin::new(&mut retrying).poll(&mut Context::from_waker(Waker::noop()));
+        assert!(poll().is_pending());
+        clock.advance(SECOND);
+        assert!(poll().is_pending());
+        clock.advance(SECOND);
+        assert_eq!(poll(), Poll::Ready(Err(3)));
+        assert_eq!(clock.monotonic(), 2 * SECOND);
+    }
+}
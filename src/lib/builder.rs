@@ -0,0 +1,155 @@
+//! Detaching a service from another program's `main` in a few lines.
+//!
+//! `daemonize` leaves logging, the log path and the foreground case to the caller, as
+//! `detach-rs` needs to control each of them. A program that only wants the usual
+//! behavior, usually from the flags of a flattened `DetachArgs`, builds a `DaemonBuilder`
+//! instead and hands it the service future:
+//!
+//! ```no_run
+//! use detach::DaemonBuilder;
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     DaemonBuilder::new()
+//!         .detach(true)
+//!         .log_file("/var/log/myservice.log")
+//!         .timeout(Some(3600))
+//!         .run(detach::run_service_async())
+//! }
+//! ```
+use crate::stdin::OnStdinEof;
+use crate::{LoggingConfig, ServiceManager, daemonize, resolve_log_path};
+use log::{debug, info};
+use std::path::PathBuf;
+
+/// How to run a service future: detached or in the foreground, where to log, for how long.
+#[derive(Debug, Clone)]
+pub struct DaemonBuilder {
+    detach: bool,
+    log_file: Option<PathBuf>,
+    level: log::LevelFilter,
+    console_level: Option<log::LevelFilter>,
+    timeout: Option<u64>,
+    on_stdin_eof: OnStdinEof,
+    init_logging: bool,
+}
+
+impl Default for DaemonBuilder {
+    fn default() -> Self {
+        DaemonBuilder::new()
+    }
+}
+
+impl DaemonBuilder {
+    /// A builder that runs the service in the foreground, logging at `Info` to a
+    /// timestamped file in the platform's log directory, without a timeout.
+    pub fn new() -> Self {
+        DaemonBuilder {
+            detach: false,
+            log_file: None,
+            level: log::LevelFilter::Info,
+            console_level: None,
+            timeout: None,
+            on_stdin_eof: OnStdinEof::Warn,
+            init_logging: true,
+        }
+    }
+
+    /// Detach into the background before running the service.
+    ///
+    /// Ignored, with a log message, when the process was started by systemd or launchd,
+    /// which supervise it themselves, and off Unix.
+    pub fn detach(mut self, detach: bool) -> Self {
+        self.detach = detach;
+        self
+    }
+
+    /// Log to `path`; relative paths are resolved against the current directory.
+    pub fn log_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.log_file = Some(path.into());
+        self
+    }
+
+    /// The level of the log file, and of the console unless `console_level` is set.
+    pub fn level(mut self, level: log::LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// A separate level for the console, which is only logged to in the foreground.
+    pub fn console_level(mut self, level: Option<log::LevelFilter>) -> Self {
+        self.console_level = level;
+        self
+    }
+
+    /// Stop the service after this many seconds.
+    pub fn timeout(mut self, seconds: Option<u64>) -> Self {
+        self.timeout = seconds;
+        self
+    }
+
+    /// What the service gets when it reads end of file through `stdin::stdin()`, which a
+    /// detached service does on its first read.
+    pub fn on_stdin_eof(mut self, mode: OnStdinEof) -> Self {
+        self.on_stdin_eof = mode;
+        self
+    }
+
+    /// Whether `run` installs the log4rs logger (the default). Pass `false` when the
+    /// program has set up its own.
+    pub fn init_logging(mut self, init: bool) -> Self {
+        self.init_logging = init;
+        self
+    }
+
+    /// Sets up logging, detaches if requested and runs `service` to completion.
+    ///
+    /// # Returns
+    /// - `Ok(())`: In the foreground, once the service finished or timed out. A detached
+    ///   daemon exits the process instead of returning.
+    /// - `Err(anyhow::Error)`: If logging or detaching failed, or the foreground service
+    ///   returned an error.
+    pub fn run<F>(self, service: F) -> Result<(), anyhow::Error>
+    where
+        F: std::future::Future<Output = Result<(), anyhow::Error>> + Send + 'static,
+    {
+        let log_file = resolve_log_path(self.log_file.as_deref())?;
+        let manager = ServiceManager::detect().filter(|_| self.detach);
+        let detach = self.detach && manager.is_none();
+        if self.init_logging {
+            LoggingConfig {
+                to_console: !detach,
+                console_level: self.console_level,
+                ..LoggingConfig::new(&log_file, self.level)
+            }
+            .init()?;
+        }
+        if let Some(manager) = manager {
+            info!("Started by {}; staying in the foreground.", manager);
+        }
+        crate::stdin::set_on_stdin_eof(self.on_stdin_eof);
+
+        if detach {
+            debug!("Detaching process... Check logs at {:?}", log_file);
+            return daemonize(&log_file, self.level, self.timeout, service);
+        }
+
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        rt.block_on(async {
+            match self.timeout {
+                Some(seconds) => {
+                    let limit = std::time::Duration::from_secs(seconds);
+                    match tokio::time::timeout(limit, service).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            debug!("Timeout reached after {} seconds.", seconds);
+                            Ok(())
+                        }
+                    }
+                }
+                None => service.await,
+            }
+        })
+    }
+}
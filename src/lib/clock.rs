@@ -0,0 +1,222 @@
+//! Time as seen by timeouts, schedules, backoff and heartbeats.
+//!
+//! The timeouts and scheduled restarts of command runs and the supervisor, the daemon
+//! timeout, retry backoff, start delays and the built-in heartbeat wait and ask for the
+//! time through a `Clock`, so they run on `SystemClock` in production and on a `MockClock`
+//! in tests. A mock clock only moves when told to: sleeps and timeouts on it complete as
+//! soon as `advance` has moved it far enough, without any real waiting.
+//!
+//! The rest of the crate, such as the job queue, the metrics endpoint and timestamps in
+//! logs and records, still uses the tokio timer and the system time directly.
+//!
+//! ```
+//! use detach::clock::{Clock, MockClock};
+//! use std::time::Duration;
+//!
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let clock = MockClock::new(chrono::Local::now());
+//! let sleeper = tokio::spawn(clock.sleep(Duration::from_secs(3600)));
+//! clock.advance(Duration::from_secs(3600));
+//! sleeper.await.unwrap();
+//! assert_eq!(clock.monotonic(), Duration::from_secs(3600));
+//! # });
+//! ```
+use chrono::{DateTime, Local};
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// A future returned by `Clock::sleep`.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// A source of wall-clock time, monotonic time and sleeps.
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    /// The current local wall-clock time, used for schedules and deadlines.
+    fn now(&self) -> DateTime<Local>;
+
+    /// A monotonic reading for measuring durations; only differences between readings
+    /// are meaningful.
+    fn monotonic(&self) -> Duration;
+
+    /// Returns a future that completes once `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// The clock of the operating system, sleeping with `tokio::time::sleep`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn monotonic(&self) -> Duration {
+        static ORIGIN: OnceLock<Instant> = OnceLock::new();
+        ORIGIN.get_or_init(Instant::now).elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Returns the system clock as the shared handle options structs hold.
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// A clock that stands still until `advance` moves it.
+///
+/// Clones share the same time, so a test can keep one and hand the other to the code
+/// under test.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    start: DateTime<Local>,
+    /// Time advanced since `start`; sleepers wait for it to pass their deadline
+    elapsed: Arc<watch::Sender<Duration>>,
+}
+
+impl MockClock {
+    /// Creates a clock reading `start` that does not move on its own.
+    pub fn new(start: DateTime<Local>) -> Self {
+        Self {
+            start,
+            elapsed: Arc::new(watch::Sender::new(Duration::ZERO)),
+        }
+    }
+
+    /// Moves the clock forward, completing every sleep whose deadline has been reached.
+    pub fn advance(&self, by: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += by);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Local> {
+        let elapsed = chrono::Duration::from_std(*self.elapsed.borrow()).unwrap_or_default();
+        self.start + elapsed
+    }
+
+    fn monotonic(&self) -> Duration {
+        *self.elapsed.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let mut elapsed = self.elapsed.subscribe();
+        let deadline = *elapsed.borrow() + duration;
+        Box::pin(async move {
+            if elapsed.wait_for(|now| *now >= deadline).await.is_err() {
+                // Every handle to the clock is gone, so it will never get there.
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+/// How often `sleep_until` looks at the wall clock.
+pub const WALL_CLOCK_CHECK: Duration = Duration::from_secs(30);
+
+/// Waits until `clock` reads `deadline` or later.
+///
+/// `Clock::sleep` measures monotonic time, which on Linux stands still while the host is
+/// suspended. This looks at the wall clock at least every `WALL_CLOCK_CHECK` instead, so a
+/// deadline the host slept through is noticed soon after it wakes up.
+pub async fn sleep_until(clock: &dyn Clock, deadline: DateTime<Local>) {
+    while let Some(left) = (deadline - clock.now())
+        .to_std()
+        .ok()
+        .filter(|left| !left.is_zero())
+    {
+        clock.sleep(left.min(WALL_CLOCK_CHECK)).await;
+    }
+}
+
+/// Returned by `timeout` when the time ran out before the future completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed(pub Duration);
+
+impl std::fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// Runs `future` until it completes or `duration` passes on `clock`, whichever is first.
+///
+/// The counterpart of `tokio::time::timeout` for an arbitrary `Clock`.
+pub async fn timeout<F>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed>
+where
+    F: Future,
+{
+    tokio::select! {
+        output = future => Ok(output),
+        _ = clock.sleep(duration) => Err(Elapsed(duration)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::task::{Context, Poll, Waker};
+
+    fn clock() -> MockClock {
+        MockClock::new(Local.with_ymd_and_hms(2026, 10, 16, 12, 0, 0).unwrap())
+    }
+
+    /// Polls `future` once, without a runtime.
+    fn poll<F: Future + Unpin>(future: &mut F) -> Poll<F::Output> {
+        Pin::new(future).poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn mock_sleep_completes_once_advanced_past_its_deadline() {
+        let clock = clock();
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        assert!(poll(&mut sleep).is_pending());
+        clock.advance(Duration::from_secs(9));
+        assert!(poll(&mut sleep).is_pending());
+        clock.advance(Duration::from_secs(1));
+        assert!(poll(&mut sleep).is_ready());
+        assert_eq!(clock.monotonic(), Duration::from_secs(10));
+        assert_eq!(
+            clock.now(),
+            Local.with_ymd_and_hms(2026, 10, 16, 12, 0, 10).unwrap()
+        );
+    }
+
+    #[test]
+    fn mock_timeout_elapses_only_when_the_clock_moves() {
+        let clock = clock();
+        let limit = Duration::from_secs(60);
+        let mut waiting = Box::pin(timeout(&clock, limit, std::future::pending::<()>()));
+        assert!(poll(&mut waiting).is_pending());
+        clock.advance(limit);
+        assert_eq!(poll(&mut waiting), Poll::Ready(Err(Elapsed(limit))));
+
+        let mut done = Box::pin(timeout(&clock, limit, async { 7 }));
+        assert_eq!(poll(&mut done), Poll::Ready(Ok(7)));
+    }
+
+    #[test]
+    fn sleep_until_notices_a_deadline_passed_in_one_jump() {
+        let clock = clock();
+        let deadline = clock.now() + chrono::Duration::hours(8);
+        let mut waiting = Box::pin(sleep_until(&clock, deadline));
+        assert!(poll(&mut waiting).is_pending());
+        // Each check waits at most WALL_CLOCK_CHECK, so a clock that moves less stays put
+        clock.advance(WALL_CLOCK_CHECK / 2);
+        assert!(poll(&mut waiting).is_pending());
+        // A host waking up from suspend sees the whole night pass at once
+        clock.advance(Duration::from_secs(8 * 3600));
+        assert!(poll(&mut waiting).is_ready());
+    }
+}
@@ -0,0 +1,1057 @@
+//! Running shell commands without terminating the calling process.
+//!
+//! `run_command` is the composable counterpart of `run_command_and_exit`: it runs a
+//! command through `sh -c`, applies an optional timeout, and hands back a
+//! `CommandOutcome` describing how the command ended instead of deciding what the
+//! caller should do with it.
+//!
+//! Besides the timeout, `RunOptions` can bound the command's CPU time and the amount of
+//! output it writes (see the `limits` module), and keep its last lines of output in an
+//! `OutputTail` so they can be reported without reading log files.
+use crate::clock::{self, Clock, timeout};
+use crate::context::{ReloadReceiver, ServiceContext};
+use crate::cores::{CoreDumps, report_core_dump};
+use crate::isolation::Isolation;
+use crate::landlock::FsSandbox;
+use crate::limits::{Metered, OutputBudget};
+use crate::pidfd::ProcessHandle;
+use crate::redact::Redactor;
+use crate::sanitize::{BinaryOutput, Sanitize, Sanitizer};
+use crate::seccomp::SeccompProfile;
+#[cfg(unix)]
+use crate::signal::{SIGHUP, SIGINT, SIGKILL, send_signal, send_signal_group};
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+
+/// A single line of output produced by a command started with `OutputMode::Stream`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputLine {
+    /// A line written to the command's standard output
+    Stdout(String),
+    /// A line written to the command's standard error
+    Stderr(String),
+}
+
+/// What to do with the standard output and standard error of a command.
+#[derive(Debug, Clone, Default)]
+pub enum OutputMode {
+    /// Share the caller's stdout/stderr (the behavior of `run_command_and_exit`)
+    #[default]
+    Inherit,
+    /// Collect stdout/stderr into the `CommandOutcome` as strings
+    Capture,
+    /// Send each line to the given channel as it is produced
+    Stream(mpsc::Sender<OutputLine>),
+    /// Write stdout and stderr straight to this file
+    File(std::sync::Arc<std::fs::File>),
+}
+
+/// The longest line an `OutputTail` keeps; the rest of a longer line is dropped.
+const MAX_TAIL_LINE: usize = 4096;
+
+/// The most recent lines a command wrote to stdout and stderr, kept in memory.
+///
+/// Unlike the log file, the tail cannot be rotated away or truncated, so status requests
+/// and crash reports can always include it. Clones share the same lines.
+///
+/// ```
+/// use detach::OutputTail;
+///
+/// let tail = OutputTail::new(2);
+/// for line in ["one", "two", "three"] {
+///     tail.push(line.to_string());
+/// }
+/// assert_eq!(tail.lines(), ["two", "three"]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct OutputTail {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl OutputTail {
+    /// Creates an empty tail keeping the last `capacity` lines.
+    pub fn new(capacity: usize) -> Self {
+        OutputTail {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Appends a line, dropping the oldest one if the tail is full.
+    pub fn push(&self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Returns the kept lines, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        let lines = self.lines.lock().unwrap_or_else(|e| e.into_inner());
+        lines.iter().cloned().collect()
+    }
+}
+
+/// Options controlling how `run_command` executes a command.
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Maximum time the command may run before it is interrupted
+    pub timeout: Option<Duration>,
+    /// Interrupts the command as on timeout once this turns `true`
+    pub interrupt: Option<watch::Receiver<bool>>,
+    /// How long to wait after SIGINT before the command is killed on timeout
+    pub grace_period: Duration,
+    /// What to do with the command's output
+    pub output: OutputMode,
+    /// Core dump limit to set in the child, and where to collect its core files
+    pub cores: Option<CoreDumps>,
+    /// Namespaces and privilege restrictions for the child (Linux only)
+    pub isolation: Option<Isolation>,
+    /// Landlock filesystem sandbox for the child (Linux only)
+    pub sandbox: Option<FsSandbox>,
+    /// Seccomp filter installed in the child right before exec (Linux only)
+    pub seccomp: Option<SeccompProfile>,
+    /// Reload requests to forward to the command as SIGHUP (Unix only)
+    pub reload: Option<ReloadReceiver>,
+    /// Signals to pass on to the command as they are sent on this channel (Unix only)
+    pub signals: Option<broadcast::Sender<i32>>,
+    /// Start the command in its own process group and signal the whole group (Unix only)
+    pub process_group: bool,
+    /// CPU time the command may use before the kernel stops it (`RLIMIT_CPU`, Unix only)
+    pub cpu_limit: Option<Duration>,
+    /// Bytes of stdout and stderr together the command may write before it is killed
+    pub max_output: Option<u64>,
+    /// Where to keep the last lines of the command's output
+    pub tail: Option<OutputTail>,
+    /// What is done to output on its way into an `OutputMode::File`; with rules the output
+    /// is read through a pipe and written by `pump`, without them the command writes the
+    /// file itself
+    pub sanitize: Option<Sanitize>,
+    /// Secrets replaced with `[REDACTED]` in output written to an `OutputMode::File`; with
+    /// a redactor the output is read through a pipe and written by `pump`, as with
+    /// `sanitize`
+    pub redactor: Option<Arc<Redactor>>,
+    /// Variables set for the command on top of the inherited environment
+    pub env: Vec<(String, String)>,
+    /// The clock the timeout, the grace period and the duration are measured on
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            interrupt: None,
+            grace_period: Duration::from_millis(2000),
+            output: OutputMode::Inherit,
+            cores: None,
+            isolation: None,
+            sandbox: None,
+            seccomp: None,
+            reload: None,
+            signals: None,
+            process_group: false,
+            cpu_limit: None,
+            max_output: None,
+            tail: None,
+            sanitize: None,
+            redactor: None,
+            env: Vec::new(),
+            clock: clock::system(),
+        }
+    }
+}
+
+/// How a child process ended, decoded from its `ExitStatus`.
+///
+/// A plain exit code cannot tell "the program returned 1" apart from "the program crashed";
+/// this keeps the two apart and records whether a core file was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The process exited on its own with this code
+    Exited(i32),
+    /// The process was terminated by a signal
+    Signaled {
+        /// The signal number
+        signal: i32,
+        /// Whether the kernel reported a core dump
+        core_dumped: bool,
+    },
+    /// Neither an exit code nor a signal is available (should not happen on Unix)
+    Unknown,
+}
+
+impl ExitReason {
+    /// Decodes an `ExitStatus`.
+    pub fn from_status(status: ExitStatus) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(signal) = status.signal() {
+                return ExitReason::Signaled {
+                    signal,
+                    core_dumped: status.core_dumped(),
+                };
+            }
+        }
+        match status.code() {
+            Some(code) => ExitReason::Exited(code),
+            None => ExitReason::Unknown,
+        }
+    }
+
+    /// The exit code a shell would report: the code itself, or 128 + the signal number.
+    pub fn shell_code(&self) -> i32 {
+        match self {
+            ExitReason::Exited(code) => *code,
+            ExitReason::Signaled { signal, .. } => 128 + signal,
+            ExitReason::Unknown => 1,
+        }
+    }
+
+    /// Returns `true` if the process exited on its own with code 0.
+    pub fn success(&self) -> bool {
+        *self == ExitReason::Exited(0)
+    }
+}
+
+impl std::fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::Exited(code) => write!(f, "exited with code {}", code),
+            ExitReason::Signaled {
+                signal,
+                core_dumped,
+            } => {
+                write!(f, "killed by signal {}", signal)?;
+                #[cfg(unix)]
+                if let Some(name) = crate::signal::signal_name(*signal) {
+                    write!(f, " ({})", name)?;
+                }
+                if *core_dumped {
+                    write!(f, ", core dumped")?;
+                }
+                Ok(())
+            }
+            ExitReason::Unknown => write!(f, "ended for an unknown reason"),
+        }
+    }
+}
+
+/// The result of running a command with `run_command`.
+#[derive(Debug)]
+pub struct CommandOutcome {
+    /// The process ID the command ran as, if it was known
+    pub pid: Option<u32>,
+    /// The exit status of the command
+    pub status: ExitStatus,
+    /// Wall-clock time between spawning the command and reaping it
+    pub duration: Duration,
+    /// Whether the command was stopped because it exceeded `RunOptions::timeout`, or was
+    /// interrupted through `RunOptions::interrupt`
+    pub timed_out: bool,
+    /// Whether the command was killed because it exceeded `RunOptions::max_output`
+    pub output_exceeded: bool,
+    /// Captured standard output, only set with `OutputMode::Capture`
+    pub stdout: Option<String>,
+    /// Captured standard error, only set with `OutputMode::Capture`
+    pub stderr: Option<String>,
+}
+
+impl CommandOutcome {
+    /// Returns `true` if the command finished in time, within its output budget, and
+    /// exited successfully.
+    pub fn success(&self) -> bool {
+        !self.timed_out && !self.output_exceeded && self.status.success()
+    }
+
+    /// Decodes how the command ended: exit code, or signal and core-dump flag.
+    pub fn exit_reason(&self) -> ExitReason {
+        ExitReason::from_status(self.status)
+    }
+}
+
+/// Runs a command string through `sh -c` (`cmd /C` on Windows) and reports how it ended.
+///
+/// Unlike `run_command_and_exit`, this function never terminates the process and never
+/// treats a non-zero exit status or a timeout as an error; both are reported through the
+/// returned `CommandOutcome` so embedding programs can decide what to do.
+///
+/// On timeout the command first receives SIGINT (on Unix) and is killed if it is still
+/// running after `RunOptions::grace_period`. Reload requests arriving on
+/// `RunOptions::reload` are passed on to the command as SIGHUP, and signals sent on
+/// `RunOptions::signals` while it runs are passed on as they are. With
+/// `RunOptions::process_group` every signal goes to the command's whole process group.
+///
+/// While the command runs, its PID is published as `ServiceContext::child_pid` on the
+/// process-wide context.
+///
+/// With `RunOptions::max_output` or `RunOptions::tail` the output is read through pipes
+/// even with `OutputMode::Inherit` and `OutputMode::File`, and copied to where it would
+/// have gone. The command is killed as soon as it has written more than `max_output`
+/// bytes; every complete line it writes is added to the `tail`.
+///
+/// Piped output is read until the pipes close, or for at most `DRAIN_TIMEOUT` after the
+/// command exited: processes it left running in the background (`sh -c 'server &'`)
+/// keep the pipes open, and must not hold up `run_command` for as long as they run.
+///
+/// # Arguments
+/// - `cmd_str`: The command string to be executed (e.g., "ls -la", "echo hello | grep he").
+/// - `opts`: Timeout and output handling options.
+///
+/// # Returns
+/// - `Ok(CommandOutcome)`: The command ran, whatever its exit status.
+/// - `Err(anyhow::Error)`: The command could not be spawned or waited on, or its sandbox
+///   or seccomp profile could not be set up, or isolation was requested off Linux.
+pub async fn run_command(cmd_str: &str, opts: RunOptions) -> anyhow::Result<CommandOutcome> {
+    let mut command = shell(cmd_str);
+    command.envs(opts.env.iter().map(|(key, value)| (key, value)));
+    let budget = opts.max_output.map(OutputBudget::new);
+    let sanitizer = || {
+        if !matches!(opts.output, OutputMode::File(_)) {
+            return None;
+        }
+        let sanitizer = match (opts.sanitize, &opts.redactor) {
+            (None, None) => return None,
+            // Redacting alone leaves everything else as it is
+            (rules, _) => Sanitizer::new(rules.unwrap_or(Sanitize {
+                max_line: None,
+                binary: BinaryOutput::Raw,
+            })),
+        };
+        Some(match &opts.redactor {
+            Some(redactor) => sanitizer.redacting(redactor.clone()),
+            None => sanitizer,
+        })
+    };
+    match &opts.output {
+        OutputMode::Inherit | OutputMode::File(_)
+            if budget.is_some() || opts.tail.is_some() || sanitizer().is_some() =>
+        {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        OutputMode::Inherit => {}
+        OutputMode::File(file) => {
+            command.stdout(file.try_clone()?).stderr(file.try_clone()?);
+        }
+        OutputMode::Capture | OutputMode::Stream(_) => {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+    }
+    #[cfg(unix)]
+    if opts.process_group {
+        command.process_group(0);
+    }
+    #[cfg(unix)]
+    if let Some(cores) = opts.cores.clone() {
+        // SAFETY: `apply_rlimit` only calls getrlimit/setrlimit, which are async-signal-safe.
+        unsafe {
+            command.pre_exec(move || cores.apply_rlimit());
+        }
+    }
+    if let Some(cpu) = opts.cpu_limit {
+        #[cfg(unix)]
+        // SAFETY: `apply_cpu_limit` only calls getrlimit/setrlimit, which are async-signal-safe.
+        unsafe {
+            command.pre_exec(move || crate::limits::apply_cpu_limit(cpu));
+        }
+        #[cfg(not(unix))]
+        return Err(anyhow::anyhow!(
+            "A CPU limit ({:?}) is only available on Unix",
+            cpu
+        ));
+    }
+    if let Some(isolation) = opts.isolation.filter(|isolation| !isolation.is_default()) {
+        #[cfg(target_os = "linux")]
+        {
+            let prepared = isolation.prepare();
+            // SAFETY: `apply` only makes raw system calls on data prepared before the fork.
+            unsafe {
+                command.pre_exec(move || prepared.apply());
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(anyhow::anyhow!(
+            "Namespace isolation ({:?}) is only available on Linux",
+            isolation
+        ));
+    }
+    if let Some(sandbox) = &opts.sandbox
+        && let Some(prepared) = sandbox.prepare()?
+    {
+        #[cfg(target_os = "linux")]
+        // SAFETY: `restrict_self` only calls prctl and landlock_restrict_self.
+        unsafe {
+            command.pre_exec(move || prepared.restrict_self());
+        }
+        #[cfg(not(target_os = "linux"))]
+        match prepared {}
+    }
+    // Installed last: once the filter is in place it also applies to the remaining setup.
+    if let Some(profile) = &opts.seccomp {
+        #[cfg(target_os = "linux")]
+        {
+            let filter = profile.compile()?;
+            // SAFETY: `apply` only calls prctl on memory allocated before the fork.
+            unsafe {
+                command.pre_exec(move || filter.apply());
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(anyhow::anyhow!(
+            "Seccomp profile {} requested, but seccomp is only available on Linux",
+            profile
+        ));
+    }
+
+    let clock = &*opts.clock;
+    let started = clock.monotonic();
+    let mut child = crate::reaper::spawn(&mut command)?;
+    let pid = child.id();
+    let handle = pid.map(|pid| Arc::new(ProcessHandle::open(pid)));
+    ServiceContext::current().set_child(handle.clone());
+    let (stop_reading, reading) = watch::channel(false);
+
+    let stdout_task = match child.stdout.take() {
+        Some(pipe) => Some(spawn_reader(
+            metered(pipe, &budget, &opts.tail),
+            &opts.output,
+            OutputLine::Stdout,
+            sink(&opts.output, false)?,
+            sanitizer(),
+            reading.clone(),
+        )),
+        None => None,
+    };
+    let stderr_task = match child.stderr.take() {
+        Some(pipe) => Some(spawn_reader(
+            metered(pipe, &budget, &opts.tail),
+            &opts.output,
+            OutputLine::Stderr,
+            sink(&opts.output, true)?,
+            sanitizer(),
+            reading,
+        )),
+        None => None,
+    };
+
+    let mut timed_out = false;
+    let mut output_exceeded = false;
+    let mut forward = Forward {
+        child: handle,
+        reload: opts.reload.clone(),
+        signals: opts.signals.as_ref().map(broadcast::Sender::subscribe),
+        process_group: opts.process_group,
+    };
+    let waited = {
+        let run = forward.wait_within(&mut child, budget.as_deref());
+        tokio::select! {
+            status = run => Ok(status),
+            limit = expired(clock, opts.timeout, opts.interrupt.clone()) => Err(limit),
+        }
+    };
+    let status = match waited {
+        Ok(Some(status)) => status?, // Command completed within timeout
+        Ok(None) => {
+            output_exceeded = true;
+            warn!(
+                "Command wrote more than {} bytes of output. Killing it.",
+                budget.as_ref().map_or(0, |budget| budget.limit())
+            );
+            #[cfg(unix)]
+            if let Some(pid) = child.id() {
+                forward.deliver(pid, SIGKILL);
+            }
+            child.kill().await?;
+            child.wait().await?
+        }
+        Err(limit) => {
+            timed_out = true;
+            #[cfg(unix)]
+            {
+                warn!(
+                    "{}. Attempting graceful shutdown (SIGINT).",
+                    cut_short(limit)
+                );
+                if let Some(pid) = child.id() {
+                    forward.deliver(pid, SIGINT);
+                }
+
+                // Give the process a short grace period to shut down gracefully
+                if timeout(clock, opts.grace_period, child.wait())
+                    .await
+                    .is_err()
+                {
+                    warn!("Process did not exit after SIGINT. Sending SIGKILL.");
+                    child.kill().await?; // Force kill
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                warn!("{}. Killing process.", cut_short(limit));
+                child.kill().await?;
+            }
+            child.wait().await? // Wait for it to be killed or exit
+        }
+    };
+    let duration = clock.monotonic().saturating_sub(started);
+    ServiceContext::current().set_child_pid(None);
+    crate::reaper::release(pid);
+
+    let readers = async { tokio::join!(join_reader(stdout_task), join_reader(stderr_task)) };
+    tokio::pin!(readers);
+    let (stdout, stderr) = match timeout(clock, DRAIN_TIMEOUT, &mut readers).await {
+        Ok(output) => output,
+        Err(_) => {
+            warn!(
+                "The output of the command is still held open by processes it left running; \
+                 no longer reading it."
+            );
+            stop_reading.send_replace(true);
+            readers.await
+        }
+    };
+    let reason = ExitReason::from_status(status);
+    if matches!(reason, ExitReason::Signaled { .. }) && !timed_out && !output_exceeded {
+        warn!("Command finished in {:?}: {}.", duration, reason);
+    } else {
+        info!("Command finished in {:?}: {}.", duration, reason);
+    }
+    // The kernel sends SIGXCPU at the soft CPU limit and SIGKILL at the hard one
+    #[cfg(unix)]
+    if let (Some(cpu), ExitReason::Signaled { signal, .. }) = (opts.cpu_limit, reason) {
+        if signal == libc::SIGXCPU {
+            warn!("The command used up its CPU limit of {:?}.", cpu);
+        } else if signal == SIGKILL && !timed_out && !output_exceeded {
+            warn!("The command may have used up its CPU limit of {:?}.", cpu);
+        }
+    }
+    if let ExitReason::Signaled {
+        core_dumped: true, ..
+    } = reason
+        && let Some(pid) = pid
+    {
+        let cwd = std::env::current_dir().unwrap_or_default();
+        report_core_dump(pid, &cwd, opts.cores.as_ref());
+    }
+
+    Ok(CommandOutcome {
+        pid,
+        status,
+        duration,
+        timed_out,
+        output_exceeded,
+        stdout,
+        stderr,
+    })
+}
+
+/// Builds the shell invocation for `cmd_str`: `sh -c`, or `cmd /C` on Windows, so
+/// pipelines and redirections work as typed.
+#[cfg(not(windows))]
+fn shell(cmd_str: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd_str);
+    command
+}
+
+#[cfg(windows)]
+fn shell(cmd_str: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").arg(cmd_str);
+    command
+}
+
+/// Where the signals for a running command come from.
+struct Forward {
+    /// The handle signals are sent through, unless they go to the process group
+    child: Option<Arc<ProcessHandle>>,
+    reload: Option<ReloadReceiver>,
+    signals: Option<broadcast::Receiver<i32>>,
+    process_group: bool,
+}
+
+impl Forward {
+    /// Waits for `child` to exit, passing on reload requests as SIGHUP and every received
+    /// signal as is.
+    async fn wait(&mut self, child: &mut tokio::process::Child) -> std::io::Result<ExitStatus> {
+        loop {
+            tokio::select! {
+                status = child.wait() => return status,
+                changed = changed(&mut self.reload) => {
+                    if changed.is_err() {
+                        // The context is gone, so no more reloads can arrive.
+                        self.reload = None;
+                        continue;
+                    }
+                    #[cfg(unix)]
+                    if let Some(pid) = child.id() {
+                        info!("Forwarding reload to command as SIGHUP.");
+                        self.deliver(pid, SIGHUP);
+                    }
+                }
+                received = recv(&mut self.signals) => match received {
+                    Ok(signal) => {
+                        if let Some(pid) = child.id() {
+                            self.deliver(pid, signal);
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => self.signals = None,
+                },
+            }
+        }
+    }
+
+    /// Like `wait`, but returns `None` as soon as the command has used up `budget`.
+    async fn wait_within(
+        &mut self,
+        child: &mut tokio::process::Child,
+        budget: Option<&OutputBudget>,
+    ) -> Option<std::io::Result<ExitStatus>> {
+        let Some(budget) = budget else {
+            return Some(self.wait(child).await);
+        };
+        tokio::select! {
+            status = self.wait(child) => Some(status),
+            () = budget.exceeded() => None,
+        }
+    }
+
+    /// Sends `signal` to the command, or to its process group.
+    fn deliver(&self, pid: u32, signal: i32) {
+        #[cfg(unix)]
+        {
+            let result = match &self.child {
+                _ if self.process_group => send_signal_group(pid, signal),
+                Some(child) if child.pid() == pid => child.signal(signal),
+                _ => send_signal(pid, signal),
+            };
+            if let Err(e) = result {
+                warn!("Failed to send signal {} to command: {}", signal, e);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (pid, signal, self.process_group);
+    }
+}
+
+async fn changed(
+    reload: &mut Option<ReloadReceiver>,
+) -> Result<(), tokio::sync::watch::error::RecvError> {
+    match reload {
+        Some(rx) => rx.changed().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn recv(
+    signals: &mut Option<broadcast::Receiver<i32>>,
+) -> Result<i32, broadcast::error::RecvError> {
+    match signals {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Completes once `timeout` has passed on `clock`, returning it, or once `interrupt`
+/// turns `true`, returning `None`; without either it never does.
+pub(crate) async fn expired(
+    clock: &dyn Clock,
+    timeout: Option<Duration>,
+    interrupt: Option<watch::Receiver<bool>>,
+) -> Option<Duration> {
+    let timed_out = async {
+        match timeout {
+            Some(limit) => clock.sleep(limit).await,
+            None => std::future::pending().await,
+        }
+    };
+    let interrupted = async {
+        if let Some(mut interrupt) = interrupt
+            && interrupt.wait_for(|&interrupt| interrupt).await.is_ok()
+        {
+            return;
+        }
+        std::future::pending().await
+    };
+    tokio::select! {
+        () = timed_out => timeout,
+        () = interrupted => None,
+    }
+}
+
+/// Says why a command is being stopped, as `expired` returned it.
+pub(crate) fn cut_short(limit: Option<Duration>) -> String {
+    match limit {
+        Some(limit) => format!("Command timed out after {:?}", limit),
+        None => "Command interrupted".to_string(),
+    }
+}
+
+/// A pipe of the child, counted against `budget` and copied into `tail` if there are any.
+type Pipe = Box<dyn AsyncRead + Unpin + Send>;
+
+fn metered<R>(pipe: R, budget: &Option<Arc<OutputBudget>>, tail: &Option<OutputTail>) -> Pipe
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let pipe: Pipe = match budget {
+        Some(budget) => Box::new(Metered::new(pipe, budget.clone())),
+        None => Box::new(pipe),
+    };
+    match tail {
+        Some(tail) => Box::new(Tapped {
+            inner: pipe,
+            tail: tail.clone(),
+            partial: Vec::new(),
+        }),
+        None => pipe,
+    }
+}
+
+/// Splits what is read from a pipe into lines for an `OutputTail`.
+struct Tapped {
+    inner: Pipe,
+    tail: OutputTail,
+    /// The start of a line whose newline has not been read yet
+    partial: Vec<u8>,
+}
+
+impl Tapped {
+    fn push(&mut self, bytes: &[u8]) {
+        let mut rest = bytes;
+        while let Some(end) = rest.iter().position(|&byte| byte == b'\n') {
+            self.keep(&rest[..end]);
+            let line = String::from_utf8_lossy(&self.partial);
+            self.tail.push(line.trim_end_matches('\r').to_string());
+            self.partial.clear();
+            rest = &rest[end + 1..];
+        }
+        self.keep(rest);
+    }
+
+    /// Adds to the current line, up to `MAX_TAIL_LINE` bytes.
+    fn keep(&mut self, bytes: &[u8]) {
+        // A long line must not grow without bound
+        let room = MAX_TAIL_LINE.saturating_sub(self.partial.len());
+        self.partial
+            .extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+}
+
+impl AsyncRead for Tapped {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = std::pin::Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let std::task::Poll::Ready(Ok(())) = &poll {
+            if buf.filled().len() == before && !self.partial.is_empty() {
+                // End of output: keep a last line that has no newline
+                let line = String::from_utf8_lossy(&self.partial).into_owned();
+                self.tail.push(line);
+                self.partial.clear();
+            } else {
+                let read = &buf.filled()[before..];
+                self.push(read);
+            }
+        }
+        poll
+    }
+}
+
+/// Where a piped stream goes when the output mode did not ask for a pipe: our own
+/// stdout or stderr for `OutputMode::Inherit`, the file for `OutputMode::File`.
+fn sink(mode: &OutputMode, stderr: bool) -> std::io::Result<Option<Box<dyn Write + Send>>> {
+    Ok(match mode {
+        OutputMode::Inherit if stderr => Some(Box::new(std::io::stderr())),
+        OutputMode::Inherit => Some(Box::new(std::io::stdout())),
+        OutputMode::File(file) => Some(Box::new(file.try_clone()?)),
+        OutputMode::Capture | OutputMode::Stream(_) => None,
+    })
+}
+
+/// How long the pipes of a command that exited are still read while processes it left
+/// behind hold them open.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Drains one of the child's pipes according to `mode`, until the pipe closes or `stop`
+/// turns `true`.
+///
+/// With `OutputMode::Capture` the task yields the (lossily decoded) contents read so far;
+/// with `OutputMode::Stream` every line is forwarded to the channel and the task yields
+/// `None`. With a `sink`, everything is copied there by `pump::pump`, through the
+/// `sanitizer` if there is one, and the task yields `None`.
+fn spawn_reader(
+    pipe: Pipe,
+    mode: &OutputMode,
+    wrap: fn(String) -> OutputLine,
+    sink: Option<Box<dyn Write + Send>>,
+    sanitizer: Option<Sanitizer>,
+    mut stop: watch::Receiver<bool>,
+) -> JoinHandle<Option<String>> {
+    let sender = match mode {
+        OutputMode::Stream(sender) => Some(sender.clone()),
+        _ => None,
+    };
+    tokio::spawn(async move {
+        let mut captured = None;
+        {
+            let drain = drain(pipe, sender, wrap, sink, sanitizer, &mut captured);
+            tokio::pin!(drain);
+            tokio::select! {
+                () = &mut drain => {}
+                stopped = async { stop.wait_for(|stop| *stop).await.is_ok() } => {
+                    if !stopped {
+                        // run_command is gone without stopping the reading; keep
+                        // draining so the child never blocks on a full pipe.
+                        drain.await;
+                    }
+                }
+            }
+        }
+        captured.map(|buf| String::from_utf8_lossy(&buf).into_owned())
+    })
+}
+
+/// Drains `pipe` for `spawn_reader`, to the `sink` or the `sender` if there is one and
+/// into `captured` otherwise.
+async fn drain(
+    pipe: Pipe,
+    sender: Option<mpsc::Sender<OutputLine>>,
+    wrap: fn(String) -> OutputLine,
+    sink: Option<Box<dyn Write + Send>>,
+    sanitizer: Option<Sanitizer>,
+    captured: &mut Option<Vec<u8>>,
+) {
+    if let Some(sink) = sink {
+        if let Err(e) = crate::pump::pump(pipe, sink, sanitizer).await {
+            warn!("Failed to copy the output of the command: {}", e);
+        }
+        return;
+    }
+    match sender {
+        Some(sender) => {
+            let mut sender = Some(sender);
+            let mut reader = BufReader::new(pipe);
+            let mut line = Vec::new();
+            // Read as bytes: a line that is not valid UTF-8 must not end the draining
+            while let Ok(read) = reader.read_until(b'\n', &mut line).await
+                && read > 0
+            {
+                let text = String::from_utf8_lossy(trim_newline(&line)).into_owned();
+                line.clear();
+                // Keep draining after the receiver is gone so the child never blocks
+                // on a full pipe.
+                if let Some(tx) = &sender
+                    && tx.send(wrap(text)).await.is_err()
+                {
+                    sender = None;
+                }
+            }
+        }
+        None => {
+            // What is read stays in the buffer if the reading is stopped half-way
+            let buf = captured.insert(Vec::new());
+            let mut pipe = pipe;
+            let _ = pipe.read_to_end(buf).await;
+        }
+    }
+}
+
+/// `line` without the `\n` or `\r\n` it ends in, if any.
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+async fn join_reader(task: Option<JoinHandle<Option<String>>>) -> Option<String> {
+    match task {
+        Some(task) => task.await.ok().flatten(),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stream_keeps_draining_after_invalid_utf8() {
+        let (lines, mut received) = mpsc::channel(16);
+        let opts = RunOptions {
+            output: OutputMode::Stream(lines),
+            ..RunOptions::default()
+        };
+        let run = tokio::spawn(run_command("printf 'a\\377b\\r\\n'; seq 20000", opts));
+        let mut stdout = Vec::new();
+        while let Some(line) = received.recv().await {
+            if let OutputLine::Stdout(line) = line {
+                stdout.push(line);
+            }
+        }
+        let outcome = run.await.unwrap().unwrap();
+        assert!(outcome.success(), "{}", outcome.exit_reason());
+        assert_eq!(stdout.len(), 20001);
+        assert_eq!(stdout[0], "a\u{fffd}b");
+        assert_eq!(stdout[20000], "20000");
+    }
+
+    /// Leaves a `sleep` running in the background that keeps the command's output open,
+    /// and prints its PID between two lines.
+    #[cfg(unix)]
+    const LEAVES_OUTPUT_OPEN: &str = "echo before; sleep 30 & echo $!; echo after";
+
+    /// Kills the `sleep` of `LEAVES_OUTPUT_OPEN` that printed `lines`, and checks the rest.
+    #[cfg(unix)]
+    fn kill_left_sleep(lines: &[&str]) {
+        assert_eq!(lines.len(), 3, "{:?}", lines);
+        unsafe { libc::kill(lines[1].parse().unwrap(), libc::SIGKILL) };
+        assert_eq!((lines[0], lines[2]), ("before", "after"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn capture_does_not_wait_for_background_processes() {
+        let opts = RunOptions {
+            output: OutputMode::Capture,
+            ..RunOptions::default()
+        };
+        let started = std::time::Instant::now();
+        let outcome = run_command(LEAVES_OUTPUT_OPEN, opts).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let stdout = outcome.stdout.unwrap_or_default();
+        kill_left_sleep(&stdout.lines().collect::<Vec<_>>());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn limited_output_does_not_wait_for_background_processes() {
+        let name = format!("detach-command-test-{}.log", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let tail = OutputTail::new(10);
+        let opts = RunOptions {
+            output: OutputMode::File(Arc::new(std::fs::File::create(&path).unwrap())),
+            max_output: Some(1 << 20),
+            tail: Some(tail.clone()),
+            ..RunOptions::default()
+        };
+        let started = std::time::Instant::now();
+        let outcome = run_command(LEAVES_OUTPUT_OPEN, opts).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(outcome.success(), "{}", outcome.exit_reason());
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines = tail.lines();
+        kill_left_sleep(&lines.iter().map(String::as_str).collect::<Vec<_>>());
+        assert_eq!(written, lines.join("\n") + "\n");
+    }
+
+    #[cfg(all(unix, feature = "redact"))]
+    #[tokio::test]
+    async fn file_output_is_redacted() {
+        let name = format!("detach-command-redact-{}.log", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let redactor = Redactor::new(&[], &["hunter[0-9]+".into()]).unwrap();
+        let opts = RunOptions {
+            output: OutputMode::File(Arc::new(std::fs::File::create(&path).unwrap())),
+            redactor: Some(Arc::new(redactor)),
+            ..RunOptions::default()
+        };
+        let outcome = run_command("printf 'pw hun'; printf 'ter22\\n\\001'", opts)
+            .await
+            .unwrap();
+        assert!(outcome.success(), "{}", outcome.exit_reason());
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Without sanitize rules nothing but the secret is changed
+        assert_eq!(written, b"pw [REDACTED]\n\x01");
+    }
+
+    #[test]
+    fn tail_keeps_the_last_lines() {
+        let tail = OutputTail::new(2);
+        assert!(tail.lines().is_empty());
+        tail.clone().push("one".to_string());
+        tail.push("two".to_string());
+        tail.push("three".to_string());
+        assert_eq!(tail.lines(), ["two", "three"]);
+
+        let none = OutputTail::new(0);
+        none.push("one".to_string());
+        assert!(none.lines().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tail_joins_lines_split_across_reads() {
+        let tail = OutputTail::new(10);
+        let pipe = (&b"fir"[..]).chain(&b"st\r\nsecond\nunfinished"[..]);
+        let mut read = Vec::new();
+        metered(pipe, &None, &Some(tail.clone()))
+            .read_to_end(&mut read)
+            .await
+            .unwrap();
+        assert_eq!(read, b"first\r\nsecond\nunfinished");
+        assert_eq!(tail.lines(), ["first", "second", "unfinished"]);
+    }
+
+    #[tokio::test]
+    async fn tail_cuts_long_lines() {
+        let tail = OutputTail::new(10);
+        let long = vec![b'x'; MAX_TAIL_LINE + 100];
+        let pipe = std::io::Cursor::new([&long[..], b"\nshort\n"].concat());
+        let mut read = Vec::new();
+        metered(pipe, &None, &Some(tail.clone()))
+            .read_to_end(&mut read)
+            .await
+            .unwrap();
+        assert_eq!(read.len(), MAX_TAIL_LINE + 107);
+        let lines = tail.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), MAX_TAIL_LINE);
+        assert_eq!(lines[1], "short");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn output_over_the_limit_kills_the_command() {
+        let tail = OutputTail::new(3);
+        let opts = RunOptions {
+            output: OutputMode::Capture,
+            max_output: Some(1000),
+            tail: Some(tail.clone()),
+            ..RunOptions::default()
+        };
+        let started = std::time::Instant::now();
+        let outcome = run_command("seq 1000000; sleep 30", opts).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(outcome.output_exceeded);
+        assert!(!outcome.success());
+        assert!(outcome.stdout.unwrap_or_default().len() < 1 << 20);
+    }
+
+    #[test]
+    fn trim_newline_strips_one_line_ending() {
+        assert_eq!(trim_newline(b"line\r\n"), b"line");
+        assert_eq!(trim_newline(b"line\n"), b"line");
+        assert_eq!(trim_newline(b"line"), b"line");
+        assert_eq!(trim_newline(b"\n\n"), b"\n");
+    }
+}
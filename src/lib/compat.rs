@@ -0,0 +1,624 @@
+//! A drop-in replacement for the `daemonize` crate's builder.
+//!
+//! Projects that daemonize with `daemonize::Daemonize` can switch to this module by
+//! changing the import; the builder methods and their order of effects are the same. Once
+//! the process is detached, the rest of this crate (async services, supervision, metrics)
+//! is available as usual.
+//!
+//! ```no_run
+//! use detach::compat::Daemonize;
+//! use std::fs::File;
+//!
+//! let stdout = File::create("/tmp/daemon.out").unwrap();
+//! let daemonize = Daemonize::new()
+//!     .pid_file("/tmp/test.pid")
+//!     .chown_pid_file(true)
+//!     .working_directory("/tmp")
+//!     .user("nobody")
+//!     .group("daemon")
+//!     .umask(0o027)
+//!     .stdout(stdout)
+//!     .privileged_action(|| "Executed before dropping privileges");
+//!
+//! match daemonize.start() {
+//!     Ok(message) => println!("Success, daemonized: {}", message),
+//!     Err(e) => eprintln!("Error, {}", e),
+//! }
+//! ```
+//!
+//! On OpenBSD the builder also takes `unveil` and `pledge` options, applied in the daemon
+//! after privileges are dropped; see `detach::openbsd`.
+use libc::{STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// A user to switch to, by name or numeric id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum User {
+    /// Looked up with `getpwnam`
+    Name(String),
+    /// Used as is
+    Id(u32),
+}
+
+impl From<&str> for User {
+    fn from(name: &str) -> Self {
+        User::Name(name.to_string())
+    }
+}
+
+impl From<String> for User {
+    fn from(name: String) -> Self {
+        User::Name(name)
+    }
+}
+
+impl From<u32> for User {
+    fn from(id: u32) -> Self {
+        User::Id(id)
+    }
+}
+
+/// A group to switch to, by name or numeric id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Group {
+    /// Looked up with `getgrnam`
+    Name(String),
+    /// Used as is
+    Id(u32),
+}
+
+impl From<&str> for Group {
+    fn from(name: &str) -> Self {
+        Group::Name(name.to_string())
+    }
+}
+
+impl From<String> for Group {
+    fn from(name: String) -> Self {
+        Group::Name(name)
+    }
+}
+
+impl From<u32> for Group {
+    fn from(id: u32) -> Self {
+        Group::Id(id)
+    }
+}
+
+/// The owner given to a directory created by `create_directory`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner {
+    /// The owning user
+    pub user: User,
+    /// The owning group; `None` leaves the group unchanged
+    pub group: Option<Group>,
+}
+
+impl Owner {
+    /// Parses `USER` or `USER:GROUP`; numeric values are used as ids.
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let (user, group) = match input.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (input, None),
+        };
+        if user.is_empty() || group.is_some_and(str::is_empty) {
+            return Err(anyhow::anyhow!(
+                "Expected USER or USER:GROUP, got \"{}\"",
+                input
+            ));
+        }
+        Ok(Owner {
+            user: user.parse::<u32>().map_or_else(|_| user.into(), User::Id),
+            group: group.map(|group| {
+                group
+                    .parse::<u32>()
+                    .map_or_else(|_| group.into(), Group::Id)
+            }),
+        })
+    }
+}
+
+/// Creates `path` with `mode` and `owner` if it does not exist yet.
+///
+/// Meant for log directories such as `/var/log/myservice`, which only root can create:
+/// call it while still privileged, before logging is set up and privileges are dropped.
+/// Missing parents are created with default permissions. An existing directory is left
+/// as it is.
+///
+/// # Arguments
+/// - `path`: The directory to create.
+/// - `mode`: Permission bits for the directory, applied regardless of the umask; `None`
+///   keeps the default.
+/// - `owner`: Who should own the directory; `None` keeps the current user.
+///
+/// # Returns
+/// - `Ok(())`: If the directory exists afterwards.
+/// - `Err(anyhow::Error)`: If it could not be created, chmod'ed or chown'ed.
+pub fn create_directory(
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<&Owner>,
+) -> Result<(), anyhow::Error> {
+    let uid = owner.map(|owner| resolve_user(&owner.user)).transpose()?;
+    let gid = owner
+        .and_then(|owner| owner.group.as_ref())
+        .map(resolve_group)
+        .transpose()?;
+    make_directory(path, mode, uid, gid)
+}
+
+fn make_directory(
+    path: &Path,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if path.is_dir() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(path)
+        .map_err(|e| anyhow::anyhow!("Failed to create directory {}: {}", path.display(), e))?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .map_err(|e| anyhow::anyhow!("Failed to set the mode of {}: {}", path.display(), e))?;
+    }
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(path, uid, gid)
+            .map_err(|e| anyhow::anyhow!("Failed to chown {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+/// Where a standard stream of the daemon goes.
+#[derive(Debug)]
+pub struct Stdio(StdioTarget);
+
+#[derive(Debug)]
+enum StdioTarget {
+    DevNull,
+    Keep,
+    File(File),
+}
+
+impl Stdio {
+    /// Redirect the stream to `/dev/null` (the default).
+    pub fn devnull() -> Self {
+        Stdio(StdioTarget::DevNull)
+    }
+
+    /// Leave the stream as inherited from the parent.
+    pub fn keep() -> Self {
+        Stdio(StdioTarget::Keep)
+    }
+}
+
+impl From<File> for Stdio {
+    fn from(file: File) -> Self {
+        Stdio(StdioTarget::File(file))
+    }
+}
+
+/// Daemonization options, mirroring `daemonize::Daemonize`.
+///
+/// `T` is the result of the `privileged_action`, returned by `start`.
+pub struct Daemonize<T> {
+    directory: PathBuf,
+    pid_file: Option<PathBuf>,
+    chown_pid_file: bool,
+    user: Option<User>,
+    group: Option<Group>,
+    umask: libc::mode_t,
+    root: Option<PathBuf>,
+    directories: Vec<(PathBuf, u32)>,
+    privileged_action: Box<dyn FnOnce() -> T>,
+    exit_action: Box<dyn FnOnce()>,
+    stdin: Stdio,
+    stdout: Stdio,
+    stderr: Stdio,
+    #[cfg(target_os = "openbsd")]
+    unveil: Vec<(PathBuf, String)>,
+    #[cfg(target_os = "openbsd")]
+    pledge: Option<(String, Option<String>)>,
+}
+
+impl<T> std::fmt::Debug for Daemonize<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("Daemonize");
+        debug
+            .field("directory", &self.directory)
+            .field("pid_file", &self.pid_file)
+            .field("chown_pid_file", &self.chown_pid_file)
+            .field("user", &self.user)
+            .field("group", &self.group)
+            .field("umask", &self.umask)
+            .field("root", &self.root)
+            .field("directories", &self.directories)
+            .field("stdin", &self.stdin)
+            .field("stdout", &self.stdout)
+            .field("stderr", &self.stderr);
+        #[cfg(target_os = "openbsd")]
+        debug
+            .field("unveil", &self.unveil)
+            .field("pledge", &self.pledge);
+        debug.finish()
+    }
+}
+
+impl Default for Daemonize<()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Daemonize<()> {
+    /// Starts from the defaults: working directory `/`, umask `027`, no PID file, standard
+    /// streams redirected to `/dev/null`.
+    pub fn new() -> Self {
+        Daemonize {
+            directory: PathBuf::from("/"),
+            pid_file: None,
+            chown_pid_file: false,
+            user: None,
+            group: None,
+            umask: 0o027,
+            root: None,
+            directories: Vec::new(),
+            privileged_action: Box::new(|| ()),
+            exit_action: Box::new(|| ()),
+            stdin: Stdio::devnull(),
+            stdout: Stdio::devnull(),
+            stderr: Stdio::devnull(),
+            #[cfg(target_os = "openbsd")]
+            unveil: Vec::new(),
+            #[cfg(target_os = "openbsd")]
+            pledge: None,
+        }
+    }
+}
+
+impl<T> Daemonize<T> {
+    /// Creates and locks a PID file at `path`, and writes the daemon's PID to it.
+    pub fn pid_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.pid_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Makes the PID file owned by the `user`/`group` the daemon switches to.
+    pub fn chown_pid_file(mut self, chown: bool) -> Self {
+        self.chown_pid_file = chown;
+        self
+    }
+
+    /// Changes to `path` after forking (default `/`).
+    pub fn working_directory<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.directory = path.as_ref().to_path_buf();
+        self
+    }
+
+    /// Switches to this user after the privileged action has run.
+    pub fn user<U: Into<User>>(mut self, user: U) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Switches to this group after the privileged action has run.
+    pub fn group<G: Into<Group>>(mut self, group: G) -> Self {
+        self.group = Some(group.into());
+        self
+    }
+
+    /// Sets the file creation mask (default `0o027`).
+    pub fn umask(mut self, mask: u32) -> Self {
+        self.umask = mask as libc::mode_t;
+        self
+    }
+
+    /// Changes the root directory after the privileged action has run.
+    pub fn chroot<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.root = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Creates `path` with `mode` if it is missing, owned by the `user`/`group` the daemon
+    /// switches to, before privileges are dropped. Use it for log or state directories
+    /// under `/var` that the unprivileged daemon has to write to.
+    pub fn directory<P: AsRef<Path>>(mut self, path: P, mode: u32) -> Self {
+        self.directories.push((path.as_ref().to_path_buf(), mode));
+        self
+    }
+
+    /// Runs `action` in the daemon before privileges are dropped; its result is returned
+    /// by `start`.
+    pub fn privileged_action<N, F: FnOnce() -> N + 'static>(self, action: F) -> Daemonize<N> {
+        Daemonize {
+            directory: self.directory,
+            pid_file: self.pid_file,
+            chown_pid_file: self.chown_pid_file,
+            user: self.user,
+            group: self.group,
+            umask: self.umask,
+            root: self.root,
+            directories: self.directories,
+            privileged_action: Box::new(action),
+            exit_action: self.exit_action,
+            stdin: self.stdin,
+            stdout: self.stdout,
+            stderr: self.stderr,
+            #[cfg(target_os = "openbsd")]
+            unveil: self.unveil,
+            #[cfg(target_os = "openbsd")]
+            pledge: self.pledge,
+        }
+    }
+
+    /// Runs `action` in the original process just before it exits.
+    pub fn exit_action<F: FnOnce() + 'static>(mut self, action: F) -> Self {
+        self.exit_action = Box::new(action);
+        self
+    }
+
+    /// Where the daemon's standard output goes (default `/dev/null`).
+    pub fn stdout<S: Into<Stdio>>(mut self, stdio: S) -> Self {
+        self.stdout = stdio.into();
+        self
+    }
+
+    /// Where the daemon's standard error goes (default `/dev/null`).
+    pub fn stderr<S: Into<Stdio>>(mut self, stdio: S) -> Self {
+        self.stderr = stdio.into();
+        self
+    }
+
+    /// Makes `path` visible to the daemon with `permissions` (`r`, `w`, `x`, `c`); all
+    /// other paths are hidden once the daemon is set up (OpenBSD).
+    ///
+    /// Paths are relative to the `chroot`, if one is set.
+    #[cfg(target_os = "openbsd")]
+    pub fn unveil<P: AsRef<Path>>(mut self, path: P, permissions: &str) -> Self {
+        self.unveil
+            .push((path.as_ref().to_path_buf(), permissions.to_string()));
+        self
+    }
+
+    /// Pledges `promises` once the daemon is set up (OpenBSD); see
+    /// `detach::openbsd::promises` for ready-made sets.
+    #[cfg(target_os = "openbsd")]
+    pub fn pledge(mut self, promises: &str) -> Self {
+        self.pledge = Some((promises.to_string(), None));
+        self
+    }
+
+    /// Like `pledge`, also restricting programs the daemon executes to `exec_promises`.
+    #[cfg(target_os = "openbsd")]
+    pub fn pledge_exec(mut self, promises: &str, exec_promises: &str) -> Self {
+        self.pledge = Some((promises.to_string(), Some(exec_promises.to_string())));
+        self
+    }
+
+    /// Detaches the process. The original process exits; the daemon continues here.
+    ///
+    /// Like `detach::daemonize`, this refuses to fork inside a running tokio runtime. Build
+    /// the runtime after `start` returns.
+    ///
+    /// # Returns
+    /// - `Ok(T)`: In the daemon, the result of the privileged action.
+    /// - `Err(anyhow::Error)`: If any step fails. Failures after the first fork are
+    ///   reported in the daemon, whose standard streams may already be redirected.
+    pub fn start(self) -> Result<T, anyhow::Error> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(anyhow::anyhow!(
+                "Daemonize::start must be called before a tokio runtime is started"
+            ));
+        }
+
+        // Resolve names while the parent's error reporting still reaches the terminal, and
+        // prepare what the session leader needs: between the forks it only makes
+        // async-signal-safe system calls (see `detach::daemonize`).
+        let uid = self.user.as_ref().map(resolve_user).transpose()?;
+        let gid = self.group.as_ref().map(resolve_group).transpose()?;
+        let directory = path_cstring(&self.directory)?;
+
+        unsafe {
+            let pid = libc::fork();
+            if pid < 0 {
+                return Err(anyhow::anyhow!(
+                    "First fork failed: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            if pid > 0 {
+                (self.exit_action)();
+                std::process::exit(0);
+            }
+        }
+
+        unsafe {
+            if libc::chdir(directory.as_ptr()) < 0 {
+                return Err(anyhow::anyhow!(
+                    "Failed to change directory to {}: {}",
+                    self.directory.display(),
+                    std::io::Error::last_os_error()
+                ));
+            }
+            if libc::setsid() < 0 {
+                return Err(anyhow::anyhow!("Failed to create new session"));
+            }
+            libc::umask(self.umask);
+            let pid = libc::fork();
+            if pid < 0 {
+                return Err(anyhow::anyhow!(
+                    "Second fork failed: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            if pid > 0 {
+                libc::_exit(0);
+            }
+        }
+
+        let pid_file = match &self.pid_file {
+            Some(path) => Some(create_pid_file(path)?),
+            None => None,
+        };
+
+        redirect(&self.stdin, STDIN_FILENO, false)?;
+        redirect(&self.stdout, STDOUT_FILENO, true)?;
+        redirect(&self.stderr, STDERR_FILENO, true)?;
+
+        if self.chown_pid_file
+            && let Some(path) = &self.pid_file
+        {
+            let path = path_cstring(path)?;
+            let uid = uid.unwrap_or(u32::MAX) as libc::uid_t;
+            let gid = gid.unwrap_or(u32::MAX) as libc::gid_t;
+            if unsafe { libc::chown(path.as_ptr(), uid, gid) } < 0 {
+                return Err(anyhow::anyhow!(
+                    "Failed to chown PID file: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+
+        for (path, mode) in &self.directories {
+            make_directory(path, Some(*mode), uid, gid)?;
+        }
+
+        let output = (self.privileged_action)();
+
+        if let Some(root) = &self.root {
+            let root = path_cstring(root)?;
+            if unsafe { libc::chroot(root.as_ptr()) } < 0 {
+                return Err(anyhow::anyhow!(
+                    "Failed to chroot: {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            std::env::set_current_dir("/")?;
+        }
+        if let Some(gid) = gid
+            && unsafe { libc::setgid(gid as libc::gid_t) } < 0
+        {
+            return Err(anyhow::anyhow!(
+                "Failed to set group {}: {}",
+                gid,
+                std::io::Error::last_os_error()
+            ));
+        }
+        if let Some(uid) = uid
+            && unsafe { libc::setuid(uid as libc::uid_t) } < 0
+        {
+            return Err(anyhow::anyhow!(
+                "Failed to set user {}: {}",
+                uid,
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        if let Some(mut file) = pid_file {
+            file.set_len(0)?;
+            write!(file, "{}", std::process::id())?;
+            // Keep the file open, and with it the lock, for the life of the daemon.
+            std::mem::forget(file);
+        }
+
+        #[cfg(target_os = "openbsd")]
+        {
+            if !self.unveil.is_empty() {
+                for (path, permissions) in &self.unveil {
+                    crate::openbsd::unveil(path, permissions)?;
+                }
+                crate::openbsd::unveil_lock()?;
+            }
+            if let Some((promises, exec_promises)) = &self.pledge {
+                crate::openbsd::pledge(promises, exec_promises.as_deref())?;
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Opens and exclusively locks the PID file, failing if another daemon holds it.
+fn create_pid_file(path: &Path) -> Result<File, anyhow::Error> {
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o644)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open PID file {}: {}", path.display(), e))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(std::fs::TryLockError::WouldBlock) => Err(anyhow::anyhow!(
+            "PID file {} is locked by another process",
+            path.display()
+        )),
+        Err(std::fs::TryLockError::Error(e)) => Err(anyhow::anyhow!(
+            "Failed to lock PID file {}: {}",
+            path.display(),
+            e
+        )),
+    }
+}
+
+fn redirect(stdio: &Stdio, target: RawFd, write: bool) -> Result<(), anyhow::Error> {
+    let devnull;
+    let fd = match &stdio.0 {
+        StdioTarget::Keep => return Ok(()),
+        StdioTarget::File(file) => file.as_raw_fd(),
+        StdioTarget::DevNull => {
+            devnull = std::fs::OpenOptions::new()
+                .read(!write)
+                .write(write)
+                .open("/dev/null")?;
+            devnull.as_raw_fd()
+        }
+    };
+    if unsafe { libc::dup2(fd, target) } < 0 {
+        return Err(anyhow::anyhow!(
+            "Failed to redirect fd {}: {}",
+            target,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn resolve_user(user: &User) -> Result<u32, anyhow::Error> {
+    match user {
+        User::Id(id) => Ok(*id),
+        User::Name(name) => {
+            let c_name = CString::new(name.as_str())?;
+            let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+            if passwd.is_null() {
+                return Err(anyhow::anyhow!("Unknown user \"{}\"", name));
+            }
+            Ok(unsafe { (*passwd).pw_uid })
+        }
+    }
+}
+
+pub(crate) fn resolve_group(group: &Group) -> Result<u32, anyhow::Error> {
+    match group {
+        Group::Id(id) => Ok(*id),
+        Group::Name(name) => {
+            let c_name = CString::new(name.as_str())?;
+            let entry = unsafe { libc::getgrnam(c_name.as_ptr()) };
+            if entry.is_null() {
+                return Err(anyhow::anyhow!("Unknown group \"{}\"", name));
+            }
+            Ok(unsafe { (*entry).gr_gid })
+        }
+    }
+}
+
+fn path_cstring(path: &Path) -> Result<CString, anyhow::Error> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
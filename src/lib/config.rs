@@ -0,0 +1,216 @@
+//! Service definition files.
+//!
+//! A service definition lives in `~/.config/detach/services/<name>.toml` (or under
+//! `$XDG_CONFIG_HOME` when it is set). `detach-rs init` scaffolds a commented file there
+//! so users can start from a working example instead of a blank page.
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::Write as _;
+use std::path::PathBuf;
+
+/// Returns the directory service definitions are stored in.
+///
+/// # Returns
+/// - `Ok(PathBuf)`: `$XDG_CONFIG_HOME/detach/services`, or `$HOME/.config/detach/services`.
+/// - `Err(anyhow::Error)`: If neither `XDG_CONFIG_HOME` nor `HOME` is set.
+pub fn services_dir() -> Result<PathBuf, anyhow::Error> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".config"))
+            .ok_or_else(|| anyhow::anyhow!("Neither XDG_CONFIG_HOME nor HOME is set"))?,
+    };
+    Ok(config_home.join("detach").join("services"))
+}
+
+/// Returns the directory runtime state such as crash reports is kept in.
+///
+/// # Returns
+/// - `Ok(PathBuf)`: `$XDG_STATE_HOME/detach` or `$HOME/.local/state/detach`;
+///   `~/Library/Application Support/detach` on macOS; `%LOCALAPPDATA%\detach` on Windows.
+/// - `Err(anyhow::Error)`: If the home directory cannot be determined.
+pub fn state_dir() -> Result<PathBuf, anyhow::Error> {
+    if cfg!(target_os = "macos") {
+        return Ok(home_dir()?.join("Library/Application Support/detach"));
+    }
+    if cfg!(windows) {
+        return Ok(local_app_data()?.join("detach"));
+    }
+    let state_home = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".local").join("state"))
+            .ok_or_else(|| anyhow::anyhow!("Neither XDG_STATE_HOME nor HOME is set"))?,
+    };
+    Ok(state_home.join("detach"))
+}
+
+/// Returns the directory log files go to when `--log-file` is not given.
+///
+/// # Returns
+/// - `Ok(PathBuf)`: `state_dir()/logs`; `~/Library/Logs/detach` on macOS;
+///   `%LOCALAPPDATA%\detach\logs` on Windows.
+/// - `Err(anyhow::Error)`: If the home directory cannot be determined.
+pub fn log_dir() -> Result<PathBuf, anyhow::Error> {
+    if cfg!(target_os = "macos") {
+        return Ok(home_dir()?.join("Library/Logs/detach"));
+    }
+    Ok(state_dir()?.join("logs"))
+}
+
+/// Returns the directory for PID and lock files, which should not outlive the session.
+///
+/// # Returns
+/// - `Ok(PathBuf)`: `$XDG_RUNTIME_DIR/detach` when it is set, `state_dir()` otherwise.
+/// - `Err(anyhow::Error)`: If neither can be determined.
+pub fn runtime_dir() -> Result<PathBuf, anyhow::Error> {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() && !cfg!(windows) => Ok(PathBuf::from(dir).join("detach")),
+        _ => state_dir(),
+    }
+}
+
+fn home_dir() -> Result<PathBuf, anyhow::Error> {
+    std::env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow::anyhow!("HOME is not set"))
+}
+
+fn local_app_data() -> Result<PathBuf, anyhow::Error> {
+    std::env::var_os("LOCALAPPDATA")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow::anyhow!("LOCALAPPDATA is not set"))
+}
+
+/// Checks that `name` can be used as a service name and file name.
+///
+/// Names may only contain ASCII letters, digits, `-`, `_` and `.`, and may not start with
+/// a `.`, which keeps them safe to embed in paths and shell completions. The name of an
+/// instance adds `:INDEX` (see `instances`).
+pub fn validate_service_name(name: &str) -> Result<(), anyhow::Error> {
+    let name = crate::instances::split(name).0;
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Invalid service name \"{}\": use letters, digits, '-', '_' and '.' only",
+            name
+        ))
+    }
+}
+
+/// Renders the commented service definition written by `detach-rs init`.
+///
+/// # Arguments
+/// - `name`: The service name.
+/// - `command`: The program and its arguments.
+pub fn render_service_template(name: &str, command: &[String]) -> String {
+    let quoted: Vec<String> = command
+        .iter()
+        .map(|arg| toml::Value::String(arg.clone()).to_string())
+        .collect();
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# Service definition for `{}`, created by `detach-rs init`.",
+        name
+    );
+    let _ = writeln!(out, "# Uncomment and adjust the settings you need.");
+    let _ = writeln!(out);
+    let _ = writeln!(out, "name = {}", toml::Value::String(name.to_string()));
+    let _ = writeln!(out);
+    let _ = writeln!(out, "# The program to run and its arguments.");
+    let _ = writeln!(out, "command = [{}]", quoted.join(", "));
+    let _ = writeln!(out);
+    let _ = writeln!(
+        out,
+        "# Working directory and extra environment for the command."
+    );
+    let _ = writeln!(out, "# working_dir = \"/srv/{}\"", name);
+    let _ = writeln!(out, "# [env]");
+    let _ = writeln!(out, "# RUST_LOG = \"info\"");
+    out.push_str(
+        r#"
+[restart]
+# When to restart the command: "never", "on-failure" or "always".
+# policy = "on-failure"
+# Give up after this many restarts within `window_secs`.
+# max_restarts = 5
+# window_secs = 60
+# Delay before the first restart; doubled after each further failure.
+# backoff_secs = 1
+
+[log]
+# Where to write the log. Relative paths are resolved against the working directory.
+"#,
+    );
+    let _ = writeln!(out, "# file = \"{}.log\"", name);
+    out.push_str(
+        r#"# Level for the log file and, when running in the foreground, the console.
+# level = "info"
+# console_level = "warn"
+
+[readiness]
+# How to decide the service is up. Pick one probe.
+# tcp = "127.0.0.1:8080"
+# http = "http://127.0.0.1:8080/healthz"
+# command = ["./healthcheck.sh"]
+# timeout_secs = 30
+"#,
+    );
+    out
+}
+
+/// Writes a commented service definition for `name` into `services_dir()`.
+///
+/// # Arguments
+/// - `name`: The service name, which also becomes the file name.
+/// - `command`: The program and its arguments.
+/// - `force`: Overwrite an existing definition instead of failing.
+///
+/// # Returns
+/// - `Ok(PathBuf)`: The path of the file that was written.
+/// - `Err(anyhow::Error)`: If the name is invalid, the file exists and `force` is not set,
+///   or the file cannot be written.
+pub fn scaffold_service(
+    name: &str,
+    command: &[String],
+    force: bool,
+) -> Result<PathBuf, anyhow::Error> {
+    validate_service_name(name)?;
+    if command.is_empty() {
+        return Err(anyhow::anyhow!("No command given for service \"{}\"", name));
+    }
+
+    let dir = services_dir()?;
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.toml", name));
+
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = options.open(&path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::AlreadyExists {
+            anyhow::anyhow!(
+                "{} already exists (use --force to overwrite)",
+                path.display()
+            )
+        } else {
+            anyhow::anyhow!("Failed to create {}: {}", path.display(), e)
+        }
+    })?;
+    file.write_all(render_service_template(name, command).as_bytes())?;
+    Ok(path)
+}
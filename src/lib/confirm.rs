@@ -0,0 +1,41 @@
+//! Asking before actions that affect every instance at once.
+//!
+//! `detach-rs stop --all` and `detach-rs clean` act on all instances of the user, which
+//! is rarely what a mistyped command meant. On a terminal they ask first; without one
+//! (in a script, over a pipe, from cron) there is nobody to ask, so they refuse unless
+//! `--force` (or `--yes`) says the caller meant it.
+use std::io::{BufRead, IsTerminal, Write};
+
+/// Asks whether to `action` (e.g. "stop 3 instances") on the terminal and returns
+/// whether the answer was yes.
+///
+/// # Returns
+/// - `Ok(true)`: `force` was given, or the answer was `y` or `yes`.
+/// - `Ok(false)`: Any other answer, including an empty one or end of input.
+/// - `Err(anyhow::Error)`: Without `force` when standard input is not a terminal.
+pub fn confirm(action: &str, force: bool) -> Result<bool, anyhow::Error> {
+    if force {
+        return Ok(true);
+    }
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(anyhow::anyhow!(
+            "Refusing to {} without a terminal to confirm on; pass --force to do it anyway",
+            action
+        ));
+    }
+    let mut first = action.chars();
+    let question: String = first
+        .next()
+        .map(|c| c.to_uppercase().chain(first).collect())
+        .unwrap_or_default();
+    let mut stderr = std::io::stderr().lock();
+    write!(stderr, "{}? [y/N] ", question)?;
+    stderr.flush()?;
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
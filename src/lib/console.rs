@@ -0,0 +1,38 @@
+//! Serving tokio-console instrumentation from a daemon (`tokio-console` feature).
+//!
+//! `tokio-console` attaches to a gRPC server the `console-subscriber` crate runs next to
+//! the application. That server is a thread with its own runtime and a bound socket, so
+//! it has to be started in the daemon after the fork: started before, the thread would
+//! not exist in the child and the port would be held by a process that has exited.
+//! `with_tokio_console` starts it from inside the service future, which only ever runs in
+//! the daemon.
+//!
+//! Task instrumentation is only emitted when the crate is built with
+//! `RUSTFLAGS="--cfg tokio_unstable"`; without it the console connects but shows no
+//! tasks, and a warning says so.
+use log::{info, warn};
+use std::net::SocketAddr;
+
+/// The address tokio-console connects to by default.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:6669";
+
+/// Runs `future` with the console-subscriber server listening on `addr`; without an
+/// `addr` this is a plain `future.await`.
+///
+/// The server installs itself as the global `tracing` subscriber, so it cannot be
+/// combined with another one.
+pub async fn with_tokio_console<F>(addr: Option<SocketAddr>, future: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    if let Some(addr) = addr {
+        if !cfg!(tokio_unstable) {
+            warn!("Built without --cfg tokio_unstable; tokio-console will not see any tasks.");
+        }
+        console_subscriber::ConsoleLayer::builder()
+            .server_addr(addr)
+            .init();
+        info!("tokio-console server listening on {}", addr);
+    }
+    future.await
+}
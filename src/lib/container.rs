@@ -0,0 +1,98 @@
+//! Supervising a container instead of a native process.
+//!
+//! With `--backend podman` or `--backend docker` the command string names an image and,
+//! optionally, its arguments. Each run of the supervisor then becomes a foreground
+//! `podman run --rm` of that image, so the container's output goes to the same log as a
+//! native command's, forwarded signals reach it through the engine's signal proxy, and
+//! restarts, schedules and control requests work unchanged. The image is pulled when it
+//! is missing.
+//!
+//! The container is named `detach-<NAME>` after the instance (or `detach-<PID>` without
+//! `--name`). A container of that name left over by an earlier supervisor is removed
+//! before each run, and the container is removed again after each run in case the engine
+//! client was killed before it could clean up.
+use log::{debug, warn};
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// How the supervised command is executed.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Run the command through `sh -c`
+    #[default]
+    Process,
+    /// Run the image named by the command with `podman run`
+    Podman,
+    /// Run the image named by the command with `docker run`
+    Docker,
+}
+
+impl Backend {
+    /// Returns the container the instance `name` runs in, or `None` for `Process`.
+    pub fn container(self, name: Option<&str>) -> Option<Container> {
+        let engine = match self {
+            Backend::Process => return None,
+            Backend::Podman => "podman",
+            Backend::Docker => "docker",
+        };
+        let name = match name {
+            Some(name) => format!("detach-{}", name),
+            None => format!("detach-{}", std::process::id()),
+        };
+        Some(Container { engine, name })
+    }
+}
+
+/// A container run by a container engine on behalf of the supervisor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Container {
+    /// The engine's command-line client, `podman` or `docker`
+    pub engine: &'static str,
+    /// The container name
+    pub name: String,
+}
+
+impl Container {
+    /// Returns the shell command that runs `image_and_args` in this container in the
+    /// foreground.
+    ///
+    /// `image_and_args` is passed on as written, so it may quote arguments for the shell.
+    pub fn run_command(&self, image_and_args: &str) -> String {
+        format!(
+            "exec {} run --rm --init --name {} --pull=missing {}",
+            self.engine, self.name, image_and_args
+        )
+    }
+
+    /// Removes the container if it exists, whether it is running or not.
+    ///
+    /// Failures are logged; a container that does not exist is not an error.
+    pub async fn remove(&self) {
+        let mut command = Command::new(self.engine);
+        command
+            .args(["rm", "--force", &self.name])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        let result = crate::reaper::output(&mut command).await;
+        match result {
+            Ok(output) if output.status.success() => {
+                debug!("Removed container {} if it existed.", self.name)
+            }
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if !stderr.to_lowercase().contains("no such container") {
+                    warn!(
+                        "Failed to remove container {}: {}",
+                        self.name,
+                        stderr.trim()
+                    );
+                }
+            }
+            Err(e) => warn!(
+                "Failed to run {} to remove {}: {}",
+                self.engine, self.name, e
+            ),
+        }
+    }
+}
@@ -0,0 +1,346 @@
+//! Shared handles a service uses to talk to the daemon around it.
+//!
+//! The service future is an opaque `Future` handed to `daemonize`, so anything it wants to
+//! report has to travel through a side channel. `ServiceContext` is that channel: a cheap
+//! clonable handle, available process-wide through `ServiceContext::current()`, that the
+//! daemon's exporters read from.
+use crate::audit::LifecycleEvent;
+use crate::command::OutputTail;
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::pidfd::ProcessHandle;
+use crate::state::State;
+use log::info;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch};
+
+/// A request from a management interface to the supervisor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlRequest {
+    /// Start the command now if it is waiting to be restarted
+    Start,
+    /// Stop the command and do not restart it
+    Stop,
+    /// Stop the command and start it again right away
+    Restart,
+}
+
+/// Receives reload requests; see `ServiceContext::subscribe_reload`.
+pub type ReloadReceiver = watch::Receiver<u64>;
+
+#[derive(Debug)]
+struct Inner {
+    metrics: Metrics,
+    health: Health,
+    state: State,
+    /// Number of reloads requested so far
+    reload: watch::Sender<u64>,
+    events: broadcast::Sender<LifecycleEvent>,
+    recent: Mutex<VecDeque<LifecycleEvent>>,
+    /// How many past events `recent` keeps; see `profile::Profile::event_buffers`
+    recent_limit: usize,
+    requests: broadcast::Sender<ControlRequest>,
+    started: Instant,
+    output: OnceLock<OutputTail>,
+    /// `KEY=VALUE` labels given with `--tag`
+    tags: OnceLock<Vec<(String, String)>>,
+    /// TCP ports the command listens on, given or allocated with `--port`
+    ports: OnceLock<Vec<u16>>,
+    /// How the instance was launched; see `launch`
+    launch: OnceLock<serde_json::Value>,
+    /// The running command, if any
+    child: Mutex<Option<Arc<ProcessHandle>>>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        let (channel, recent_limit) = crate::profile::profile().event_buffers();
+        Self {
+            metrics: Metrics::default(),
+            health: Health::default(),
+            state: State::default(),
+            reload: watch::Sender::new(0),
+            events: broadcast::Sender::new(channel),
+            recent: Mutex::new(VecDeque::with_capacity(recent_limit)),
+            recent_limit,
+            requests: broadcast::Sender::new(16),
+            started: Instant::now(),
+            output: OnceLock::new(),
+            tags: OnceLock::new(),
+            ports: OnceLock::new(),
+            launch: OnceLock::new(),
+            child: Mutex::new(None),
+        }
+    }
+}
+
+/// The handle a service uses to publish metrics and health to the daemon, and to learn
+/// when it should reload its configuration.
+#[derive(Debug, Clone, Default)]
+pub struct ServiceContext {
+    inner: Arc<Inner>,
+}
+
+impl ServiceContext {
+    /// Creates a context that is not shared with anything else.
+    ///
+    /// Most services want `ServiceContext::current()` instead, which is the context the
+    /// daemon exports.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the process-wide context, creating it on first use.
+    pub fn current() -> ServiceContext {
+        static CURRENT: OnceLock<ServiceContext> = OnceLock::new();
+        CURRENT.get_or_init(ServiceContext::new).clone()
+    }
+
+    /// The service's metrics registry.
+    ///
+    /// ```
+    /// use detach::ServiceContext;
+    ///
+    /// let ctx = ServiceContext::new();
+    /// let served = ctx.metrics().counter("requests_total", "Requests served");
+    /// served.inc();
+    /// assert!(ctx.metrics().render_prometheus().contains("requests_total 1"));
+    /// ```
+    pub fn metrics(&self) -> &Metrics {
+        &self.inner.metrics
+    }
+
+    /// The service's health, as reported by the service.
+    ///
+    /// ```
+    /// use detach::ServiceContext;
+    ///
+    /// let ctx = ServiceContext::new();
+    /// ctx.health().set_degraded("db unreachable");
+    /// assert!(!ctx.health().get().is_healthy());
+    /// ctx.health().set_healthy();
+    /// ```
+    pub fn health(&self) -> &Health {
+        &self.inner.health
+    }
+
+    /// Where the instance is in its lifecycle; see `state`.
+    pub fn state(&self) -> &State {
+        &self.inner.state
+    }
+
+    /// Time since the context was created; for `ServiceContext::current()`, roughly the
+    /// uptime of the daemon.
+    pub fn uptime(&self) -> Duration {
+        self.inner.started.elapsed()
+    }
+
+    /// Records the PID of the command that was just started, or `None` once it has exited.
+    ///
+    /// Opens a `ProcessHandle` on it; see `set_child`.
+    pub fn set_child_pid(&self, pid: Option<u32>) {
+        self.set_child(pid.map(|pid| Arc::new(ProcessHandle::open(pid))));
+    }
+
+    /// Records the command that was just started, or `None` once it has exited.
+    ///
+    /// `run_command` calls this on the process-wide context for every command it runs. The
+    /// PID is also written to the registry with the state (see `state`).
+    pub fn set_child(&self, child: Option<Arc<ProcessHandle>>) {
+        let pid = child.as_ref().map(|child| child.pid());
+        *self.inner.child.lock().unwrap_or_else(|e| e.into_inner()) = child;
+        self.inner.state.set_child(pid);
+    }
+
+    /// The command that is running, if any.
+    ///
+    /// Signals for it should go through the handle, which cannot reach another process
+    /// that took over its PID where pidfds are available (see `pidfd`).
+    pub fn child(&self) -> Option<Arc<ProcessHandle>> {
+        self.inner
+            .child
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// The PID of the command that is running, if any.
+    pub fn child_pid(&self) -> Option<u32> {
+        self.child().map(|child| child.pid())
+    }
+
+    /// Asks the service to reload its configuration.
+    ///
+    /// Called for SIGHUP by `with_sighup_reload`; services may also call it themselves.
+    pub fn request_reload(&self) {
+        self.inner.reload.send_modify(|count| *count += 1);
+    }
+
+    /// Returns a receiver that wakes up on every reload request made after this call.
+    ///
+    /// ```no_run
+    /// # async fn example() {
+    /// let mut reloads = detach::ServiceContext::current().subscribe_reload();
+    /// while reloads.changed().await.is_ok() {
+    ///     // re-read the configuration here
+    /// }
+    /// # }
+    /// ```
+    pub fn subscribe_reload(&self) -> ReloadReceiver {
+        self.inner.reload.subscribe()
+    }
+
+    /// Asks the supervisor to start, stop or restart the command.
+    ///
+    /// Sent by management interfaces such as D-Bus; returns `false` if no supervisor is
+    /// listening.
+    pub fn request(&self, request: ControlRequest) -> bool {
+        self.inner.requests.send(request).is_ok()
+    }
+
+    /// Returns a receiver for every control request made after this call.
+    pub fn subscribe_requests(&self) -> broadcast::Receiver<ControlRequest> {
+        self.inner.requests.subscribe()
+    }
+
+    /// Publishes a lifecycle event to subscribers and keeps it among the recent events.
+    ///
+    /// The supervisor publishes its starts, stops, restarts, signals and reloads here.
+    pub fn publish_event(&self, event: LifecycleEvent) {
+        let mut recent = self.inner.recent.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() == self.inner.recent_limit {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        let _ = self.inner.events.send(event);
+    }
+
+    /// Returns a receiver for every lifecycle event published after this call.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<LifecycleEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// Starts keeping the last `lines` lines of the supervised command's output, and
+    /// returns the tail they go to. Only the first call sets the number of lines.
+    pub fn keep_output(&self, lines: usize) -> OutputTail {
+        self.inner
+            .output
+            .get_or_init(|| OutputTail::new(lines))
+            .clone()
+    }
+
+    /// The tail set up by `keep_output`, if any; commands run by the supervisor write
+    /// their output to it.
+    pub fn output_tail(&self) -> Option<OutputTail> {
+        self.inner.output.get().cloned()
+    }
+
+    /// Returns the last lines of command output kept since `keep_output`, oldest first,
+    /// or `None` if output is not being kept.
+    pub fn recent_output(&self) -> Option<Vec<String>> {
+        self.inner.output.get().map(OutputTail::lines)
+    }
+
+    /// Labels the instance with `KEY=VALUE` tags, which `status` reports and `detach-rs
+    /// stop --tag` and friends select by. Only the first call has an effect.
+    pub fn set_tags(&self, tags: Vec<(String, String)>) {
+        let _ = self.inner.tags.set(tags);
+    }
+
+    /// The tags set with `set_tags`, in the order they were given.
+    pub fn tags(&self) -> &[(String, String)] {
+        self.inner.tags.get().map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Records the TCP ports the command listens on, which `status` reports. Only the
+    /// first call has an effect.
+    pub fn set_ports(&self, ports: Vec<u16>) {
+        let _ = self.inner.ports.set(ports);
+    }
+
+    /// The ports set with `set_ports`.
+    pub fn ports(&self) -> &[u16] {
+        self.inner
+            .ports
+            .get()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Records how the instance was launched (see `launch::capture`), which the control
+    /// socket writes out for `detach-rs inspect` once it listens. Only the first call has
+    /// an effect.
+    pub fn set_launch(&self, snapshot: serde_json::Value) {
+        let _ = self.inner.launch.set(snapshot);
+    }
+
+    /// The snapshot recorded with `set_launch`, if any.
+    pub fn launch(&self) -> Option<&serde_json::Value> {
+        self.inner.launch.get()
+    }
+
+    /// Returns up to the last 100 lifecycle events (10 with `--profile minimal`), oldest
+    /// first.
+    pub fn recent_events(&self) -> Vec<LifecycleEvent> {
+        let recent = self.inner.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.iter().cloned().collect()
+    }
+}
+
+/// A random ID for this start of the daemon, in UUID (version 4) form.
+///
+/// It is created on first use and stays the same for the life of the process, across
+/// the fork into the background and across restarts of a supervised command, so it
+/// tells apart the log lines of different starts of the same service.
+pub fn run_id() -> &'static str {
+    static RUN_ID: OnceLock<String> = OnceLock::new();
+    RUN_ID.get_or_init(|| {
+        let mut bytes: [u8; 16] = rand::random();
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    })
+}
+
+/// Runs `future` while turning every SIGHUP into `ctx.request_reload()`.
+///
+/// This replaces the default action of SIGHUP, which would terminate the daemon, with the
+/// conventional "reload your configuration". On non-Unix platforms it is a plain
+/// `future.await`.
+pub async fn with_sighup_reload<F>(ctx: ServiceContext, future: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        if let Ok(mut hangups) = signal(SignalKind::hangup()) {
+            let listen = async move {
+                while hangups.recv().await.is_some() {
+                    info!("Received SIGHUP, requesting reload.");
+                    ctx.request_reload();
+                }
+            };
+            tokio::pin!(future);
+            tokio::select! {
+                output = &mut future => return output,
+                _ = listen => {}
+            }
+            return future.await;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = ctx;
+    future.await
+}
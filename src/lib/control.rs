@@ -0,0 +1,466 @@
+//! A Unix control socket for talking to a running instance.
+//!
+//! An instance started with `--name NAME` listens on `NAME.sock` in
+//! `config::runtime_dir()`. Clients send one request line and read JSON lines back:
+//!
+//! - `events`: the recent lifecycle events (starts, ready, stops, restarts, signals,
+//!   reloads), then the connection is closed.
+//! - `events follow`: the recent events, then every new one as it happens, until the
+//!   client disconnects or the instance exits.
+//! - `status`: one object with the instance `name`, `pid`, `state`, `health` and
+//!   `last_event`.
+//! - `dump`: the `diagnostics::report` of the instance, which is also logged there.
+//! - `start`, `stop`, `restart`: passed to the supervisor as a `ControlRequest`; answered
+//!   with `{"ok":true}`, or an `error` if the instance does not supervise a command.
+//!
+//! With the `grpc` feature, `--grpc` offers the same operations as a gRPC service; see
+//! `grpc`.
+//!
+//! Every event carries the instance `name`, so the streams of several instances can be
+//! merged. `detach-rs events [-f] [--name NAME]` and `detach-rs status` are the
+//! command-line clients.
+use crate::context::{ControlRequest, ServiceContext};
+use crate::tasks::TaskGroup;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Returns the control socket path of the instance called `name`.
+pub fn socket_path(name: &str) -> Result<PathBuf, anyhow::Error> {
+    crate::config::validate_service_name(name)?;
+    Ok(crate::config::runtime_dir()?.join(format!("{}.sock", name)))
+}
+
+/// Returns the names and socket paths of all instances with a control socket, sorted by
+/// name. Sockets left behind by instances that were killed are included.
+pub fn list_sockets() -> Result<Vec<(String, PathBuf)>, anyhow::Error> {
+    let dir = crate::config::runtime_dir()?;
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow::anyhow!("Failed to read {}: {}", dir.display(), e)),
+    };
+    let mut sockets: Vec<(String, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sock"))
+        .filter_map(|path| {
+            let name = path.file_stem()?.to_str()?.to_string();
+            Some((name, path))
+        })
+        .collect();
+    sockets.sort();
+    Ok(sockets)
+}
+
+/// Returns whether an instance is listening on the control socket at `path`, rather than
+/// the socket being left behind by one that was killed.
+pub fn is_listening(path: &Path) -> bool {
+    std::os::unix::net::UnixStream::connect(path).is_ok()
+}
+
+/// Returns whether the instance name `name` matches the glob `pattern`, in which `*`
+/// matches any run of characters and `?` any single character.
+///
+/// # Examples
+///
+/// ```
+/// use detach::control::glob_match;
+///
+/// assert!(glob_match("worker-*", "worker-3"));
+/// assert!(glob_match("web-?", "web-a"));
+/// assert!(!glob_match("worker-*", "web-1"));
+/// ```
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where to resume after the last `*`: its position in the pattern, and in the name
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Runs `future` while serving the control socket of the instance `name` at `path`.
+///
+/// The socket is bound before `future` starts, so a second instance with the same name
+/// fails immediately; a socket file nobody listens on any more is replaced. The file is
+/// removed when `future` completes. The launch snapshot recorded in `ctx`, if any, is
+/// written next to it and left behind for `detach-rs inspect`, and so is the state of
+/// the instance (see `state`). Without a `path` this is a plain `future.await`.
+///
+/// # Arguments
+/// - `path`: Where to listen, usually `socket_path(name)`.
+/// - `name`: The instance name reported with every event.
+/// - `ctx`: The context whose lifecycle events are served.
+/// - `future`: The service future.
+pub async fn with_control_socket<F>(
+    path: Option<PathBuf>,
+    name: String,
+    ctx: ServiceContext,
+    future: F,
+) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    let Some(path) = path else {
+        return future.await;
+    };
+    let listener = bind(&path).await?;
+    info!("Control socket listening on {}", path.display());
+    if let Some(snapshot) = ctx.launch()
+        && let Err(e) = crate::launch::write(&path.with_extension("json"), &name, snapshot)
+    {
+        warn!("Cannot record how this instance was launched: {}", e);
+    }
+    ctx.state().record_to(path.with_extension("state"));
+
+    let clients = TaskGroup::new();
+    let result = tokio::select! {
+        result = future => result,
+        _ = serve(listener, name, ctx, &clients) => Ok(()),
+    };
+    clients.shutdown().await;
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+/// Binds a Unix socket at `path`, replacing a stale socket file but refusing to take
+/// over one another process is listening on.
+pub(crate) async fn bind(path: &Path) -> Result<UnixListener, anyhow::Error> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(anyhow::anyhow!(
+                "Another instance is already listening on {}",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+        .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", path.display(), e))
+}
+
+async fn serve(listener: UnixListener, name: String, ctx: ServiceContext, clients: &TaskGroup) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let name = name.clone();
+        let ctx = ctx.clone();
+        clients.spawn("control socket client", async move {
+            if let Err(e) = handle(stream, &name, &ctx).await {
+                warn!("Control socket client failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle(stream: UnixStream, name: &str, ctx: &ServiceContext) -> anyhow::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut request = String::new();
+    BufReader::new(reader).read_line(&mut request).await?;
+
+    let words = request.split_whitespace().collect::<Vec<_>>();
+    let control = match words[..] {
+        ["start"] => Some(ControlRequest::Start),
+        ["stop"] => Some(ControlRequest::Stop),
+        ["restart"] => Some(ControlRequest::Restart),
+        _ => None,
+    };
+    if let Some(control) = control {
+        info!("{:?} requested over the control socket.", control);
+        let answer = if ctx.request(control) {
+            serde_json::json!({ "ok": true })
+        } else {
+            serde_json::json!({ "error": "This instance does not supervise a command" })
+        };
+        writer.write_all(format!("{}\n", answer).as_bytes()).await?;
+        return Ok(());
+    }
+    let follow = match words[..] {
+        ["status"] => {
+            let status = status(Some(name), ctx);
+            writer.write_all(format!("{}\n", status).as_bytes()).await?;
+            return Ok(());
+        }
+        ["dump"] => {
+            let mut report = crate::diagnostics::log_report(ctx);
+            report["name"] = name.into();
+            writer.write_all(format!("{}\n", report).as_bytes()).await?;
+            return Ok(());
+        }
+        ["events"] => false,
+        ["events", "follow"] => true,
+        _ => {
+            let error =
+                serde_json::json!({ "error": format!("unknown request: {}", request.trim()) });
+            writer.write_all(format!("{}\n", error).as_bytes()).await?;
+            return Ok(());
+        }
+    };
+
+    // Subscribe first so nothing published while the history is written is lost.
+    let mut events = ctx.subscribe_events();
+    let line = |event: &crate::audit::LifecycleEvent| {
+        let mut json = event.to_json();
+        json["name"] = name.into();
+        format!("{}\n", json)
+    };
+    for event in ctx.recent_events() {
+        writer.write_all(line(&event).as_bytes()).await?;
+    }
+    if !follow {
+        return Ok(());
+    }
+    loop {
+        match events.recv().await {
+            Ok(event) => writer.write_all(line(&event).as_bytes()).await?,
+            Err(RecvError::Lagged(missed)) => {
+                let warning = serde_json::json!({ "name": name, "missed": missed });
+                writer
+                    .write_all(format!("{}\n", warning).as_bytes())
+                    .await?;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Returns the status of this instance: `name`, `pid`, `run_id`, `state` and `state_since`,
+/// `health`, `last_event`, `last_run` once a run of the command has ended (see
+/// `state::LastRun`), `child_pid` and `child_tracking` (see `pidfd`) while a command runs,
+/// `orphans` with `--subreaper` (see `reaper`), `tags` and `ports` if it has any and, when
+/// command output is kept (`ServiceContext::keep_output`), `recent_output`.
+pub fn status(name: Option<&str>, ctx: &ServiceContext) -> serde_json::Value {
+    let mut status = serde_json::json!({
+        "name": name,
+        "pid": std::process::id(),
+        "run_id": crate::context::run_id(),
+        "state": ctx.state().get().as_str(),
+        "state_since": ctx
+            .state()
+            .since()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+        "health": ctx.health().get().to_string(),
+        "last_event": ctx.recent_events().last().map(crate::audit::LifecycleEvent::to_json),
+    });
+    if let Some(last_run) = ctx.state().last_run() {
+        status["last_run"] = last_run.to_json();
+    }
+    if let Some(child) = ctx.child() {
+        status["child_pid"] = child.pid().into();
+        status["child_tracking"] = child.kind().into();
+    }
+    if crate::reaper::is_enabled() {
+        status["orphans"] = crate::reaper::orphans().into();
+    }
+    if !ctx.tags().is_empty() {
+        status["tags"] = ctx
+            .tags()
+            .iter()
+            .map(|(key, value)| (key.clone(), serde_json::Value::from(value.as_str())))
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+    }
+    if !ctx.ports().is_empty() {
+        status["ports"] = ctx.ports().into();
+    }
+    if let Some(output) = ctx.recent_output() {
+        status["recent_output"] = output.into();
+    }
+    status
+}
+
+/// Reads the lifecycle events of the instance listening on `path`, calling `on_event`
+/// with each JSON line.
+///
+/// # Arguments
+/// - `path`: The instance's control socket.
+/// - `follow`: Keep reading new events until the instance exits, instead of returning
+///   after the recent ones.
+/// - `on_event`: Called with each line, without the trailing newline.
+///
+/// # Returns
+/// - `Ok(())`: When the instance closed the connection.
+/// - `Err(anyhow::Error)`: If the socket could not be reached or read.
+pub async fn read_events<F>(path: &Path, follow: bool, mut on_event: F) -> anyhow::Result<()>
+where
+    F: FnMut(&str),
+{
+    let mut stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {}", path.display(), e))?;
+    let request = if follow {
+        "events follow\n"
+    } else {
+        "events\n"
+    };
+    stream.write_all(request.as_bytes()).await?;
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        on_event(&line);
+    }
+    Ok(())
+}
+
+/// Sends a one-line `request` such as `status` or `restart` to the instance listening on
+/// `path` and returns its answer.
+///
+/// # Returns
+/// - `Ok(serde_json::Value)`: The instance's answer; it may be an object with an `error`.
+/// - `Err(anyhow::Error)`: If the socket could not be reached or the answer is not JSON.
+pub async fn query(path: &Path, request: &str) -> anyhow::Result<serde_json::Value> {
+    let mut stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {}", path.display(), e))?;
+    stream
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer).await?;
+    serde_json::from_str(&answer)
+        .map_err(|e| anyhow::anyhow!("Invalid answer from {}: {}", path.display(), e))
+}
+
+/// Waits until the instance listening on `path` is ready after its command was
+/// (re)started, and fails once `timeout` has passed without that.
+///
+/// An instance is ready when its `status` shows a command other than `previous_child`
+/// in the `ready` state (see `state`: it has kept running for a second and is healthy),
+/// and each of its `ports` accepts connections on the loopback address.
+///
+/// # Arguments
+/// - `path`: The instance's control socket.
+/// - `previous_child`: The `child_pid` before the restart, which does not count as ready.
+/// - `timeout`: How long to wait at most.
+///
+/// # Returns
+/// - `Ok(serde_json::Value)`: The status that showed the instance ready.
+/// - `Err(anyhow::Error)`: With the last reason it was not ready, after `timeout`.
+pub async fn wait_ready(
+    path: &Path,
+    previous_child: Option<u64>,
+    timeout: std::time::Duration,
+) -> anyhow::Result<serde_json::Value> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut waiting_for = "the command to start".to_string();
+    loop {
+        if tokio::time::Instant::now() >= deadline {
+            return Err(anyhow::anyhow!(
+                "Not ready after {:?}: waiting for {}",
+                timeout,
+                waiting_for
+            ));
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let status = match query(path, "status").await {
+            Ok(status) => status,
+            Err(e) => {
+                waiting_for = format!("the control socket ({})", e);
+                continue;
+            }
+        };
+        let child = status["child_pid"]
+            .as_u64()
+            .filter(|&pid| Some(pid) != previous_child);
+        let Some(child) = child else {
+            waiting_for = "the command to start".to_string();
+            continue;
+        };
+        if status["state"] != "ready" {
+            waiting_for = match status["state"].as_str() {
+                Some("degraded") => format!(
+                    "command {} to become healthy ({})",
+                    child,
+                    status["health"].as_str().unwrap_or("?")
+                ),
+                Some(state) => format!("command {} to become ready ({})", child, state),
+                None => format!("command {} to become ready", child),
+            };
+            continue;
+        }
+        let mut closed = None;
+        for port in status["ports"].as_array().into_iter().flatten() {
+            let Some(port) = port.as_u64().and_then(|port| u16::try_from(port).ok()) else {
+                continue;
+            };
+            let v4 = tokio::net::TcpStream::connect((std::net::Ipv4Addr::LOCALHOST, port));
+            let v6 = tokio::net::TcpStream::connect((std::net::Ipv6Addr::LOCALHOST, port));
+            if v4.await.is_err() && v6.await.is_err() {
+                closed = Some(port);
+                break;
+            }
+        }
+        if let Some(port) = closed {
+            waiting_for = format!("port {} to accept connections", port);
+            continue;
+        }
+        return Ok(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_handles_stars_and_question_marks() {
+        let cases = [
+            // Exact names
+            ("web", "web", true),
+            ("web", "web-1", false),
+            ("web-1", "web", false),
+            ("", "", true),
+            ("", "web", false),
+            // `*` matches any run of characters, none included
+            ("*", "", true),
+            ("*", "anything", true),
+            ("*-1", "web-1", true),
+            ("*-1", "web-12", false),
+            ("web*", "web", true),
+            ("web*", "webserver", true),
+            ("web*", "api", false),
+            ("*api*", "my-api-2", true),
+            ("w*b*1", "web-worker-1", true),
+            ("w*b*1", "web-worker-2", false),
+            ("a*a*a", "aaaa", true),
+            ("**", "web", true),
+            // `?` matches exactly one character
+            ("web-?", "web-a", true),
+            ("web-?", "web-", false),
+            ("web-?", "web-10", false),
+            ("?eb-*", "web-10", true),
+            ("wéb-?", "wéb-ü", true),
+        ];
+        for (pattern, name, expected) in cases {
+            let matched = glob_match(pattern, name);
+            assert_eq!(matched, expected, "{} ~ {}", pattern, name);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Core-dump handling for supervised commands.
+//!
+//! Whether a crashing child writes a core file depends on its `RLIMIT_CORE`, which it
+//! inherits from us and which is often 0 in login sessions. `CoreDumps` sets the limit in
+//! the child just before it executes, and `report_core_dump` tells the operator where the
+//! core ended up once a child was reported as having dumped one.
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+
+/// What to do about core dumps of supervised commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CoreDumps {
+    /// Raise the soft `RLIMIT_CORE` to the hard limit so cores are written
+    Allow,
+    /// Set the soft `RLIMIT_CORE` to 0 so no cores are written
+    Disable,
+    /// Like `Allow`, and move core files written to the working directory into this one
+    Dir(PathBuf),
+}
+
+impl CoreDumps {
+    /// Parses `allow`, `disable` or `dir:<PATH>`.
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        match input {
+            "allow" => Ok(CoreDumps::Allow),
+            "disable" => Ok(CoreDumps::Disable),
+            _ => match input.strip_prefix("dir:") {
+                Some(dir) if !dir.is_empty() => Ok(CoreDumps::Dir(PathBuf::from(dir))),
+                _ => Err(anyhow::anyhow!(
+                    "Invalid core dump mode \"{}\": expected allow, disable or dir:<PATH>",
+                    input
+                )),
+            },
+        }
+    }
+
+    /// Sets `RLIMIT_CORE` for the calling process according to this mode.
+    ///
+    /// Meant to run in the child between fork and exec, so it only makes async-signal-safe
+    /// system calls and does not allocate.
+    #[cfg(unix)]
+    pub fn apply_rlimit(&self) -> std::io::Result<()> {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        limit.rlim_cur = match self {
+            CoreDumps::Disable => 0,
+            CoreDumps::Allow | CoreDumps::Dir(_) => limit.rlim_max,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Logs where the core file of a crashed child went and, with `CoreDumps::Dir`, moves it.
+///
+/// On Linux the destination is derived from `/proc/sys/kernel/core_pattern`: a pipe
+/// pattern means a handler such as systemd-coredump took it; a relative pattern means the
+/// file sits in the child's working directory. Patterns using specifiers other than `%p`
+/// cannot be resolved and are only reported.
+///
+/// # Arguments
+/// - `pid`: The PID of the child that dumped core.
+/// - `cwd`: The child's working directory.
+/// - `mode`: The configured core dump handling, if any.
+pub fn report_core_dump(pid: u32, cwd: &Path, mode: Option<&CoreDumps>) {
+    let pattern = std::fs::read_to_string("/proc/sys/kernel/core_pattern")
+        .map(|p| p.trim().to_string())
+        .unwrap_or_else(|_| "core".to_string());
+
+    if let Some(handler) = pattern.strip_prefix('|') {
+        warn!(
+            "Child {} dumped core; it was handed to {}.",
+            pid,
+            handler.split_whitespace().next().unwrap_or(handler)
+        );
+        return;
+    }
+
+    let Some(core_path) = resolve_core_path(&pattern, pid, cwd) else {
+        warn!(
+            "Child {} dumped core (core_pattern \"{}\" cannot be resolved to a file).",
+            pid, pattern
+        );
+        return;
+    };
+
+    match mode {
+        Some(CoreDumps::Dir(dir)) if core_path.exists() => {
+            let target = dir.join(format!("core.{}", pid));
+            match move_file(&core_path, &target) {
+                Ok(()) => warn!("Child {} dumped core: {}", pid, target.display()),
+                Err(e) => warn!(
+                    "Child {} dumped core: {} (failed to move it to {}: {})",
+                    pid,
+                    core_path.display(),
+                    dir.display(),
+                    e
+                ),
+            }
+        }
+        _ if core_path.exists() => warn!("Child {} dumped core: {}", pid, core_path.display()),
+        _ => info!(
+            "Child {} dumped core, but {} does not exist.",
+            pid,
+            core_path.display()
+        ),
+    }
+}
+
+/// Expands a non-pipe `core_pattern` into the path of the core file, if possible.
+fn resolve_core_path(pattern: &str, pid: u32, cwd: &Path) -> Option<PathBuf> {
+    let mut name = String::new();
+    let mut chars = pattern.chars();
+    let mut has_pid = false;
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            name.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => name.push('%'),
+            Some('p') => {
+                has_pid = true;
+                name.push_str(&pid.to_string());
+            }
+            _ => return None,
+        }
+    }
+    // Without %p the kernel appends ".<pid>" when core_uses_pid is set.
+    if !has_pid
+        && std::fs::read_to_string("/proc/sys/kernel/core_uses_pid")
+            .map(|v| v.trim() == "1")
+            .unwrap_or(false)
+    {
+        name.push_str(&format!(".{}", pid));
+    }
+    let path = PathBuf::from(name);
+    Some(if path.is_absolute() {
+        path
+    } else {
+        cwd.join(path)
+    })
+}
+
+/// Renames `from` to `to`, falling back to copy-and-remove across filesystems.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from)
+}
@@ -0,0 +1,737 @@
+//! A D-Bus interface for desktop Linux integration (feature `dbus`).
+//!
+//! `with_dbus_service` puts the instance on the session or system bus as
+//! `org.detach.Manager`, or `org.detach.Manager.<name>` for an instance started with
+//! `--name`, and exports the object `/org/detach/Manager` implementing:
+//!
+//! - `org.detach.Manager.Start()`: start the command now if it is waiting to be restarted
+//! - `org.detach.Manager.Stop()`: stop the command and do not restart it
+//! - `org.detach.Manager.Restart()`: stop the command and start it again right away
+//! - `org.detach.Manager.Status() -> s`: a JSON object with the instance name, PID,
+//!   state, health and last lifecycle event
+//! - signal `org.detach.Manager.StateChanged(s action, s trigger, s detail, s state)` for
+//!   every lifecycle event, with the state it left the instance in
+//!
+//! so standard tools can drive it:
+//!
+//! ```text
+//! busctl --user call org.detach.Manager.web /org/detach/Manager org.detach.Manager Restart
+//! busctl --user monitor org.detach.Manager.web
+//! ```
+//!
+//! The connection speaks the D-Bus wire protocol directly over the bus socket and only
+//! implements what this interface needs: `EXTERNAL` authentication, the basic string and
+//! integer types, and a few header fields.
+use crate::audit::LifecycleEvent;
+use crate::context::{ControlRequest, ServiceContext};
+use log::{info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{broadcast, mpsc};
+
+/// The object path the instance is exported at.
+pub const OBJECT_PATH: &str = "/org/detach/Manager";
+/// The interface name of the exported methods and signals.
+pub const INTERFACE: &str = "org.detach.Manager";
+
+/// The message bus to connect to.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bus {
+    /// The user's session bus (`DBUS_SESSION_BUS_ADDRESS`)
+    Session,
+    /// The system bus (`DBUS_SYSTEM_BUS_ADDRESS`)
+    System,
+}
+
+/// Returns the bus name of the instance called `name`, or `org.detach.Manager` for an
+/// unnamed one. Characters not allowed in bus names are replaced by `_`.
+pub fn bus_name(name: Option<&str>) -> String {
+    let Some(name) = name else {
+        return INTERFACE.to_string();
+    };
+    let mut element: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !element.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        element.insert(0, '_');
+    }
+    format!("{}.{}", INTERFACE, element)
+}
+
+/// Runs `future` while serving the D-Bus interface of the instance `name` on `bus`.
+///
+/// The bus name is claimed before `future` starts, so a second instance with the same
+/// name fails immediately. If the bus connection is lost later, a warning is logged and
+/// `future` keeps running. Without a `bus` this is a plain `future.await`.
+///
+/// # Arguments
+/// - `bus`: The bus to connect to.
+/// - `name`: The instance name, used in the bus name and in `Status`.
+/// - `ctx`: The context requests are sent to and events are read from.
+/// - `future`: The service future.
+pub async fn with_dbus_service<F>(
+    bus: Option<Bus>,
+    name: Option<String>,
+    ctx: ServiceContext,
+    future: F,
+) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    let Some(bus) = bus else {
+        return future.await;
+    };
+    let service_name = bus_name(name.as_deref());
+    let connection = Connection::open(bus, &service_name).await?;
+    info!("Serving {} on the {:?} D-Bus bus", service_name, bus);
+
+    tokio::pin!(future);
+    tokio::select! {
+        result = &mut future => return result,
+        result = connection.serve(name, ctx) => {
+            if let Err(e) = result {
+                warn!("D-Bus connection lost: {}", e);
+            }
+        }
+    }
+    future.await
+}
+
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+/// Messages larger than this are refused rather than buffered.
+const MAX_MESSAGE: u32 = 16 * 1024 * 1024;
+
+/// A received message; only the parts this module looks at.
+#[derive(Debug, Default)]
+struct Message {
+    kind: u8,
+    flags: u8,
+    serial: u32,
+    big_endian: bool,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    reply_serial: Option<u32>,
+    sender: Option<String>,
+    body: Vec<u8>,
+}
+
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    serial: u32,
+}
+
+impl Connection {
+    /// Connects, authenticates, says hello and claims `service_name`.
+    async fn open(bus: Bus, service_name: &str) -> anyhow::Result<Connection> {
+        let stream = connect(bus).await?;
+        let (reader, writer) = stream.into_split();
+        let mut connection = Connection {
+            reader: BufReader::new(reader),
+            writer,
+            serial: 0,
+        };
+        connection.authenticate().await?;
+
+        connection
+            .call("Hello", None, Vec::new())
+            .await
+            .map_err(|e| anyhow::anyhow!("D-Bus Hello failed: {}", e))?;
+        let mut body = Body::default();
+        body.string(service_name);
+        body.u32(4); // DBUS_NAME_FLAG_DO_NOT_QUEUE
+        let reply = connection
+            .call("RequestName", Some("su"), body.0)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to claim {}: {}", service_name, e))?;
+        match reply.read_u32(0) {
+            Some(1) | Some(4) => Ok(connection),
+            Some(3) => Err(anyhow::anyhow!(
+                "D-Bus name {} is already taken by another instance",
+                service_name
+            )),
+            other => Err(anyhow::anyhow!(
+                "Unexpected RequestName reply for {}: {:?}",
+                service_name,
+                other
+            )),
+        }
+    }
+
+    async fn authenticate(&mut self) -> anyhow::Result<()> {
+        let uid = unsafe { libc::geteuid() }.to_string();
+        let hex: String = uid.bytes().map(|b| format!("{:02x}", b)).collect();
+        self.writer
+            .write_all(format!("\0AUTH EXTERNAL {}\r\n", hex).as_bytes())
+            .await?;
+        let mut line = String::new();
+        self.reader.read_line(&mut line).await?;
+        if !line.starts_with("OK ") {
+            return Err(anyhow::anyhow!(
+                "D-Bus authentication failed: {}",
+                line.trim()
+            ));
+        }
+        self.writer.write_all(b"BEGIN\r\n").await?;
+        Ok(())
+    }
+
+    fn next_serial(&mut self) -> u32 {
+        self.serial += 1;
+        self.serial
+    }
+
+    /// Calls a method of the bus itself and waits for its reply, skipping anything else
+    /// that arrives in between.
+    async fn call(
+        &mut self,
+        member: &str,
+        signature: Option<&str>,
+        body: Vec<u8>,
+    ) -> anyhow::Result<Message> {
+        let serial = self.next_serial();
+        let mut fields = vec![
+            (FIELD_PATH, Field::Path("/org/freedesktop/DBus")),
+            (FIELD_INTERFACE, Field::Str("org.freedesktop.DBus")),
+            (FIELD_MEMBER, Field::Str(member)),
+            (FIELD_DESTINATION, Field::Str("org.freedesktop.DBus")),
+        ];
+        if let Some(signature) = signature {
+            fields.push((FIELD_SIGNATURE, Field::Signature(signature)));
+        }
+        let message = encode(METHOD_CALL, 0, serial, &fields, &body);
+        self.writer.write_all(&message).await?;
+        loop {
+            let reply = read_message(&mut self.reader).await?;
+            if reply.reply_serial != Some(serial) {
+                continue;
+            }
+            return match reply.kind {
+                METHOD_RETURN => Ok(reply),
+                _ => Err(anyhow::anyhow!(
+                    "{}",
+                    reply.read_string(0).unwrap_or_default()
+                )),
+            };
+        }
+    }
+
+    /// Answers method calls and emits `StateChanged` until the connection fails.
+    async fn serve(self, name: Option<String>, ctx: ServiceContext) -> anyhow::Result<()> {
+        let Connection {
+            mut reader,
+            mut writer,
+            mut serial,
+        } = self;
+        // Reading a message is not cancel-safe, so it gets its own task.
+        let (messages, mut incoming) = mpsc::channel(16);
+        let read_task = tokio::spawn(async move {
+            loop {
+                let message = read_message(&mut reader).await;
+                let failed = message.is_err();
+                if messages.send(message).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        let _read_task = AbortOnDrop(read_task);
+        let mut events = ctx.subscribe_events();
+
+        loop {
+            let outgoing = tokio::select! {
+                message = incoming.recv() => {
+                    let Some(message) = message else {
+                        return Err(anyhow::anyhow!("connection closed"));
+                    };
+                    let message = message?;
+                    if message.kind != METHOD_CALL {
+                        continue;
+                    }
+                    serial += 1;
+                    let reply = respond(&message, serial, name.as_deref(), &ctx);
+                    if message.flags & NO_REPLY_EXPECTED != 0 {
+                        continue;
+                    }
+                    reply
+                }
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        serial += 1;
+                        state_changed(&event, serial)
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+            };
+            writer.write_all(&outgoing).await?;
+        }
+    }
+}
+
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Builds the reply to a method call.
+fn respond(call: &Message, serial: u32, name: Option<&str>, ctx: &ServiceContext) -> Vec<u8> {
+    let path = call.path.as_deref().unwrap_or("");
+    let member = call.member.as_deref().unwrap_or("");
+    let interface = call.interface.as_deref();
+
+    if member == "Introspect"
+        && matches!(
+            interface,
+            None | Some("org.freedesktop.DBus.Introspectable")
+        )
+    {
+        return match introspect(path) {
+            Some(xml) => reply(call, serial, Some("s"), string_body(&xml)),
+            None => error(
+                call,
+                serial,
+                "org.freedesktop.DBus.Error.UnknownObject",
+                path,
+            ),
+        };
+    }
+    if interface == Some("org.freedesktop.DBus.Peer") && member == "Ping" {
+        return reply(call, serial, None, Vec::new());
+    }
+    if path != OBJECT_PATH || !matches!(interface, None | Some(INTERFACE)) {
+        return error(
+            call,
+            serial,
+            "org.freedesktop.DBus.Error.UnknownMethod",
+            &format!("No method {} on {}", member, path),
+        );
+    }
+
+    let request = match member {
+        "Status" => {
+            let status = crate::control::status(name, ctx);
+            return reply(call, serial, Some("s"), string_body(&status.to_string()));
+        }
+        "Start" => ControlRequest::Start,
+        "Stop" => ControlRequest::Stop,
+        "Restart" => ControlRequest::Restart,
+        _ => {
+            return error(
+                call,
+                serial,
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                &format!("No method {} on {}", member, INTERFACE),
+            );
+        }
+    };
+    info!(
+        "{} requested over D-Bus by {}",
+        member,
+        call.sender.as_deref().unwrap_or("unknown")
+    );
+    if ctx.request(request) {
+        reply(call, serial, None, Vec::new())
+    } else {
+        error(
+            call,
+            serial,
+            "org.detach.Error.NotSupervised",
+            "This instance does not supervise a command",
+        )
+    }
+}
+
+/// Returns the introspection XML of `path`: the interface at `OBJECT_PATH`, and the next
+/// path element on the way there.
+fn introspect(path: &str) -> Option<String> {
+    const HEADER: &str = "<!DOCTYPE node PUBLIC \"-//freedesktop//DTD D-BUS Object Introspection 1.0//EN\"\n \"http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd\">\n";
+    if path == OBJECT_PATH {
+        return Some(format!(
+            "{}<node>\n  <interface name=\"{}\">\n    <method name=\"Start\"/>\n    <method name=\"Stop\"/>\n    <method name=\"Restart\"/>\n    <method name=\"Status\">\n      <arg name=\"status\" type=\"s\" direction=\"out\"/>\n    </method>\n    <signal name=\"StateChanged\">\n      <arg name=\"action\" type=\"s\"/>\n      <arg name=\"trigger\" type=\"s\"/>\n      <arg name=\"detail\" type=\"s\"/>\n      <arg name=\"state\" type=\"s\"/>\n    </signal>\n  </interface>\n  <interface name=\"org.freedesktop.DBus.Introspectable\">\n    <method name=\"Introspect\">\n      <arg name=\"xml\" type=\"s\" direction=\"out\"/>\n    </method>\n  </interface>\n  <interface name=\"org.freedesktop.DBus.Peer\">\n    <method name=\"Ping\"/>\n  </interface>\n</node>\n",
+            HEADER, INTERFACE
+        ));
+    }
+    let rest = OBJECT_PATH.strip_prefix(path.trim_end_matches('/'))?;
+    let child = rest.strip_prefix('/')?.split('/').next()?;
+    Some(format!(
+        "{}<node>\n  <node name=\"{}\"/>\n</node>\n",
+        HEADER, child
+    ))
+}
+
+fn state_changed(event: &LifecycleEvent, serial: u32) -> Vec<u8> {
+    let json = event.to_json();
+    let mut body = Body::default();
+    for key in ["action", "trigger", "detail", "state"] {
+        body.string(json[key].as_str().unwrap_or_default());
+    }
+    let fields = [
+        (FIELD_PATH, Field::Path(OBJECT_PATH)),
+        (FIELD_INTERFACE, Field::Str(INTERFACE)),
+        (FIELD_MEMBER, Field::Str("StateChanged")),
+        (FIELD_SIGNATURE, Field::Signature("ssss")),
+    ];
+    encode(SIGNAL, NO_REPLY_EXPECTED, serial, &fields, &body.0)
+}
+
+fn reply(call: &Message, serial: u32, signature: Option<&str>, body: Vec<u8>) -> Vec<u8> {
+    let mut fields = vec![(FIELD_REPLY_SERIAL, Field::U32(call.serial))];
+    if let Some(sender) = &call.sender {
+        fields.push((FIELD_DESTINATION, Field::Str(sender)));
+    }
+    if let Some(signature) = signature {
+        fields.push((FIELD_SIGNATURE, Field::Signature(signature)));
+    }
+    encode(METHOD_RETURN, NO_REPLY_EXPECTED, serial, &fields, &body)
+}
+
+fn error(call: &Message, serial: u32, name: &str, text: &str) -> Vec<u8> {
+    let mut fields = vec![
+        (FIELD_ERROR_NAME, Field::Str(name)),
+        (FIELD_REPLY_SERIAL, Field::U32(call.serial)),
+        (FIELD_SIGNATURE, Field::Signature("s")),
+    ];
+    if let Some(sender) = &call.sender {
+        fields.push((FIELD_DESTINATION, Field::Str(sender)));
+    }
+    encode(
+        ERROR,
+        NO_REPLY_EXPECTED,
+        serial,
+        &fields,
+        &string_body(text),
+    )
+}
+
+fn string_body(value: &str) -> Vec<u8> {
+    let mut body = Body::default();
+    body.string(value);
+    body.0
+}
+
+/// A header field value.
+enum Field<'a> {
+    Str(&'a str),
+    Path(&'a str),
+    Signature(&'a str),
+    U32(u32),
+}
+
+/// A little-endian marshalling buffer. Alignment is relative to the start of the buffer,
+/// which is correct for message bodies because they start on an 8-byte boundary.
+#[derive(Default)]
+struct Body(Vec<u8>);
+
+impl Body {
+    fn align(&mut self, to: usize) {
+        while !self.0.len().is_multiple_of(to) {
+            self.0.push(0);
+        }
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        let value = value.replace('\0', "");
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.0.push(value.len() as u8);
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+}
+
+fn encode(kind: u8, flags: u8, serial: u32, fields: &[(u8, Field)], body: &[u8]) -> Vec<u8> {
+    let mut out = Body::default();
+    out.0.extend_from_slice(&[b'l', kind, flags, 1]);
+    out.u32(body.len() as u32);
+    out.u32(serial);
+    out.u32(0); // length of the header field array, patched below
+    for (code, value) in fields {
+        out.align(8);
+        out.0.push(*code);
+        match value {
+            Field::Str(s) => {
+                out.signature("s");
+                out.string(s);
+            }
+            Field::Path(s) => {
+                out.signature("o");
+                out.string(s);
+            }
+            Field::Signature(s) => {
+                out.signature("g");
+                out.signature(s);
+            }
+            Field::U32(v) => {
+                out.signature("u");
+                out.u32(*v);
+            }
+        }
+    }
+    let fields_len = (out.0.len() - 16) as u32;
+    out.0[12..16].copy_from_slice(&fields_len.to_le_bytes());
+    out.align(8);
+    out.0.extend_from_slice(body);
+    out.0
+}
+
+async fn read_message(reader: &mut BufReader<OwnedReadHalf>) -> anyhow::Result<Message> {
+    let mut fixed = [0u8; 16];
+    reader.read_exact(&mut fixed).await?;
+    let big_endian = match fixed[0] {
+        b'l' => false,
+        b'B' => true,
+        other => {
+            return Err(anyhow::anyhow!(
+                "Invalid D-Bus endianness byte {:#x}",
+                other
+            ));
+        }
+    };
+    let read_u32 = |bytes: &[u8]| {
+        let bytes: [u8; 4] = bytes.try_into().unwrap_or_default();
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let body_len = read_u32(&fixed[4..8]);
+    let fields_len = read_u32(&fixed[12..16]);
+    if body_len > MAX_MESSAGE || fields_len > MAX_MESSAGE {
+        return Err(anyhow::anyhow!("D-Bus message too large"));
+    }
+    let padded = (fields_len as usize).next_multiple_of(8);
+    let mut fields = vec![0u8; padded];
+    reader.read_exact(&mut fields).await?;
+    let mut body = vec![0u8; body_len as usize];
+    reader.read_exact(&mut body).await?;
+
+    let mut message = Message {
+        kind: fixed[1],
+        flags: fixed[2],
+        serial: read_u32(&fixed[8..12]),
+        big_endian,
+        body,
+        ..Message::default()
+    };
+    // Offsets below are relative to the field array, which starts 8-aligned at byte 16.
+    let mut cursor = Cursor {
+        data: &fields[..fields_len as usize],
+        pos: 0,
+        big_endian,
+    };
+    while cursor.pos < cursor.data.len() {
+        cursor.align(8);
+        let code = cursor.u8()?;
+        let signature = cursor.signature()?;
+        match (signature.as_str(), code) {
+            ("s" | "o", FIELD_PATH) => message.path = Some(cursor.string()?),
+            ("s", FIELD_INTERFACE) => message.interface = Some(cursor.string()?),
+            ("s", FIELD_MEMBER) => message.member = Some(cursor.string()?),
+            ("s", FIELD_SENDER) => message.sender = Some(cursor.string()?),
+            ("u", FIELD_REPLY_SERIAL) => message.reply_serial = Some(cursor.u32()?),
+            ("s" | "o", _) => {
+                cursor.string()?;
+            }
+            ("g", _) => {
+                cursor.signature()?;
+            }
+            ("u", _) => {
+                cursor.u32()?;
+            }
+            (other, _) => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported D-Bus header field type {}",
+                    other
+                ));
+            }
+        }
+    }
+    Ok(message)
+}
+
+impl Message {
+    /// Reads a `u32` at `offset` of the body.
+    fn read_u32(&self, offset: usize) -> Option<u32> {
+        let mut cursor = Cursor {
+            data: &self.body,
+            pos: offset,
+            big_endian: self.big_endian,
+        };
+        cursor.u32().ok()
+    }
+
+    /// Reads a string at `offset` of the body.
+    fn read_string(&self, offset: usize) -> Option<String> {
+        let mut cursor = Cursor {
+            data: &self.body,
+            pos: offset,
+            big_endian: self.big_endian,
+        };
+        cursor.string().ok()
+    }
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl Cursor<'_> {
+    fn align(&mut self, to: usize) {
+        self.pos = self.pos.next_multiple_of(to);
+    }
+
+    fn take(&mut self, len: usize) -> anyhow::Result<&[u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len());
+        let end = end.ok_or_else(|| anyhow::anyhow!("Truncated D-Bus message"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        self.align(4);
+        let big_endian = self.big_endian;
+        let bytes: [u8; 4] = self.take(4)?.try_into()?;
+        Ok(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let len = self.u32()? as usize;
+        let value = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.take(1)?;
+        Ok(value)
+    }
+
+    fn signature(&mut self) -> anyhow::Result<String> {
+        let len = self.u8()? as usize;
+        let value = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.take(1)?;
+        Ok(value)
+    }
+}
+
+/// Connects to the first usable `unix:` address of `bus`.
+async fn connect(bus: Bus) -> anyhow::Result<UnixStream> {
+    let address = match bus {
+        Bus::Session => std::env::var("DBUS_SESSION_BUS_ADDRESS").ok().or_else(|| {
+            std::env::var("XDG_RUNTIME_DIR")
+                .ok()
+                .map(|dir| format!("unix:path={}/bus", dir))
+        }),
+        Bus::System => Some(
+            std::env::var("DBUS_SYSTEM_BUS_ADDRESS")
+                .unwrap_or_else(|_| "unix:path=/var/run/dbus/system_bus_socket".to_string()),
+        ),
+    };
+    let address = address.ok_or_else(|| {
+        anyhow::anyhow!("No D-Bus session bus: DBUS_SESSION_BUS_ADDRESS is not set")
+    })?;
+
+    let mut last_error = None;
+    for entry in address.split(';') {
+        let Some(params) = entry.strip_prefix("unix:") else {
+            continue;
+        };
+        for param in params.split(',') {
+            let result = match param.split_once('=') {
+                Some(("path", path)) => std::os::unix::net::UnixStream::connect(unescape(path)),
+                Some(("abstract", name)) => connect_abstract(&unescape(name)),
+                _ => continue,
+            };
+            match result {
+                Ok(stream) => {
+                    stream.set_nonblocking(true)?;
+                    return Ok(UnixStream::from_std(stream)?);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+    }
+    Err(match last_error {
+        Some(e) => anyhow::anyhow!("Failed to connect to D-Bus at {}: {}", address, e),
+        None => anyhow::anyhow!("No supported unix: entry in D-Bus address {}", address),
+    })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn connect_abstract(name: &str) -> std::io::Result<std::os::unix::net::UnixStream> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+    std::os::unix::net::UnixStream::connect_addr(&addr)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn connect_abstract(_name: &str) -> std::io::Result<std::os::unix::net::UnixStream> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "abstract sockets are only available on Linux",
+    ))
+}
+
+/// Decodes `%xx` escapes in a D-Bus address value.
+fn unescape(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            out.push(byte);
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
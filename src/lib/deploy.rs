@@ -0,0 +1,172 @@
+//! Replacing a running instance with a new version without a gap.
+//!
+//! `detach-rs deploy NAME --new-cmd CMD` starts the new version next to the old one, with
+//! the options the old one was launched with (from its launch record, see `launch`) and
+//! only the command changed, as the next instance of the service: `web` is replaced by
+//! `web:2`, `web:2` by `web:3`. Once the new instance is ready (see
+//! `control::wait_ready`), `NAME.latest` in `config::runtime_dir()` is pointed at its
+//! control socket and the old instance is stopped. If it does not become ready, it is
+//! stopped instead and the old one keeps running.
+//!
+//! Both versions run at the same time, so a fixed `--port` cannot be shared between
+//! them; use `--port auto`. The new version gets the environment `deploy` runs in, since
+//! the launch record only holds a redacted copy of the old one's.
+use crate::instances;
+use std::path::Path;
+
+/// Rewrites the recorded command line of an instance to start `name` running `command`,
+/// detached.
+///
+/// `--name`, `--command`, `--detach`, `--no-detach`, `--instances` and `--instance` are
+/// dropped from `command_line`, which starts with the program, and the new ones added.
+///
+/// # Returns
+/// - `Ok(Vec<String>)`: The arguments, without the program.
+/// - `Err(anyhow::Error)`: If the recorded command line had secrets redacted, which
+///   cannot be started again.
+pub fn relaunch_args(
+    command_line: &[String],
+    name: &str,
+    command: &str,
+) -> Result<Vec<String>, anyhow::Error> {
+    if command_line
+        .iter()
+        .any(|arg| arg.contains(crate::redact::REDACTED))
+    {
+        return Err(anyhow::anyhow!(
+            "The recorded command line has redacted values, so it cannot be started again"
+        ));
+    }
+    const WITH_VALUE: &[&str] = &["--name", "--command", "--instances", "--instance"];
+    const FLAGS: &[&str] = &["--detach", "--no-detach"];
+    let mut args = Vec::new();
+    let mut rest = command_line.iter().skip(1);
+    while let Some(arg) = rest.next() {
+        let option = arg
+            .split_once('=')
+            .map_or(arg.as_str(), |(option, _)| option);
+        if WITH_VALUE.contains(&option) {
+            if !arg.contains('=') {
+                rest.next();
+            }
+        } else if !FLAGS.contains(&arg.as_str()) {
+            args.push(arg.clone());
+        }
+    }
+    args.extend([
+        "--detach".to_string(),
+        "--name".to_string(),
+        name.to_string(),
+        "--command".to_string(),
+        command.to_string(),
+    ]);
+    Ok(args)
+}
+
+/// Replaces the running instance of the service `name` with one running `command`.
+///
+/// # Arguments
+/// - `name`: The service; it must have exactly one running instance.
+/// - `command`: The command of the new version.
+/// - `ready_timeout`: How long the new instance may take to become ready.
+/// - `progress`: Called with a line for each step.
+///
+/// # Returns
+/// - `Ok(String)`: The name of the new instance, which now runs alone.
+/// - `Err(anyhow::Error)`: If the new instance could not be started or did not become
+///   ready (the old one keeps running then), or the old one could not be stopped.
+pub async fn deploy<F>(
+    name: &str,
+    command: &str,
+    ready_timeout: std::time::Duration,
+    mut progress: F,
+) -> Result<String, anyhow::Error>
+where
+    F: FnMut(&str),
+{
+    let running: Vec<(String, std::path::PathBuf)> = crate::control::list_sockets()?
+        .into_iter()
+        .filter(|(instance, path)| {
+            instances::belongs_to(instance, name) && crate::control::is_listening(path)
+        })
+        .collect();
+    let (old, old_socket) = match &running[..] {
+        [single] => single.clone(),
+        [] => return Err(anyhow::anyhow!("No instance of \"{}\" is running", name)),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "\"{}\" has {} running instances; deploy replaces a single one, use \
+                 `detach-rs restart {}` for a pool",
+                name,
+                running.len(),
+                name
+            ));
+        }
+    };
+    let record = crate::launch::read(&old)?;
+    let command_line: Vec<String> = record["command_line"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|arg| arg.as_str().map(str::to_string))
+        .collect();
+    let (service, index) = instances::split(&old);
+    let new = instances::instance_name(service, index.unwrap_or(1).saturating_add(1));
+    let args = relaunch_args(&command_line, &new, command)
+        .map_err(|e| anyhow::anyhow!("Cannot start a new version of {}: {}", old, e))?;
+
+    progress(&format!("Starting {} next to {}...", new, old));
+    let program = std::env::current_exe()?;
+    let mut launcher = std::process::Command::new(program);
+    launcher.args(&args);
+    if let Some(cwd) = record["cwd"].as_str() {
+        launcher.current_dir(cwd);
+    }
+    let status = launcher
+        .status()
+        .map_err(|e| anyhow::anyhow!("Failed to start {}: {}", new, e))?;
+    if !status.success() {
+        return Err(anyhow::anyhow!(
+            "{} did not start ({}); {} keeps running",
+            new,
+            crate::command::ExitReason::from_status(status),
+            old
+        ));
+    }
+
+    let new_socket = crate::control::socket_path(&new)?;
+    if let Err(e) = crate::control::wait_ready(&new_socket, None, ready_timeout).await {
+        let _ = crate::control::query(&new_socket, "stop").await;
+        return Err(anyhow::anyhow!(
+            "{} did not become ready, so it was stopped and {} keeps running: {}",
+            new,
+            old,
+            e
+        ));
+    }
+    progress(&format!("{} is ready.", new));
+    point_latest(name, &new_socket)?;
+
+    progress(&format!("Stopping {}...", old));
+    let answer = crate::control::query(&old_socket, "stop").await?;
+    if let Some(error) = answer.get("error").and_then(|e| e.as_str()) {
+        return Err(anyhow::anyhow!(
+            "{} runs the new version, but {} could not be stopped: {}",
+            new,
+            old,
+            error
+        ));
+    }
+    Ok(new)
+}
+
+/// Points `NAME.latest` next to the control sockets at `socket`, replacing it
+/// atomically.
+fn point_latest(name: &str, socket: &Path) -> Result<(), anyhow::Error> {
+    let latest = crate::control::socket_path(name)?.with_extension("latest");
+    let partial = latest.with_extension("latest.partial");
+    let _ = std::fs::remove_file(&partial);
+    std::os::unix::fs::symlink(socket, &partial)
+        .and_then(|()| std::fs::rename(&partial, &latest))
+        .map_err(|e| anyhow::anyhow!("Failed to update {}: {}", latest.display(), e))
+}
@@ -0,0 +1,92 @@
+//! A "thread dump" for detached async services.
+//!
+//! A JVM answers SIGQUIT with the stacks of all its threads; a tokio service has no
+//! equivalent, and a detached one has no terminal to print it to anyway. `report` collects
+//! what can be learned from the outside instead: uptime, the runtime's worker and task
+//! counts, the health and metrics the service published on its `ServiceContext`, memory
+//! use, the last lines of command output and of the log file. The report is logged on
+//! SIGUSR2 (see `with_diagnostics_signal`) and returned by the control socket's `dump`
+//! request, which `detach-rs dump` sends.
+use crate::context::ServiceContext;
+use log::info;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// How many lines of the log file a report includes.
+pub const LOG_LINES: usize = 20;
+
+static LOG_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the log file whose last lines go into every report of this process.
+pub fn set_log_file(path: PathBuf) {
+    let _ = LOG_FILE.set(path);
+}
+
+/// Collects the diagnostics of this process as a JSON object.
+///
+/// Runtime counts are only included when called from inside a tokio runtime, the log
+/// lines only after `set_log_file`.
+pub fn report(ctx: &ServiceContext) -> serde_json::Value {
+    let runtime = tokio::runtime::Handle::try_current().ok().map(|handle| {
+        let metrics = handle.metrics();
+        serde_json::json!({
+            "workers": metrics.num_workers(),
+            "alive_tasks": metrics.num_alive_tasks(),
+            "global_queue_depth": metrics.global_queue_depth(),
+        })
+    });
+    let recent_log = LOG_FILE
+        .get()
+        .and_then(|path| crate::logs::last_lines(path, LOG_LINES).ok());
+    serde_json::json!({
+        "pid": std::process::id(),
+        "run_id": crate::context::run_id(),
+        "uptime_secs": ctx.uptime().as_secs(),
+        "runtime": runtime,
+        "health": ctx.health().get().to_string(),
+        "memory": crate::memory::MemoryStats::collect().to_json(),
+        "metrics": ctx.metrics().to_json(),
+        "last_event": ctx.recent_events().last().map(crate::audit::LifecycleEvent::to_json),
+        "recent_output": ctx.recent_output(),
+        "recent_log": recent_log,
+    })
+}
+
+/// Logs `report` as an indented, human-readable block and returns it.
+pub fn log_report(ctx: &ServiceContext) -> serde_json::Value {
+    let report = report(ctx);
+    let text = serde_json::to_string_pretty(&report).unwrap_or_else(|_| report.to_string());
+    info!("Diagnostics:\n{}", text);
+    report
+}
+
+/// Runs `future` while logging a diagnostics report on every SIGUSR2.
+///
+/// Without `enabled`, or off Unix, this is a plain `future.await`. Leave it disabled when
+/// SIGUSR2 is forwarded to a supervised command, which expects it for itself.
+pub async fn with_diagnostics_signal<F>(enabled: bool, ctx: ServiceContext, future: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    #[cfg(unix)]
+    if enabled {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        if let Ok(mut requests) = signal(SignalKind::user_defined2()) {
+            let listen = async move {
+                while requests.recv().await.is_some() {
+                    info!("Received SIGUSR2, dumping diagnostics.");
+                    log_report(&ctx);
+                }
+            };
+            tokio::pin!(future);
+            tokio::select! {
+                output = &mut future => return output,
+                _ = listen => {}
+            }
+            return future.await;
+        }
+    }
+    let _ = (enabled, ctx);
+    future.await
+}
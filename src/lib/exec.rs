@@ -0,0 +1,121 @@
+//! Running a one-off command the way a running instance runs its own.
+//!
+//! "Works in my shell but not in the daemon" usually comes down to a difference in
+//! environment variables, working directory or user. `detach-rs exec <NAME> -- <COMMAND>`
+//! removes the guesswork: it reads all three from the instance's supervised command (or
+//! from the instance itself when no command is running) and starts `COMMAND` with them,
+//! in the foreground, passing its exit status on.
+//!
+//! The context is read from `/proc/<PID>`, so this is only available on Linux, and
+//! reading another user's process needs root, which is also what switching to that user
+//! takes.
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// The environment, working directory and user of a process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessContext {
+    /// The process this was read from
+    pub pid: u32,
+    /// Its environment, in order
+    pub env: Vec<(OsString, OsString)>,
+    /// Its working directory
+    pub cwd: PathBuf,
+    /// Its real user id
+    pub uid: u32,
+    /// Its real group id
+    pub gid: u32,
+}
+
+impl ProcessContext {
+    /// Reads the context of the process `pid` from `/proc`.
+    #[cfg(target_os = "linux")]
+    pub fn read(pid: u32) -> Result<Self, anyhow::Error> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let proc = PathBuf::from(format!("/proc/{}", pid));
+        let failed = |what: &str, e: std::io::Error| {
+            anyhow::anyhow!("Cannot read the {} of process {}: {}", what, pid, e)
+        };
+        let environ = std::fs::read(proc.join("environ")).map_err(|e| failed("environment", e))?;
+        let env = environ
+            .split(|&byte| byte == 0)
+            .filter_map(|entry| {
+                let at = entry.iter().position(|&byte| byte == b'=')?;
+                Some((
+                    std::ffi::OsStr::from_bytes(&entry[..at]).to_os_string(),
+                    std::ffi::OsStr::from_bytes(&entry[at + 1..]).to_os_string(),
+                ))
+            })
+            .collect();
+        let cwd =
+            std::fs::read_link(proc.join("cwd")).map_err(|e| failed("working directory", e))?;
+        let status =
+            std::fs::read_to_string(proc.join("status")).map_err(|e| failed("status", e))?;
+        let id = |field: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(field))
+                .and_then(|ids| ids.split_whitespace().next()?.parse::<u32>().ok())
+                .ok_or_else(|| anyhow::anyhow!("No {} in the status of process {}", field, pid))
+        };
+        Ok(ProcessContext {
+            pid,
+            env,
+            cwd,
+            uid: id("Uid:")?,
+            gid: id("Gid:")?,
+        })
+    }
+
+    /// Reading another process's context needs `/proc`, which only Linux provides.
+    #[cfg(not(target_os = "linux"))]
+    pub fn read(pid: u32) -> Result<Self, anyhow::Error> {
+        Err(anyhow::anyhow!(
+            "Reading the environment of process {} needs /proc, which this system lacks",
+            pid
+        ))
+    }
+
+    /// Runs `program` with `args` in this context and waits for it.
+    ///
+    /// Standard input, output and error are inherited. When the context belongs to
+    /// another user, the command is started as that user, which only works as root.
+    ///
+    /// # Returns
+    /// - `Ok(ExitStatus)`: The command ran; its status may be a failure.
+    /// - `Err(anyhow::Error)`: It could not be started, or the user cannot be switched.
+    pub fn run(
+        &self,
+        program: &str,
+        args: &[String],
+    ) -> Result<std::process::ExitStatus, anyhow::Error> {
+        use std::os::unix::process::CommandExt;
+
+        let mut command = std::process::Command::new(program);
+        command
+            .args(args)
+            .env_clear()
+            .envs(self.env.iter().map(|(key, value)| (key, value)))
+            .current_dir(&self.cwd);
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        if (uid, gid) != (self.uid, self.gid) {
+            if uid != 0 {
+                return Err(anyhow::anyhow!(
+                    "The instance runs as uid {} (gid {}); run exec as root to switch to it",
+                    self.uid,
+                    self.gid
+                ));
+            }
+            command.uid(self.uid).gid(self.gid);
+        }
+        command.status().map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to start {} in {}: {}",
+                program,
+                self.cwd.display(),
+                e
+            )
+        })
+    }
+}
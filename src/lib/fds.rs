@@ -0,0 +1,155 @@
+//! The open files and sockets of an instance.
+//!
+//! "Address already in use" and slowly leaking descriptors are easiest to understand by
+//! looking at what a process holds open. `detach-rs fds <NAME>` lists the descriptors of
+//! the instance and every process below it, read from `/proc/<PID>/fd`, with sockets
+//! resolved to their protocol and addresses through `/proc/net`, so `lsof` is not
+//! needed. Only available on Linux.
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+
+/// One open file descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenFile {
+    /// The process holding it
+    pub pid: u32,
+    /// The descriptor number
+    pub fd: u32,
+    /// `file`, `pipe`, `tcp`, `tcp6`, `udp`, `udp6`, `unix`, `socket` (of another
+    /// family) or `other` (e.g. an eventfd)
+    pub kind: String,
+    /// The path, the socket's addresses or the kernel's name for it
+    pub description: String,
+    /// Whether it is a listening TCP socket or a bound UDP socket
+    pub listening: bool,
+}
+
+/// Returns the open descriptors of the processes `pids`, in that order and by
+/// descriptor number within each process.
+///
+/// Processes that exit while being read, or whose descriptors may not be read, are
+/// skipped.
+#[cfg(target_os = "linux")]
+pub fn open_files(pids: &[u32]) -> Vec<OpenFile> {
+    let sockets = read_sockets();
+    let mut files = Vec::new();
+    for &pid in pids {
+        let Ok(entries) = std::fs::read_dir(format!("/proc/{}/fd", pid)) else {
+            continue;
+        };
+        let mut fds: Vec<(u32, std::path::PathBuf)> = entries
+            .filter_map(Result::ok)
+            .filter_map(|entry| {
+                let fd = entry.file_name().to_str()?.parse().ok()?;
+                Some((fd, std::fs::read_link(entry.path()).ok()?))
+            })
+            .collect();
+        fds.sort();
+        for (fd, target) in fds {
+            let target = target.to_string_lossy().into_owned();
+            let (kind, description, listening) = if let Some(inode) = target
+                .strip_prefix("socket:[")
+                .and_then(|rest| rest.strip_suffix(']'))
+                .and_then(|inode| inode.parse::<u64>().ok())
+            {
+                match sockets.get(&inode) {
+                    Some(socket) => socket.clone(),
+                    None => ("socket".to_string(), target, false),
+                }
+            } else if target.starts_with("pipe:") {
+                ("pipe".to_string(), target, false)
+            } else if target.starts_with('/') {
+                ("file".to_string(), target, false)
+            } else {
+                ("other".to_string(), target, false)
+            };
+            files.push(OpenFile {
+                pid,
+                fd,
+                kind,
+                description,
+                listening,
+            });
+        }
+    }
+    files
+}
+
+/// Reading descriptors needs `/proc`, which only Linux provides.
+#[cfg(not(target_os = "linux"))]
+pub fn open_files(pids: &[u32]) -> Vec<OpenFile> {
+    let _ = pids;
+    Vec::new()
+}
+
+/// Maps socket inodes to their kind, description and whether they listen.
+#[cfg(target_os = "linux")]
+fn read_sockets() -> HashMap<u64, (String, String, bool)> {
+    let mut sockets = HashMap::new();
+    for kind in ["tcp", "tcp6", "udp", "udp6"] {
+        let Ok(table) = std::fs::read_to_string(format!("/proc/net/{}", kind)) else {
+            continue;
+        };
+        for line in table.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (Some(local), Some(remote), Some(state), Some(inode)) = (
+                fields.get(1).and_then(|address| parse_address(address)),
+                fields.get(2).and_then(|address| parse_address(address)),
+                fields.get(3),
+                fields.get(9).and_then(|inode| inode.parse::<u64>().ok()),
+            ) else {
+                continue;
+            };
+            // 0A is LISTEN for TCP; an unconnected UDP socket is reported as 07 (CLOSE)
+            let listening = if kind.starts_with("tcp") {
+                *state == "0A"
+            } else {
+                *state == "07"
+            };
+            let description = if listening {
+                format!("{} (listening)", local)
+            } else {
+                format!("{} -> {}", local, remote)
+            };
+            sockets.insert(inode, (kind.to_string(), description, listening));
+        }
+    }
+    if let Ok(table) = std::fs::read_to_string("/proc/net/unix") {
+        for line in table.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let Some(inode) = fields.get(6).and_then(|inode| inode.parse::<u64>().ok()) else {
+                continue;
+            };
+            // Flags 00010000 is __SO_ACCEPTCON, set on listening sockets
+            let listening = fields.get(3).is_some_and(|flags| *flags == "00010000");
+            let path = fields.get(7).copied().unwrap_or("(unnamed)");
+            let description = if listening {
+                format!("{} (listening)", path)
+            } else {
+                path.to_string()
+            };
+            sockets.insert(inode, ("unix".to_string(), description, listening));
+        }
+    }
+    sockets
+}
+
+/// Turns a `/proc/net` address like `0100007F:1F90` into `127.0.0.1:8080`.
+#[cfg(target_os = "linux")]
+fn parse_address(address: &str) -> Option<String> {
+    let (ip, port) = address.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    // The address is printed as 32-bit words, each in host byte order
+    let bytes: Vec<u8> = (0..ip.len() / 8)
+        .map(|i| u32::from_str_radix(ip.get(i * 8..i * 8 + 8)?, 16).ok())
+        .collect::<Option<Vec<u32>>>()?
+        .into_iter()
+        .flat_map(u32::to_ne_bytes)
+        .collect();
+    let ip: std::net::IpAddr = match bytes.len() {
+        4 => std::net::Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?).into(),
+        16 => std::net::Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).into(),
+        _ => return None,
+    };
+    Some(std::net::SocketAddr::new(ip, port).to_string())
+}
@@ -0,0 +1,196 @@
+//! Fork-safety audit.
+//!
+//! `fork()` copies only the calling thread. Locks held by other threads stay locked forever
+//! in the child, signal handlers that rely on a helper thread stop working, and every open
+//! descriptor is inherited. `PreForkGuard` inspects this state right before
+//! `detach_process` forks, reports what would be carried over, and blocks signal delivery
+//! until the guard is dropped on the other side of the fork, so no handler can run in the
+//! half-initialized daemon.
+use log::{error, info, warn};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How thoroughly the process is checked before it forks.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ForkAudit {
+    /// Only the built-in check for a running tokio runtime
+    #[default]
+    Off,
+    /// Log warnings about state that is unsafe to carry across fork
+    Warn,
+    /// Like `Warn`, but refuse to fork when extra threads or signal handlers are found
+    Strict,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(0);
+
+/// Sets the audit mode used by `daemonize` and `daemonize_local` for the whole process.
+pub fn set_fork_audit(mode: ForkAudit) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// Returns the audit mode set with `set_fork_audit`.
+pub fn fork_audit() -> ForkAudit {
+    match MODE.load(Ordering::Relaxed) {
+        1 => ForkAudit::Warn,
+        2 => ForkAudit::Strict,
+        _ => ForkAudit::Off,
+    }
+}
+
+/// What the audit found.
+#[derive(Debug, Clone, Default)]
+pub struct ForkReport {
+    /// Number of threads in the process, if it could be determined
+    pub threads: Option<usize>,
+    /// Open descriptors other than stdin/stdout/stderr, with what they point to
+    pub descriptors: Vec<(i32, String)>,
+    /// Signals with a handler function installed
+    pub handled_signals: Vec<i32>,
+}
+
+impl ForkReport {
+    /// Inspects the current process.
+    pub fn collect() -> Self {
+        let threads = std::fs::read_dir("/proc/self/task")
+            .ok()
+            .map(|tasks| tasks.count());
+
+        // Collect the names first so the directory handle is closed before the links are
+        // read; its own entry then no longer resolves and drops out.
+        let fds: Vec<i32> = std::fs::read_dir("/proc/self/fd")
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+                    .filter(|&fd| fd > 2)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut descriptors: Vec<(i32, String)> = fds
+            .into_iter()
+            .filter_map(|fd| {
+                let target = std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()?;
+                Some((fd, target.display().to_string()))
+            })
+            .collect();
+        descriptors.sort();
+
+        let mut handled_signals = Vec::new();
+        for signal in 1..32 {
+            // SIGSEGV and SIGBUS carry the Rust runtime's stack overflow handler, which is
+            // fork-safe and present in every Rust program.
+            if matches!(
+                signal,
+                libc::SIGKILL | libc::SIGSTOP | libc::SIGSEGV | libc::SIGBUS
+            ) {
+                continue;
+            }
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            if unsafe { libc::sigaction(signal, std::ptr::null(), &mut action) } == 0
+                && action.sa_sigaction != libc::SIG_DFL
+                && action.sa_sigaction != libc::SIG_IGN
+            {
+                handled_signals.push(signal);
+            }
+        }
+
+        ForkReport {
+            threads,
+            descriptors,
+            handled_signals,
+        }
+    }
+
+    /// Returns `true` if the report contains state that breaks a forked child.
+    pub fn is_unsafe(&self) -> bool {
+        self.threads.is_some_and(|threads| threads > 1) || !self.handled_signals.is_empty()
+    }
+
+    /// Logs the findings; problems at warn level, or error level when `strict`.
+    pub fn log(&self, strict: bool) {
+        let problem = |message: String| {
+            if strict {
+                error!("{}", message);
+            } else {
+                warn!("{}", message);
+            }
+        };
+        if let Some(threads) = self.threads.filter(|&threads| threads > 1) {
+            problem(format!(
+                "Fork audit: {} threads are running; only the calling thread survives fork, \
+                 and locks held by the others stay locked in the daemon.",
+                threads
+            ));
+        }
+        if !self.handled_signals.is_empty() {
+            let names: Vec<String> = self
+                .handled_signals
+                .iter()
+                .map(|&signal| {
+                    crate::signal::signal_name(signal)
+                        .map(str::to_string)
+                        .unwrap_or_else(|| signal.to_string())
+                })
+                .collect();
+            problem(format!(
+                "Fork audit: signal handlers are installed for {}; handlers that depend on \
+                 other threads or runtimes will not work in the daemon.",
+                names.join(", ")
+            ));
+        }
+        for (fd, target) in &self.descriptors {
+            warn!(
+                "Fork audit: descriptor {} ({}) is inherited by the daemon.",
+                fd, target
+            );
+        }
+        if !self.is_unsafe() && self.descriptors.is_empty() {
+            info!("Fork audit: nothing unsafe to carry across fork.");
+        }
+    }
+}
+
+/// Audits the process and blocks all signals until dropped.
+///
+/// Create it right before forking and drop it once the child has set itself up. The
+/// signal mask is inherited across fork, so the guard restores it in both processes.
+#[derive(Debug)]
+pub struct PreForkGuard {
+    saved_mask: libc::sigset_t,
+}
+
+impl PreForkGuard {
+    /// Runs the audit selected with `set_fork_audit` and blocks signals.
+    ///
+    /// # Returns
+    /// - `Ok(PreForkGuard)`: Forking may proceed.
+    /// - `Err(anyhow::Error)`: `ForkAudit::Strict` found threads or signal handlers.
+    pub fn new() -> Result<Self, anyhow::Error> {
+        let mode = fork_audit();
+        if mode != ForkAudit::Off {
+            let report = ForkReport::collect();
+            report.log(mode == ForkAudit::Strict);
+            if mode == ForkAudit::Strict && report.is_unsafe() {
+                return Err(anyhow::anyhow!(
+                    "Refusing to fork: the fork audit found unsafe process state"
+                ));
+            }
+        }
+
+        unsafe {
+            let mut all: libc::sigset_t = std::mem::zeroed();
+            let mut saved_mask: libc::sigset_t = std::mem::zeroed();
+            libc::sigfillset(&mut all);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &all, &mut saved_mask);
+            Ok(PreForkGuard { saved_mask })
+        }
+    }
+}
+
+impl Drop for PreForkGuard {
+    fn drop(&mut self) {
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.saved_mask, std::ptr::null_mut());
+        }
+    }
+}
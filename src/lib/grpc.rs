@@ -0,0 +1,537 @@
+//! The control API as a gRPC service (feature `grpc`).
+//!
+//! `with_grpc_service` serves `detach.v1.Control`, described by
+//! `proto/detach/v1/control.proto`, on a Unix socket (`unix:/run/web.grpc`) or a TCP
+//! address (`127.0.0.1:50051`). It offers what the control socket does:
+//!
+//! - `Start`, `Stop`, `Restart`: passed to the supervisor as a `ControlRequest`; fail with
+//!   `FAILED_PRECONDITION` if the instance does not supervise a command.
+//! - `Status`: the instance name, PID, state, health and last lifecycle event.
+//! - `Logs`: a stream of the recent lifecycle events and, with `follow`, every new one.
+//!
+//! so clients generated from the proto file, or `grpcurl`, can drive the instance:
+//!
+//! ```text
+//! grpcurl -plaintext -proto proto/detach/v1/control.proto -unix /run/web.grpc \
+//!     detach.v1.Control/Restart
+//! ```
+//!
+//! A Unix socket is protected by its file permissions. Over TCP anyone who can reach the
+//! port is a client, so without a token the service only listens on loopback addresses
+//! and refuses `Start`, `Stop` and `Restart`. With a token (`--grpc-token-file`) it may
+//! listen on any address, and every call has to carry it as
+//! `authorization: Bearer <token>` metadata or fails with `UNAUTHENTICATED`:
+//!
+//! ```text
+//! grpcurl -plaintext -H "authorization: Bearer $(cat /etc/detach/web.token)" \
+//!     -proto proto/detach/v1/control.proto 10.0.0.5:50051 detach.v1.Control/Stop
+//! ```
+//!
+//! The token travels in clear text, so expose the port only on networks you trust or
+//! behind a TLS-terminating proxy.
+//!
+//! The messages and the routing are written out here rather than generated at build time,
+//! so building the feature does not need `protoc`.
+use crate::audit::LifecycleEvent;
+use crate::context::{ControlRequest, ServiceContext};
+use log::{info, warn};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream, UnixListenerStream};
+use tonic::codegen::{BoxFuture, BoxStream, Context, Poll, Service, http};
+use tonic::server::NamedService;
+use tonic::{Code, Status};
+
+/// The full name of the service, as used in request paths.
+pub const SERVICE_NAME: &str = "detach.v1.Control";
+
+/// Where the gRPC service listens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// A Unix socket at this path
+    Unix(PathBuf),
+    /// A TCP port
+    Tcp(SocketAddr),
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = String;
+
+    /// Parses `unix:PATH` with an absolute `PATH`, or `HOST:PORT`.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = input.strip_prefix("unix:") {
+            let path = PathBuf::from(path);
+            if !path.is_absolute() {
+                return Err(format!("socket path must be absolute: {}", path.display()));
+            }
+            return Ok(Endpoint::Unix(path));
+        }
+        input
+            .parse()
+            .map(Endpoint::Tcp)
+            .map_err(|_| format!("expected unix:PATH or HOST:PORT, got {}", input))
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// The messages of `proto/detach/v1/control.proto`.
+pub mod proto {
+    /// The argument of `Start`, `Stop` and `Restart`.
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct ControlRequest {}
+
+    /// The answer to `Start`, `Stop` and `Restart`.
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct ControlReply {}
+
+    /// The argument of `Status`.
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct StatusRequest {}
+
+    /// The answer to `Status`.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct StatusReply {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(uint32, tag = "2")]
+        pub pid: u32,
+        #[prost(string, tag = "3")]
+        pub health: String,
+        #[prost(message, optional, tag = "4")]
+        pub last_event: Option<Event>,
+        #[prost(string, tag = "5")]
+        pub state: String,
+    }
+
+    /// The argument of `Logs`.
+    #[derive(Clone, Copy, PartialEq, prost::Message)]
+    pub struct LogsRequest {
+        /// Keep the stream open and send new events until the instance exits
+        #[prost(bool, tag = "1")]
+        pub follow: bool,
+    }
+
+    /// A lifecycle event, or the number of events a slow client missed.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Event {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub time: String,
+        #[prost(string, tag = "3")]
+        pub action: String,
+        #[prost(string, tag = "4")]
+        pub trigger: String,
+        #[prost(string, tag = "5")]
+        pub detail: String,
+        #[prost(uint32, tag = "6")]
+        pub pid: u32,
+        #[prost(uint64, tag = "7")]
+        pub missed: u64,
+        #[prost(string, tag = "8")]
+        pub state: String,
+    }
+}
+
+impl proto::Event {
+    fn new(name: &str, event: &LifecycleEvent) -> Self {
+        proto::Event {
+            name: name.to_string(),
+            time: event
+                .time
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            action: event.action.as_str().to_string(),
+            trigger: event.trigger.as_str().to_string(),
+            detail: event.detail.clone(),
+            pid: std::process::id(),
+            missed: 0,
+            state: event.state.as_str().to_string(),
+        }
+    }
+}
+
+/// Reads the bearer token TCP clients have to present from `path`.
+///
+/// Surrounding whitespace is ignored. A warning is logged if other users can read the
+/// file.
+pub fn read_token(path: &Path) -> anyhow::Result<String> {
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} does not contain a token",
+            path.display()
+        ));
+    }
+    if let Ok(metadata) = std::fs::metadata(path) {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            warn!(
+                "{} is readable by other users; restrict it with chmod 600.",
+                path.display()
+            );
+        }
+    }
+    Ok(token.to_string())
+}
+
+/// Runs `future` while serving the gRPC control API of the instance `name` at `endpoint`.
+///
+/// The endpoint is bound before `future` starts, so a second instance on the same socket
+/// or port fails immediately; a socket file nobody listens on any more is replaced and
+/// the file is removed when `future` completes. If the server fails later, a warning is
+/// logged and `future` keeps running. Without an `endpoint` this is a plain
+/// `future.await`.
+///
+/// Listening on a TCP address that is not a loopback address requires a `token`.
+///
+/// # Arguments
+/// - `endpoint`: Where to listen.
+/// - `token`: The bearer token TCP clients have to present, usually from `read_token`.
+/// - `name`: The instance name, reported by `Status` and with every event.
+/// - `ctx`: The context requests are sent to and events are read from.
+/// - `future`: The service future.
+pub async fn with_grpc_service<F>(
+    endpoint: Option<Endpoint>,
+    token: Option<String>,
+    name: Option<String>,
+    ctx: ServiceContext,
+    future: F,
+) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    let Some(endpoint) = endpoint else {
+        return future.await;
+    };
+    let tcp = match &endpoint {
+        Endpoint::Tcp(addr) if !addr.ip().is_loopback() && token.is_none() => {
+            return Err(anyhow::anyhow!(
+                "Refusing to serve gRPC on {} without a token; use a loopback address or --grpc-token-file",
+                addr
+            ));
+        }
+        Endpoint::Tcp(_) => true,
+        Endpoint::Unix(_) => false,
+    };
+    let service = ControlService {
+        name,
+        ctx,
+        tcp,
+        token: token.map(Arc::from),
+    };
+    let router = tonic::transport::Server::builder().add_service(service);
+    let serve: BoxFuture<(), tonic::transport::Error> = match &endpoint {
+        Endpoint::Unix(path) => {
+            let listener = crate::control::bind(path).await?;
+            let incoming = UnixListenerStream::new(listener);
+            Box::pin(router.serve_with_incoming(incoming))
+        }
+        Endpoint::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", addr, e))?;
+            let incoming = TcpListenerStream::new(listener);
+            Box::pin(router.serve_with_incoming(incoming))
+        }
+    };
+    info!("gRPC control API listening on {}", endpoint);
+
+    tokio::pin!(future);
+    let result = tokio::select! {
+        result = &mut future => result,
+        result = serve => {
+            if let Err(e) = result {
+                warn!("gRPC control API failed: {}", e);
+            }
+            future.await
+        }
+    };
+    if let Endpoint::Unix(path) = &endpoint {
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+/// Routes the requests of `detach.v1.Control` to the methods below.
+#[derive(Clone)]
+struct ControlService {
+    name: Option<String>,
+    ctx: ServiceContext,
+    /// Served over TCP rather than a Unix socket
+    tcp: bool,
+    token: Option<Arc<str>>,
+}
+
+impl ControlService {
+    /// Checks that a call over TCP carries the token, or, without one, that it does not
+    /// change the instance.
+    fn authorize(&self, headers: &http::HeaderMap, mutating: bool) -> Result<(), Status> {
+        if !self.tcp {
+            return Ok(());
+        }
+        match &self.token {
+            Some(token) => {
+                let given = headers
+                    .get(http::header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "));
+                if given.is_some_and(|given| same_token(given, token)) {
+                    Ok(())
+                } else {
+                    Err(Status::unauthenticated("A valid bearer token is required"))
+                }
+            }
+            None if mutating => Err(Status::permission_denied(
+                "Start, Stop and Restart over TCP require --grpc-token-file",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn control(&self, request: ControlRequest) -> Result<proto::ControlReply, Status> {
+        info!("{:?} requested over gRPC.", request);
+        if self.ctx.request(request) {
+            Ok(proto::ControlReply {})
+        } else {
+            Err(Status::failed_precondition(
+                "This instance does not supervise a command",
+            ))
+        }
+    }
+
+    fn status(&self) -> proto::StatusReply {
+        let name = self.name.clone().unwrap_or_default();
+        proto::StatusReply {
+            last_event: self
+                .ctx
+                .recent_events()
+                .last()
+                .map(|event| proto::Event::new(&name, event)),
+            name,
+            pid: std::process::id(),
+            health: self.ctx.health().get().to_string(),
+            state: self.ctx.state().get().to_string(),
+        }
+    }
+
+    fn logs(&self, follow: bool) -> BoxStream<proto::Event> {
+        // Subscribe first so nothing published while the history is sent is lost.
+        let live = BroadcastStream::new(self.ctx.subscribe_events());
+        let history = tokio_stream::iter(self.ctx.recent_events().into_iter().map(Ok));
+        let name = self.name.clone().unwrap_or_default();
+        let to_message = move |item: Result<LifecycleEvent, BroadcastStreamRecvError>| {
+            Ok(match item {
+                Ok(event) => proto::Event::new(&name, &event),
+                Err(BroadcastStreamRecvError::Lagged(missed)) => proto::Event {
+                    name: name.clone(),
+                    missed,
+                    ..Default::default()
+                },
+            })
+        };
+        if follow {
+            Box::pin(history.chain(live).map(to_message))
+        } else {
+            Box::pin(history.map(to_message))
+        }
+    }
+}
+
+impl Service<http::Request<tonic::body::Body>> for ControlService {
+    type Response = http::Response<tonic::body::Body>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<tonic::body::Body>) -> Self::Future {
+        let this = self.clone();
+        let method = req.uri().path().strip_prefix('/');
+        let method = method.and_then(|method| method.strip_prefix(SERVICE_NAME));
+        let mutating = matches!(method, Some("/Start" | "/Stop" | "/Restart"));
+        if let Err(status) = self.authorize(req.headers(), mutating) {
+            warn!(
+                "Refused gRPC call {}: {}",
+                req.uri().path(),
+                status.message()
+            );
+            return Box::pin(async move { Ok(status.into_http()) });
+        }
+        match method {
+            Some("/Start") => Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(tonic_prost::ProstCodec::default());
+                let method =
+                    Method(move |_: proto::ControlRequest| this.control(ControlRequest::Start));
+                Ok(grpc.unary(method, req).await)
+            }),
+            Some("/Stop") => Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(tonic_prost::ProstCodec::default());
+                let method =
+                    Method(move |_: proto::ControlRequest| this.control(ControlRequest::Stop));
+                Ok(grpc.unary(method, req).await)
+            }),
+            Some("/Restart") => Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(tonic_prost::ProstCodec::default());
+                let method =
+                    Method(move |_: proto::ControlRequest| this.control(ControlRequest::Restart));
+                Ok(grpc.unary(method, req).await)
+            }),
+            Some("/Status") => Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(tonic_prost::ProstCodec::default());
+                let method = Method(move |_: proto::StatusRequest| Ok(this.status()));
+                Ok(grpc.unary(method, req).await)
+            }),
+            Some("/Logs") => Box::pin(async move {
+                let mut grpc = tonic::server::Grpc::new(tonic_prost::ProstCodec::default());
+                let method =
+                    Method(move |request: proto::LogsRequest| Ok(this.logs(request.follow)));
+                Ok(grpc.server_streaming(method, req).await)
+            }),
+            _ => Box::pin(async move { Ok(Status::new(Code::Unimplemented, "").into_http()) }),
+        }
+    }
+}
+
+impl NamedService for ControlService {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+/// Compares two tokens in time that depends only on their lengths.
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Adapts a function from a request message to a reply into the service `tonic::server`
+/// calls for a single method.
+struct Method<F>(F);
+
+impl<F, M, R> Service<tonic::Request<M>> for Method<F>
+where
+    F: FnMut(M) -> Result<R, Status>,
+{
+    type Response = tonic::Response<R>;
+    type Error = Status;
+    type Future = std::future::Ready<Result<Self::Response, Status>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: tonic::Request<M>) -> Self::Future {
+        std::future::ready((self.0)(request.into_inner()).map(tonic::Response::new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(tcp: bool, token: Option<&str>) -> ControlService {
+        ControlService {
+            name: Some("web".to_string()),
+            ctx: ServiceContext::new(),
+            tcp,
+            token: token.map(Arc::from),
+        }
+    }
+
+    /// Request headers with `authorization` set to `value`, if given.
+    fn headers(value: Option<&str>) -> http::HeaderMap {
+        let mut headers = http::HeaderMap::new();
+        if let Some(value) = value {
+            headers.insert(http::header::AUTHORIZATION, value.parse().unwrap());
+        }
+        headers
+    }
+
+    /// The code `authorize` fails with, or `Ok` for a call it lets through.
+    fn code(service: &ControlService, authorization: Option<&str>, mutating: bool) -> Code {
+        match service.authorize(&headers(authorization), mutating) {
+            Ok(()) => Code::Ok,
+            Err(status) => status.code(),
+        }
+    }
+
+    #[test]
+    fn same_token_compares_whole_tokens() {
+        assert!(same_token("s3cret", "s3cret"));
+        assert!(!same_token("s3cres", "s3cret"));
+        assert!(!same_token("s3cre", "s3cret"));
+        assert!(!same_token("s3cret ", "s3cret"));
+        assert!(!same_token("", "s3cret"));
+    }
+
+    #[test]
+    fn unix_socket_calls_need_no_token() {
+        let service = service(false, Some("s3cret"));
+        assert_eq!(code(&service, None, true), Code::Ok);
+        assert_eq!(code(&service, Some("Bearer wrong"), true), Code::Ok);
+    }
+
+    #[test]
+    fn tcp_without_a_token_only_reads() {
+        let service = service(true, None);
+        assert_eq!(code(&service, None, false), Code::Ok);
+        assert_eq!(code(&service, None, true), Code::PermissionDenied);
+        assert_eq!(
+            code(&service, Some("Bearer anything"), true),
+            Code::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn tcp_with_a_token_requires_it_on_every_call() {
+        let service = service(true, Some("s3cret"));
+        for mutating in [false, true] {
+            assert_eq!(code(&service, Some("Bearer s3cret"), mutating), Code::Ok);
+            for refused in [
+                None,
+                Some("Bearer wrong"),
+                Some("bearer s3cret"),
+                Some("s3cret"),
+            ] {
+                assert_eq!(
+                    code(&service, refused, mutating),
+                    Code::Unauthenticated,
+                    "{:?}",
+                    refused
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn endpoints_are_absolute_unix_sockets_or_socket_addresses() {
+        assert_eq!(
+            "unix:/run/web.grpc".parse(),
+            Ok(Endpoint::Unix(PathBuf::from("/run/web.grpc")))
+        );
+        assert_eq!(
+            "127.0.0.1:50051".parse(),
+            Ok(Endpoint::Tcp(SocketAddr::from(([127, 0, 0, 1], 50051))))
+        );
+        assert!("unix:web.grpc".parse::<Endpoint>().is_err());
+        assert!("localhost".parse::<Endpoint>().is_err());
+    }
+}
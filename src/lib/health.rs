@@ -0,0 +1,89 @@
+//! Application-level health reported by the service itself.
+//!
+//! A live process is not necessarily a working one. `Health` lets the service say so:
+//! `set_degraded("db unreachable")` is logged, forwarded to systemd as `STATUS=` and served
+//! by the `/health` and `/status` endpoints, and `set_healthy()` clears it again.
+use log::{info, warn};
+use tokio::sync::watch;
+
+/// The health a service last reported.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HealthState {
+    /// Working normally (also the state before anything was reported)
+    #[default]
+    Healthy,
+    /// Running, but impaired for the given reason
+    Degraded(String),
+}
+
+impl HealthState {
+    /// Returns `true` for `HealthState::Healthy`.
+    pub fn is_healthy(&self) -> bool {
+        *self == HealthState::Healthy
+    }
+}
+
+impl std::fmt::Display for HealthState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthState::Healthy => write!(f, "healthy"),
+            HealthState::Degraded(reason) => write!(f, "degraded: {}", reason),
+        }
+    }
+}
+
+/// The handle a service reports its health through; obtained from `ServiceContext::health`.
+#[derive(Debug, Clone)]
+pub struct Health {
+    state: watch::Sender<HealthState>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            state: watch::Sender::new(HealthState::Healthy),
+        }
+    }
+}
+
+impl Health {
+    /// Reports that the service is working normally.
+    pub fn set_healthy(&self) {
+        self.set(HealthState::Healthy);
+    }
+
+    /// Reports that the service is running but impaired, e.g. `"db unreachable"`.
+    pub fn set_degraded(&self, reason: impl Into<String>) {
+        self.set(HealthState::Degraded(reason.into()));
+    }
+
+    /// The last reported state.
+    pub fn get(&self) -> HealthState {
+        self.state.borrow().clone()
+    }
+
+    /// Returns a receiver that is notified on every change, for exporters.
+    pub fn subscribe(&self) -> watch::Receiver<HealthState> {
+        self.state.subscribe()
+    }
+
+    /// Stores `state` and announces it, unless it is unchanged.
+    fn set(&self, state: HealthState) {
+        let changed = self.state.send_if_modified(|current| {
+            if *current == state {
+                return false;
+            }
+            *current = state.clone();
+            true
+        });
+        if !changed {
+            return;
+        }
+        match &state {
+            HealthState::Healthy => info!("Service reported healthy."),
+            HealthState::Degraded(reason) => warn!("Service reported degraded: {}", reason),
+        }
+        #[cfg(unix)]
+        let _ = crate::notify::sd_notify(&format!("STATUS={}", state));
+    }
+}
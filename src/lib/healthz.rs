@@ -0,0 +1,148 @@
+//! One health check for all running instances.
+//!
+//! Each instance answers for itself on its control socket, while a load balancer or an
+//! uptime checker wants a single URL. `detach-rs healthz --listen ADDR` serves
+//! `GET /healthz` for every instance in `config::runtime_dir()` together: `200` if all of
+//! them are `ready` (see `state`), `503` otherwise, with the details as JSON:
+//!
+//! ```text
+//! {"healthy":false,"services":[{"name":"web","state":"ready","health":"healthy","required":true,"healthy":true},{"name":"worker","state":"degraded","health":"degraded: queue full","required":true,"healthy":false}],"missing":[]}
+//! ```
+//!
+//! With `--require NAME`, only the named services (and all of their instances) count,
+//! and each of them must have at least one running instance; the others are listed but
+//! do not change the answer. Without instances to check, the answer is `503`, since a
+//! check that passes with nothing running would hide a host where nothing started.
+//! Serving the check over HTTP needs the `http` feature; checking once does not.
+#[cfg(feature = "http")]
+use log::info;
+use std::net::SocketAddr;
+use std::time::Duration;
+#[cfg(feature = "http")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "http")]
+use tokio::net::TcpListener;
+
+/// How long an instance may take to answer before it counts as unhealthy.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Checks the running instances.
+///
+/// # Arguments
+/// - `required`: The services that count; all of them if empty.
+///
+/// # Returns
+/// - `Ok(serde_json::Value)`: The report, with the verdict in `healthy`, each running
+///   instance in `services` and the required services without a running instance in
+///   `missing`.
+/// - `Err(anyhow::Error)`: If the runtime directory cannot be read.
+pub async fn check(required: &[String]) -> Result<serde_json::Value, anyhow::Error> {
+    let mut services = Vec::new();
+    let mut healthy = true;
+    for (name, path) in crate::control::list_sockets()? {
+        if !crate::control::is_listening(&path) {
+            continue;
+        }
+        let counts = required.is_empty()
+            || required
+                .iter()
+                .any(|service| crate::instances::belongs_to(&name, service));
+        let query = tokio::time::timeout(QUERY_TIMEOUT, crate::control::query(&path, "status"));
+        let (state, health) = match query.await {
+            Ok(Ok(status)) => (
+                status["state"].as_str().unwrap_or("unknown").to_string(),
+                status["health"].clone(),
+            ),
+            Ok(Err(e)) => ("unreachable".to_string(), e.to_string().into()),
+            Err(_) => ("unreachable".to_string(), "no answer".into()),
+        };
+        let ready = state == "ready";
+        healthy &= ready || !counts;
+        services.push(serde_json::json!({
+            "name": name,
+            "state": state,
+            "health": health,
+            "required": counts,
+            "healthy": ready,
+        }));
+    }
+    let missing: Vec<&String> = required
+        .iter()
+        .filter(|service| {
+            !services.iter().any(|status| {
+                status["name"]
+                    .as_str()
+                    .is_some_and(|name| crate::instances::belongs_to(name, service))
+            })
+        })
+        .collect();
+    let checked = services.iter().any(|status| status["required"] == true);
+    Ok(serde_json::json!({
+        "healthy": healthy && checked && missing.is_empty(),
+        "services": services,
+        "missing": missing,
+    }))
+}
+
+/// Serves `GET /healthz` on `addr` until the process is stopped.
+///
+/// Every request checks the instances again (see `check`); other paths get `404`.
+///
+/// # Returns
+/// - `Err(anyhow::Error)`: If `addr` cannot be bound, or this build lacks the `http`
+///   feature; otherwise it does not return.
+#[cfg(feature = "http")]
+pub async fn serve(addr: SocketAddr, required: Vec<String>) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen for health checks on {}: {}", addr, e))?;
+    info!(
+        "Serving the health of all instances on http://{}/healthz",
+        addr
+    );
+    let required = std::sync::Arc::new(required);
+    let clients = crate::tasks::TaskGroup::new();
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let required = required.clone();
+        clients.spawn("health check client", async move {
+            let mut buf = [0u8; 1024];
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf));
+            let Ok(Ok(n)) = read.await else {
+                return;
+            };
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+            let (status, body) = if path == "/healthz" {
+                let report = check(&required).await.unwrap_or_else(
+                    |e| serde_json::json!({ "healthy": false, "error": e.to_string() }),
+                );
+                if report["healthy"] == true {
+                    ("200 OK", report.to_string())
+                } else {
+                    ("503 Service Unavailable", report.to_string())
+                }
+            } else {
+                ("404 Not Found", r#"{"error":"not found"}"#.to_string())
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+                status,
+                body.len() + 1,
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+#[cfg(not(feature = "http"))]
+pub async fn serve(addr: SocketAddr, required: Vec<String>) -> Result<(), anyhow::Error> {
+    let _ = required;
+    Err(anyhow::anyhow!(
+        "Cannot serve health checks on {}: this build lacks the http feature",
+        addr
+    ))
+}
@@ -0,0 +1,415 @@
+//! A persistent history of the lifecycle events of each service.
+//!
+//! The events a running instance serves over its control socket are lost when it exits,
+//! and an audit log is opt-in and unbounded. For questions like "how often has this
+//! crashed this week?", every instance started with `--name` also appends its lifecycle
+//! events (starts, ready, exits, restarts, stops, signals and reloads) to
+//! `NAME.ndjson` under `config::state_dir()/history`, one JSON object per line as served
+//! by `detach-rs events`:
+//!
+//! ```text
+//! {"time":"2026-10-16T03:00:01.204+02:00","action":"exit","trigger":"command","detail":"run #4: exited with code 1","state":"failed","pid":4242,"run_id":"5f0c…","name":"web"}
+//! ```
+//!
+//! Entries older than the retention period (`--history-retention`, 30 days by default)
+//! are dropped when an instance starts. `detach-rs history NAME` reads them back,
+//! together with those of the instances of `NAME` (see `instances`).
+//!
+//! `stats` condenses a history into reliability figures (restarts, crashes, uptime and
+//! the mean time between failures), which `detach-rs status` adds to each instance.
+use crate::context::ServiceContext;
+use chrono::{DateTime, Local};
+use log::warn;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+/// How long entries are kept unless `--history-retention` says otherwise.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Returns the directory holding the history files.
+pub fn dir() -> Result<PathBuf, anyhow::Error> {
+    Ok(crate::config::state_dir()?.join("history"))
+}
+
+/// Returns the history file of the instance `name`.
+pub fn path(name: &str) -> Result<PathBuf, anyhow::Error> {
+    crate::config::validate_service_name(name)?;
+    Ok(dir()?.join(format!("{}.ndjson", name)))
+}
+
+/// Runs `future` while appending the lifecycle events of `ctx` to the history of the
+/// instance `name`.
+///
+/// Entries older than `retention` are removed first. Events published until `future`
+/// completes are written, including the final stop or exit. Without a `name`, or with a
+/// zero `retention`, this is a plain `future.await`. Failing to write the history is
+/// logged and never stops the service.
+pub async fn with_history<F>(
+    name: Option<String>,
+    retention: Duration,
+    ctx: ServiceContext,
+    future: F,
+) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    // `future` is awaited in one place only, so it is stored once in this future
+    let history =
+        name.filter(|_| !retention.is_zero())
+            .and_then(|name| match open(&name, retention) {
+                Ok(history) => Some((name, history)),
+                Err(e) => {
+                    warn!("Cannot keep the event history of {}: {}", name, e);
+                    None
+                }
+            });
+    let mut recording = history.is_some();
+    let mut events = ctx.subscribe_events();
+    let write = |event: &crate::audit::LifecycleEvent| {
+        if let Some((name, history)) = &history {
+            let mut entry = event.to_json();
+            entry["name"] = name.as_str().into();
+            history.append(&entry);
+        }
+    };
+    tokio::pin!(future);
+    let result = loop {
+        tokio::select! {
+            result = &mut future => break result,
+            event = events.recv(), if recording => match event {
+                Ok(event) => write(&event),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("{} lifecycle events were not written to the history.", missed);
+                }
+                Err(RecvError::Closed) => recording = false,
+            },
+        }
+    };
+    // The last events are published right before the service returns
+    while recording {
+        match events.try_recv() {
+            Ok(event) => write(&event),
+            Err(TryRecvError::Lagged(_)) => continue,
+            Err(_) => recording = false,
+        }
+    }
+    result
+}
+
+/// An open history file.
+struct History {
+    file: std::fs::File,
+    path: PathBuf,
+}
+
+impl History {
+    /// Appends `entry` as one line; a failure is logged.
+    fn append(&self, entry: &serde_json::Value) {
+        if let Err(e) = writeln!(&self.file, "{}", entry) {
+            warn!("Failed to write {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Opens the history of `name` for appending, after removing the entries older than
+/// `retention`.
+fn open(name: &str, retention: Duration) -> Result<History, anyhow::Error> {
+    let path = path(name)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    let cutoff = chrono::Duration::from_std(retention)
+        .ok()
+        .and_then(|retention| Local::now().checked_sub_signed(retention));
+    if let Some(cutoff) = cutoff {
+        prune(&path, cutoff).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to remove old entries from {}: {}",
+                path.display(),
+                e
+            )
+        })?;
+    }
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+    Ok(History { file, path })
+}
+
+/// Rewrites `path` without the entries from before `cutoff`, through a temporary file.
+fn prune(path: &Path, cutoff: DateTime<Local>) -> std::io::Result<()> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let lines = text.lines().count();
+    let kept: String = text
+        .lines()
+        .filter(|line| time(line).is_none_or(|time| time >= cutoff))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    if kept.lines().count() == lines {
+        return Ok(());
+    }
+    let partial = path.with_extension("ndjson.partial");
+    std::fs::write(&partial, kept)?;
+    std::fs::rename(&partial, path)
+}
+
+/// Returns the time of a history line, if it has a readable one.
+fn time(line: &str) -> Option<DateTime<Local>> {
+    let entry: serde_json::Value = serde_json::from_str(line).ok()?;
+    let time = DateTime::parse_from_rfc3339(entry["time"].as_str()?).ok()?;
+    Some(time.with_timezone(&Local))
+}
+
+/// Reads the history of the service `name` and all of its instances, oldest first.
+///
+/// # Arguments
+/// - `name`: A service or a single instance such as `web:2`.
+/// - `since`: Only entries from this time on, if given.
+///
+/// # Returns
+/// - `Ok(Vec<serde_json::Value>)`: The entries, empty if there is no history.
+/// - `Err(anyhow::Error)`: If the history directory or a file cannot be read.
+pub fn read(
+    name: &str,
+    since: Option<DateTime<Local>>,
+) -> Result<Vec<serde_json::Value>, anyhow::Error> {
+    crate::config::validate_service_name(name)?;
+    let dir = dir()?;
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow::anyhow!("Failed to read {}: {}", dir.display(), e)),
+    };
+    let mut history = Vec::new();
+    for path in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+        let belongs = path.extension().is_some_and(|ext| ext == "ndjson")
+            && path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|instance| crate::instances::belongs_to(instance, name));
+        if !belongs {
+            continue;
+        }
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        for line in text.lines() {
+            let Some(time) = time(line) else {
+                continue;
+            };
+            if since.is_none_or(|since| time >= since) {
+                history.push((time, serde_json::from_str(line)?));
+            }
+        }
+    }
+    history.sort_by_key(|(time, _)| *time);
+    Ok(history.into_iter().map(|(_, entry)| entry).collect())
+}
+
+/// Returns `true` for an entry that records a crash: the command exiting by itself and
+/// leaving the instance failed.
+pub fn is_crash(entry: &serde_json::Value) -> bool {
+    entry["action"] == "exit" && entry["state"] == "failed"
+}
+
+/// Reliability figures computed from a history by `stats`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Starts of the command, the first of each instance included
+    pub starts: usize,
+    /// Starts after the first of each instance: restarts on request or schedule, and
+    /// instances started again after they stopped, failed or were killed
+    pub restarts: usize,
+    /// Those of `restarts` in the 24 hours before `now`
+    pub restarts_24h: usize,
+    /// Exits that left the instance failed; see `is_crash`
+    pub crashes: usize,
+    /// The total time the command was running, over all instances
+    pub uptime: Duration,
+    /// Mean time between failures: `uptime` divided by `crashes`, if there were any
+    pub mtbf: Option<Duration>,
+}
+
+impl Stats {
+    /// Renders the figures as a JSON object, with durations in whole seconds.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "starts": self.starts,
+            "restarts": self.restarts,
+            "restarts_24h": self.restarts_24h,
+            "crashes": self.crashes,
+            "uptime_secs": self.uptime.as_secs(),
+            "mtbf_secs": self.mtbf.map(|mtbf| mtbf.as_secs()),
+        })
+    }
+}
+
+/// Computes reliability figures from `history`, as returned by `read`.
+///
+/// A run counts as up from its `start` until the `exit`, `restart` or `stop` after it,
+/// or the next `start` of the same instance if the instance was killed in between. A run
+/// that a new supervisor adopted (see `adopt`) stays up across the takeover.
+/// The last run of each instance that has not ended counts until `running_until`, or
+/// not at all without it.
+///
+/// # Arguments
+/// - `history`: The entries, oldest first.
+/// - `now`: The end of the 24 hours `restarts_24h` covers.
+/// - `running_until`: Where a run that has not ended is cut off; `Some(now)` for a
+///   running instance.
+pub fn stats(
+    history: &[serde_json::Value],
+    now: DateTime<Local>,
+    running_until: Option<DateTime<Local>>,
+) -> Stats {
+    let mut stats = Stats::default();
+    let day_ago = now - chrono::Duration::hours(24);
+    let mut uptime = chrono::Duration::zero();
+    // Per instance: whether it has started before, and since when its run is up
+    let mut instances: std::collections::HashMap<&str, Option<DateTime<Local>>> =
+        std::collections::HashMap::new();
+    for entry in history {
+        let (Some(name), Some(time)) = (
+            entry["name"].as_str(),
+            entry["time"]
+                .as_str()
+                .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+                .map(|time| time.with_timezone(&Local)),
+        ) else {
+            continue;
+        };
+        let action = entry["action"].as_str().unwrap_or_default();
+        let seen = instances.contains_key(name);
+        let up_since = instances.entry(name).or_default();
+        match action {
+            "start" => {
+                stats.starts += 1;
+                if seen {
+                    stats.restarts += 1;
+                    if time >= day_ago {
+                        stats.restarts_24h += 1;
+                    }
+                }
+                if let Some(since) = up_since.replace(time) {
+                    uptime += time - since;
+                }
+            }
+            "adopt" => {
+                up_since.get_or_insert(time);
+            }
+            "exit" | "restart" | "stop" => {
+                if let Some(since) = up_since.take() {
+                    uptime += time - since;
+                }
+            }
+            _ => {}
+        }
+        if is_crash(entry) {
+            stats.crashes += 1;
+        }
+    }
+    if let Some(until) = running_until {
+        for since in instances.values().flatten() {
+            uptime += until - *since;
+        }
+    }
+    stats.uptime = uptime.to_std().unwrap_or_default();
+    stats.mtbf = (stats.crashes > 0).then(|| stats.uptime / stats.crashes as u32);
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2026, 10, 16, 12, 0, 0).unwrap()
+    }
+
+    /// A history entry of instance `name`, `hours_ago` hours before `now`.
+    fn entry(hours_ago: i64, name: &str, action: &str, state: &str) -> serde_json::Value {
+        let time = now() - chrono::Duration::hours(hours_ago);
+        serde_json::json!({
+            "time": time.to_rfc3339(),
+            "action": action,
+            "state": state,
+            "name": name,
+        })
+    }
+
+    /// The entries of several instances, oldest first.
+    fn history() -> Vec<serde_json::Value> {
+        let mut history = vec![
+            // Crashes after an hour, runs for 44 hours, is restarted and stopped
+            entry(48, "web", "start", "starting"),
+            entry(47, "web", "exit", "failed"),
+            entry(46, "web", "start", "starting"),
+            entry(2, "web", "restart", "stopping"),
+            entry(2, "web", "start", "starting"),
+            entry(1, "web", "exit", "stopped"),
+            // Adopted by a new supervisor, crashes, and is still running since
+            entry(10, "web-2", "start", "starting"),
+            entry(8, "web-2", "adopt", "ready"),
+            entry(6, "web-2", "exit", "failed"),
+            entry(5, "web-2", "start", "starting"),
+            // Killed without an exit, started again and stopped
+            entry(20, "web-3", "start", "starting"),
+            entry(19, "web-3", "start", "starting"),
+            entry(18, "web-3", "stop", "stopping"),
+            // Started before the history begins
+            entry(3, "web-4", "adopt", "ready"),
+            entry(2, "web-4", "stop", "stopped"),
+            serde_json::json!({"action": "start"}),
+        ];
+        history.sort_by_key(|entry| entry["time"].as_str().map(str::to_string));
+        history
+    }
+
+    #[test]
+    fn stats_counts_starts_restarts_and_crashes() {
+        let stats = stats(&history(), now(), Some(now()));
+        assert_eq!(stats.starts, 7);
+        assert_eq!(stats.restarts, 4);
+        // web's restart 46 hours ago is too old
+        assert_eq!(stats.restarts_24h, 3);
+        assert_eq!(stats.crashes, 2);
+    }
+
+    #[test]
+    fn stats_adds_up_the_uptime_of_all_instances() {
+        // web 1 + 44 + 1 hours, web-2 4 + 5, web-3 1 + 1, web-4 1
+        let running = stats(&history(), now(), Some(now()));
+        assert_eq!(running.uptime, 58 * HOUR);
+        assert_eq!(running.mtbf, Some(29 * HOUR));
+
+        // Without a cut-off, web-2's current run does not count yet
+        let ended = stats(&history(), now(), None);
+        assert_eq!(ended.uptime, 53 * HOUR);
+    }
+
+    #[test]
+    fn stats_without_crashes_has_no_mtbf() {
+        let history: Vec<_> = history()
+            .into_iter()
+            .filter(|entry| entry["name"] == "web-3")
+            .collect();
+        let quiet = stats(&history, now(), Some(now()));
+        assert_eq!(quiet.crashes, 0);
+        assert_eq!(quiet.uptime, 2 * HOUR);
+        assert_eq!(quiet.mtbf, None);
+        assert_eq!(quiet.to_json()["mtbf_secs"], serde_json::Value::Null);
+
+        assert_eq!(stats(&[], now(), Some(now())), Stats::default());
+    }
+}
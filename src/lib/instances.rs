@@ -0,0 +1,94 @@
+//! Running several copies of one service.
+//!
+//! `--instances N` with `--name NAME` starts `N` instances called `NAME:1` to `NAME:N`
+//! from one command line. Each is a separate `detach-rs` process, started by `launch`
+//! with the same arguments and a hidden `--instance I`, so each has its own PID, control
+//! socket, log file (`-I` added to its name) and, with `--port auto`, its own port; the
+//! command can tell them apart by `{{instance}}` (see `template`).
+//!
+//! They are managed together: `stop NAME`, `restart NAME` and `--name NAME` select all
+//! instances of `NAME`, while `stop NAME:2` picks out one.
+use std::path::{Path, PathBuf};
+
+/// Returns the name of instance `index` of the service `name`, e.g. `web:2`.
+pub fn instance_name(name: &str, index: u16) -> String {
+    format!("{}:{}", name, index)
+}
+
+/// Splits an instance name like `web:2` into the service name and the index; other
+/// names are returned whole, without an index.
+pub fn split(name: &str) -> (&str, Option<u16>) {
+    if let Some((service, index)) = name.rsplit_once(':')
+        && !index.starts_with('0')
+        && index.bytes().all(|byte| byte.is_ascii_digit())
+        && let Ok(index) = index.parse::<u16>()
+    {
+        return (service, Some(index));
+    }
+    (name, None)
+}
+
+/// Returns `true` if `name` is `service` itself or one of its instances.
+pub fn belongs_to(name: &str, service: &str) -> bool {
+    name == service || matches!(split(name), (name, Some(_)) if name == service)
+}
+
+/// Returns the log file of instance `index`: `log_file` with `-INDEX` added to its
+/// name, before the extension.
+pub fn log_file(log_file: &Path, index: u16) -> PathBuf {
+    let stem = log_file
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let name = match log_file.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, index),
+    };
+    log_file.with_file_name(name)
+}
+
+/// Starts `count` instances by running this program again with the same arguments and
+/// `--instance 1` to `--instance COUNT`, and waits for them.
+///
+/// Detached instances return as soon as they have detached, so this only waits for all
+/// of them to be started; in the foreground it waits until all of them have finished.
+///
+/// # Returns
+/// - `Ok(())`: If every instance started, or in the foreground finished, successfully.
+/// - `Err(anyhow::Error)`: If this program cannot be run again, or any instance failed.
+pub fn launch(count: u16) -> Result<(), anyhow::Error> {
+    let program = std::env::current_exe()
+        .map_err(|e| anyhow::anyhow!("Cannot find this program to start instances: {}", e))?;
+    let mut children = Vec::with_capacity(count.into());
+    for index in 1..=count {
+        let child = std::process::Command::new(&program)
+            .args(std::env::args_os().skip(1))
+            .arg("--instance")
+            .arg(index.to_string())
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("Failed to start instance {}: {}", index, e))?;
+        children.push((index, child));
+    }
+    let mut failed = 0;
+    for (index, mut child) in children {
+        match child.wait() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                eprintln!(
+                    "Instance {} {}",
+                    index,
+                    crate::command::ExitReason::from_status(status)
+                );
+                failed += 1;
+            }
+            Err(e) => {
+                eprintln!("Instance {}: {}", index, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(anyhow::anyhow!("{} of {} instances failed", failed, count));
+    }
+    Ok(())
+}
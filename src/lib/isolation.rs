@@ -0,0 +1,158 @@
+//! Namespace isolation for commands (Linux).
+//!
+//! Mirrors the most-used systemd hardening directives for people running `detach-rs`
+//! directly: `PrivateTmp=`, `NoNewPrivileges=` and `PrivateNetwork=`. The child is moved
+//! into fresh mount and network namespaces with `unshare` between fork and exec. Without
+//! root, an unprivileged user namespace is created first that maps the caller's own uid
+//! and gid, which is enough to own the new namespaces; files of other users then show up
+//! as owned by `nobody`.
+
+/// Which network a command sees.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NetworkMode {
+    /// The host's network, as without isolation
+    #[default]
+    Host,
+    /// A new network namespace with only a loopback interface, which is down
+    None,
+}
+
+/// Hardening applied to a command before it starts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Isolation {
+    /// Give the command its own empty `/tmp` and `/var/tmp`
+    pub private_tmp: bool,
+    /// Keep the command and its children from gaining privileges through setuid binaries
+    /// or file capabilities
+    pub no_new_privileges: bool,
+    /// The network the command sees
+    pub network: NetworkMode,
+}
+
+impl Isolation {
+    /// Returns `true` if nothing is isolated.
+    pub fn is_default(&self) -> bool {
+        *self == Isolation::default()
+    }
+
+    /// Returns `true` if the command needs namespaces of its own.
+    fn needs_namespaces(&self) -> bool {
+        self.private_tmp || self.network == NetworkMode::None
+    }
+}
+
+/// Everything `Isolation::apply` needs, prepared before the fork so applying it does not
+/// allocate.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct PreparedIsolation {
+    isolation: Isolation,
+    /// `Some((uid_map, gid_map))` when an unprivileged user namespace is needed
+    user_maps: Option<(std::ffi::CString, std::ffi::CString)>,
+}
+
+#[cfg(target_os = "linux")]
+impl Isolation {
+    /// Prepares the isolation for use in a child's `pre_exec`.
+    pub fn prepare(&self) -> PreparedIsolation {
+        let user_maps = (self.needs_namespaces() && unsafe { libc::geteuid() } != 0).then(|| {
+            let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+            (
+                std::ffi::CString::new(format!("{} {} 1", uid, uid)).unwrap_or_default(),
+                std::ffi::CString::new(format!("{} {} 1", gid, gid)).unwrap_or_default(),
+            )
+        });
+        PreparedIsolation {
+            isolation: *self,
+            user_maps,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl PreparedIsolation {
+    /// Applies the isolation to the calling process.
+    ///
+    /// Meant to run in the child between fork and exec, so it only makes async-signal-safe
+    /// system calls and does not allocate.
+    pub fn apply(&self) -> std::io::Result<()> {
+        let check = |ret: libc::c_int| {
+            if ret < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+
+        if self.isolation.needs_namespaces() {
+            let mut flags = 0;
+            if self.isolation.private_tmp {
+                flags |= libc::CLONE_NEWNS;
+            }
+            if self.isolation.network == NetworkMode::None {
+                flags |= libc::CLONE_NEWNET;
+            }
+            if self.user_maps.is_some() {
+                flags |= libc::CLONE_NEWUSER;
+            }
+            check(unsafe { libc::unshare(flags) })?;
+            if let Some((uid_map, gid_map)) = &self.user_maps {
+                // An unprivileged process must give up setgroups before writing gid_map.
+                write_proc(c"/proc/self/setgroups", c"deny")?;
+                write_proc(c"/proc/self/uid_map", uid_map)?;
+                write_proc(c"/proc/self/gid_map", gid_map)?;
+            }
+        }
+
+        if self.isolation.private_tmp {
+            // Keep the new mounts from propagating back to the host.
+            check(unsafe {
+                libc::mount(
+                    std::ptr::null(),
+                    c"/".as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                )
+            })?;
+            for dir in [c"/tmp", c"/var/tmp"] {
+                if unsafe { libc::access(dir.as_ptr(), libc::F_OK) } < 0 {
+                    continue;
+                }
+                check(unsafe {
+                    libc::mount(
+                        c"tmpfs".as_ptr(),
+                        dir.as_ptr(),
+                        c"tmpfs".as_ptr(),
+                        libc::MS_NOSUID | libc::MS_NODEV,
+                        c"mode=1777".as_ptr().cast(),
+                    )
+                })?;
+            }
+        }
+
+        if self.isolation.no_new_privileges {
+            check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `contents` to a file under `/proc` with raw system calls.
+#[cfg(target_os = "linux")]
+fn write_proc(path: &std::ffi::CStr, contents: &std::ffi::CStr) -> std::io::Result<()> {
+    let bytes = contents.to_bytes();
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let written = libc::write(fd, bytes.as_ptr().cast(), bytes.len());
+        let error = std::io::Error::last_os_error();
+        libc::close(fd);
+        if written < 0 {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+//! A log4rs appender speaking the systemd journal's native protocol.
+//!
+//! Under systemd, writing to stdout already ends up in the journal, but as plain text:
+//! every line gets the same priority and multi-line messages are split up. Sending each
+//! record as a datagram to `/run/systemd/journal/socket` keeps the level as `PRIORITY`
+//! (so `journalctl -p warning` works), keeps messages whole, and turns structured fields
+//! from `log_kv!` into journal fields that `journalctl FIELD=value` can filter on.
+use log::kv::{Error, Key, Value, VisitSource};
+use log4rs::append::Append;
+use log4rs::encode::Encode;
+use log4rs::encode::writer::simple::SimpleWriter;
+use std::os::unix::net::UnixDatagram;
+
+/// The socket journald listens on for native protocol messages.
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Returns `true` if the journal socket exists.
+pub fn is_available() -> bool {
+    std::path::Path::new(JOURNALD_SOCKET).exists()
+}
+
+/// An appender writing to the systemd journal.
+#[derive(Debug)]
+pub struct JournaldAppender {
+    socket: UnixDatagram,
+    identifier: String,
+    encoder: Box<dyn Encode>,
+}
+
+impl JournaldAppender {
+    /// Connects to the journal; records are tagged with `SYSLOG_IDENTIFIER=identifier` and
+    /// their `MESSAGE` is rendered with `encoder`.
+    pub fn new(identifier: &str, encoder: Box<dyn Encode>) -> std::io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+        Ok(JournaldAppender {
+            socket,
+            identifier: identifier.to_string(),
+            encoder,
+        })
+    }
+}
+
+impl Append for JournaldAppender {
+    fn append(&self, record: &log::Record) -> anyhow::Result<()> {
+        let mut writer = SimpleWriter(Vec::new());
+        self.encoder.encode(&mut writer, record)?;
+        let mut message = writer.0;
+        while message.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+            message.pop();
+        }
+
+        let priority = match record.level() {
+            log::Level::Error => "3",
+            log::Level::Warn => "4",
+            log::Level::Info => "6",
+            log::Level::Debug | log::Level::Trace => "7",
+        };
+        let mut payload = Vec::with_capacity(message.len() + 128);
+        push_field(&mut payload, "MESSAGE", &message);
+        push_field(&mut payload, "PRIORITY", priority.as_bytes());
+        push_field(
+            &mut payload,
+            "SYSLOG_IDENTIFIER",
+            self.identifier.as_bytes(),
+        );
+        push_field(&mut payload, "CODE_MODULE", record.target().as_bytes());
+        if let Some(file) = record.file() {
+            push_field(&mut payload, "CODE_FILE", file.as_bytes());
+        }
+        if let Some(line) = record.line() {
+            push_field(&mut payload, "CODE_LINE", line.to_string().as_bytes());
+        }
+        record.key_values().visit(&mut Fields(&mut payload))?;
+
+        self.socket.send(&payload)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+/// Appends one field: `KEY=value\n`, or the length-prefixed form for values with newlines.
+fn push_field(payload: &mut Vec<u8>, key: &str, value: &[u8]) {
+    payload.extend_from_slice(key.as_bytes());
+    if value.contains(&b'\n') {
+        payload.push(b'\n');
+        payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        payload.push(b'=');
+    }
+    payload.extend_from_slice(value);
+    payload.push(b'\n');
+}
+
+/// Turns structured fields into journal fields, whose names may only contain uppercase
+/// letters, digits and underscores and must not start with an underscore or digit.
+struct Fields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let mut name: String = key
+            .as_str()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if !name.starts_with(|c: char| c.is_ascii_uppercase()) {
+            name.insert_str(0, "F_");
+        }
+        push_field(self.0, &name, value.to_string().as_bytes());
+        Ok(())
+    }
+}
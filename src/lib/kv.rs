@@ -0,0 +1,216 @@
+//! Structured key-value logging.
+//!
+//! `log_kv!` attaches fields to an ordinary `log` record, and `LineEncoder` renders them in
+//! the configured `LogFormat`: appended as `key=value` pairs in text logs, or as members of
+//! the JSON object in JSON logs. Services built on this crate get consistent structured logs
+//! without having to pick between `log` and `tracing` themselves.
+//!
+//! The encoder can also stamp every record with `LogField`s describing where it came
+//! from (service name, PIDs, host name, run ID), so logs collected from many hosts and
+//! many daemons stay attributable after they are merged.
+use crate::LogFormat;
+use crate::context::{ServiceContext, run_id};
+use log::kv::{Error, Key, Value, VisitSource};
+use log4rs::encode::{Encode, Write};
+
+/// Logs a message with structured fields.
+///
+/// Fields are `key = value` pairs. Numbers, booleans and strings are recorded as such; any
+/// other type can be captured through `Display` with `key:% = value` or `Debug` with
+/// `key:? = value`.
+///
+/// ```no_run
+/// use log::Level;
+///
+/// let path = std::path::Path::new("/var/lib/app/state.db");
+/// detach::log_kv!(Level::Info, "request served", status = 200, route = "/health");
+/// detach::log_kv!(Level::Warn, "state file missing", path:% = path.display());
+/// ```
+#[macro_export]
+macro_rules! log_kv {
+    ($level:expr, $msg:expr $(,)?) => {
+        $crate::__log::log!($level, "{}", $msg)
+    };
+    ($level:expr, $msg:expr, $($key:ident $(:$capture:tt)? = $value:expr),+ $(,)?) => {
+        $crate::__log::log!($level, $($key $(:$capture)? = $value),+; "{}", $msg)
+    };
+}
+
+/// Metadata `LineEncoder` can add to every record, under the name given in parentheses.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogField {
+    /// The service name given with `--name` (`service`)
+    Service,
+    /// The PID of the `detach-rs` process, as of the record (`pid`)
+    Pid,
+    /// The PID of the running command, if there is one (`child_pid`)
+    ChildPid,
+    /// The host name (`host`)
+    Host,
+    /// The ID of this daemon start, see `context::run_id` (`run_id`)
+    RunId,
+}
+
+impl LogField {
+    /// The key the field is written under.
+    pub fn key(self) -> &'static str {
+        match self {
+            LogField::Service => "service",
+            LogField::Pid => "pid",
+            LogField::ChildPid => "child_pid",
+            LogField::Host => "host",
+            LogField::RunId => "run_id",
+        }
+    }
+}
+
+/// The log4rs encoder used for every appender, rendering records in a `LogFormat`.
+///
+/// Text lines look like `<time> - <LEVEL> - <message> key=value ...`; JSON lines are
+/// objects with `time`, `level`, `target` and `message` members plus one per field.
+/// Metadata selected with `with_fields` comes before the record's own fields.
+#[derive(Debug, Clone)]
+pub struct LineEncoder {
+    format: LogFormat,
+    fields: Vec<LogField>,
+    service: Option<String>,
+    host: String,
+}
+
+impl LineEncoder {
+    /// Creates an encoder writing `format`.
+    pub fn new(format: LogFormat) -> Self {
+        Self {
+            format,
+            fields: Vec::new(),
+            service: None,
+            host: String::new(),
+        }
+    }
+
+    /// Adds `fields` to every record; `service` is the value of `LogField::Service`.
+    ///
+    /// Fields without a value, such as `child_pid` while no command runs, are left out.
+    pub fn with_fields(mut self, fields: &[LogField], service: Option<&str>) -> Self {
+        self.fields = fields.to_vec();
+        self.service = service.map(str::to_string);
+        if fields.contains(&LogField::Host) {
+            self.host = hostname();
+        }
+        self
+    }
+
+    /// The current values of the selected fields.
+    fn metadata(&self) -> Vec<(&'static str, serde_json::Value)> {
+        self.fields
+            .iter()
+            .filter_map(|&field| {
+                let value: serde_json::Value = match field {
+                    LogField::Service => self.service.clone()?.into(),
+                    LogField::Pid => std::process::id().into(),
+                    LogField::ChildPid => ServiceContext::current().child_pid()?.into(),
+                    LogField::Host => self.host.clone().into(),
+                    LogField::RunId => run_id().into(),
+                };
+                Some((field.key(), value))
+            })
+            .collect()
+    }
+}
+
+impl Encode for LineEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &log::Record) -> anyhow::Result<()> {
+        let time = chrono::Local::now().format("%+");
+        match self.format {
+            LogFormat::Text => {
+                let mut fields = TextFields(String::new());
+                for (key, value) in self.metadata() {
+                    let value = match value {
+                        serde_json::Value::String(s) => s,
+                        other => other.to_string(),
+                    };
+                    fields.push(key, &value);
+                }
+                record.key_values().visit(&mut fields)?;
+                writeln!(
+                    w,
+                    "{} - {} - {}{}",
+                    time,
+                    record.level(),
+                    record.args(),
+                    fields.0
+                )?;
+            }
+            LogFormat::Json => {
+                let mut object = serde_json::Map::new();
+                object.insert("time".into(), time.to_string().into());
+                object.insert("level".into(), record.level().as_str().into());
+                object.insert("target".into(), record.target().into());
+                object.insert("message".into(), record.args().to_string().into());
+                for (key, value) in self.metadata() {
+                    object.insert(key.into(), value);
+                }
+                let mut fields = JsonFields(object);
+                record.key_values().visit(&mut fields)?;
+                writeln!(w, "{}", serde_json::Value::Object(fields.0))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends ` key=value` for each field, quoting values that contain spaces or quotes.
+struct TextFields(String);
+
+impl TextFields {
+    fn push(&mut self, key: &str, value: &str) {
+        if value.is_empty() || value.contains([' ', '"', '=']) {
+            self.0.push_str(&format!(" {}={:?}", key, value));
+        } else {
+            self.0.push_str(&format!(" {}={}", key, value));
+        }
+    }
+}
+
+impl<'kvs> VisitSource<'kvs> for TextFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        self.push(key.as_str(), &value.to_string());
+        Ok(())
+    }
+}
+
+/// Adds each field to a JSON object, keeping numbers and booleans typed.
+struct JsonFields(serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let json = if let Some(b) = value.to_bool() {
+            b.into()
+        } else if let Some(n) = value.to_u64() {
+            n.into()
+        } else if let Some(n) = value.to_i64() {
+            n.into()
+        } else if let Some(n) = value.to_f64() {
+            n.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), json);
+        Ok(())
+    }
+}
+
+/// The name of this host, or an empty string if it cannot be determined.
+pub(crate) fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0 {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            return String::from_utf8_lossy(&buf[..len]).into_owned();
+        }
+    }
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_default()
+}
@@ -0,0 +1,330 @@
+//! Landlock filesystem sandboxing (Linux 5.13+).
+//!
+//! Landlock lets an unprivileged process give up filesystem access for itself and every
+//! process it starts: after `restrict_self`, only the paths in the ruleset can be
+//! reached, with the access rights given for them. `FsSandbox` describes the declared
+//! paths; `prepare` builds the kernel ruleset up front, so applying it in a child's
+//! `pre_exec` is a single system call.
+//!
+//! Programs cannot start without their interpreter and shared libraries, so the usual
+//! system locations (`/usr`, `/bin`, `/lib`, `/etc`, ...) are always readable and a few
+//! device nodes such as `/dev/null` are writable.
+use std::path::PathBuf;
+
+/// What to do when the kernel has no Landlock support.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SandboxMode {
+    /// Log a warning and run without the sandbox
+    #[default]
+    BestEffort,
+    /// Refuse to run
+    Required,
+}
+
+/// Paths a sandboxed process may read or write.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FsSandbox {
+    /// Readable (and executable) paths, including everything beneath them
+    pub read: Vec<PathBuf>,
+    /// Readable and writable paths, including everything beneath them
+    pub write: Vec<PathBuf>,
+    /// Behavior on kernels without Landlock
+    pub mode: SandboxMode,
+}
+
+/// Always readable, so programs can start at all.
+const SYSTEM_READ: &[&str] = &[
+    "/bin",
+    "/sbin",
+    "/usr",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/etc",
+    "/dev/urandom",
+    "/dev/random",
+    "/dev/zero",
+    "/proc/self",
+];
+
+/// Always writable.
+const SYSTEM_WRITE: &[&str] = &["/dev/null", "/dev/tty"];
+
+impl FsSandbox {
+    /// Returns `true` if no paths were declared, in which case nothing is restricted.
+    pub fn is_empty(&self) -> bool {
+        self.read.is_empty() && self.write.is_empty()
+    }
+
+    /// Fails if the sandbox is `SandboxMode::Required` and the kernel cannot enforce it.
+    ///
+    /// Lets callers report the problem before detaching, rather than in the log of the
+    /// first run.
+    pub fn check(&self) -> Result<(), anyhow::Error> {
+        if self.is_empty() || self.mode == SandboxMode::BestEffort {
+            return Ok(());
+        }
+        abi_version().map(|_| ()).map_err(unavailable)
+    }
+}
+
+impl FsSandbox {
+    /// Sandboxes the calling process, and everything it starts later, right away.
+    ///
+    /// Call it before any other thread is started: only the calling thread is restricted.
+    ///
+    /// # Returns
+    /// - `Ok(true)`: The sandbox is in effect.
+    /// - `Ok(false)`: No paths were declared, or Landlock is unavailable in
+    ///   `SandboxMode::BestEffort`.
+    /// - `Err(anyhow::Error)`: The sandbox could not be set up and is required, or
+    ///   enforcing it failed.
+    pub fn restrict_current_process(&self) -> Result<bool, anyhow::Error> {
+        match self.prepare()? {
+            #[cfg(target_os = "linux")]
+            Some(prepared) => {
+                prepared.restrict_self().map_err(|e| {
+                    anyhow::anyhow!("Failed to enforce the Landlock sandbox: {}", e)
+                })?;
+                Ok(true)
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(never) => match never {},
+            None => Ok(false),
+        }
+    }
+}
+
+fn unavailable(reason: std::io::Error) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Landlock is not available ({}); refusing to run without the filesystem sandbox \
+         because --sandbox required is set",
+        reason
+    )
+}
+
+/// Returns the Landlock ABI version the kernel supports.
+#[cfg(target_os = "linux")]
+pub fn abi_version() -> std::io::Result<i64> {
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+    let version = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<u8>(),
+            0usize,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if version < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(version)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn abi_version() -> std::io::Result<i64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Landlock is Linux-only",
+    ))
+}
+
+/// A ruleset built by `FsSandbox::prepare`, ready to be enforced.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct PreparedSandbox {
+    ruleset: std::os::fd::OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+mod access {
+    pub const EXECUTE: u64 = 1 << 0;
+    pub const WRITE_FILE: u64 = 1 << 1;
+    pub const READ_FILE: u64 = 1 << 2;
+    pub const READ_DIR: u64 = 1 << 3;
+    /// Everything ABI 1 can restrict, from EXECUTE to MAKE_SYM
+    pub const ABI_1: u64 = (1 << 13) - 1;
+    pub const REFER: u64 = 1 << 13;
+    pub const TRUNCATE: u64 = 1 << 14;
+    pub const IOCTL_DEV: u64 = 1 << 15;
+
+    /// Rights that apply to files; the rest only make sense on directories.
+    pub const FILE: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE | IOCTL_DEV;
+    pub const READ: u64 = EXECUTE | READ_FILE | READ_DIR;
+
+    /// All rights the kernel with this ABI version can restrict.
+    pub fn handled(abi: i64) -> u64 {
+        let mut handled = ABI_1;
+        if abi >= 2 {
+            handled |= REFER;
+        }
+        if abi >= 3 {
+            handled |= TRUNCATE;
+        }
+        if abi >= 5 {
+            handled |= IOCTL_DEV;
+        }
+        handled
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl FsSandbox {
+    /// Builds the Landlock ruleset for the declared paths.
+    ///
+    /// Paths that do not exist are skipped with a warning.
+    ///
+    /// # Returns
+    /// - `Ok(Some(PreparedSandbox))`: The ruleset, to be enforced with `restrict_self`.
+    /// - `Ok(None)`: No paths were declared, or Landlock is unavailable in
+    ///   `SandboxMode::BestEffort` (a warning is logged).
+    /// - `Err(anyhow::Error)`: Landlock is unavailable in `SandboxMode::Required`, or the
+    ///   ruleset could not be built.
+    pub fn prepare(&self) -> Result<Option<PreparedSandbox>, anyhow::Error> {
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+        use std::os::unix::fs::OpenOptionsExt;
+
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let abi = match abi_version() {
+            Ok(abi) => abi,
+            Err(e) if self.mode == SandboxMode::BestEffort => {
+                log::warn!(
+                    "Landlock is not available ({}); running without the filesystem sandbox.",
+                    e
+                );
+                return Ok(None);
+            }
+            Err(e) => return Err(unavailable(e)),
+        };
+        let handled = access::handled(abi);
+
+        #[repr(C)]
+        struct RulesetAttr {
+            handled_access_fs: u64,
+        }
+        #[repr(C, packed)]
+        struct PathBeneathAttr {
+            allowed_access: u64,
+            parent_fd: i32,
+        }
+        const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        };
+        if fd < 0 {
+            return Err(anyhow::anyhow!(
+                "Failed to create Landlock ruleset: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        let system = SYSTEM_READ
+            .iter()
+            .map(|path| (PathBuf::from(path), access::READ, false))
+            .chain(
+                SYSTEM_WRITE
+                    .iter()
+                    .map(|path| (PathBuf::from(path), handled, false)),
+            );
+        let declared = self
+            .read
+            .iter()
+            .map(|path| (path.clone(), access::READ, true))
+            .chain(self.write.iter().map(|path| (path.clone(), handled, true)));
+        for (path, rights, declared) in system.chain(declared) {
+            let file = match std::fs::File::options()
+                .read(true)
+                .custom_flags(libc::O_PATH)
+                .open(&path)
+            {
+                Ok(file) => file,
+                Err(e) => {
+                    if declared {
+                        log::warn!("Sandbox: skipping {}: {}", path.display(), e);
+                    }
+                    continue;
+                }
+            };
+            let mut rights = rights & handled;
+            if !file.metadata().is_ok_and(|meta| meta.is_dir()) {
+                rights &= access::FILE;
+            }
+            let rule = PathBeneathAttr {
+                allowed_access: rights,
+                parent_fd: file.as_raw_fd(),
+            };
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0u32,
+                )
+            };
+            if ret < 0 {
+                return Err(anyhow::anyhow!(
+                    "Failed to add {} to the Landlock ruleset: {}",
+                    path.display(),
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+        Ok(Some(PreparedSandbox { ruleset }))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl FsSandbox {
+    /// Landlock is Linux-only: warns and returns `Ok(None)`, or fails in
+    /// `SandboxMode::Required`.
+    pub fn prepare(&self) -> Result<Option<std::convert::Infallible>, anyhow::Error> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        match abi_version() {
+            Err(e) if self.mode == SandboxMode::Required => Err(unavailable(e)),
+            _ => {
+                log::warn!("Landlock is Linux-only; running without the filesystem sandbox.");
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl PreparedSandbox {
+    /// Sets `no_new_privs` and enforces the ruleset on the calling thread and its future
+    /// children.
+    ///
+    /// Meant for `CommandExt::pre_exec`, or for a process that has not started other
+    /// threads yet: threads that already exist are not restricted.
+    pub fn restrict_self(&self) -> std::io::Result<()> {
+        use std::os::fd::AsRawFd;
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                || libc::syscall(
+                    libc::SYS_landlock_restrict_self,
+                    self.ruleset.as_raw_fd(),
+                    0u32,
+                ) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! How an instance was launched, kept for post-mortems.
+//!
+//! When a named instance starts, `with_control_socket` writes a snapshot of how it was
+//! launched next to its socket, as `NAME.json` in `config::runtime_dir()`: the command
+//! line, working directory, user, log file, environment and the effective value of every
+//! option with where it came from (its default, the environment or the command line).
+//! Values of variables whose names look like they hold a secret (`*TOKEN*`,
+//! `*PASSWORD*`, ...) are replaced with `[REDACTED]`, and so is everything `--redact-env`
+//! and `--redact-regex` select.
+//!
+//! The file outlives the instance, so `detach-rs inspect NAME` can show how a service
+//! that died was started. The next start under the same name replaces it, and
+//! `detach-rs clean` removes it once no instance of that name is running.
+use crate::redact::{REDACTED, Redactor};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Parts of variable names whose values are always redacted.
+const SECRET_NAMES: &[&str] = &[
+    "TOKEN",
+    "SECRET",
+    "PASSWORD",
+    "PASSWD",
+    "CREDENTIAL",
+    "API_KEY",
+    "ACCESS_KEY",
+    "PRIVATE_KEY",
+];
+
+/// Returns the path of the launch snapshot of the instance called `name`.
+pub fn snapshot_path(name: &str) -> Result<PathBuf, anyhow::Error> {
+    Ok(crate::control::socket_path(name)?.with_extension("json"))
+}
+
+/// Captures the command line, working directory, user, log file, (redacted) environment
+/// and effective configuration of this process.
+///
+/// Called before detaching, while the launching terminal can still be told about
+/// errors; `write` adds what only the daemon knows, like its PID.
+///
+/// # Arguments
+/// - `matches`: What `Args` was parsed from; every option of `Args` is recorded from it
+///   under its long name, as `{"value": ..., "source": ...}`.
+/// - `log_file`: The resolved log file, if logging to one.
+/// - `redactor`: Applied to the command line, option values and environment.
+pub fn capture(
+    matches: &clap::ArgMatches,
+    log_file: Option<&Path>,
+    redactor: Option<&Redactor>,
+) -> serde_json::Value {
+    let redact = |text: String| match redactor {
+        Some(redactor) => redactor.redact(&text).into_owned(),
+        None => text,
+    };
+    let command_line: Vec<String> = std::env::args_os()
+        .map(|arg| redact(arg.to_string_lossy().into_owned()))
+        .collect();
+    let environment: serde_json::Map<String, serde_json::Value> = std::env::vars_os()
+        .map(|(name, value)| {
+            let name = name.to_string_lossy().into_owned();
+            let value = if looks_secret(&name) {
+                REDACTED.to_string()
+            } else {
+                redact(value.to_string_lossy().into_owned())
+            };
+            (name, value.into())
+        })
+        .collect();
+    let configuration: serde_json::Map<String, serde_json::Value> =
+        <crate::Args as clap::CommandFactory>::command()
+            .get_arguments()
+            .filter(|arg| !matches!(arg.get_id().as_str(), "help" | "version"))
+            .map(|arg| {
+                let id = arg.get_id().as_str();
+                let key = arg.get_long().unwrap_or(id).to_string();
+                let values: Vec<String> = matches
+                    .get_raw(id)
+                    .into_iter()
+                    .flatten()
+                    .map(|value| redact(value.to_string_lossy().into_owned()))
+                    .collect();
+                let value = match (values.len(), arg.get_action()) {
+                    (0, _) => serde_json::Value::Null,
+                    (1, action) if !matches!(action, clap::ArgAction::Append) => {
+                        values[0].clone().into()
+                    }
+                    _ => values.into(),
+                };
+                let source = match matches.value_source(id) {
+                    Some(clap::parser::ValueSource::CommandLine) => "command line",
+                    Some(clap::parser::ValueSource::EnvVariable) => "environment",
+                    Some(_) => "default",
+                    None => "unset",
+                };
+                (key, serde_json::json!({ "value": value, "source": source }))
+            })
+            .collect();
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "command_line": command_line,
+        "cwd": std::env::current_dir().ok(),
+        "uid": uid,
+        "gid": gid,
+        "log_file": log_file,
+        "environment": environment,
+        "configuration": configuration,
+    })
+}
+
+/// Writes `snapshot`, with the `name`, `pid`, `run_id` and start time of this process
+/// added, to `path`.
+///
+/// The file is only readable by its owner, and replaced atomically so `inspect` never
+/// sees half of it.
+pub fn write(path: &Path, name: &str, snapshot: &serde_json::Value) -> Result<(), anyhow::Error> {
+    let mut snapshot = snapshot.clone();
+    snapshot["name"] = name.into();
+    snapshot["pid"] = std::process::id().into();
+    snapshot["run_id"] = crate::context::run_id().into();
+    snapshot["started"] = chrono::Local::now()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+        .into();
+
+    let partial = path.with_extension("json.partial");
+    let written = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&partial)
+        .and_then(|mut file| {
+            serde_json::to_writer_pretty(&mut file, &snapshot)?;
+            file.write_all(b"\n")
+        })
+        .and_then(|()| std::fs::rename(&partial, path));
+    written.map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Reads the launch snapshot of the instance called `name`.
+///
+/// # Returns
+/// - `Ok(serde_json::Value)`: The snapshot, as `write` left it.
+/// - `Err(anyhow::Error)`: If there is none, e.g. because no instance of that name was
+///   started since the last `clean` or reboot, or it cannot be read.
+pub fn read(name: &str) -> Result<serde_json::Value, anyhow::Error> {
+    let path = snapshot_path(name)?;
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(anyhow::anyhow!(
+                "No instance named \"{}\" was started since the last `detach-rs clean` \
+                 (there is no {})",
+                name,
+                path.display()
+            ));
+        }
+        Err(e) => return Err(anyhow::anyhow!("Failed to read {}: {}", path.display(), e)),
+    };
+    serde_json::from_str(&text)
+        .map_err(|e| anyhow::anyhow!("{} is not a launch snapshot: {}", path.display(), e))
+}
+
+fn looks_secret(name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    SECRET_NAMES.iter().any(|part| name.contains(part))
+}
@@ -0,0 +1,160 @@
+//! Resource budgets for a command, beyond its wall-clock timeout.
+//!
+//! A detached batch job that spins in a loop or floods its log can go unnoticed for a
+//! long time, since nobody watches its terminal. Two budgets bound it:
+//!
+//! - a CPU-time limit, enforced by the kernel through `RLIMIT_CPU` in the child: SIGXCPU
+//!   once the limit is used up, SIGKILL a second later if the command ignores it;
+//! - an output limit, enforced by `run_command`, which reads the command's output through
+//!   a pipe, counts the bytes and kills the command once it has written more.
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::sync::Notify;
+
+/// How long a command that handles SIGXCPU may keep running before it is killed.
+#[cfg(unix)]
+const CPU_GRACE: libc::rlim_t = 1;
+
+/// Parses a byte size such as `4096`, `512K`, `10M` or `1G` (binary multiples; a
+/// trailing `B` or `iB` is accepted, so `10MiB` works too).
+///
+/// # Examples
+///
+/// ```
+/// use detach::limits::parse_size;
+///
+/// assert_eq!(parse_size("10M").unwrap(), 10 * 1024 * 1024);
+/// assert_eq!(parse_size("512").unwrap(), 512);
+/// assert!(parse_size("ten").is_err());
+/// ```
+pub fn parse_size(input: &str) -> Result<u64, anyhow::Error> {
+    let trimmed = input.trim();
+    let upper = trimmed.to_ascii_uppercase();
+    let unit = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+    let (digits, multiplier) = match unit.char_indices().last() {
+        Some((i, 'K')) => (&unit[..i], 1u64 << 10),
+        Some((i, 'M')) => (&unit[..i], 1 << 20),
+        Some((i, 'G')) => (&unit[..i], 1 << 30),
+        Some((i, 'T')) => (&unit[..i], 1 << 40),
+        _ => (unit, 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Invalid size \"{}\": expected a number of bytes with an optional K, M, G or T suffix",
+                input
+            )
+        })
+}
+
+/// Sets `RLIMIT_CPU` for the calling process: `cpu`, rounded up to whole seconds, as the
+/// soft limit and one second more as the hard limit, never above the current hard limit.
+///
+/// Meant to run in the child between fork and exec, so it only makes async-signal-safe
+/// system calls and does not allocate.
+#[cfg(unix)]
+pub fn apply_cpu_limit(cpu: Duration) -> io::Result<()> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_CPU, &mut limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let seconds = cpu.as_secs() + u64::from(cpu.subsec_nanos() > 0);
+    let soft = (seconds.max(1) as libc::rlim_t).min(limit.rlim_max);
+    limit.rlim_cur = soft;
+    limit.rlim_max = soft.saturating_add(CPU_GRACE).min(limit.rlim_max);
+    if unsafe { libc::setrlimit(libc::RLIMIT_CPU, &limit) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The bytes a command may still write, shared by the readers of its stdout and stderr.
+#[derive(Debug)]
+pub(crate) struct OutputBudget {
+    limit: u64,
+    written: AtomicU64,
+    exceeded: Notify,
+}
+
+impl OutputBudget {
+    pub(crate) fn new(limit: u64) -> Arc<Self> {
+        Arc::new(OutputBudget {
+            limit,
+            written: AtomicU64::new(0),
+            exceeded: Notify::new(),
+        })
+    }
+
+    /// The limit this budget was created with.
+    pub(crate) fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Completes once more than `limit` bytes were counted.
+    pub(crate) async fn exceeded(&self) {
+        let notified = self.exceeded.notified();
+        if self.written.load(Ordering::SeqCst) > self.limit {
+            return;
+        }
+        notified.await
+    }
+
+    /// Counts `bytes` just read and returns how many of them are still within the limit.
+    fn take(&self, bytes: usize) -> usize {
+        let before = self.written.fetch_add(bytes as u64, Ordering::SeqCst);
+        if before <= self.limit && before + bytes as u64 > self.limit {
+            self.exceeded.notify_waiters();
+        }
+        self.limit.saturating_sub(before).min(bytes as u64) as usize
+    }
+}
+
+/// Wraps one of the command's pipes and counts what is read from it against a budget.
+///
+/// Once the budget is used up, reads end as if the pipe was closed: the bytes beyond the
+/// limit are dropped, and a process still writing (a grandchild that outlived the
+/// command, say) gets SIGPIPE once the pipe is dropped instead of filling the log.
+pub(crate) struct Metered<R> {
+    inner: R,
+    budget: Arc<OutputBudget>,
+}
+
+impl<R> Metered<R> {
+    pub(crate) fn new(inner: R, budget: Arc<OutputBudget>) -> Self {
+        Metered { inner, budget }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Metered<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.budget.written.load(Ordering::SeqCst) > self.budget.limit {
+            return Poll::Ready(Ok(()));
+        }
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = &poll {
+            let kept = self.budget.take(buf.filled().len() - before);
+            buf.set_filled(before + kept);
+        }
+        poll
+    }
+}
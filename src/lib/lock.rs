@@ -0,0 +1,87 @@
+//! Exclusive lock files for single-instance jobs.
+//!
+//! The lock is an `flock(2)` on a file (`LockFileEx` on Windows), so it is released by the
+//! kernel when the process holding it exits, however it exits. There are no stale locks to
+//! clean up after a crash. On Linux, NFS mounts emulate `flock` with POSIX record locks, so
+//! a lock file on shared storage also keeps instances on different machines apart.
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// A held exclusive lock. The lock is released when this value is dropped or the process
+/// exits.
+#[derive(Debug)]
+pub struct LockFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl LockFile {
+    /// Tries to take the exclusive lock on `path` without blocking.
+    ///
+    /// The file is created if needed. Once the lock is held, the file's contents are
+    /// replaced with the current PID to help identify the holder.
+    ///
+    /// # Returns
+    /// - `Ok(Some(LockFile))`: The lock was acquired.
+    /// - `Ok(None)`: Another process holds the lock.
+    /// - `Err(anyhow::Error)`: The file could not be opened or locked.
+    pub fn try_acquire(path: &Path) -> Result<Option<LockFile>, anyhow::Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("Failed to open lock file {}: {}", path.display(), e))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => {
+                return Err(anyhow::anyhow!("Failed to lock {}: {}", path.display(), e));
+            }
+        }
+
+        let mut lock = LockFile {
+            file,
+            path: path.to_path_buf(),
+        };
+        lock.write_pid(std::process::id())?;
+        Ok(Some(lock))
+    }
+
+    /// Replaces the lock file's contents with `pid`.
+    ///
+    /// `daemonize` forks twice after the lock is taken, so the daemon calls this again to
+    /// record its own PID instead of the launcher's.
+    pub fn write_pid(&mut self, pid: u32) -> Result<(), anyhow::Error> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(format!("{}\n", pid).as_bytes())?;
+        Ok(())
+    }
+
+    /// The path of the lock file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Runs `future` while holding `lock`, if there is one.
+///
+/// The lock file is first updated with the PID of the process running the future, which
+/// after `daemonize` is the daemon rather than the launcher. The lock is released once the
+/// future completes.
+pub async fn hold_lock<F>(lock: Option<LockFile>, future: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    let _lock = lock.map(|mut lock| {
+        if let Err(e) = lock.write_pid(std::process::id()) {
+            log::warn!("Failed to record PID in {}: {}", lock.path().display(), e);
+        }
+        lock
+    });
+    future.await
+}
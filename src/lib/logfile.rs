@@ -0,0 +1,286 @@
+//! Writing the log file, and what to do when it cannot be written.
+//!
+//! A full disk, revoked permissions or a file system remounted read-only make every write
+//! to the log fail. log4rs's own file appender prints each failure to stderr, which a
+//! detached process has pointed at `/dev/null` or at the very log that fails, so the
+//! instance carries on without a trace. `LogFileAppender` writes the log file instead and,
+//! when a write fails, does what `--on-log-error` selects (see `OnLogError`): drop the
+//! record and count it in `detach_log_dropped_total`, hold up logging until the file can
+//! be written again, send the record to syslog, or stop the instance.
+//!
+//! The first failure is published as a `log-error` lifecycle event, which `events` and
+//! `status` show and `--alert-webhook` and `--alert-email` are alerted about (see
+//! `alert`). Every record is tried on the file first, so logging resumes there by itself
+//! once there is room again, with a note of how many records went elsewhere meanwhile; a
+//! later failure is reported again.
+//!
+//! A log file that is deleted while it is written keeps taking records, and disk space,
+//! with no name to read them by; one renamed away by `logrotate` keeps growing under the
+//! old name. At most every `CHECK_INTERVAL`, and before every retry while writes fail, the
+//! file is compared with the one at the log path, and if it is gone or another one is in
+//! its place, the log path is opened (and created) again. Deleting the log to make room
+//! on a full disk therefore lets logging resume in a fresh file, which starts with a note
+//! saying so.
+use crate::audit::{AuditAction, AuditTrigger, LifecycleEvent};
+use crate::context::ServiceContext;
+use log4rs::append::Append;
+use log4rs::encode::Encode;
+use log4rs::encode::writer::simple::SimpleWriter;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often a blocked write is tried again with `OnLogError::Block`.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the file being written is checked against the one at the log path.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What happens to a record the log file cannot take.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnLogError {
+    /// Drop it and count it in the detach_log_dropped_total metric
+    #[default]
+    Drop,
+    /// Retry every second until it is written, holding up whatever logs in the meantime
+    Block,
+    /// Send it to syslog instead (Unix; elsewhere it is dropped)
+    Syslog,
+    /// Drop it and stop the instance with SIGTERM, as if it had been sent one
+    Shutdown,
+}
+
+/// A log4rs appender writing to a file, handling write failures as `OnLogError` says.
+#[derive(Debug)]
+pub struct LogFileAppender {
+    path: PathBuf,
+    encoder: Box<dyn Encode>,
+    on_error: OnLogError,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    file: File,
+    /// Whether the last write failed
+    failing: bool,
+    /// How many records did not go to the file since it started failing
+    missed: u64,
+    /// When `file` was last compared with the one at the log path
+    checked: Instant,
+}
+
+impl LogFileAppender {
+    /// Opens `path` for appending, creating it and its directory if needed; records are
+    /// rendered with `encoder`.
+    pub fn new(
+        path: &Path,
+        encoder: Box<dyn Encode>,
+        on_error: OnLogError,
+    ) -> std::io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = open(path)?;
+        Ok(LogFileAppender {
+            path: path.to_path_buf(),
+            encoder,
+            on_error,
+            state: Mutex::new(State {
+                file,
+                failing: false,
+                missed: 0,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    /// Notes in the file that it can be written again, and how many records missed it.
+    fn recovered(&self, state: &mut State) {
+        state.failing = false;
+        let missed = std::mem::take(&mut state.missed);
+        let meanwhile = match self.on_error {
+            OnLogError::Block => "logging was held up until now".to_string(),
+            OnLogError::Syslog if cfg!(unix) => format!("{} records went to syslog", missed),
+            _ => format!("{} records were dropped", missed),
+        };
+        self.note(
+            state,
+            format_args!("The log file can be written again; {}.", meanwhile),
+        );
+    }
+
+    /// Opens the log path again if the file being written is no longer there under it:
+    /// deleted, or renamed away and replaced.
+    fn check(&self, state: &mut State) {
+        state.checked = Instant::now();
+        if !replaced(&state.file, &self.path) {
+            return;
+        }
+        // If it cannot be opened, writing the old file decides what happens to the record
+        if let Ok(file) = open(&self.path) {
+            state.file = file;
+            self.note(
+                state,
+                format_args!("The log file was deleted or replaced; continuing in a new one."),
+            );
+        }
+    }
+
+    /// Writes a warning of our own to the file, as it would be logged.
+    fn note(&self, state: &mut State, message: std::fmt::Arguments) {
+        let mut note = SimpleWriter(Vec::new());
+        let encoded = self.encoder.encode(
+            &mut note,
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target(module_path!())
+                .args(message)
+                .build(),
+        );
+        if encoded.is_ok() {
+            let _ = state.file.write_all(&note.0);
+        }
+    }
+
+    /// Reports the first failed write of a run of them on stderr and as a lifecycle event.
+    fn report(&self, error: &std::io::Error) {
+        let action = match self.on_error {
+            OnLogError::Drop => "dropping records until it can",
+            OnLogError::Block => "holding up logging until it can",
+            OnLogError::Syslog if cfg!(unix) => "sending records to syslog until it can",
+            OnLogError::Syslog => "dropping records until it can (no syslog here)",
+            OnLogError::Shutdown => "stopping the instance",
+        };
+        let detail = format!(
+            "log file {} cannot be written ({}); {}",
+            self.path.display(),
+            error,
+            action
+        );
+        eprintln!("detach-rs: The {}.", detail);
+        let event = LifecycleEvent::new(AuditAction::LogError, AuditTrigger::Limit, &detail);
+        ServiceContext::current().publish_event(event);
+    }
+}
+
+impl Append for LogFileAppender {
+    fn append(&self, record: &log::Record) -> anyhow::Result<()> {
+        let mut line = SimpleWriter(Vec::new());
+        self.encoder.encode(&mut line, record)?;
+        let line = line.0;
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.failing || state.checked.elapsed() >= CHECK_INTERVAL {
+            self.check(&mut state);
+        }
+        let error = match state.file.write_all(&line) {
+            Ok(()) => {
+                if state.failing {
+                    self.recovered(&mut state);
+                }
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        let first = !std::mem::replace(&mut state.failing, true);
+        state.missed += 1;
+        if self.on_error == OnLogError::Block {
+            if first {
+                self.report(&error);
+            }
+            loop {
+                std::thread::sleep(RETRY_INTERVAL);
+                self.check(&mut state);
+                if state.file.write_all(&line).is_ok() {
+                    self.recovered(&mut state);
+                    return Ok(());
+                }
+            }
+        }
+        // Nothing below may log while the file is locked: it would come back here
+        drop(state);
+        if first {
+            self.report(&error);
+        }
+        match self.on_error {
+            #[cfg(unix)]
+            OnLogError::Syslog => syslog(record.level(), &line),
+            OnLogError::Shutdown if first => {
+                count_dropped();
+                shutdown();
+            }
+            _ => count_dropped(),
+        }
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+fn open(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+/// Returns `true` if `file` is not the file at `path` (any more).
+#[cfg(unix)]
+fn replaced(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let Ok(open) = file.metadata() else {
+        return false;
+    };
+    match std::fs::metadata(path) {
+        Ok(current) => (open.dev(), open.ino()) != (current.dev(), current.ino()),
+        Err(e) => e.kind() == std::io::ErrorKind::NotFound,
+    }
+}
+
+/// Returns `true` if `path` is gone; off Unix a replaced file is not told apart.
+#[cfg(not(unix))]
+fn replaced(_file: &File, path: &Path) -> bool {
+    !path.exists()
+}
+
+fn count_dropped() {
+    ServiceContext::current()
+        .metrics()
+        .counter(
+            "detach_log_dropped_total",
+            "Log records dropped because the log file could not be written",
+        )
+        .inc();
+}
+
+/// Sends a rendered record to syslog, as `detach-rs` under the daemon facility.
+#[cfg(unix)]
+fn syslog(level: log::Level, line: &[u8]) {
+    static OPEN: std::sync::Once = std::sync::Once::new();
+    // SAFETY: the identifier is a static C string, as openlog keeps the pointer.
+    OPEN.call_once(|| unsafe {
+        libc::openlog(c"detach-rs".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON)
+    });
+    let priority = match level {
+        log::Level::Error => libc::LOG_ERR,
+        log::Level::Warn => libc::LOG_WARNING,
+        log::Level::Info => libc::LOG_INFO,
+        log::Level::Debug | log::Level::Trace => libc::LOG_DEBUG,
+    };
+    let text: Vec<u8> = line.iter().copied().filter(|&b| b != 0).collect();
+    let Ok(text) = std::ffi::CString::new(text.trim_ascii_end()) else {
+        return;
+    };
+    // SAFETY: the format string takes exactly the one C string passed.
+    unsafe { libc::syslog(priority, c"%s".as_ptr(), text.as_ptr()) };
+}
+
+/// Stops the instance the way a SIGTERM from outside would.
+fn shutdown() {
+    #[cfg(unix)]
+    if let Err(e) = crate::signal::send_signal(std::process::id(), crate::signal::SIGTERM) {
+        eprintln!("detach-rs: Failed to stop after the log error: {}", e);
+    }
+    #[cfg(not(unix))]
+    std::process::exit(1);
+}
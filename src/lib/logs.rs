@@ -0,0 +1,137 @@
+//! Printing and following log files, the way `tail -n N -f` does.
+//!
+//! Following works by polling the file rather than through inotify or kqueue, so it
+//! behaves the same on every platform, Windows included, and needs no running instance:
+//! `detach-rs logs` can follow the log of a daemon that was started long ago, or one
+//! that has not been started yet.
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often a followed file is checked for new output.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Returns the most recently modified `detach-*.log` in `dir`, the file a run without
+/// `--log-file` writes to.
+pub fn newest_log(dir: &Path) -> Result<PathBuf, anyhow::Error> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", dir.display(), e))?;
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with("detach-") && name.ends_with(".log")
+        })
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .max()
+        .map(|(_, path)| path)
+        .ok_or_else(|| anyhow::anyhow!("No detach-*.log files in {}", dir.display()))
+}
+
+/// Writes the last `lines` lines of `path` to `out` and, with `follow`, keeps writing
+/// whatever is appended until the process is interrupted.
+///
+/// A followed file that shrinks (truncated, or replaced by log rotation) is read again
+/// from the start; one that disappears is waited for.
+///
+/// # Arguments
+/// - `path`: The log file.
+/// - `lines`: How many lines from the end to start with; `0` starts at the end.
+/// - `follow`: Keep polling for new output instead of returning at the end of the file.
+/// - `out`: Where to write; flushed after every chunk.
+///
+/// # Returns
+/// - `Ok(())`: Without `follow`, once the end of the file was reached.
+/// - `Err(anyhow::Error)`: If the file cannot be opened at first, or `out` fails.
+pub fn print_log<W: Write>(
+    path: &Path,
+    lines: usize,
+    follow: bool,
+    out: &mut W,
+) -> Result<(), anyhow::Error> {
+    let mut file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+    let len = file.metadata()?.len();
+    let mut pos = tail_offset(&mut file, len, lines)?;
+    pos += copy_from(&mut file, pos, out)?;
+    if !follow {
+        return Ok(());
+    }
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        pos = copy_appended(path, pos, out)?;
+    }
+}
+
+/// Writes what was appended to `path` past `pos` to `out`, and returns the position to
+/// continue from next time.
+///
+/// The file is opened again on every call, so a rotated file is picked up under the same
+/// name; one that shrank is read again from the start, and one that is missing is skipped.
+pub fn copy_appended<W: Write>(path: &Path, pos: u64, out: &mut W) -> Result<u64, anyhow::Error> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(pos),
+        Err(e) => return Err(anyhow::anyhow!("Failed to open {}: {}", path.display(), e)),
+    };
+    let len = file.metadata()?.len();
+    let pos = if len < pos { 0 } else { pos };
+    if len == pos {
+        return Ok(pos);
+    }
+    Ok(pos + copy_from(&mut file, pos, out)?)
+}
+
+/// Returns the last `lines` lines of `path`, oldest first, decoded lossily.
+pub fn last_lines(path: &Path, lines: usize) -> Result<Vec<String>, anyhow::Error> {
+    let mut file = File::open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+    let len = file.metadata()?.len();
+    let pos = tail_offset(&mut file, len, lines)?;
+    file.seek(SeekFrom::Start(pos))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Copies `file` from `pos` to its end into `out` and returns the number of bytes copied.
+fn copy_from<W: Write>(file: &mut File, pos: u64, out: &mut W) -> Result<u64, anyhow::Error> {
+    file.seek(SeekFrom::Start(pos))?;
+    let copied = std::io::copy(file, out)?;
+    out.flush()?;
+    Ok(copied)
+}
+
+/// Returns the offset of the start of the last `lines` lines of `file`, which is `len`
+/// bytes long. A final line without a newline counts as a line.
+fn tail_offset(file: &mut File, len: u64, lines: usize) -> std::io::Result<u64> {
+    if lines == 0 {
+        return Ok(len);
+    }
+    let mut buf = [0u8; 8192];
+    let mut end = len;
+    let mut seen = 0;
+    while end > 0 {
+        let start = end.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        for (i, &byte) in chunk.iter().enumerate().rev() {
+            let offset = start + i as u64;
+            // The newline ending the last line does not start another one
+            if byte == b'\n' && offset + 1 != len {
+                seen += 1;
+                if seen == lines {
+                    return Ok(offset + 1);
+                }
+            }
+        }
+        end = start;
+    }
+    Ok(0)
+}
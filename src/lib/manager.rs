@@ -0,0 +1,67 @@
+//! Detecting a service manager that already supervises this process.
+//!
+//! Under systemd or launchd the double fork of `daemonize` is harmful: the manager tracks
+//! the process it started, sees it exit right away, and either restarts it or forgets
+//! about the daemon, which then runs as an untracked orphan. `ServiceManager::detect`
+//! recognizes these parents so the caller can stay in the foreground instead.
+
+/// A service manager that started this process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceManager {
+    /// systemd, as the system or a user instance
+    Systemd,
+    /// launchd (macOS)
+    Launchd,
+}
+
+impl std::fmt::Display for ServiceManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceManager::Systemd => write!(f, "systemd"),
+            ServiceManager::Launchd => write!(f, "launchd"),
+        }
+    }
+}
+
+impl ServiceManager {
+    /// Returns the service manager that is this process's direct parent, if any.
+    ///
+    /// - systemd: the parent process is named `systemd` and `INVOCATION_ID` is set, as
+    ///   systemd does for every unit it starts.
+    /// - launchd: on macOS, the parent is PID 1.
+    pub fn detect() -> Option<ServiceManager> {
+        #[cfg(unix)]
+        {
+            let ppid = unsafe { libc::getppid() };
+            if cfg!(target_os = "macos") && ppid == 1 {
+                return Some(ServiceManager::Launchd);
+            }
+            let comm = std::fs::read_to_string(format!("/proc/{}/comm", ppid)).ok();
+            if comm.as_deref().map(str::trim) == Some("systemd")
+                && std::env::var_os("INVOCATION_ID").is_some()
+            {
+                return Some(ServiceManager::Systemd);
+            }
+        }
+        None
+    }
+
+    /// Returns the service manager this process runs under, judging by the environment
+    /// it sets up for its services rather than by the direct parent.
+    ///
+    /// - systemd: `INVOCATION_ID` or `NOTIFY_SOCKET` is set; both are inherited by
+    ///   everything a unit starts.
+    /// - launchd: on macOS, the parent is PID 1.
+    pub fn from_environment() -> Option<ServiceManager> {
+        if std::env::var_os("INVOCATION_ID").is_some()
+            || std::env::var_os("NOTIFY_SOCKET").is_some()
+        {
+            return Some(ServiceManager::Systemd);
+        }
+        #[cfg(target_os = "macos")]
+        if unsafe { libc::getppid() } == 1 {
+            return Some(ServiceManager::Launchd);
+        }
+        None
+    }
+}
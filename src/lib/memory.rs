@@ -0,0 +1,247 @@
+//! Memory use of the daemon, from the operating system and from the allocator.
+//!
+//! A resident set that grows for weeks is the typical symptom of a leak in a long-running
+//! service, but the RSS alone cannot tell a leak from allocator fragmentation or caches
+//! the allocator holds on to. When the embedding program uses jemalloc or mimalloc as
+//! its global allocator and enables the matching feature of this crate (`jemalloc` for
+//! `tikv-jemallocator`, `mimalloc` for the `mimalloc` crate), `MemoryStats` also reports
+//! what the allocator has handed out and what it keeps mapped.
+//!
+//! `with_memory_stats` logs the numbers periodically (`--stats-interval`), along with the
+//! rate at which command output is written, and keeps them up to date as gauges on the
+//! service's metrics, so they are served by `--metrics-listen` as well.
+use crate::context::ServiceContext;
+use log::info;
+use std::time::Duration;
+
+/// How often the gauges are refreshed when only the metrics endpoint needs them.
+const METRICS_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Counters kept by the global allocator, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorStats {
+    /// `jemalloc` or `mimalloc`
+    pub name: &'static str,
+    /// Bytes allocated by the application and not freed yet (jemalloc only)
+    pub allocated: Option<u64>,
+    /// Bytes in pages the allocator has mapped and touched (jemalloc) or estimates as
+    /// resident (mimalloc)
+    pub resident: Option<u64>,
+    /// Bytes the allocator has reserved from the operating system
+    pub committed: Option<u64>,
+}
+
+/// A snapshot of the memory use of this process, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Resident set size (Linux)
+    pub rss: Option<u64>,
+    /// Peak resident set size
+    pub peak_rss: Option<u64>,
+    /// Virtual memory size (Linux)
+    pub virtual_size: Option<u64>,
+    /// Statistics of the global allocator, with the `jemalloc` or `mimalloc` feature
+    pub allocator: Option<AllocatorStats>,
+}
+
+impl MemoryStats {
+    /// Reads the current numbers from the operating system and the allocator.
+    pub fn collect() -> Self {
+        let mut stats = MemoryStats {
+            allocator: allocator_stats(),
+            ..MemoryStats::default()
+        };
+        #[cfg(target_os = "linux")]
+        if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+            let kib = |name: &str| {
+                status
+                    .lines()
+                    .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                    .and_then(|value| value.split_whitespace().next()?.parse::<u64>().ok())
+                    .map(|kib| kib * 1024)
+            };
+            stats.rss = kib("VmRSS");
+            stats.peak_rss = kib("VmHWM");
+            stats.virtual_size = kib("VmSize");
+            return stats;
+        }
+        #[cfg(unix)]
+        {
+            let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+            if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } == 0 {
+                // ru_maxrss is in bytes on macOS and in KiB elsewhere
+                let unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+                stats.peak_rss = Some(usage.ru_maxrss as u64 * unit);
+            }
+        }
+        stats
+    }
+
+    /// The snapshot as a JSON object; unknown values are `null`.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "rss_bytes": self.rss,
+            "peak_rss_bytes": self.peak_rss,
+            "virtual_bytes": self.virtual_size,
+            "allocator": self.allocator.map(|allocator| serde_json::json!({
+                "name": allocator.name,
+                "allocated_bytes": allocator.allocated,
+                "resident_bytes": allocator.resident,
+                "committed_bytes": allocator.committed,
+            })),
+        })
+    }
+
+    /// Sets the `detach_memory_*` and `detach_allocator_*` gauges of `ctx` to this snapshot.
+    pub fn publish(&self, ctx: &ServiceContext) {
+        let metrics = ctx.metrics();
+        let set = |name: &str, help: &str, value: Option<u64>| {
+            if let Some(value) = value {
+                metrics.gauge(name, help).set(value as f64);
+            }
+        };
+        set("detach_memory_rss_bytes", "Resident set size", self.rss);
+        set(
+            "detach_memory_peak_rss_bytes",
+            "Peak resident set size",
+            self.peak_rss,
+        );
+        if let Some(allocator) = self.allocator {
+            set(
+                "detach_allocator_allocated_bytes",
+                "Bytes allocated and not freed, per the global allocator",
+                allocator.allocated,
+            );
+            set(
+                "detach_allocator_resident_bytes",
+                "Bytes the global allocator keeps resident",
+                allocator.resident,
+            );
+            set(
+                "detach_allocator_committed_bytes",
+                "Bytes the global allocator reserved from the system",
+                allocator.committed,
+            );
+        }
+    }
+}
+
+impl std::fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mib = |bytes: Option<u64>| match bytes {
+            Some(bytes) => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+            None => "?".to_string(),
+        };
+        write!(f, "rss {}, peak {}", mib(self.rss), mib(self.peak_rss))?;
+        if let Some(allocator) = self.allocator {
+            write!(f, "; {}:", allocator.name)?;
+            if allocator.allocated.is_some() {
+                write!(f, " allocated {},", mib(allocator.allocated))?;
+            }
+            write!(
+                f,
+                " resident {}, committed {}",
+                mib(allocator.resident),
+                mib(allocator.committed)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+fn allocator_stats() -> Option<AllocatorStats> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // Most statistics are cached until the epoch advances
+    epoch::advance().ok()?;
+    Some(AllocatorStats {
+        name: "jemalloc",
+        allocated: stats::allocated::read().ok().map(|n| n as u64),
+        resident: stats::resident::read().ok().map(|n| n as u64),
+        committed: stats::mapped::read().ok().map(|n| n as u64),
+    })
+}
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+fn allocator_stats() -> Option<AllocatorStats> {
+    let mut values = [0usize; 8];
+    let [
+        elapsed,
+        user,
+        system,
+        rss,
+        peak_rss,
+        commit,
+        peak_commit,
+        faults,
+    ] = &mut values;
+    // SAFETY: every argument points to a distinct, writable usize.
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            elapsed,
+            user,
+            system,
+            rss,
+            peak_rss,
+            commit,
+            peak_commit,
+            faults,
+        );
+    }
+    Some(AllocatorStats {
+        name: "mimalloc",
+        allocated: None,
+        resident: Some(*rss as u64),
+        committed: Some(*commit as u64),
+    })
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+fn allocator_stats() -> Option<AllocatorStats> {
+    None
+}
+
+/// Runs `future` while logging `MemoryStats` every `log_every` and publishing them as
+/// gauges on `ctx`. The command output copied since the last time (see `pump`) is logged
+/// with them, if there was any.
+///
+/// With `metrics` but no `log_every`, the gauges are refreshed every 15 seconds without
+/// logging; with neither, this is a plain `future.await`.
+pub async fn with_memory_stats<F>(
+    log_every: Option<Duration>,
+    metrics: bool,
+    ctx: ServiceContext,
+    future: F,
+) -> F::Output
+where
+    F: std::future::Future,
+{
+    let interval = match (log_every, metrics) {
+        (Some(interval), _) => interval,
+        (None, true) => METRICS_INTERVAL,
+        (None, false) => return future.await,
+    };
+    let report = async move {
+        let mut ticks = tokio::time::interval(interval);
+        let mut output = (crate::pump::throughput(), std::time::Instant::now());
+        loop {
+            ticks.tick().await;
+            let stats = MemoryStats::collect();
+            stats.publish(&ctx);
+            if log_every.is_some() {
+                info!("Memory: {}", stats);
+                let now = (crate::pump::throughput(), std::time::Instant::now());
+                if now.0 != output.0 {
+                    info!("Output: {}", now.0.since(output.0, now.1 - output.1));
+                }
+                output = now;
+            }
+        }
+    };
+    tokio::pin!(future);
+    tokio::select! {
+        output = &mut future => output,
+        _ = report => unreachable!("the memory stats loop never ends"),
+    }
+}
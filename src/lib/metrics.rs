@@ -0,0 +1,385 @@
+//! Application metrics for services.
+//!
+//! `Metrics` is a small registry of counters, gauges and histograms. Handles are cheap to
+//! clone and update without locking (histograms take a short lock), so a service can keep
+//! them in its own structs and bump them on the hot path. The registry renders itself in
+//! the Prometheus text format and as JSON; `with_metrics_endpoint` serves both over HTTP,
+//! together with the service's health, next to the service future, so simple daemons get
+//! app-level metrics with no extra infrastructure. The registry is always there; serving
+//! it needs the `http` feature.
+use crate::context::ServiceContext;
+#[cfg(feature = "http")]
+use crate::health::HealthState;
+#[cfg(feature = "http")]
+use crate::tasks::TaskGroup;
+#[cfg(feature = "http")]
+use log::info;
+use log::warn;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "http")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "http")]
+use tokio::net::TcpListener;
+
+/// Default histogram buckets, in seconds, suitable for request latencies.
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A monotonically increasing count.
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    /// Adds one.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Adds `n`.
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The current count.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that can go up and down.
+#[derive(Debug, Clone, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    /// Sets the value.
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Adds `delta`, which may be negative.
+    pub fn add(&self, delta: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + delta).to_bits())
+            });
+    }
+
+    /// The current value.
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+struct HistogramState {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// A distribution of observed values, counted into fixed buckets.
+#[derive(Debug, Clone)]
+pub struct Histogram(Arc<Mutex<HistogramState>>);
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        let mut bounds = bounds.to_vec();
+        bounds.retain(|b| b.is_finite());
+        bounds.sort_by(f64::total_cmp);
+        bounds.dedup();
+        let counts = vec![0; bounds.len()];
+        Histogram(Arc::new(Mutex::new(HistogramState {
+            bounds,
+            counts,
+            sum: 0.0,
+            count: 0,
+        })))
+    }
+
+    /// Records one observation.
+    pub fn observe(&self, value: f64) {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(i) = state.bounds.iter().position(|&bound| value <= bound) {
+            state.counts[i] += 1;
+        }
+        state.sum += value;
+        state.count += 1;
+    }
+
+    /// Records the time elapsed since `start`, in seconds.
+    pub fn observe_since(&self, start: std::time::Instant) {
+        self.observe(start.elapsed().as_secs_f64());
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Histogram(Histogram),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    help: String,
+    metric: Metric,
+}
+
+/// A registry of named metrics.
+///
+/// Asking for a metric that already exists returns a handle to the existing one, so
+/// independent parts of a service can share a metric by name.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    entries: Arc<Mutex<BTreeMap<String, Entry>>>,
+}
+
+impl Metrics {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the counter called `name`, registering it if needed.
+    pub fn counter(&self, name: &str, help: &str) -> Counter {
+        match self.register(name, help, || Metric::Counter(Counter::default())) {
+            Metric::Counter(counter) => counter,
+            _ => Counter::default(),
+        }
+    }
+
+    /// Returns the gauge called `name`, registering it if needed.
+    pub fn gauge(&self, name: &str, help: &str) -> Gauge {
+        match self.register(name, help, || Metric::Gauge(Gauge::default())) {
+            Metric::Gauge(gauge) => gauge,
+            _ => Gauge::default(),
+        }
+    }
+
+    /// Returns the histogram called `name`, registering it with `buckets` if needed.
+    ///
+    /// `buckets` are the inclusive upper bounds; a `+Inf` bucket is always added.
+    pub fn histogram(&self, name: &str, help: &str, buckets: &[f64]) -> Histogram {
+        match self.register(name, help, || Metric::Histogram(Histogram::new(buckets))) {
+            Metric::Histogram(histogram) => histogram,
+            _ => Histogram::new(buckets),
+        }
+    }
+
+    /// Looks up `name`, creating it with `make` when missing.
+    ///
+    /// A metric registered under the same name with a different type is kept; the caller
+    /// then gets a detached handle that is never exported, and a warning is logged.
+    fn register(&self, name: &str, help: &str, make: impl FnOnce() -> Metric) -> Metric {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let new = make();
+        let entry = entries.entry(name.to_string()).or_insert_with(|| Entry {
+            help: help.to_string(),
+            metric: new.clone(),
+        });
+        if std::mem::discriminant(&entry.metric) != std::mem::discriminant(&new) {
+            warn!(
+                "Metric {} is already registered with a different type.",
+                name
+            );
+            return new;
+        }
+        entry.metric.clone()
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+        for (name, entry) in entries.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, entry.help.replace('\n', " "));
+            match &entry.metric {
+                Metric::Counter(counter) => {
+                    let _ = writeln!(out, "# TYPE {} counter", name);
+                    let _ = writeln!(out, "{} {}", name, counter.get());
+                }
+                Metric::Gauge(gauge) => {
+                    let _ = writeln!(out, "# TYPE {} gauge", name);
+                    let _ = writeln!(out, "{} {}", name, gauge.get());
+                }
+                Metric::Histogram(histogram) => {
+                    let state = histogram.0.lock().unwrap_or_else(|e| e.into_inner());
+                    let _ = writeln!(out, "# TYPE {} histogram", name);
+                    let mut cumulative = 0;
+                    for (bound, count) in state.bounds.iter().zip(&state.counts) {
+                        cumulative += count;
+                        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+                    }
+                    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, state.count);
+                    let _ = writeln!(out, "{}_sum {}", name, state.sum);
+                    let _ = writeln!(out, "{}_count {}", name, state.count);
+                }
+            }
+        }
+        out
+    }
+
+    /// Returns all metrics as a JSON object keyed by metric name.
+    ///
+    /// Counters and gauges map to numbers; histograms to objects with `count`, `sum` and
+    /// cumulative `buckets`.
+    pub fn to_json(&self) -> serde_json::Value {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut object = serde_json::Map::new();
+        for (name, entry) in entries.iter() {
+            let value = match &entry.metric {
+                Metric::Counter(counter) => counter.get().into(),
+                Metric::Gauge(gauge) => gauge.get().into(),
+                Metric::Histogram(histogram) => {
+                    let state = histogram.0.lock().unwrap_or_else(|e| e.into_inner());
+                    let mut buckets = serde_json::Map::new();
+                    let mut cumulative = 0;
+                    for (bound, count) in state.bounds.iter().zip(&state.counts) {
+                        cumulative += count;
+                        buckets.insert(bound.to_string(), cumulative.into());
+                    }
+                    buckets.insert("+Inf".into(), state.count.into());
+                    serde_json::json!({
+                        "count": state.count,
+                        "sum": state.sum,
+                        "buckets": buckets,
+                    })
+                }
+            };
+            object.insert(name.clone(), value);
+        }
+        serde_json::Value::Object(object)
+    }
+}
+
+/// Runs `future` while serving the metrics and health of `ctx` over HTTP on `addr`.
+///
+/// - `GET /metrics`: the Prometheus text format
+/// - `GET /metrics.json`: the JSON rendering of the metrics
+/// - `GET /health`: `200` with `healthy`, or `503` with `degraded: <reason>`
+/// - `GET /status`: a JSON object with `health`, `reason` and `metrics`
+///
+/// The endpoint is bound before `future` starts, so a port conflict fails
+/// immediately, and it stops when `future` completes. Without an `addr` this is a plain
+/// `future.await`; with one and without the `http` feature it fails before `future` starts.
+///
+/// # Arguments
+/// - `addr`: The address to listen on, e.g. `127.0.0.1:9100`.
+/// - `ctx`: The context whose metrics and health are served.
+/// - `future`: The service future.
+#[cfg(feature = "http")]
+pub async fn with_metrics_endpoint<F>(
+    addr: Option<SocketAddr>,
+    ctx: ServiceContext,
+    future: F,
+) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    let Some(addr) = addr else {
+        return future.await;
+    };
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen for metrics on {}: {}", addr, e))?;
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    let clients = TaskGroup::new();
+    let result = tokio::select! {
+        result = future => result,
+        _ = serve(listener, ctx, &clients) => Ok(()),
+    };
+    clients.shutdown().await;
+    result
+}
+
+#[cfg(not(feature = "http"))]
+pub async fn with_metrics_endpoint<F>(
+    addr: Option<SocketAddr>,
+    _ctx: ServiceContext,
+    future: F,
+) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    match addr {
+        None => future.await,
+        Some(addr) => Err(anyhow::anyhow!(
+            "Cannot serve metrics on {}: this build lacks the http feature",
+            addr
+        )),
+    }
+}
+
+#[cfg(feature = "http")]
+async fn serve(listener: TcpListener, ctx: ServiceContext, clients: &TaskGroup) {
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let ctx = ctx.clone();
+        clients.spawn("metrics client", async move {
+            let mut buf = [0u8; 1024];
+            let n = match tokio::time::timeout(
+                std::time::Duration::from_secs(5),
+                stream.read(&mut buf),
+            )
+            .await
+            {
+                Ok(Ok(n)) => n,
+                _ => return,
+            };
+            let request = String::from_utf8_lossy(&buf[..n]);
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+            let health = ctx.health().get();
+            let (status, content_type, body) = match path {
+                "/metrics" => (
+                    "200 OK",
+                    "text/plain; version=0.0.4",
+                    ctx.metrics().render_prometheus(),
+                ),
+                "/metrics.json" => (
+                    "200 OK",
+                    "application/json",
+                    ctx.metrics().to_json().to_string(),
+                ),
+                "/health" if health.is_healthy() => {
+                    ("200 OK", "text/plain", format!("{}\n", health))
+                }
+                "/health" => (
+                    "503 Service Unavailable",
+                    "text/plain",
+                    format!("{}\n", health),
+                ),
+                "/status" => {
+                    let reason = match &health {
+                        HealthState::Healthy => None,
+                        HealthState::Degraded(reason) => Some(reason.as_str()),
+                    };
+                    let status = serde_json::json!({
+                        "health": if health.is_healthy() { "healthy" } else { "degraded" },
+                        "reason": reason,
+                        "metrics": ctx.metrics().to_json(),
+                    });
+                    ("200 OK", "application/json", status.to_string())
+                }
+                _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                content_type,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        });
+    }
+}
@@ -13,25 +13,686 @@
 //! *   **`--no-detach`**:
 //!     Run the process in the foreground, disabling daemonization.
 //!
+//! *   **`--strict`**:
+//!     Makes `--detach` an error when `detach-rs` was started by systemd or launchd.
+//!     Without it, `detach-rs` notices the service manager and stays in the foreground
+//!     (logging a notice), because double-forking would leave the manager tracking a
+//!     process that exited and the daemon running unsupervised.
+//!
 //! *   **`--tail`**:
-//!     Enables log tailing. When used, the service will run in the foreground and
-//!     output its logs directly to the console while also writing them to the log file.
+//!     Enables log tailing. The service runs in the background, as with `--detach`, and the
+//!     terminal follows its log file. The first Ctrl-C only stops following: the service
+//!     keeps running, and a hint says how to follow its log again and how to stop it. When
+//!     the service exits, so does the tail, with the service's exit status. On Windows,
+//!     with `--no-detach`, or when not logging to a file, the service runs in the
+//!     foreground instead and logs to the console as well as the log file.
+//!
+//! *   **`--kill-on-interrupt`**:
+//!     With `--tail`, runs the service in the foreground, logging to the console, so Ctrl-C
+//!     stops it as it did before `--tail` left the service running.
+//!     Example: `--tail --kill-on-interrupt`
+//!
+//! *   **`--wait [<SECONDS>]`** (Unix only):
+//!     With `--detach` and `--name`, the terminal that launched the instance stays on its
+//!     log until the instance is ready (its command has kept running for a second, is
+//!     healthy and accepts connections on its `--port`s, as a rolling `restart` waits
+//!     for), then returns while the daemon carries on, the way one watches the first start
+//!     of a new service. Pressing any key or Ctrl-C stops watching early and leaves the
+//!     instance starting. Exits with status 1 if the instance ends first, or is not ready
+//!     within SECONDS (default 60), in which case it is left running.
+//!     Example: `--detach --name api --command ./api --port 8080 --wait 120`
 //!
 //! *   **`--log-file <PATH>`**:
-//!     Specifies the path to the log file. Defaults to `./detach.log`.
+//!     Specifies the path to the log file. Defaults to a timestamped
+//!     `detach-<YYYYmmdd-HHMMSS>.log` in the platform's log directory:
+//!     `$XDG_STATE_HOME/detach/logs` (or `~/.local/state/detach/logs`) on Linux,
+//!     `~/Library/Logs/detach` on macOS and `%LOCALAPPDATA%\detach\logs` on Windows.
+//!     Relative paths are resolved against the directory `detach-rs` was started in.
+//!     If the file is deleted, or renamed away by `logrotate`, a new one is created
+//!     under the same path for the next record logged a second or more later, without
+//!     a `copytruncate` or a signal.
 //!     Example: `--log-file /var/log/my_service.log`
 //!
+//! *   **`--log-dir-owner <USER[:GROUP]>`**, **`--log-dir-mode <MODE>`** (Unix only):
+//!     When the log file's directory does not exist, it is created at startup, before
+//!     logging is set up, with these owner and octal permission bits. This lets a service
+//!     started as root log to a fresh `/var/log/<name>` that its unprivileged user can
+//!     read or write, instead of failing with a permission error. An existing directory
+//!     is left unchanged.
+//!     Example: `--log-file /var/log/myservice/out.log --log-dir-owner myservice:adm --log-dir-mode 750`
+//!
 //! *   **`-t, --timeout <SECONDS>`**:
 //!     Sets a timeout (in seconds) after which the service will automatically terminate.
-//!     This applies to both detached and non-detached modes.
+//!     This applies to both detached and non-detached modes. It is shorthand for setting
+//!     both `--run-timeout` and `--daemon-timeout`; either of those overrides it.
 //!     Example: `--timeout 60` (service will run for 60 seconds)
 //!
+//! *   **`--run-timeout <SECONDS>`**:
+//!     Interrupts each execution of `--command` after the given number of seconds (SIGINT,
+//!     then SIGKILL after a short grace period).
+//!
+//! *   **`--cpu-limit <DURATION>`**:
+//!     Limits the CPU time of each execution of `--command` through `RLIMIT_CPU`: the
+//!     kernel sends SIGXCPU once the command has used that much processor time, and
+//!     SIGKILL a second later if it is still running. Unlike `--run-timeout`, time spent
+//!     sleeping or waiting on the network does not count. Unix only.
+//!     Example: `--cpu-limit 300s`
+//!
+//! *   **`--max-output <SIZE>`**:
+//!     Kills `--command` once it has written more than this many bytes to stdout and stderr
+//!     together, so a runaway job cannot fill the disk with its log. Accepts plain bytes or
+//!     a `K`, `M`, `G` or `T` suffix (binary multiples). The output is passed through to the
+//!     log as before, just counted on the way.
+//!     Example: `--max-output 10M`
+//!
+//! *   **`--daemon-timeout <SECONDS>`**:
+//!     Bounds the lifetime of the whole `detach-rs` process, detached or not. A command that
+//!     is still running when it expires is interrupted as if its run timeout had expired, so
+//!     the effective limit for a single command is the smaller of the two.
+//!
+//! *   **`--until <DATETIME>`**:
+//!     Stops the daemon at an absolute local wall-clock time, e.g. for batch windows. Accepts
+//!     `2024-06-01T00:00`, `2024-06-01 06:30:00`, `2024-06-01`, RFC 3339, or a time of day
+//!     such as `06:00` meaning its next occurrence. Combines with `--daemon-timeout`;
+//!     whichever comes first wins.
+//!     Example: `--until 06:00` (run this scraper until 6am)
+//!
+//! *   **`--delay <DURATION>`**:
+//!     Waits before starting the service or command, after daemonizing, e.g. to start after
+//!     slow dependencies at boot. Accepts `30s`, `5m`, `1h 30m` or plain seconds. The delay
+//!     counts towards `--daemon-timeout`/`--until` but not towards `--run-timeout`.
+//!     Example: `--delay 30s`
+//!
+//! *   **`--start-jitter <RANGE>`**:
+//!     Adds a random delay drawn from the range before the first run (on top of `--delay`)
+//!     and before each restart scheduled with `--restart-at`, so fleets of pollers launched
+//!     together do not hit an upstream at once. `60s` is short for `0-60s`.
+//!     Example: `--start-jitter 0-60s`
+//!
+//! *   **`--exclusive-lock <PATH>`**:
+//!     Takes an exclusive `flock` on the given file before starting and holds it for the
+//!     lifetime of the service or command, so a scheduled job never runs twice at once even
+//!     if the scheduler misfires. When another instance already holds the lock, detach-rs
+//!     logs "another instance holds the lock, skipping" and exits successfully. The file
+//!     may live on shared storage to keep instances on different machines apart.
+//!     Example: `--exclusive-lock /var/lock/nightly-backup.lock`
+//!
+//! *   **`--command <COMMAND>`**:
+//!     Runs the given command through `sh -c` instead of the built-in heartbeat service.
+//!     Combine with `--detach` to run it in the background. `{{name}}`, `{{instance}}`,
+//!     `{{port}}` (and `{{port_N}}`) and `{{log_dir}}` in the command are replaced with
+//!     the instance's `--name`, index, `--port` and log directory; other text in double
+//!     braces, such as a Go template, is left as it is. See `template`.
+//!     A command run once, without `--name` or any option that needs the supervisor,
+//!     runs on a single-threaded runtime, which starts faster; `cargo bench --bench
+//!     startup` measures how long launching a short job takes.
+//!     Example: `--command "./backup.sh" --detach --run-timeout 3600`,
+//!     `--name web --port auto --command "./server --id {{name}} --listen :{{port}}"`
+//!
+//! *   **`--backend <BACKEND>`**:
+//!     `process` (default) runs `--command` through `sh -c`; `podman` or `docker` treat it
+//!     as an image and its arguments and run it in a container named `detach-<NAME>`
+//!     (`--name`, or the PID), pulled when missing and removed after each run. The
+//!     container's output goes to the same log, and restarts, schedules and control
+//!     requests work as for a process. Cannot be combined with the sandbox, isolation,
+//!     `--seccomp` or `--cores` options, which only apply to a native process.
+//!     Example: `--name web --backend podman --command "docker.io/library/nginx:alpine"`
+//!
 //! *   **`-l, --logging <LEVEL>`**:
 //!     Sets the logging level for the service.
 //!     Supported levels: `error`, `warn`, `info`, `debug`, `trace`.
 //!     Defaults to `info`.
 //!     Example: `--logging debug`
 //!
+//! *   **`-v, --verbose`**:
+//!     Raises the logging level by one step per occurrence, starting from `--logging`
+//!     (or `info` when it is not given). `-v` yields `debug`, `-vv` yields `trace`.
+//!
+//! *   **`-q, --quiet`**:
+//!     Only log errors to the console. The log file still receives the level selected by
+//!     `--logging`/`-v`. Cannot be combined with `-v` or `--console-level`.
+//!
+//! *   **`--on-stdin-eof <MODE>`**:
+//!     What a service gets when it reads end of file from standard input through
+//!     `detach::stdin`, which is what a detached process reads, as its input is
+//!     `/dev/null`. The first end of file is logged in every mode, with a hint that the
+//!     code assumed a terminal; then `warn` (default) returns the end of file as usual,
+//!     `abort` fails the read so a service that propagates the error ends, and `wait`
+//!     never completes the read so the service carries on without input. For programs
+//!     embedding `detach` through `DetachArgs`; the built-in service and `--command` do
+//!     not read standard input this way.
+//!     Example: `--detach --on-stdin-eof abort`
+//!
+//! *   **`--console-level <LEVEL>`**:
+//!     Sets the logging level for the console separately from the log file, so the file can
+//!     capture full detail while the console stays readable. Defaults to the file level.
+//!     Example: `--logging debug --console-level warn`
+//!
+//! *   **`--console-stream <STREAM>`**:
+//!     Selects where console logging goes: `stdout` (default) or `stderr`. Logging to
+//!     `stderr` keeps `stdout` clean for the output of a command run with `--command`.
+//!     Example: `--console-stream stderr`
+//!
+//! *   **`--log-format <FORMAT>`**:
+//!     Selects how log lines are rendered in the file and on the console: `text` (default)
+//!     or `json`, one object per line. Fields logged with `detach::log_kv!` are appended
+//!     as `key=value` pairs in text and become object members in JSON.
+//!     Example: `--log-format json`
+//!
+//! *   **`--log-fields <FIELDS>`**:
+//!     Stamps every log line with metadata, so logs shipped from many hosts and daemons
+//!     stay attributable once merged: `service` (the `--name`), `pid` (of `detach-rs`),
+//!     `child-pid` (of the running command, while there is one), `host` and `run-id` (a
+//!     UUID that changes with every start of the daemon). The fields come right after the
+//!     message, as `service=web pid=4242` in text and as members of the JSON object.
+//!     The run ID is logged at startup in any case and is part of every lifecycle event,
+//!     of `detach-rs status`, diagnostics, crash reports and the `--keep-runs` index, so
+//!     the log segment of a given start can be matched to its events and crash report.
+//!     Example: `--log-format json --log-fields service,host,run-id`
+//!
+//! *   **`--log-to <DEST>`**:
+//!     `file` (default) writes the log file and, in the foreground, the console.
+//!     `journald` sends records to the systemd journal with their level as priority and
+//!     `log_kv!` fields as journal fields, and nothing else. `os-log` writes to the macOS
+//!     unified log (see `--os-log`, subsystem `detach-rs` by default) and the console.
+//!     `auto` picks `journald` when running under systemd (`INVOCATION_ID` or
+//!     `NOTIFY_SOCKET` set), `os-log` when started by launchd, and `file` otherwise, so
+//!     one binary behaves sensibly wherever it is deployed.
+//!     Example: `--log-to auto`
+//!
+//! *   **`--on-log-error <MODE>`**:
+//!     What happens when the log file cannot be written (a full disk, revoked
+//!     permissions, a read-only remount): `drop` (default) drops records and counts them
+//!     in the `detach_log_dropped_total` metric, `block` holds up logging and retries
+//!     every second, `syslog` sends records to syslog instead, and `shutdown` stops the
+//!     instance as SIGTERM would. The first failure is published as a `log-error` event
+//!     and alerted to `--alert-webhook` and `--alert-email`; once the file can be written
+//!     again, logging resumes there with a note of how many records went elsewhere.
+//!     Example: `--on-log-error syslog --alert-email ops@example.com`
+//!
+//! *   **`--restart-at <SCHEDULE>`**:
+//!     Supervises `--command` and restarts it on a schedule, stopping it gracefully first
+//!     (SIGINT, then SIGKILL). Takes `HH:MM` for a daily restart or, with the `cron`
+//!     feature, a cron expression such as `0 3 * * Sun` (in five fields, weekday 0 or 7
+//!     is Sunday, as in crontab). `--run-timeout` applies to each run, `--daemon-timeout`
+//!     and `--until` to the supervisor as a whole. Each planned restart is logged.
+//!     Example: `--command ./leaky-server --detach --restart-at 03:00`
+//!
+//! *   **`--timezone <TZ>`**, **`--dst-gap <POLICY>`**, **`--dst-repeat <POLICY>`**:
+//!     The timezone the times of `--restart-at` are in: `local` (the default) or, with the
+//!     `timezone` feature, an IANA name such as `Europe/Berlin` or `UTC`, so the schedule
+//!     stays put whatever timezone the host is set to. When a DST change skips an hour, a
+//!     restart due in it is shifted by the jump (`--dst-gap shift`, the default: 02:30
+//!     becomes 03:30) or left out that day (`skip`); when one repeats an hour, a restart
+//!     due in it happens the first time only (`--dst-repeat once`, the default) or both
+//!     times (`twice`).
+//!     Example: `--command ./report --restart-at '30 2 * * *' --timezone Europe/Berlin --dst-gap skip`
+//!
+//! *   **`--missed <POLICY>`**:
+//!     What happens to restarts of `--restart-at` that come due while the host is asleep,
+//!     as laptops are: `skip` leaves them out and waits for the next one, `run-once` (the
+//!     default) restarts once when the host wakes up, however many were missed, and
+//!     `run-all` restarts once for each of them (up to 100), one after the other. The
+//!     schedule follows the wall clock, so a missed restart is noticed within half a
+//!     minute of waking up; the decision is logged. A host that was off starts the command
+//!     afresh when `detach-rs` is started again.
+//!     Example: `--command ./sync --restart-at '0 * * * *' --missed skip`
+//!
+//! *   **`--cores <MODE>`**:
+//!     Controls core dumps of `--command`: `allow` raises the child's `RLIMIT_CORE` to the
+//!     hard limit, `disable` sets it to 0, and `dir:<PATH>` allows them and moves core files
+//!     the child writes to its working directory into `<PATH>`. When a child dumps core the
+//!     log says where the core went (a file, or the `core_pattern` handler on Linux).
+//!     Example: `--command ./server --cores dir:/var/crash/server`
+//!
+//! *   **`--seccomp <PROFILE>`**:
+//!     Installs a seccomp syscall filter in `--command` right before it starts (Linux,
+//!     x86_64 and aarch64). `default` makes administrative calls (mount, module loading,
+//!     ptrace, bpf, reboot, keyrings, ...) fail with EPERM; `strict` also denies
+//!     networking, uid/gid changes, chown and io_uring (which would run networking calls
+//!     out of the filter's sight). Anything else is read as a Docker/OCI JSON
+//!     profile (`defaultAction` plus `syscalls` entries; entries with argument conditions
+//!     are skipped). The command is not started if the profile cannot be loaded.
+//!     Example: `--command ./untrusted-job --seccomp strict`
+//!
+//! *   **`--private-tmp`**, **`--no-new-privileges`**, **`--network <MODE>`**:
+//!     Hardening for `--command` modeled on systemd's `PrivateTmp=`, `NoNewPrivileges=`
+//!     and `PrivateNetwork=` (Linux). `--private-tmp` mounts fresh tmpfs instances on
+//!     `/tmp` and `/var/tmp` in a new mount namespace; `--no-new-privileges` stops setuid
+//!     binaries and file capabilities from raising privileges; `--network none` runs the
+//!     command in a new network namespace with only an inactive loopback (`host`, the
+//!     default, changes nothing). Without root an unprivileged user namespace mapping
+//!     the current user is created to hold the new namespaces.
+//!     Example: `--command ./job --private-tmp --no-new-privileges --network none`
+//!
+//! *   **`--allow-read <PATH>`**, **`--allow-write <PATH>`**, **`--sandbox <MODE>`**:
+//!     Restricts filesystem access with Landlock (Linux 5.13+) to the given paths and
+//!     everything beneath them; both flags can be repeated. With `--command` the command is
+//!     sandboxed, otherwise the daemon itself (its log and lock file stay writable).
+//!     System directories such as `/usr`, `/lib` and `/etc` remain readable so programs
+//!     can start. When the kernel lacks Landlock, `--sandbox best-effort` (the default)
+//!     logs a warning and runs unsandboxed, `--sandbox required` refuses to start.
+//!     Example: `--command ./myapp --allow-read /etc/myapp --allow-write /var/lib/myapp`
+//!
+//! *   **`--forward-signals <LIST>`**, **`--signal-group`**:
+//!     Run `--command` under the supervisor, which acts as a signal proxy: SIGTERM, SIGINT,
+//!     SIGHUP, SIGUSR1, SIGUSR2 and SIGWINCH sent to `detach-rs` are passed on to the
+//!     command instead of stopping `detach-rs`. `--forward-signals` replaces that list with
+//!     a comma-separated one (names with or without `SIG`, or numbers); `--signal-group`
+//!     starts the command in its own process group and signals the whole group, so shell
+//!     pipelines and worker children get the signal too. `--restart-at` and `--cores` also
+//!     use the supervisor and forward the default list.
+//!     Example: `--command './server | tee out.log' --forward-signals TERM,USR1 --signal-group`
+//!
+//! *   **`--stop-timeout <DURATION>`**:
+//!     How long a supervised command may take to exit after it was asked to stop, by a
+//!     SIGTERM or SIGINT to `detach-rs` or a `stop` or `restart` request, before it is
+//!     sent SIGKILL (default `10s`). When `detach-rs` itself is stopped with
+//!     `--signal-group`, it then waits the same time for the rest of the command's
+//!     process group, sends it SIGTERM and finally SIGKILL, so no worker outlives it. A
+//!     summary of the shutdown is logged before it exits.
+//!     Example: `--command ./server --signal-group --stop-timeout 30s`
+//!
+//! *   **`--subreaper`** (Linux only):
+//!     Runs `--command` under the supervisor, which becomes a child subreaper: processes
+//!     the command double-forks or leaves running when it exits are re-parented to
+//!     `detach-rs` instead of init. They are reaped when they exit, listed as `orphans` in
+//!     `status`, and sent SIGTERM and then SIGKILL (after `--stop-timeout`) when
+//!     `detach-rs` is stopped, so the whole service tree goes with it.
+//!     Example: `--name legacy --subreaper --command "/etc/init.d/legacyd start"`
+//!
+//! *   **`--name <NAME>`** (Unix only):
+//!     Names this instance and serves a control socket at `NAME.sock` in
+//!     `$XDG_RUNTIME_DIR/detach` (or the state directory), which `detach-rs events`
+//!     connects to. Starting a second instance with the same name fails. Runs the command
+//!     under the supervisor. If an earlier instance of that name died but its command is
+//!     still running (same PID and start time, same command), the new instance adopts it
+//!     instead of starting a duplicate (Linux only; see `adopt`).
+//!     Example: `--name myservice --command ./server`
+//!
+//! *   **`--tag <KEY=VALUE>`** (Unix only, requires `--name`):
+//!     Labels the instance, for managing fleets of detached jobs in groups: the tags are
+//!     part of its `status`, shown by `list`, and select instances with `--tag` in
+//!     `list`, `stop` and `restart`. Repeat to add several; an instance is selected when
+//!     it has all the tags asked for.
+//!     Example: `--name worker-3 --tag env=prod --tag role=worker --command ./work.sh`
+//!
+//! *   **`--instances <N>`** (Unix only, requires `--name` and `--command`):
+//!     Starts `N` copies of the command, as the instances `NAME:1` to `NAME:N`, each a
+//!     separate process with its own PID, control socket and log file (`-1`, `-2`, ...
+//!     added to the file name). Use `--port auto` to give each its own port, and
+//!     `{{instance}}` in the command to tell them apart. They are managed together:
+//!     `stop NAME`, `restart NAME` and `--name NAME` act on all instances, while
+//!     `stop NAME:2` acts on one. In the foreground, waits for all of them.
+//!     Example: `--name web --instances 4 --port auto --command "./server --listen :{{port}}"`
+//!
+//! *   **`--port <PORT|auto>`** (requires `--command`):
+//!     Declares a TCP port the command listens on. Before anything is started, each
+//!     declared port is checked to be free on every address; a taken one stops the run
+//!     with the PID and command line of the process holding it (found through `/proc`
+//!     on Linux), instead of a command that fails to bind and, under the supervisor,
+//!     keeps being restarted. `auto` picks a free port, for running several copies of a
+//!     service side by side. The command is told its port in `$PORT`; with several,
+//!     `$PORT_1`, `$PORT_2`, ... hold all of them in order. The ports are part of the
+//!     instance's `status` and of its launch record (see `inspect`). Runs the command
+//!     under the supervisor.
+//!     Example: `--name web-2 --port auto --command './server --listen ":$PORT"'`
+//!
+//! *   **`--dbus [BUS]`** (Unix, with the `dbus` feature):
+//!     Puts the instance on the `session` (default) or `system` bus as
+//!     `org.detach.Manager` (`org.detach.Manager.<NAME>` with `--name`), exporting
+//!     `Start`, `Stop`, `Restart` and `Status` methods on `/org/detach/Manager` and a
+//!     `StateChanged` signal for every lifecycle event, so desktop tools and scripts can
+//!     control it with `busctl` or `gdbus`. Runs the command under the supervisor.
+//!     Example: `--name web --dbus --command ./server`, then
+//!     `busctl --user call org.detach.Manager.web /org/detach/Manager org.detach.Manager Restart`
+//!
+//! *   **`--grpc <ADDR>`** (Unix, with the `grpc` feature):
+//!     Serves the control API as the gRPC service `detach.v1.Control`
+//!     (`proto/detach/v1/control.proto`) on a Unix socket (`unix:PATH`) or `HOST:PORT`,
+//!     with `Start`, `Stop`, `Restart`, `Status` and a streaming `Logs` of lifecycle
+//!     events. Runs the command under the supervisor.
+//!     Example: `--name web --grpc unix:/run/web.grpc --command ./server`
+//!
+//! *   **`--grpc-token-file <PATH>`** (with `--grpc`):
+//!     Reads a bearer token from `PATH` that gRPC clients connecting over TCP have to send
+//!     as `authorization: Bearer <token>`. Without it a TCP endpoint must be a loopback
+//!     address and only answers `Status` and `Logs`.
+//!     Example: `--grpc 0.0.0.0:50051 --grpc-token-file /etc/detach/web.token`
+//!
+//! *   **`--audit-log <PATH>`**:
+//!     Appends a JSON line to `PATH` for every start, stop, restart and exit of the
+//!     command, its becoming ready, every signal forwarded to it and every reload
+//!     request, with the trigger
+//!     (`cli`, `signal`, `schedule`, `timeout`, `lifetime` or `command`) and the user who
+//!     started `detach-rs`. Separate from the service log, for post-incident review.
+//!     Runs the command under the supervisor.
+//!     Example: `--command ./server --restart-at '0 4 * * *' --audit-log /var/log/server-audit.jsonl`
+//!
+//! *   **`--history-retention <DURATION>`**:
+//!     An instance started with `--name` always keeps its lifecycle events in
+//!     `history/NAME.ndjson` in the state directory, for `history`. Events older than
+//!     `DURATION` (default `30d`) are dropped when an instance starts; `0` keeps no
+//!     history at all.
+//!     Example: `--name web --command ./server --history-retention 90d`
+//!
+//! *   **`--alert-restarts <RESTARTS/WINDOW>`**:
+//!     Alerts when the command was started again more than `RESTARTS` times within
+//!     `WINDOW` (e.g. `5/10m`), counting the restarts in the event history of the instance
+//!     (see `--history-retention`) as well. The alert is logged and sent to every
+//!     `--alert-webhook` and `--alert-email`, once each time the threshold is crossed.
+//!     Requires `--name` and `--command`.
+//!     Example: `--name web --command ./server --alert-restarts 5/10m --alert-webhook https://hooks.example.com/ops`
+//!
+//! *   **`--alert-webhook <URL>`**, **`--alert-email <ADDRESS>`**:
+//!     Where alerts go: those of `--alert-restarts`, and one when the log file cannot be
+//!     written (see `--on-log-error`). Each may be given several times. A webhook
+//!     receives the alert as a JSON `POST`, sent with `curl` (with the `http` feature); an
+//!     email is handed to `sendmail`, with the JSON as its body. A delivery that fails is
+//!     tried twice more, a few seconds apart. Requires `--command`.
+//!     Example: `--alert-restarts 3/1h --alert-email ops@example.com`
+//!
+//! *   **`--status-interval <DURATION>`**:
+//!     An instance started with `--name` writes its state, health, uptime, memory use
+//!     and metrics to `status/NAME.json` in the state directory every `DURATION` (default
+//!     `10s`) and when it stops, replacing the file atomically, so monitors can read them
+//!     without the control socket. Its `heartbeat` tells when it was written, which
+//!     stops advancing if the instance hangs. `0` writes no status file.
+//!     Example: `--name web --command ./server --status-interval 30s`
+//!
+//! *   **`--output-tail [LINES]`**:
+//!     Keeps the last `LINES` lines (default 50) of the command's stdout and stderr in
+//!     memory. They are included in `detach-rs status`, in `detach-rs dump` and in crash
+//!     reports, so the reason for a failure is at hand even after the log was rotated away.
+//!     The output is read through a pipe and copied on to the log, so the command no longer
+//!     writes to the log file (or, with `--no-detach`, the terminal) directly.
+//!     Example: `--command ./backup.sh --output-tail 100 --crash-report`
+//!
+//! *   **`--crash-report [KB]`** (with the `crash-report` feature):
+//!     When the service or command fails, writes a `.tar.gz` with the last `KB` KiB of the
+//!     log (default 64), the command line, environment, failure and host information to
+//!     `~/.local/state/detach/crash-reports/`, and names it in the failure log message.
+//!     With `--output-tail`, the kept command output is included as well.
+//!     Example: `--command ./server --crash-report 256`
+//!
+//! *   **`--redact-env <PATTERN>`**, **`--redact-regex <REGEX>`** (with the `redact` feature):
+//!     Replace secrets with `[REDACTED]` in everything the log appenders write, and in
+//!     the command output written to `--keep-runs` run logs and queue job logs.
+//!     `--redact-env` takes a regex matched against whole environment variable names and
+//!     redacts the values of the matching variables; `--redact-regex` redacts any match.
+//!     Both can be given several times.
+//!     Example: `--redact-env 'AWS_SECRET.*' --redact-regex 'ghp_[A-Za-z0-9]{36}'`
+//!
+//! *   **`--metrics-listen <ADDR>`** (with the `http` feature):
+//!     Serves the metrics registered through `ServiceContext::current().metrics()` over HTTP
+//!     while the service or command runs: Prometheus text at `/metrics`, JSON at
+//!     `/metrics.json`. Supervised commands report run counts and durations, and when the
+//!     last run started and ended, how long it took and its exit code
+//!     (`detach_command_last_*`).
+//!     The health reported through `ServiceContext::current().health()` is served at
+//!     `/health` (503 while degraded) and, with the metrics, at `/status`.
+//!     Example: `--command ./worker --restart-at 03:00 --metrics-listen 127.0.0.1:9100`
+//!
+//! *   **`--stats-interval <DURATION>`**:
+//!     Logs the memory use of `detach-rs` at this interval: resident and peak set size
+//!     and, when built with the `jemalloc` or `mimalloc` feature by a program that uses
+//!     that allocator, the bytes the allocator has allocated, keeps resident and reserved.
+//!     The same numbers are exported as `detach_memory_*` and `detach_allocator_*` gauges
+//!     with `--metrics-listen`, so slow growth in a long-running daemon can be graphed.
+//!     The output a supervised command wrote in the interval is logged as well, with its
+//!     rate and the number of writes it took; the `detach_output_bytes_total` and
+//!     `detach_output_writes_total` counters hold the totals.
+//!     Example: `--stats-interval 10m --metrics-listen 127.0.0.1:9100`
+//!
+//! *   **`--profile <PROFILE>`**:
+//!     `standard` (default) or `minimal`, for routers and single-board computers where
+//!     every megabyte counts. `minimal` runs the service or command on a single-threaded
+//!     runtime, keeps only a few lifecycle events in memory, and opens no control socket
+//!     and no metrics endpoint: `stop`, `status` and the other subcommands that talk to
+//!     an instance cannot reach it (stop it with a signal), and `--metrics-listen`,
+//!     `--grpc` and `--dbus` are refused. A build with the `embedded` feature defaults to
+//!     `minimal`. See `profile`.
+//!     Example: `--profile minimal --detach --command "./sensor-upload"`
+//!
+//! *   **`--fork-audit [MODE]`**:
+//!     Before forking, inspects the thread count, open descriptors and installed signal
+//!     handlers and logs what would be carried into the daemon. `warn` (the default when the
+//!     flag is given) only logs; `strict` also refuses to fork when other threads or signal
+//!     handlers are found, which catches the classic "daemonize after starting tokio" bug.
+//!     Example: `--fork-audit strict`
+//!
+//! *   **`--os-log <SUBSYSTEM[:CATEGORY]>`**:
+//!     Also sends log records to the macOS unified logging system (category `default`
+//!     unless given), so `log stream --predicate 'subsystem == "com.example.app"'` and
+//!     Console.app show them. Levels map to os_log types: error to error, warn to
+//!     default, info to info, debug and trace to debug. Ignored on other platforms.
+//!     Example: `--os-log com.example.backup:jobs`
+//!
+//! *   **`--keep-awake`**:
+//!     Holds a power assertion (like `caffeinate -i`) while the service or command runs,
+//!     so a detached backup or sync is neither paused by idle sleep nor throttled by App
+//!     Nap (macOS; elsewhere the flag is ignored with a warning).
+//!     Example: `--command ./backup.sh --detach --keep-awake`
+//!
+//! *   **`--queue-worker`**, **`--max-parallel <N>`** (Unix only):
+//!     Runs the jobs added with `detach-rs enqueue` instead of the built-in service, in
+//!     order and at most `N` (default 1) at a time, each with its output in its own log
+//!     under `queue/logs` in the state directory. On SIGTERM the running jobs are stopped
+//!     and stay queued; jobs a crashed worker left running are queued again at start.
+//!     Example: `detach-rs --queue-worker --max-parallel 4 --detach`
+//!
+//! *   **`--keep-runs <N>`**:
+//!     Gives each run of a supervised `--command` its own log file,
+//!     `runs/<NAME>/<TIMESTAMP>.log` in the state directory, instead of interleaving all
+//!     runs in the log file, and appends a line per finished run (start, finish, duration,
+//!     exit code) to `runs/<NAME>/index.jsonl`. Only the newest `N` runs are kept.
+//!     Requires `--name`. With `--queue-worker`, keeps only the newest `N` finished jobs
+//!     and their logs.
+//!     Example: `--name poller --command ./poll.sh --restart-at 03:00 --keep-runs 14`
+//!
+//! *   **`--max-line-length <SIZE>`**, **`--binary-output <MODE>`**:
+//!     Keep a command's output from corrupting the log files it is captured in (the run
+//!     logs of `--keep-runs`, the job logs of `--queue-worker`). A line is cut after
+//!     `SIZE` bytes (64K by default, `0` for no limit) and ends in
+//!     `[... N bytes truncated]`. With `escape` (default), bytes that are not valid UTF-8
+//!     and control characters other than tab, carriage return and escape are written as
+//!     `\xNN`; `hexdump` writes reads that look binary as a hexdump instead, and `raw`
+//!     writes everything as it is.
+//!     Example: `--name backup --command ./dump.sh --keep-runs 7 --binary-output hexdump`
+//!
+//! *   **`--watchdog <DURATION>`**, **`--watchdog-abort`**:
+//!     Watches the async runtime from a separate thread through a canary task. When the
+//!     canary has not run for `DURATION` (all workers blocked, a deadlock, a task that never
+//!     yields), logs an error with the worker and task counts and, on Linux, the state of
+//!     every thread. With `--watchdog-abort` the process then aborts, so a hung daemon is
+//!     restarted by its supervisor instead of sitting dead.
+//!     Example: `--command ./server --watchdog 60s --watchdog-abort`
+//!
+//! *   **`--tokio-console [ADDR]`** (with the `tokio-console` feature):
+//!     Serves the instrumentation `tokio-console` attaches to on `ADDR` (default
+//!     `127.0.0.1:6669`), started in the daemon after detaching so the port belongs to the
+//!     running service. Tasks only show up when built with `RUSTFLAGS="--cfg tokio_unstable"`.
+//!     Example: `--detach --tokio-console`, then `tokio-console http://127.0.0.1:6669`
+//!
+//! *   **`--completions <SHELL>`**:
+//!     Prints a completion script for `bash`, `elvish`, `fish`, `powershell` or `zsh` to
+//!     stdout and exits.
+//!     Example: `detach-rs --completions bash > /etc/bash_completion.d/detach-rs`
+//!
+//! ## Signals:
+//!
+//! *   **`SIGHUP`**:
+//!     Requests a reload instead of terminating the daemon. Services receive it through
+//!     `ServiceContext::subscribe_reload`; a command run with `--command` is sent SIGHUP.
+//!
+//! *   **`SIGTERM`**, **`SIGINT`**, **`SIGUSR1`**, **`SIGUSR2`**, **`SIGWINCH`**:
+//!     Passed on to a supervised command (see `--forward-signals`). A forwarded SIGTERM or
+//!     SIGINT also keeps the supervisor from restarting the command once it exits.
+//!
+//! *   **`SIGUSR2`**:
+//!     Unless it is forwarded to a supervised command, logs a diagnostics report (see the
+//!     `dump` subcommand) instead of terminating the daemon.
+//!
+//! ## Subcommands:
+//!
+//! *   **`init <NAME> [--force] -- <COMMAND>...`** (alias `new`):
+//!     Writes a commented `~/.config/detach/services/<NAME>.toml` with restart policy,
+//!     log settings and readiness probe stubs for the given command.
+//!     Example: `detach-rs init myservice -- ./target/release/myservice --flag`
+//!
+//! *   **`events [-f] [--name <NAME>]`** (Unix only):
+//!     Prints the recent lifecycle events of running instances (see `--name`) as JSON
+//!     lines: starts, commands becoming ready, stops, restarts, exits, forwarded signals
+//!     and reloads, each with its trigger, the state it left the instance in and the
+//!     instance name. With `-f`, keeps printing new events as they happen, so tooling can
+//!     react to restarts and crashes without polling. Without `--name`, the events of all
+//!     instances are merged.
+//!     Example: `detach-rs events -f --name myservice | jq 'select(.action == "exit")'`
+//!
+//! *   **`logs [-f] [-n <N>] [FILE]`**:
+//!     Prints the last `N` lines (default 10) of `FILE`, or of the newest `detach-*.log`
+//!     in the log directory, and with `-f` keeps printing new lines as they are written,
+//!     following the file across truncation and rotation. Works on every platform,
+//!     including Windows, where daemonization is not available.
+//!     Example: `detach-rs logs -f -n 100`
+//!
+//! *   **`status [--name <NAME>]`** (Unix only):
+//!     Prints one JSON line per running instance (see `--name`) with its name, PID, state,
+//!     health, last lifecycle event and `last_run` of the command. An instance is `defined`
+//!     until its command starts, `starting` until the command has kept running for a
+//!     second, then `ready`, or `degraded` while it reports itself degraded, `stopping`
+//!     while a stop or restart is under way and finally `stopped` or `failed`. For a
+//!     `--name` that is no longer running, prints the state it ended in. Instances with an
+//!     event history (see `history`) also get `stats` from it: `restarts` in total and
+//!     `restarts_24h` in the last day, `crashes`, the cumulative `uptime_secs` of the
+//!     command and the mean time between failures `mtbf_secs` (`null` without crashes).
+//!     Example: `detach-rs status --name myservice`
+//!
+//! *   **`list [PATTERN] [--tag <KEY=VALUE>]...`** (Unix only):
+//!     Prints a table of the running instances (see `--name`) with their PID, state,
+//!     health, tags, last run and last lifecycle event, then the instances that have
+//!     stopped or failed since, and those that were killed (shown as `failed`), until
+//!     `clean`. The last run is when the command last started, how long it ran and its
+//!     exit code; it is kept in the registry, so it is still there after the instance
+//!     has ended and, until it has a run of its own, when one by the same name starts.
+//!     `PATTERN` is a glob over the names, where `*` matches any run of characters and `?`
+//!     a single one; `--tag` only lists instances with that tag.
+//!     Example: `detach-rs list 'worker-*'`
+//!
+//! *   **`healthz [--listen <ADDR>] [--require <NAME>]...`** (Unix only):
+//!     Checks all running instances (see `--name`) at once: healthy only if every one of
+//!     them is `ready`, or with `--require` every instance of the named services, which
+//!     must then be running. Prints the result as JSON and exits non-zero if it is not
+//!     healthy. With `--listen` (and the `http` feature), serves it at `GET /healthz`
+//!     instead, answering `200` or `503`, for load balancers and uptime checkers.
+//!     Example: `detach-rs healthz --listen 0.0.0.0:9110 --require web --require db`
+//!
+//! *   **`stop <PATTERN | --name NAME | --tag KEY=VALUE... | --all> [--force]`**,
+//!     **`restart <PATTERN | --name NAME | --tag KEY=VALUE... | --all> [--force]`** (Unix only):
+//!     Stops the supervised command of the matching running instances, which then exit
+//!     instead of restarting it, or has them restart it right away. The result is
+//!     printed per instance, and the exit status is non-zero if any of them failed.
+//!     `--all` acts on every running instance, after asking on a terminal; without a
+//!     terminal it refuses unless `--force` (or `--yes`) is given.
+//!     Several instances are restarted one at a time, each only after the one before is
+//!     ready again: its new command has kept running for a second, it is healthy and
+//!     its `--port`s accept connections. One that is not ready within
+//!     `--ready-timeout` seconds (default 60) stops the rolling restart, so a pool
+//!     started with `--instances` never goes down as a whole. `stop --wait` returns only
+//!     once each instance has exited, waited for through a pidfd on Linux, so a reused
+//!     PID cannot end the wait early (see `pidfd`).
+//!     Example: `detach-rs restart 'worker-*'`, `detach-rs stop --tag role=worker --wait`
+//!
+//! *   **`deploy <NAME> --new-cmd <COMMAND> [--ready-timeout <SECONDS>]`** (Unix only):
+//!     Blue/green deployment of a single instance: starts the new command next to the
+//!     running instance of `NAME`, with the options that one was launched with (see
+//!     `inspect`), as its next instance (`web` is followed by `web:2`, `web:2` by
+//!     `web:3`). Once the new one is ready, as for a rolling `restart`,
+//!     `NAME.latest` next to the control sockets is pointed at its socket and the old
+//!     one is stopped. A new version that is not ready in time is stopped instead, and
+//!     the old one keeps running. Both run at once, so use `--port auto` rather than a
+//!     fixed port.
+//!     Example: `detach-rs deploy web --new-cmd "./server-v2 --listen :{{port}}"`
+//!
+//! *   **`exec <NAME> -- <COMMAND>...`** (Linux only):
+//!     Runs the command in the foreground with exactly the environment, working
+//!     directory and user of the instance's supervised command (or of the instance, when
+//!     no command is running), read from `/proc`, and exits with its status. For finding
+//!     out why something works in a shell but not in the daemon. Switching to the
+//!     instance's user needs root.
+//!     Example: `detach-rs exec myservice -- env`
+//!
+//! *   **`tree <NAME>`** (Linux only):
+//!     Shows the instance and every process below it (its supervised command and
+//!     whatever that started, such as the stages of an `sh -c` pipeline or worker
+//!     processes), indented by parent, with their PIDs, resident memory and CPU use over
+//!     a quarter of a second, read from `/proc`.
+//!     Example: `detach-rs tree myservice`
+//!
+//! *   **`fds <NAME>`** (Linux only):
+//!     Lists the open file descriptors of the instance and every process below it, with
+//!     files by path and sockets by protocol and addresses, listening ones marked, read
+//!     from `/proc/<PID>/fd` and `/proc/net`. For finding out who holds a port or what
+//!     leaks descriptors, without `lsof`.
+//!     Example: `detach-rs fds myservice | grep listening`
+//!
+//! *   **`doctor <NAME>`** (Linux only):
+//!     Checks the processes below the instance for a controlling terminal or a terminal
+//!     device (`/dev/tty`, `/dev/pts/*`, ...) open that the instance itself does not
+//!     have, which is what a "daemon" that mysteriously hangs is usually waiting on, and
+//!     prints each with advice on running it non-interactively. Exits with an error if
+//!     any was found. A detached supervisor runs the same check every 10 seconds and
+//!     logs a warning for each such process.
+//!     Example: `detach-rs doctor myservice`
+//!
+//! *   **`inspect <NAME> [--json]`** (Unix only):
+//!     Shows how the instance was launched: the effective value of every option and where
+//!     it came from (its default, the environment or the command line), its command line,
+//!     working directory, user, log file and environment, as recorded when it started, and
+//!     whether it is still running. `--json` prints the whole record as JSON instead.
+//!     Values of variables named like secrets (`*TOKEN*`, `*PASSWORD*`, ...) and whatever
+//!     `--redact-env` and `--redact-regex` select are not recorded. The record outlives the
+//!     instance, for finding out how a service that died was started, until the name is
+//!     started again or `clean` removes it.
+//!     Example: `detach-rs inspect myservice --json | jq .configuration`
+//!
+//! *   **`history <NAME> [--since <DURATION|TIME>] [--json]`**:
+//!     Prints the past lifecycle events of the service `NAME` and all of its instances
+//!     from their history (see `--history-retention`), whether they are running or not,
+//!     oldest first, followed by how many starts, crashes, restarts and stops there were.
+//!     A crash is the command exiting by itself with a failure. `--since` limits the
+//!     events to the last `DURATION` or those from an RFC 3339 `TIME` on; `--json` prints
+//!     them as JSON lines instead.
+//!     Example: `detach-rs history web --since 7d`
+//!
+//! *   **`clean [--force]`** (Unix only):
+//!     Removes what instances that are no longer running left behind: control sockets of
+//!     killed instances, `NAME.latest` links (see `deploy`) to them, and the launch
+//!     records (see `inspect`), recorded states (see `list`) and `--keep-runs` logs of
+//!     names with no running instance.
+//!     Lists what it will remove and asks first, like `stop --all`.
+//!     Example: `detach-rs clean --force`
+//!
+//! *   **`dump --name <NAME>`** (Unix only):
+//!     Asks a running instance for its diagnostics (uptime, worker and task counts,
+//!     health, memory use, metrics, last event and the last lines of its log), which it
+//!     also writes to its log, and prints them as JSON.
+//!     Example: `detach-rs dump --name myservice | jq .runtime`
+//!
+//! *   **`enqueue -- <COMMAND>...`**, **`jobs`** (Unix only):
+//!     `enqueue` adds the command to the job queue, to be run in the current directory
+//!     by a worker started with `--queue-worker`, and prints its job ID. `jobs` lists the
+//!     queued, running and finished jobs with their exit codes and log files.
+//!     Example: `detach-rs enqueue -- ./render.sh scene-42`
+//!
+//! *   **`remote <HOST> -- <COMMAND>...`**, **`remote logs [-f] <HOST> <PID>`**,
+//!     **`remote stop <HOST> <PID>`**:
+//!     Starts the command on `HOST` over `ssh`, in its own session and immune to hangups,
+//!     and prints its PID there; nothing needs to be installed on the host. The output
+//!     goes to `~/.local/state/detach/remote/<PID>.log` on the host, which `remote logs`
+//!     prints (`-f` to follow). `remote stop` sends SIGTERM to the command's session.
+//!     Example: `detach-rs remote deploy@build1 -- ./train.sh --epochs 100`
+//!
 //! ## Examples:
 //!
 //! *   **Run in background with default settings:**
@@ -54,48 +715,1186 @@
 //!     ./target/release/detach-rs --no-detach --tail
 //!     ```
 //!
+//! *   **Start a service and follow its log (Ctrl-C leaves it running):**
+//!     ```bash
+//!     ./target/release/detach-rs --tail --name heartbeat
+//!     ```
+//!
+//! ## Features:
+//!
+//! The default features are `crash-report`, `cron`, `http` and `redact`; each pulls in
+//! dependencies or code the core (daemonizing, `--command`, `stop`, `status`, logging)
+//! does without. For routers and single-board computers, and static musl builds for them,
+//! build with `--no-default-features --features embedded`, adding back what the device
+//! needs; `embedded` makes `--profile minimal` the default. A flag that needs a missing
+//! feature is reported by `validate`.
+//!
+//! ```bash
+//! cargo build --release --target armv7-unknown-linux-musleabihf --no-default-features --features embedded
+//! ```
+//!
 //! Note: On non-Unix systems, daemonization is not supported, and `--detach` will be ignored.
-use anyhow;
+//! The foreground service, `--command` (run through `cmd /C`), `--tail` and `logs` work
+//! there as well.
 use clap::Parser;
-use log::{info, warn};
-use std::path::PathBuf;
-use tokio::process::Command;
-use tokio::time::{timeout, Duration as TokioDuration};
+use log::info;
+use std::path::{Path, PathBuf};
+use tokio::time::Duration as TokioDuration;
+
+pub mod adopt;
+pub mod alert;
+pub mod audit;
+pub mod backoff;
+pub mod builder;
+pub mod clock;
+pub mod command;
+#[cfg(unix)]
+pub mod compat;
+pub mod config;
+pub mod confirm;
+#[cfg(feature = "tokio-console")]
+pub mod console;
+pub mod container;
+pub mod context;
+#[cfg(unix)]
+pub mod control;
+pub mod cores;
+#[cfg(all(unix, feature = "dbus"))]
+pub mod dbus;
+#[cfg(unix)]
+pub mod deploy;
+pub mod diagnostics;
+#[cfg(unix)]
+pub mod exec;
+#[cfg(unix)]
+pub mod fds;
+#[cfg(unix)]
+pub mod forkcheck;
+#[cfg(all(unix, feature = "grpc"))]
+pub mod grpc;
+pub mod health;
+#[cfg(unix)]
+pub mod healthz;
+pub mod history;
+pub mod instances;
+pub mod isolation;
+#[cfg(unix)]
+pub mod journald;
+pub mod kv;
+pub mod landlock;
+#[cfg(unix)]
+pub mod launch;
+pub mod limits;
+pub mod lock;
+pub mod logfile;
+pub mod logs;
+pub mod manager;
+pub mod memory;
+pub mod metrics;
+#[cfg(unix)]
+pub mod notify;
+#[cfg(target_os = "openbsd")]
+pub mod openbsd;
+pub mod oslog;
+pub mod pidfd;
+pub mod ports;
+pub mod power;
+pub mod prelude;
 #[cfg(unix)]
-use libc::{kill, SIGINT};
+pub mod proctree;
+pub mod profile;
+pub mod pump;
+#[cfg(unix)]
+pub mod queue;
+pub mod reaper;
+pub mod redact;
+pub mod remote;
+pub mod report;
+pub mod runlog;
+pub mod sanitize;
+pub mod schedule;
+pub mod seccomp;
+#[cfg(unix)]
+pub mod signal;
+pub mod snapshot;
+pub mod state;
+pub mod stdin;
+pub mod supervisor;
+pub mod tasks;
+pub mod template;
+pub mod tty;
+pub mod validate;
+#[cfg(unix)]
+pub mod wait;
+pub mod watchdog;
+
+pub use alert::with_alerts;
+pub use audit::{AuditLog, LifecycleEvent};
+pub use backoff::{Backoff, BackoffPolicy};
+pub use builder::DaemonBuilder;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use command::{
+    CommandOutcome, ExitReason, OutputLine, OutputMode, OutputTail, RunOptions, run_command,
+};
+pub use container::Backend;
+pub use context::{ControlRequest, ServiceContext, run_id, with_sighup_reload};
+pub use cores::CoreDumps;
+pub use diagnostics::with_diagnostics_signal;
+pub use health::{Health, HealthState};
+pub use history::with_history;
+pub use isolation::{Isolation, NetworkMode};
+pub use kv::{LineEncoder, LogField};
+pub use landlock::{FsSandbox, SandboxMode};
+pub use manager::ServiceManager;
+pub use memory::{MemoryStats, with_memory_stats};
+pub use metrics::{Metrics, with_metrics_endpoint};
+pub use oslog::OsLogTarget;
+pub use power::with_keep_awake;
+pub use redact::Redactor;
+pub use report::{CrashReport, with_crash_report};
+pub use runlog::RunLogs;
+pub use schedule::RestartSchedule;
+pub use seccomp::SeccompProfile;
+pub use snapshot::with_status_file;
+pub use state::{ServiceState, with_state};
+pub use supervisor::{SupervisorOptions, supervise_command};
+pub use watchdog::{Watchdog, with_watchdog};
+
+/// The standard stream the console appender writes to.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConsoleStream {
+    /// Log to standard output
+    #[default]
+    Stdout,
+    /// Log to standard error
+    Stderr,
+}
+
+/// How log lines are rendered.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `<time> - <LEVEL> - <message> key=value ...`
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Where log records go.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogDestination {
+    /// The log file, plus the console when enabled
+    #[default]
+    File,
+    /// The systemd journal, with levels as priorities
+    Journald,
+    /// The macOS unified log, plus the console when enabled
+    OsLog,
+    /// The journal under systemd, os_log under launchd, the log file otherwise
+    Auto,
+}
+
+impl LogDestination {
+    /// Resolves `Auto` for the environment this process runs in.
+    pub fn resolve(self) -> LogDestination {
+        if self != LogDestination::Auto {
+            return self;
+        }
+        match manager::ServiceManager::from_environment() {
+            #[cfg(unix)]
+            Some(manager::ServiceManager::Systemd) if journald::is_available() => {
+                LogDestination::Journald
+            }
+            Some(manager::ServiceManager::Launchd) => LogDestination::OsLog,
+            _ => LogDestination::File,
+        }
+    }
+}
+
+#[doc(hidden)]
+pub use log as __log;
+
+/// The options every detaching program needs, for flattening into another binary's clap
+/// arguments.
+///
+/// `Args` embeds it for `detach-rs` itself; another CLI adds `--detach`, `--no-detach`,
+/// `--log-file`, `--timeout`, `--logging`, `-v`, `-q` and `--on-stdin-eof` next to its
+/// own options, and
+/// `into_builder` turns them into a `DaemonBuilder`:
+///
+/// ```no_run
+/// use clap::Parser;
+/// use detach::DetachArgs;
+///
+/// #[derive(Parser)]
+/// struct Cli {
+///     #[command(flatten)]
+///     detach: DetachArgs,
+///     /// Port to serve on
+///     #[arg(long, default_value_t = 8080)]
+///     port: u16,
+/// }
+///
+/// fn main() -> anyhow::Result<()> {
+///     let cli = Cli::parse();
+///     cli.detach.into_builder().run(detach::run_service_async())
+/// }
+/// ```
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct DetachArgs {
+    /// Run the process in the background
+    #[arg(long, default_value_t = false)]
+    pub detach: bool,
+
+    /// Run the process in the foreground (disable detachment)
+    #[arg(long = "no-detach")]
+    pub no_detach: bool,
+
+    /// Path to the log file [default: a timestamped file in the platform's log directory]
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Timeout after a specified number of seconds
+    #[arg(long, short, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+
+    /// Set the logging level (e.g., "error", "warn", "info", "debug", "trace")
+    #[arg(long, short, value_name = "LEVEL", value_enum)]
+    pub logging: Option<log::LevelFilter>,
+
+    /// Increase verbosity, may be repeated (e.g., "-v" for debug, "-vv" for trace)
+    #[arg(long, short, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only log errors to the console
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// What a service reading standard input through `detach::stdin` gets at end of file
+    #[arg(long, value_name = "MODE", value_enum, default_value_t)]
+    pub on_stdin_eof: stdin::OnStdinEof,
+}
+
+impl DetachArgs {
+    /// Whether to detach: `--detach` without `--no-detach`.
+    pub fn should_detach(&self) -> bool {
+        self.detach && !self.no_detach
+    }
+
+    /// The log file level: `--logging`, raised by every `-v` (see `resolve_level`).
+    pub fn level(&self) -> log::LevelFilter {
+        resolve_level(self.logging, self.verbose)
+    }
+
+    /// A `DaemonBuilder` set up from these options.
+    pub fn into_builder(self) -> DaemonBuilder {
+        let builder = DaemonBuilder::new()
+            .detach(self.should_detach())
+            .level(self.level())
+            .console_level(resolve_console_level(None, self.quiet))
+            .timeout(self.timeout)
+            .on_stdin_eof(self.on_stdin_eof);
+        match self.log_file {
+            Some(path) => builder.log_file(path),
+            None => builder,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "A detached Rust background service")]
+#[command(group(clap::ArgGroup::new("alert_target").multiple(true)))]
+pub struct Args {
+    /// The options shared with other binaries through `DetachArgs`
+    #[command(flatten)]
+    pub daemon: DetachArgs,
+
+    /// Fail instead of staying in the foreground when --detach is given under systemd or launchd
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Run in the background and follow the log; Ctrl-C stops following, not the service
+    #[arg(long, default_value_t = false, conflicts_with = "detach")]
+    pub tail: bool,
+
+    /// With --tail, keep the service in the foreground so Ctrl-C stops it, not just the tail
+    #[arg(long, requires = "tail")]
+    pub kill_on_interrupt: bool,
+
+    /// With --detach and --name, show the log until the instance is ready (or a key is pressed)
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        num_args = 0..=1,
+        default_missing_value = "60",
+        requires_all = ["detach", "name"]
+    )]
+    pub wait: Option<u64>,
+
+    /// Owner "USER[:GROUP]" of the log directory when it has to be created (run as root)
+    #[cfg(unix)]
+    #[arg(long, value_name = "USER[:GROUP]", value_parser = parse_owner)]
+    pub log_dir_owner: Option<compat::Owner>,
+
+    /// Permission bits, in octal, of the log directory when it has to be created (e.g., 750)
+    #[cfg(unix)]
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    pub log_dir_mode: Option<u32>,
+
+    /// Interrupt each command execution after a specified number of seconds (overrides --timeout)
+    #[arg(long, value_name = "SECONDS")]
+    pub run_timeout: Option<u64>,
+
+    /// Stop the daemon (or foreground service) after a specified number of seconds (overrides --timeout)
+    #[arg(long, value_name = "SECONDS")]
+    pub daemon_timeout: Option<u64>,
+
+    /// Limit the CPU time of each command execution (RLIMIT_CPU, e.g., "300s")
+    #[cfg(unix)]
+    #[arg(long, value_name = "DURATION", value_parser = parse_delay, requires = "command")]
+    pub cpu_limit: Option<std::time::Duration>,
+
+    /// Kill the command once it has written more than this much output (e.g., "10M")
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "command")]
+    pub max_output: Option<u64>,
+
+    /// Stop the daemon (or foreground service) at a wall-clock time (e.g., "2024-06-01T00:00", "06:00")
+    #[arg(long, value_name = "DATETIME", value_parser = parse_until)]
+    pub until: Option<chrono::DateTime<chrono::Local>>,
+
+    /// Set the console logging level independently of the log file (defaults to --logging)
+    #[arg(long, value_name = "LEVEL", value_enum, conflicts_with = "quiet")]
+    pub console_level: Option<log::LevelFilter>,
+
+    /// Stream used for console logging
+    #[arg(long, value_name = "STREAM", value_enum, default_value_t = ConsoleStream::Stdout)]
+    pub console_stream: ConsoleStream,
+
+    /// Where logs go: "file", "journald", "os-log", or "auto" to pick by environment
+    #[arg(long, value_name = "DEST", value_enum, default_value_t = LogDestination::File)]
+    pub log_to: LogDestination,
+
+    /// Format of log lines in the file and on the console
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Add these fields to every log line (comma-separated: service, pid, child-pid, host, run-id)
+    #[arg(long, value_name = "FIELDS", value_enum, value_delimiter = ',')]
+    pub log_fields: Vec<LogField>,
+
+    /// When the log file cannot be written: "drop" records, "block" until it can, "syslog", or "shutdown"
+    #[arg(long, value_name = "MODE", value_enum, default_value_t)]
+    pub on_log_error: logfile::OnLogError,
+
+    /// Command to run
+    #[arg(long, value_name = "COMMAND", conflicts_with = "tail")]
+    pub command: Option<String>,
+
+    /// Run the command as a native process, or as an image and arguments with podman or docker
+    #[arg(long, value_name = "BACKEND", value_enum, default_value_t = Backend::Process)]
+    pub backend: Backend,
+
+    /// Wait before starting the service or command, after daemonizing (e.g., "30s", "5m")
+    #[arg(long, value_name = "DURATION", value_parser = parse_delay)]
+    pub delay: Option<std::time::Duration>,
+
+    /// Hold an exclusive lock on this file while running; skip the run if another instance holds it
+    #[arg(long, value_name = "PATH")]
+    pub exclusive_lock: Option<PathBuf>,
+
+    /// Random extra delay before the first run and scheduled restarts (e.g., "0-60s")
+    #[arg(long, value_name = "RANGE", value_parser = parse_jitter)]
+    pub start_jitter: Option<schedule::Jitter>,
+
+    /// Restart the command on a schedule: "HH:MM" daily, or a cron expression (e.g., "0 3 * * *")
+    #[arg(long, value_name = "SCHEDULE", requires = "command", value_parser = parse_restart_at)]
+    pub restart_at: Option<RestartSchedule>,
+
+    /// Timezone of --restart-at: "local" or a name such as "Europe/Berlin"
+    #[arg(long, value_name = "TZ", default_value = "local", requires = "restart_at", value_parser = parse_timezone)]
+    pub timezone: schedule::Timezone,
+
+    /// Restarts at a time a DST change skips: "shift" them by the jump, or "skip" them
+    #[arg(
+        long,
+        value_name = "POLICY",
+        value_enum,
+        default_value_t,
+        requires = "restart_at"
+    )]
+    pub dst_gap: schedule::DstGap,
+
+    /// Restarts at a time a DST change repeats: "once", or "twice"
+    #[arg(
+        long,
+        value_name = "POLICY",
+        value_enum,
+        default_value_t,
+        requires = "restart_at"
+    )]
+    pub dst_repeat: schedule::DstRepeat,
+
+    /// Restarts the host slept through: "skip" them, "run-once" for all, or "run-all"
+    #[arg(
+        long,
+        value_name = "POLICY",
+        value_enum,
+        default_value_t,
+        requires = "restart_at"
+    )]
+    pub missed: schedule::MissedRuns,
+
+    /// Core dumps of the command: "allow", "disable" or "dir:<PATH>" to collect them
+    #[arg(long, value_name = "MODE", requires = "command", value_parser = parse_cores)]
+    pub cores: Option<CoreDumps>,
+
+    /// Seccomp filter for the command: "default", "strict" or a Docker/OCI JSON profile (Linux)
+    #[arg(long, value_name = "PROFILE", requires = "command", value_parser = parse_seccomp)]
+    pub seccomp: Option<SeccompProfile>,
+
+    /// Give the command its own empty /tmp and /var/tmp (Linux)
+    #[arg(long, requires = "command")]
+    pub private_tmp: bool,
+
+    /// Keep the command from gaining privileges through setuid binaries (Linux)
+    #[arg(long, requires = "command")]
+    pub no_new_privileges: bool,
+
+    /// Network for the command: "host", or "none" for an isolated namespace (Linux)
+    #[arg(long, value_name = "MODE", value_enum, default_value_t = NetworkMode::Host)]
+    pub network: NetworkMode,
+
+    /// Sandbox to this readable path with Landlock (Linux); may be given several times
+    #[arg(long, value_name = "PATH", value_parser = parse_absolute)]
+    pub allow_read: Vec<PathBuf>,
+
+    /// Sandbox with this path readable and writable; may be given several times
+    #[arg(long, value_name = "PATH", value_parser = parse_absolute)]
+    pub allow_write: Vec<PathBuf>,
+
+    /// What to do when the kernel cannot enforce --allow-read/--allow-write
+    #[arg(long, value_name = "MODE", value_enum, default_value_t = SandboxMode::BestEffort)]
+    pub sandbox: SandboxMode,
+
+    /// Supervise the command and forward these signals to it (default: TERM,INT,HUP,USR1,USR2,WINCH)
+    #[cfg(unix)]
+    #[arg(long, value_name = "LIST", requires = "command", value_delimiter = ',', value_parser = parse_signal)]
+    pub forward_signals: Option<Vec<i32>>,
+
+    /// Supervise the command in its own process group and send forwarded signals to the group
+    #[cfg(unix)]
+    #[arg(long, requires = "command")]
+    pub signal_group: bool,
+
+    /// Supervise the command and adopt the processes it leaves behind, stopping them with it (Linux only)
+    #[arg(long, requires = "command")]
+    pub subreaper: bool,
+
+    /// How long the command may take to exit when stopped before it is killed (e.g., "30s")
+    #[arg(long, value_name = "DURATION", value_parser = parse_delay, default_value = "10s")]
+    pub stop_timeout: std::time::Duration,
+
+    /// Name of this instance; serves a control socket for `detach-rs events`
+    #[arg(long, value_name = "NAME", value_parser = parse_name)]
+    pub name: Option<String>,
+
+    /// Label this instance for `list` and for selecting it in `stop`/`restart` (repeatable)
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag, requires = "name")]
+    pub tags: Vec<(String, String)>,
+
+    /// Start this many copies of the command, called NAME:1 to NAME:N
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..), requires_all = ["name", "command"])]
+    pub instances: Option<u16>,
+
+    /// Which of the --instances this process is; set when they are started
+    #[arg(long, value_name = "INDEX", hide = true, requires = "instances")]
+    pub instance: Option<u16>,
+
+    /// TCP port the command listens on, or `auto` for a free one; passed as $PORT (repeatable)
+    #[arg(long = "port", value_name = "PORT|auto", requires = "command")]
+    pub ports: Vec<ports::PortSpec>,
+
+    /// Serve Start/Stop/Restart/Status as org.detach.Manager on this D-Bus bus
+    #[cfg(all(unix, feature = "dbus"))]
+    #[arg(long, value_name = "BUS", value_enum, num_args = 0..=1, default_missing_value = "session")]
+    pub dbus: Option<dbus::Bus>,
+
+    /// Serve Start/Stop/Restart/Status/Logs as a gRPC service on unix:PATH or HOST:PORT
+    #[cfg(all(unix, feature = "grpc"))]
+    #[arg(long, value_name = "ADDR")]
+    pub grpc: Option<grpc::Endpoint>,
+
+    /// Require the bearer token in this file from gRPC clients connecting over TCP
+    #[cfg(all(unix, feature = "grpc"))]
+    #[arg(long, value_name = "PATH", value_parser = parse_absolute, requires = "grpc")]
+    pub grpc_token_file: Option<PathBuf>,
+
+    /// Record supervisor actions (start, stop, restart, signals, reloads) as JSON lines here
+    #[arg(long, value_name = "PATH", value_parser = parse_absolute, requires = "command")]
+    pub audit_log: Option<PathBuf>,
+
+    /// With --name, how long to keep the lifecycle events shown by `history` (0 keeps none)
+    #[arg(long, value_name = "DURATION", value_parser = parse_delay, default_value = "30d")]
+    pub history_retention: std::time::Duration,
+
+    /// Alert when the command restarts more than N times within WINDOW (e.g., "5/10m")
+    #[arg(
+        long,
+        value_name = "N/WINDOW",
+        value_parser = parse_alert_threshold,
+        requires_all = ["name", "command"],
+        requires = "alert_target"
+    )]
+    pub alert_restarts: Option<alert::Threshold>,
+
+    /// POST alerts (--alert-restarts, --on-log-error) as JSON to this URL (repeatable)
+    #[arg(long, value_name = "URL", group = "alert_target", requires = "command")]
+    pub alert_webhook: Vec<String>,
+
+    /// Mail alerts (--alert-restarts, --on-log-error) to this address with sendmail (repeatable)
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        group = "alert_target",
+        requires = "command"
+    )]
+    pub alert_email: Vec<String>,
+
+    /// With --name, how often to write the status file for monitors (0 writes none)
+    #[arg(long, value_name = "DURATION", value_parser = parse_delay, default_value = "10s")]
+    pub status_interval: std::time::Duration,
+
+    /// Keep the last LINES lines of the command's output in memory for status and crash reports
+    #[arg(
+        long,
+        value_name = "LINES",
+        num_args = 0..=1,
+        default_missing_value = "50",
+        requires = "command"
+    )]
+    pub output_tail: Option<usize>,
+
+    /// On failure, bundle the last KB of the log, environment and host info into a report
+    #[arg(long, value_name = "KB", num_args = 0..=1, default_missing_value = "64")]
+    pub crash_report: Option<u64>,
+
+    /// Redact the values of environment variables whose names match this regex from logs
+    #[arg(long, value_name = "PATTERN")]
+    pub redact_env: Vec<String>,
+
+    /// Redact matches of this regex from logs
+    #[arg(long, value_name = "REGEX")]
+    pub redact_regex: Vec<String>,
+
+    /// Serve service metrics over HTTP on this address (e.g., "127.0.0.1:9100")
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_listen: Option<std::net::SocketAddr>,
+
+    /// Log memory use (and allocator statistics, if enabled) at this interval (e.g., "10m")
+    #[arg(long, value_name = "DURATION", value_parser = parse_delay)]
+    pub stats_interval: Option<std::time::Duration>,
+
+    /// Run lean for small machines: "minimal" drops the control socket and metrics
+    #[arg(long, value_name = "PROFILE", value_enum, default_value_t)]
+    pub profile: profile::Profile,
+
+    /// Check for threads, descriptors and signal handlers before forking: "warn" or "strict"
+    #[cfg(unix)]
+    #[arg(long, value_name = "MODE", value_enum, num_args = 0..=1, default_missing_value = "warn")]
+    pub fork_audit: Option<forkcheck::ForkAudit>,
+
+    /// Also log to macOS unified logging as "SUBSYSTEM[:CATEGORY]" (e.g., "com.example.app:jobs")
+    #[arg(long, value_name = "SUBSYSTEM[:CATEGORY]", value_parser = parse_os_log)]
+    pub os_log: Option<OsLogTarget>,
+
+    /// Keep the machine from idle-sleeping and exempt the daemon from App Nap (macOS)
+    #[arg(long)]
+    pub keep_awake: bool,
+
+    /// Run jobs added with `detach-rs enqueue` instead of the built-in service
+    #[cfg(unix)]
+    #[arg(long, conflicts_with = "command")]
+    pub queue_worker: bool,
+
+    /// Number of queued jobs the worker runs at the same time
+    #[cfg(unix)]
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..), requires = "queue_worker")]
+    pub max_parallel: u16,
+
+    /// Write each run of the command to its own log file and keep the newest N runs
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    pub keep_runs: Option<u16>,
+
+    /// Cut lines of captured output (--keep-runs, --queue-worker) after this many bytes; 0 for no limit
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, default_value = "64K")]
+    pub max_line_length: u64,
+
+    /// How captured output that is not text is logged: "escape" (\xNN), "hexdump" or "raw"
+    #[arg(long, value_name = "MODE", value_enum, default_value_t)]
+    pub binary_output: sanitize::BinaryOutput,
+
+    /// Log a diagnostic dump when the async runtime makes no progress for this long (e.g., "30s")
+    #[arg(long, value_name = "DURATION", value_parser = parse_delay)]
+    pub watchdog: Option<std::time::Duration>,
+
+    /// Abort the process after the --watchdog dump so it can be restarted
+    #[arg(long, requires = "watchdog")]
+    pub watchdog_abort: bool,
+
+    /// Serve tokio-console instrumentation on this address once running [default: 127.0.0.1:6669]
+    #[cfg(feature = "tokio-console")]
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = console::DEFAULT_ADDR)]
+    pub tokio_console: Option<std::net::SocketAddr>,
+
+    /// Print a shell completion script and exit
+    #[arg(long, value_name = "SHELL", value_enum)]
+    pub completions: Option<clap_complete::Shell>,
+
+    #[command(subcommand)]
+    pub subcommand: Option<Commands>,
+}
+
+/// Which running instances `stop` and `restart` act on: exactly one of a glob, a name,
+/// tags or `--all`.
+#[derive(clap::Args, Debug, Clone)]
+#[group(required = true, multiple = false)]
+pub struct InstanceSelector {
+    /// Instances whose name matches this glob (e.g., "worker-*")
+    #[arg(value_name = "PATTERN")]
+    pub pattern: Option<String>,
+
+    /// The instance with this name
+    #[arg(long, value_name = "NAME", value_parser = parse_name)]
+    pub name: Option<String>,
+
+    /// Instances labelled with this tag; repeat to require several
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+    pub tags: Vec<(String, String)>,
+
+    /// Every running instance (asks first on a terminal)
+    #[arg(long)]
+    pub all: bool,
+}
+
+/// Subcommands of `detach-rs`. Without one, the flags above run the service directly.
+#[derive(clap::Subcommand, Debug)]
+pub enum Commands {
+    /// Scaffold a commented service definition in ~/.config/detach/services
+    #[command(alias = "new")]
+    Init {
+        /// Name of the service (letters, digits, '-', '_' and '.')
+        name: String,
+
+        /// Overwrite an existing service definition
+        #[arg(long)]
+        force: bool,
+
+        /// Program and arguments to run, given after `--`
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+
+    /// Print lifecycle events of running instances as JSON lines
+    Events {
+        /// Keep printing new events as they happen
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Only the instance with this name (default: all running instances)
+        #[arg(long, value_name = "NAME", value_parser = parse_name)]
+        name: Option<String>,
+    },
+
+    /// Add a command to the job queue and print its job ID
+    Enqueue {
+        /// Program and arguments to run, given after `--`
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+
+    /// List the jobs in the queue
+    Jobs,
+
+    /// Print a log file, by default the newest one in the log directory
+    Logs {
+        /// Keep printing new lines as they are written
+        #[arg(short, long)]
+        follow: bool,
+
+        /// Number of lines from the end to start with
+        #[arg(short = 'n', long, value_name = "N", default_value_t = 10)]
+        lines: usize,
+
+        /// Log file to print (default: the newest detach-*.log in the log directory)
+        #[arg(value_name = "FILE")]
+        file: Option<PathBuf>,
+    },
+
+    /// Log and print the diagnostics of a running instance
+    Dump {
+        /// The instance to dump
+        #[arg(long, value_name = "NAME", value_parser = parse_name)]
+        name: String,
+    },
+
+    /// Print the status of running instances as JSON lines
+    Status {
+        /// Only the instance with this name (default: all running instances)
+        #[arg(long, value_name = "NAME", value_parser = parse_name)]
+        name: Option<String>,
+    },
+
+    /// List running instances, and sockets left behind by killed ones
+    List {
+        /// Only instances whose name matches this glob (e.g., "worker-*")
+        #[arg(value_name = "PATTERN")]
+        pattern: Option<String>,
+
+        /// Only instances labelled with this tag; repeat to require several
+        #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = parse_tag)]
+        tags: Vec<(String, String)>,
+    },
+
+    /// Check that all running instances are ready, once or as an HTTP /healthz endpoint
+    Healthz {
+        /// Serve GET /healthz on this address instead of checking once (e.g., "0.0.0.0:9110")
+        #[arg(long, value_name = "ADDR")]
+        listen: Option<std::net::SocketAddr>,
+
+        /// Only this service has to be ready, and must be running; repeat for several
+        #[arg(long, value_name = "NAME", value_parser = parse_name)]
+        require: Vec<String>,
+    },
+
+    /// Stop the command of running instances; they exit instead of restarting it
+    Stop {
+        #[command(flatten)]
+        instances: InstanceSelector,
+
+        /// Do not ask for confirmation, and act even without a terminal
+        #[arg(short, long, visible_alias = "yes", short_alias = 'y')]
+        force: bool,
+
+        /// Return only once each instance has exited, not as soon as it was asked to stop
+        #[arg(long)]
+        wait: bool,
+    },
+
+    /// Stop the command of running instances and start it again right away
+    Restart {
+        #[command(flatten)]
+        instances: InstanceSelector,
+
+        /// Do not ask for confirmation, and act even without a terminal
+        #[arg(short, long, visible_alias = "yes", short_alias = 'y')]
+        force: bool,
+
+        /// With several instances, how long each may take to become ready before the
+        /// rolling restart is abandoned
+        #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+        ready_timeout: u64,
+    },
+
+    /// Replace a running instance with a new version once that is ready
+    Deploy {
+        /// The service whose running instance to replace
+        #[arg(value_name = "NAME", value_parser = parse_name)]
+        name: String,
+
+        /// The command of the new version
+        #[arg(long = "new-cmd", value_name = "COMMAND")]
+        new_cmd: String,
+
+        /// How long the new version may take to become ready
+        #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+        ready_timeout: u64,
+    },
+
+    /// Run a command with the environment, working directory and user of an instance
+    Exec {
+        /// The instance whose context to use
+        #[arg(value_name = "NAME", value_parser = parse_name)]
+        name: String,
+
+        /// Program and arguments to run, given after `--`
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+
+    /// Show the processes below a running instance with their memory and CPU use
+    Tree {
+        /// The instance to show
+        #[arg(value_name = "NAME", value_parser = parse_name)]
+        name: String,
+    },
+
+    /// List the open files and sockets of a running instance and the processes below it
+    Fds {
+        /// The instance to show
+        #[arg(value_name = "NAME", value_parser = parse_name)]
+        name: String,
+    },
+
+    /// Check a running instance for processes that would block on a terminal
+    Doctor {
+        /// The instance to check
+        #[arg(value_name = "NAME", value_parser = parse_name)]
+        name: String,
+    },
+
+    /// Show how an instance was launched: configuration, command line, user and environment
+    Inspect {
+        /// The instance to show, running or not
+        #[arg(value_name = "NAME", value_parser = parse_name)]
+        name: String,
+
+        /// Print the whole record as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Show the past lifecycle events of a service: starts, crashes, restarts and stops
+    History {
+        /// The service, with all of its instances, or a single instance
+        #[arg(value_name = "NAME", value_parser = parse_name)]
+        name: String,
+
+        /// Only events from this long ago (e.g., "7d") or this RFC 3339 time on
+        #[arg(long, value_name = "DURATION|TIME", value_parser = parse_since)]
+        since: Option<chrono::DateTime<chrono::Local>>,
+
+        /// Print the events as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Remove control sockets of killed instances and run logs of instances that are gone
+    Clean {
+        /// Do not ask for confirmation, and act even without a terminal
+        #[arg(short, long, visible_alias = "yes", short_alias = 'y')]
+        force: bool,
+    },
+
+    /// Run a command detached on another host over SSH and print its PID there
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+    Remote {
+        #[command(subcommand)]
+        action: Option<RemoteAction>,
+
+        /// Host to run on, as given to ssh (e.g., user@host)
+        #[arg(required = true, value_name = "HOST")]
+        host: Option<String>,
+
+        /// Program and arguments to run, given after `--`
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+}
+
+/// Subcommands of `detach-rs remote` for a command started on a host earlier.
+#[derive(clap::Subcommand, Debug)]
+pub enum RemoteAction {
+    /// Print the output of a remote command
+    Logs {
+        /// Host the command runs on
+        host: String,
+
+        /// PID printed when the command was started
+        pid: u32,
+
+        /// Keep printing new output as it is written
+        #[arg(short, long)]
+        follow: bool,
+    },
+
+    /// Stop a remote command with SIGTERM
+    Stop {
+        /// Host the command runs on
+        host: String,
+
+        /// PID printed when the command was started
+        pid: u32,
+    },
+}
+
+fn parse_restart_at(input: &str) -> Result<RestartSchedule, String> {
+    RestartSchedule::parse(input).map_err(|e| e.to_string())
+}
+
+fn parse_timezone(input: &str) -> Result<schedule::Timezone, String> {
+    schedule::Timezone::parse(input).map_err(|e| e.to_string())
+}
+
+fn parse_cores(input: &str) -> Result<CoreDumps, String> {
+    match CoreDumps::parse(input).map_err(|e| e.to_string())? {
+        // Resolve now: the daemon changes its working directory to `/`
+        CoreDumps::Dir(dir) => std::path::absolute(&dir)
+            .map(CoreDumps::Dir)
+            .map_err(|e| e.to_string()),
+        mode => Ok(mode),
+    }
+}
+
+#[cfg(unix)]
+fn parse_signal(input: &str) -> Result<i32, String> {
+    signal::parse_signal(input).map_err(|e| e.to_string())
+}
+
+fn parse_seccomp(input: &str) -> Result<SeccompProfile, String> {
+    match SeccompProfile::parse(input).map_err(|e| e.to_string())? {
+        // Resolve now: the daemon changes its working directory to `/`
+        SeccompProfile::File(path) => std::path::absolute(&path)
+            .map(SeccompProfile::File)
+            .map_err(|e| e.to_string()),
+        profile => Ok(profile),
+    }
+}
+
+fn parse_absolute(input: &str) -> Result<PathBuf, String> {
+    // Resolve now: the daemon changes its working directory to `/`
+    std::path::absolute(input).map_err(|e| e.to_string())
+}
+
+#[cfg(unix)]
+fn parse_owner(input: &str) -> Result<compat::Owner, String> {
+    compat::Owner::parse(input).map_err(|e| e.to_string())
+}
+
+#[cfg(unix)]
+fn parse_mode(input: &str) -> Result<u32, String> {
+    match u32::from_str_radix(input.trim_start_matches("0o"), 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!(
+            "Expected an octal mode such as 750, got \"{}\"",
+            input
+        )),
+    }
+}
+
+fn parse_tag(input: &str) -> Result<(String, String), String> {
+    match input.split_once('=') {
+        Some((key, value))
+            if !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) =>
+        {
+            Ok((key.to_string(), value.to_string()))
+        }
+        _ => Err(format!(
+            "Expected KEY=VALUE with a key of letters, digits, '-', '_' and '.', got \"{}\"",
+            input
+        )),
+    }
+}
+
+fn parse_name(input: &str) -> Result<String, String> {
+    config::validate_service_name(input)
+        .map(|()| input.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn parse_os_log(input: &str) -> Result<OsLogTarget, String> {
+    OsLogTarget::parse(input).map_err(|e| e.to_string())
+}
+
+fn parse_delay(input: &str) -> Result<std::time::Duration, String> {
+    schedule::parse_duration(input).map_err(|e| e.to_string())
+}
+
+fn parse_alert_threshold(input: &str) -> Result<alert::Threshold, String> {
+    alert::Threshold::parse(input).map_err(|e| e.to_string())
+}
+
+fn parse_since(input: &str) -> Result<chrono::DateTime<chrono::Local>, String> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(input) {
+        return Ok(time.with_timezone(&chrono::Local));
+    }
+    let ago = parse_delay(input).map_err(|_| {
+        format!(
+            "expected a duration like 7d or an RFC 3339 time, got \"{}\"",
+            input
+        )
+    })?;
+    chrono::Duration::from_std(ago)
+        .ok()
+        .and_then(|ago| chrono::Local::now().checked_sub_signed(ago))
+        .ok_or_else(|| format!("{} is too long ago", input))
+}
 
+fn parse_size(input: &str) -> Result<u64, String> {
+    limits::parse_size(input).map_err(|e| e.to_string())
+}
 
-#[derive(Parser, Debug)]
-#[command(author, version, about = "A detached Rust background service")]
-pub struct Args {
-    /// Run the process in the background
-    #[arg(long, default_value_t = false)]
-    pub detach: bool,
+fn parse_jitter(input: &str) -> Result<schedule::Jitter, String> {
+    schedule::Jitter::parse(input).map_err(|e| e.to_string())
+}
 
-    /// Run the process in the foreground (disable detachment)
-    #[arg(long = "no-detach")]
-    pub no_detach: bool,
+fn parse_until(input: &str) -> Result<chrono::DateTime<chrono::Local>, String> {
+    schedule::parse_deadline(input, chrono::Local::now()).map_err(|e| e.to_string())
+}
 
-    /// tail logging
-    #[arg(long, default_value_t = false, conflicts_with = "detach")]
-    pub tail: bool,
+/// Writes a completion script for `shell` to stdout.
+///
+/// The script is generated from the `Args` definition, so it always matches the flags the
+/// binary actually accepts.
+///
+/// # Arguments
+/// - `shell`: The shell to generate completions for.
+/// - `bin_name`: The name the binary is invoked as (e.g., "detach-rs").
+pub fn print_completions(shell: clap_complete::Shell, bin_name: &str) {
+    use clap::CommandFactory;
 
-    /// Path to the log file
-    //TODO handle canonical relative path
-    #[arg(long, default_value = "./detach.log")]
-    pub log_file: PathBuf,
+    let mut cmd = Args::command();
+    clap_complete::generate(shell, &mut cmd, bin_name, &mut std::io::stdout());
+}
 
-    /// Timeout after a specified number of seconds
-    #[arg(long, short, value_name = "SECONDS")]
-    pub timeout: Option<u64>,
+impl Args {
+    /// What is done to output captured in log files: `--max-line-length` and
+    /// `--binary-output`, or `None` if that is nothing.
+    pub fn sanitize(&self) -> Option<sanitize::Sanitize> {
+        let max_line = usize::try_from(self.max_line_length).unwrap_or(usize::MAX);
+        let rules = sanitize::Sanitize {
+            max_line: (max_line > 0).then_some(max_line),
+            binary: self.binary_output,
+        };
+        (rules.max_line.is_some() || rules.binary != sanitize::BinaryOutput::Raw).then_some(rules)
+    }
 
-    /// Set the logging level (e.g., "error", "warn", "info", "debug", "trace")
-    #[arg(long, short, value_name = "LEVEL", value_enum)]
-    pub logging: Option<log::LevelFilter>,
+    /// The calendar `--restart-at` is read in: `--timezone`, `--dst-gap` and `--dst-repeat`.
+    pub fn calendar(&self) -> schedule::Calendar {
+        schedule::Calendar {
+            timezone: self.timezone,
+            gap: self.dst_gap,
+            repeat: self.dst_repeat,
+        }
+    }
 
-    /// Command to run
-    #[arg(long, value_name = "COMMAND", conflicts_with_all = ["detach", "tail"])]
-    pub command: Option<String>,
+    /// The timeout for a single command execution: `--run-timeout`, else `--timeout`.
+    pub fn run_timeout(&self) -> Option<u64> {
+        self.run_timeout.or(self.daemon.timeout)
+    }
+
+    /// The lifetime of the whole process in seconds from now: `--daemon-timeout` (else
+    /// `--timeout`), capped by the time left until `--until`.
+    pub fn daemon_timeout(&self) -> Option<u64> {
+        let timeout = self.daemon_timeout.or(self.daemon.timeout);
+        let until = self
+            .until
+            .map(|deadline| schedule::seconds_until(deadline, chrono::Local::now()));
+        match (timeout, until) {
+            (Some(timeout), Some(until)) => Some(timeout.min(until)),
+            (timeout, until) => timeout.or(until),
+        }
+    }
+
+    /// The delay before the service or command starts: `--delay` plus a fresh sample of
+    /// `--start-jitter`. Call it once and pass the result on, since the jitter is random.
+    pub fn start_delay(&self) -> Option<std::time::Duration> {
+        let jitter = self.start_jitter.map(|jitter| jitter.sample());
+        match (self.delay, jitter) {
+            (Some(delay), Some(jitter)) => Some(delay + jitter),
+            (delay, jitter) => delay.or(jitter),
+        }
+    }
+
+    /// What is left of the daemon lifetime once `start_delay` is over.
+    pub fn service_lifetime(&self, start_delay: Option<std::time::Duration>) -> Option<u64> {
+        let delay = start_delay.map_or(0, |delay| delay.as_secs());
+        self.daemon_timeout()
+            .map(|timeout| timeout.saturating_sub(delay))
+    }
+
+    /// The runtime watchdog requested with `--watchdog` and `--watchdog-abort`.
+    pub fn watchdog(&self) -> Option<Watchdog> {
+        self.watchdog.map(|stall| Watchdog {
+            stall,
+            abort: self.watchdog_abort,
+        })
+    }
+
+    /// The limit that applies to a single `--command` run, whichever of the run timeout
+    /// and the daemon lifetime left after `start_delay` expires first.
+    pub fn command_timeout(&self, start_delay: Option<std::time::Duration>) -> Option<u64> {
+        match (self.run_timeout(), self.service_lifetime(start_delay)) {
+            (Some(run), Some(daemon)) => Some(run.min(daemon)),
+            (run, daemon) => run.or(daemon),
+        }
+    }
+}
+
+/// Resolves the `--log-file` argument to the absolute path logging should use.
+///
+/// Without `--log-file`, a timestamped `detach-<YYYYmmdd-HHMMSS>.log` in
+/// `config::log_dir()` is used so consecutive runs do not share a file; if no log
+/// directory can be determined (no `HOME`), it goes in the current directory instead.
+/// Relative paths are resolved against the current directory, which matters because
+/// `daemonize` changes it to `/`. Absolute paths are returned unchanged.
+///
+/// # Arguments
+/// - `log_file`: The path passed with `--log-file`, if any.
+///
+/// # Returns
+/// - `Ok(PathBuf)`: The absolute log file path.
+/// - `Err(anyhow::Error)`: If the current directory cannot be determined.
+pub fn resolve_log_path(log_file: Option<&Path>) -> Result<PathBuf, anyhow::Error> {
+    let log_file_path = match log_file {
+        None => {
+            // Without a path, use a timestamped file in the platform's log directory
+            let now = chrono::Local::now();
+            let timestamp_str = now.format("%Y%m%d-%H%M%S").to_string();
+            let timestamped_filename = format!("detach-{}.log", timestamp_str);
+            match config::log_dir() {
+                Ok(dir) => dir.join(timestamped_filename),
+                Err(_) => std::env::current_dir()?.join(timestamped_filename),
+            }
+        }
+        // If a custom relative path is provided, resolve it
+        Some(log_file) if log_file.is_relative() => std::env::current_dir()?.join(log_file),
+        // If an absolute path is provided, use it as-is
+        Some(log_file) => log_file.to_path_buf(),
+    };
+    Ok(log_file_path)
+}
+
+/// Resolves the effective logging level from `--logging` and `-v`.
+///
+/// The level given with `--logging` (or `Info` when absent) is raised by one step for
+/// every `-v`, saturating at `Trace`. This is the level used for the log file.
+///
+/// # Arguments
+/// - `logging`: The level passed with `--logging`, if any.
+/// - `verbose`: The number of times `-v` was given.
+///
+/// # Returns
+/// The `log::LevelFilter` to pass to `setup_logging`.
+pub fn resolve_level(logging: Option<log::LevelFilter>, verbose: u8) -> log::LevelFilter {
+    let base = logging.unwrap_or(log::LevelFilter::Info);
+    log::LevelFilter::iter()
+        .nth(base as usize + verbose as usize)
+        .unwrap_or(log::LevelFilter::Trace)
+}
+
+/// Resolves the console logging level from `--console-level` and `-q`.
+///
+/// `--quiet` always wins and yields `Error`, leaving the log file untouched. Otherwise
+/// `--console-level` is used as given; `None` means the console follows the file level.
+///
+/// # Arguments
+/// - `console_level`: The level passed with `--console-level`, if any.
+/// - `quiet`: Whether `--quiet` was given.
+///
+/// # Returns
+/// The optional console `log::LevelFilter` to pass to `setup_logging`.
+pub fn resolve_console_level(
+    console_level: Option<log::LevelFilter>,
+    quiet: bool,
+) -> Option<log::LevelFilter> {
+    if quiet {
+        Some(log::LevelFilter::Error)
+    } else {
+        console_level
+    }
 }
 
 #[cfg(unix)]
@@ -107,91 +1906,56 @@ use std::os::unix::io::AsRawFd;
 
 /// Executes a given command string and exits the process with the command's exit status.
 ///
-/// This function sets up logging, executes the command using `sh -c`, and
-/// then terminates the current process, returning the command's exit code.
+/// This function executes the command using `sh -c` via `run_command`, inheriting the
+/// caller's stdout and stderr, and turns a timeout or a non-zero exit status into an error
+/// so `main` can propagate it as the process exit status.
+///
+/// Logging is only initialized when `logging` is `Some`. Programs that already installed
+/// their own logger (log4rs, env_logger, a tracing bridge, ...) should pass `None`; the
+/// command's progress is then reported through whatever logger is active.
 ///
 /// # Arguments
 /// - `cmd_str`: The command string to be executed (e.g., "ls -la", "echo hello | grep he").
-/// - `log_file_path`: The path to the log file for setting up logging.
-/// - `log_level`: The minimum log level to use for output.
+/// - `logging`: Optional logging configuration to initialize before running the command.
+/// - `timeout_seconds`: Optional number of seconds after which the command is interrupted.
 ///
 /// # Returns
-/// This function does not return `Result` in the traditional sense, as it
-/// explicitly calls `std::process::exit()`. It returns `()` for compilation.
+/// - `Ok(())`: The command exited successfully.
+/// - `Err(anyhow::Error)`: The command failed, timed out, or could not be started.
 pub async fn run_command_and_exit(
     cmd_str: String,
-    _log_file_path: &PathBuf, // Marked as unused
-    _log_level: log::LevelFilter, // Marked as unused
+    logging: Option<&LoggingConfig>,
     timeout_seconds: Option<u64>,
 ) -> anyhow::Result<()> {
+    if let Some(config) = logging {
+        config.init()?;
+    }
     info!("Executing command: \"{}\"", cmd_str);
 
-
-
-    let mut command = Command::new("sh") // Use sh to allow complex commands
-        .arg("-c")
-        .arg(&cmd_str)
-        .spawn()?; // Use spawn instead of status directly
-
-    let status_result = if let Some(seconds) = timeout_seconds {
+    if let Some(seconds) = timeout_seconds {
         info!("Command will timeout after {} seconds.", seconds);
-        match timeout(TokioDuration::from_secs(seconds), command.wait()).await {
-            Ok(Ok(status)) => Ok(status), // Command completed within timeout
-            Ok(Err(e)) => Err(anyhow::anyhow!("Failed to wait for command: {}", e)), // Error waiting for command
-                        Err(_elapsed) => { // Timeout occurred
-                warn!(
-                    "Command timed out after {} seconds. Killing process.",
-                    seconds
-                );
-                #[cfg(unix)]
-                {
-                    warn!(
-                        "Command timed out after {} seconds. Attempting graceful shutdown (SIGINT).",
-                        seconds
-                    );
-                    let pid = command.id().expect("Failed to get child process ID");
-                    unsafe {
-                        kill(pid as i32, SIGINT);
-                    }
-
-                    // Give the process a short grace period to shut down gracefully
-                    tokio::time::sleep(TokioDuration::from_millis(2000)).await;
-
-                    // Check if the command is still running
-                    if command.try_wait()?.is_none() {
-                        warn!("Process did not exit after SIGINT. Sending SIGKILL.");
-                        command.kill().await?; // Force kill
-                    }
-                }
-                #[cfg(not(unix))]
-                {
-                    command.kill().await?; // Kill the process
-                }
-                command.wait().await?; // Wait for it to be killed or exit
-                return Err(anyhow::anyhow!("Command timed out.")); // Indicate timeout as an error
-            }
-        }
-    } else {
-        Ok(command.wait().await?)
+    }
+    let opts = RunOptions {
+        timeout: timeout_seconds.map(TokioDuration::from_secs),
+        reload: Some(ServiceContext::current().subscribe_reload()),
+        tail: ServiceContext::current().output_tail(),
+        ..RunOptions::default()
     };
+    let outcome = run_command(&cmd_str, opts).await?;
 
-        let status_result_unwrapped = status_result?;
-
-    
-
-        if status_result_unwrapped.success() {
-
-            info!("Command executed successfully.");
-
-            Ok(())
-
-        } else {
-
-            let exit_code = status_result_unwrapped.code().unwrap_or(1);
-
-            Err(anyhow::anyhow!("Command failed with exit code: {}", exit_code))
-
-        }
+    if outcome.timed_out {
+        return Err(anyhow::anyhow!("Command timed out.")); // Indicate timeout as an error
+    }
+    if outcome.output_exceeded {
+        return Err(anyhow::anyhow!("Command exceeded its output limit."));
+    }
+    let reason = outcome.exit_reason();
+    if reason.success() {
+        info!("Command executed successfully.");
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("Command failed: {}", reason))
+    }
 }
 
 /// Performs the double-fork routine to completely detach a process from its controlling terminal.
@@ -263,7 +2027,10 @@ pub async fn run_command_and_exit(
 /// # Safety:
 ///
 /// This function uses `unsafe` blocks for `fork`, `setsid`, and `dup2` calls, which are POSIX
-/// system calls. Care has been taken to ensure their correct usage for daemonization.
+/// system calls. Care has been taken to ensure their correct usage for daemonization:
+/// between the forks and the start of the runtime, the children make nothing but
+/// async-signal-safe system calls, so a lock held by another thread at the time of the
+/// fork cannot deadlock them.
 #[cfg(unix)]
 pub fn daemonize<F>(
     _log_path: &PathBuf, // Marked as unused
@@ -274,77 +2041,205 @@ pub fn daemonize<F>(
 where
     F: std::future::Future<Output = Result<(), anyhow::Error>> + Send + 'static,
 {
+    detach_process()?;
+
+    // IMPORTANT: Re-initialize tokio runtime AFTER daemonization
+    // This prevents issues with forking a multi-threaded runtime.
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    // Boxed so the service future, which grows with every wrapper around it, is not
+    // copied through the frames of the runtime on the main thread's stack.
+    rt.block_on(run_daemon(timeout, Box::pin(service_future)));
+    // This part is unreachable as std::process::exit(0) is called above.
+    // However, Rust requires a return type for all branches.
+    unreachable!()
+}
+
+/// Daemonizes the process and runs a `!Send` service future on a `LocalSet`.
+///
+/// This is the counterpart of `daemonize` for services that cannot be moved across
+/// threads, such as actix-web servers or anything else built around `spawn_local`. The
+/// double-fork routine is identical; afterwards a current-thread `tokio` runtime is
+/// built and `service_future` is driven inside a `tokio::task::LocalSet`, so it may call
+/// `tokio::task::spawn_local`.
+///
+/// Timeout handling and process termination behave exactly as in `daemonize`.
+///
+/// # Parameters:
+///
+/// -   `log_path`: A `PathBuf` indicating the file where the daemon's logs should be written.
+/// -   `level`: A `log::LevelFilter` specifying the minimum level of log messages to record.
+/// -   `timeout`: An `Option<u64>` representing the maximum duration (in seconds) the daemon
+///     should run.
+/// -   `service_future`: The main logic of the daemon service. Unlike `daemonize`, it only
+///     needs to be `'static`, not `Send`.
+///
+/// # Returns:
+///
+/// -   `Ok(())`: Never returned in the daemon itself; see `daemonize`.
+/// -   `Err(anyhow::Error)`: If any step of the daemonization process fails.
+#[cfg(unix)]
+pub fn daemonize_local<F>(
+    _log_path: &PathBuf,      // Marked as unused
+    _level: log::LevelFilter, // Marked as unused
+    timeout: Option<u64>,
+    service_future: F,
+) -> Result<(), anyhow::Error>
+where
+    F: std::future::Future<Output = Result<(), anyhow::Error>> + 'static,
+{
+    detach_process()?;
+
+    // A current-thread runtime is all a LocalSet can use anyway, and it is built only
+    // after forking for the same reasons as in `daemonize`.
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = tokio::task::LocalSet::new();
+
+    local.block_on(&rt, run_daemon(timeout, Box::pin(service_future)));
+    // Unreachable for the same reason as in `daemonize`.
+    unreachable!()
+}
+
+/// Runs the double-fork routine described on `daemonize`.
+///
+/// Only the grandchild returns from this function; both intermediate parents exit. It
+/// refuses to fork from inside a `tokio` runtime, because the runtime's worker threads
+/// would not survive the fork and the daemon would hang on the first lock they held.
+///
+/// Locks held by other threads of the parent, including the allocator's and the one
+/// around Rust's stdout, stay locked in the children. So everything the children need is
+/// prepared before the first fork, and from there until this function returns they only
+/// make async-signal-safe system calls: no allocation, no locks, no logging. The session
+/// leader leaves with `_exit`, skipping the exit handlers and stdout flush of
+/// `std::process::exit`, and a child that fails reports it with a raw `write` to its
+/// stderr before exiting. Logging and runtime setup come afterwards, in the daemon.
+#[cfg(unix)]
+fn detach_process() -> Result<(), anyhow::Error> {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return Err(anyhow::anyhow!(
+            "daemonize must be called before a tokio runtime is started"
+        ));
+    }
+    // Audits the process if requested and keeps signals blocked until the daemon is set up
+    let guard = forkcheck::PreForkGuard::new()?;
+    let wait = wait::take_wait();
+    let exit_pipe = wait.as_ref().map(|_| wait::exit_pipe()).transpose()?;
+    let dev_null = StdFile::open("/dev/null")
+        .map_err(|e| anyhow::anyhow!("Failed to open /dev/null: {}", e))?;
+    let fd = dev_null.as_raw_fd();
+
     unsafe {
         // 1. First fork: Parent exits, child continues
         let pid = fork();
         if pid < 0 {
-            return Err(anyhow::anyhow!("First fork failed"));
+            return Err(anyhow::anyhow!(
+                "First fork failed: {}",
+                std::io::Error::last_os_error()
+            ));
         }
         if pid > 0 {
-            std::process::exit(0);
+            // The launching process may stay to watch the daemon start (see `wait`)
+            drop(guard);
+            let code = match (wait, exit_pipe) {
+                (Some(wait), Some((exited, running))) => {
+                    drop(running);
+                    wait.run(pid, exited)
+                }
+                _ => 0,
+            };
+            std::process::exit(code);
         }
 
         // 2. Create a new session to lose the controlling TTY
         if setsid() < 0 {
-            return Err(anyhow::anyhow!("Failed to create new session"));
+            exit_child(b"detach: failed to create a new session\n");
         }
 
         // 3. Second fork: Prevents the process from re-acquiring a TTY
         let pid = fork();
         if pid < 0 {
-            return Err(anyhow::anyhow!("Second fork failed"));
+            exit_child(b"detach: second fork failed\n");
         }
         if pid > 0 {
-            std::process::exit(0);
+            libc::_exit(0);
         }
 
         // 4. Change working directory to root to avoid locking the mount point
-        std::env::set_current_dir("/")?;
+        if libc::chdir(c"/".as_ptr()) < 0 {
+            exit_child(b"detach: failed to change directory to /\n");
+        }
 
         // 5. Redirect standard I/O to /dev/null
-        let dev_null = StdFile::open("/dev/null")?;
-        let fd = dev_null.as_raw_fd();
         dup2(fd, STDIN_FILENO);
         dup2(fd, STDOUT_FILENO);
         dup2(fd, STDERR_FILENO);
     }
+    // The daemon holds the write end of the exit pipe until it exits
+    if let Some((exited, running)) = exit_pipe {
+        drop(exited);
+        wait::hold(running);
+    }
+    Ok(())
+}
 
-    // IMPORTANT: Re-initialize tokio runtime AFTER daemonization
-    // This prevents issues with forking a multi-threaded runtime.
-    let rt = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap();
-
-    rt.block_on(async {
-        use log::{debug, info, trace, warn};
-        use tokio::time::sleep;
+/// Ends a child of `detach_process` that cannot continue, after writing `message` to its
+/// stderr. Only makes async-signal-safe system calls.
+#[cfg(unix)]
+unsafe fn exit_child(message: &[u8]) -> ! {
+    unsafe {
+        libc::write(STDERR_FILENO, message.as_ptr().cast(), message.len());
+        libc::_exit(1)
+    }
+}
 
+/// Drives the service future inside the daemon until it finishes or times out, then exits.
+#[cfg(unix)]
+async fn run_daemon<F>(timeout: Option<u64>, service_future: F)
+where
+    F: std::future::Future<Output = Result<(), anyhow::Error>>,
+{
+    use log::{debug, info, trace, warn};
 
-        debug!("Daemon process started. PID: {}", std::process::id());
-        trace!("Daemon process started. PID: {}", std::process::id());
-        warn!("Daemon process started. PID: {}", std::process::id());
+    debug!("Daemon process started. PID: {}", std::process::id());
+    trace!("Daemon process started. PID: {}", std::process::id());
+    warn!("Daemon process started. PID: {}", std::process::id());
 
-        if let Some(timeout_seconds) = timeout {
-            debug!("Setting timeout for {} seconds.", timeout_seconds);
-            tokio::select! {
-                _ = service_future => {
-                    debug!("Service future finished before timeout.");
-                }
-                _ = sleep(TokioDuration::from_secs(timeout_seconds)) => { // Use TokioDuration here
-                    debug!("Timeout reached after {} seconds. Terminating service.", timeout_seconds);
-                }
-            }
-        } else {
-            service_future.await.expect("Service future failed"); // Unwraps Result, will panic on error
+    if let Some(timeout_seconds) = timeout {
+        debug!("Setting timeout for {} seconds.", timeout_seconds);
+        serve_until_timeout(&SystemClock, timeout_seconds, service_future).await;
+    } else {
+        let result = service_future.await;
+        if result.is_err() {
+            wait::report_exit(1);
         }
+        result.expect("Service future failed"); // Unwraps Result, will panic on error
+    }
 
-        info!("Daemon process shutting down.");
-        std::process::exit(0);
-    });
-    // This part is unreachable as std::process::exit(0) is called above.
-    // However, Rust requires a return type for all branches.
-    unreachable!()
+    info!("Daemon process shutting down.");
+    wait::report_exit(0);
+    std::process::exit(0);
+}
+
+/// Drives the service future until it finishes or `timeout_seconds` have passed on `clock`.
+#[cfg(unix)]
+async fn serve_until_timeout<F>(clock: &dyn Clock, timeout_seconds: u64, service_future: F)
+where
+    F: std::future::Future<Output = Result<(), anyhow::Error>>,
+{
+    let limit = TokioDuration::from_secs(timeout_seconds);
+    match clock::timeout(clock, limit, service_future).await {
+        Ok(_) => log::debug!("Service future finished before timeout."),
+        Err(_) => log::debug!(
+            "Timeout reached after {} seconds. Terminating service.",
+            timeout_seconds
+        ),
+    }
 }
 
 #[cfg(not(unix))]
@@ -361,56 +2256,245 @@ where
     Ok(()) // Or return an error if you want to explicitly signal failure
 }
 
-#[cfg(unix)]
-pub fn setup_logging(
-    path: &PathBuf,
-    level: log::LevelFilter,
-    to_console: bool,
-) -> Result<(), anyhow::Error> {
-    use log4rs::append::console::ConsoleAppender;
-    use log4rs::append::file::FileAppender;
-    use log4rs::config::{Appender, Config, Root};
-    use log4rs::encode::pattern::PatternEncoder;
-
-    let logfile = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d} - {l} - {m}\n")))
-        .build(path)?;
+#[cfg(not(unix))]
+pub fn daemonize_local<F>(
+    _log_path: &PathBuf,      // Marked as unused
+    _level: log::LevelFilter, // Marked as unused
+    _timeout: Option<u64>,
+    _service_future: F,
+) -> Result<(), anyhow::Error>
+where
+    F: std::future::Future<Output = Result<(), anyhow::Error>> + 'static,
+{
+    eprintln!("Daemonization is not supported on this operating system.");
+    Ok(())
+}
 
-    let mut config_builder = Config::builder();
-    let mut root_builder = Root::builder();
+/// Everything `setup_logging` needs, bundled so it can be passed around or injected.
+///
+/// Library entry points such as `run_command_and_exit` accept an `Option<&LoggingConfig>`
+/// and only initialize logging when one is given, so they can be used from programs that
+/// set up their own logging first.
+#[derive(Debug, Clone)]
+pub struct LoggingConfig {
+    /// The log file to append to
+    pub path: PathBuf,
+    /// The minimum level written to the log file
+    pub level: log::LevelFilter,
+    /// Whether to also log to the console
+    pub to_console: bool,
+    /// The minimum level written to the console; `None` uses `level`
+    pub console_level: Option<log::LevelFilter>,
+    /// Whether the console appender writes to `stdout` or `stderr`
+    pub console_stream: ConsoleStream,
+    /// How log lines are rendered
+    pub format: LogFormat,
+    /// Metadata added to every line written to the file and the console
+    pub fields: Vec<LogField>,
+    /// The service name written for `LogField::Service`
+    pub service: Option<String>,
+    /// Secrets to remove from every record before it is written
+    pub redactor: Option<std::sync::Arc<Redactor>>,
+    /// Also log to the macOS unified logging system under this subsystem/category
+    pub os_log: Option<OsLogTarget>,
+    /// Where records go; the file and console settings apply to `LogDestination::File`
+    pub destination: LogDestination,
+    /// What happens to records the log file cannot take (see `logfile`)
+    pub on_error: logfile::OnLogError,
+}
 
-    config_builder =
-        config_builder.appender(Appender::builder().build("logfile", Box::new(logfile)));
-    root_builder = root_builder.appender("logfile");
+impl LoggingConfig {
+    /// Creates a file-only configuration logging at `level` to `path`.
+    pub fn new(path: impl Into<PathBuf>, level: log::LevelFilter) -> Self {
+        Self {
+            path: path.into(),
+            level,
+            to_console: false,
+            console_level: None,
+            console_stream: ConsoleStream::default(),
+            format: LogFormat::default(),
+            fields: Vec::new(),
+            service: None,
+            redactor: None,
+            os_log: None,
+            destination: LogDestination::File,
+            on_error: logfile::OnLogError::default(),
+        }
+    }
 
-    if to_console {
-        let stdout = ConsoleAppender::builder()
-            .encoder(Box::new(PatternEncoder::new("{d} - {l} - {m}\n")))
-            .build();
-        config_builder =
-            config_builder.appender(Appender::builder().build("stdout", Box::new(stdout)));
-        root_builder = root_builder.appender("stdout");
+    /// Installs this configuration as the global logger.
+    ///
+    /// The file appender and, with `to_console`, a console appender each carry their own
+    /// threshold filter, so the file and the console can run at different levels. With
+    /// `os_log` a third appender writes to the macOS unified log at the file's level. The
+    /// root logger is set to the most verbose of them.
+    ///
+    /// `destination` replaces the file appender: `Journald` logs only to the journal (the
+    /// console would end up there twice), `OsLog` logs to `os_log` and the console.
+    ///
+    /// Off Unix, where there is no journal, `Journald` falls back to the log file.
+    ///
+    /// A record the log file cannot take is handled as `on_error` says, instead of being
+    /// reported on stderr and lost (see `logfile`).
+    ///
+    /// Fails if a global logger has already been installed.
+    pub fn init(&self) -> Result<(), anyhow::Error> {
+        log4rs::init_config(self.build()?)?;
+        Ok(())
     }
 
-    let config = config_builder.build(root_builder.build(level))?;
+    /// Builds the log4rs configuration `init` installs, opening the log file, without
+    /// installing it.
+    pub fn build(&self) -> Result<log4rs::Config, anyhow::Error> {
+        use log4rs::append::console::{ConsoleAppender, Target};
+        use log4rs::config::{Appender, Config, Root};
+        use log4rs::encode::Encode;
+        use log4rs::filter::threshold::ThresholdFilter;
 
-    log4rs::init_config(config)?;
-    Ok(())
-}
+        let level = self.level;
+        let redacted = |inner: Box<dyn Encode>| -> Box<dyn Encode> {
+            match &self.redactor {
+                Some(redactor) if !redactor.is_empty() => {
+                    Box::new(redact::RedactingEncoder::new(inner, redactor.clone()))
+                }
+                _ => inner,
+            }
+        };
+        let encoder = || {
+            let line =
+                LineEncoder::new(self.format).with_fields(&self.fields, self.service.as_deref());
+            redacted(Box::new(line))
+        };
+        // Journal and os_log record their own time and level; only the message is sent.
+        let message = || {
+            redacted(Box::new(log4rs::encode::pattern::PatternEncoder::new(
+                "{m}",
+            )))
+        };
+        let destination = match self.destination.resolve() {
+            LogDestination::OsLog if !cfg!(target_os = "macos") => {
+                eprintln!("Logging to os_log is only available on macOS; logging to the file.");
+                LogDestination::File
+            }
+            LogDestination::Journald if !cfg!(unix) => {
+                eprintln!("Logging to the journal is only available on Unix; logging to the file.");
+                LogDestination::File
+            }
+            destination => destination,
+        };
 
-#[cfg(not(unix))]
+        let mut config_builder = Config::builder();
+        let mut root_builder = Root::builder();
+        let mut root_level = level;
+
+        match destination {
+            #[cfg(unix)]
+            LogDestination::Journald => {
+                let journal = journald::JournaldAppender::new("detach-rs", message())
+                    .map_err(|e| anyhow::anyhow!("Failed to connect to the journal: {}", e))?;
+                config_builder = config_builder.appender(
+                    Appender::builder()
+                        .filter(Box::new(ThresholdFilter::new(level)))
+                        .build("journald", Box::new(journal)),
+                );
+                root_builder = root_builder.appender("journald");
+            }
+            LogDestination::File => {
+                let path = self.path.display();
+                let logfile =
+                    logfile::LogFileAppender::new(&self.path, encoder(), self.on_error)
+                        .map_err(|e| anyhow::anyhow!("Failed to open log file {}: {}", path, e))?;
+                config_builder = config_builder.appender(
+                    Appender::builder()
+                        .filter(Box::new(ThresholdFilter::new(level)))
+                        .build("logfile", Box::new(logfile)),
+                );
+                root_builder = root_builder.appender("logfile");
+            }
+            #[cfg(not(unix))]
+            LogDestination::Journald => {}
+            LogDestination::OsLog | LogDestination::Auto => {}
+        }
+
+        if self.to_console && destination != LogDestination::Journald {
+            let console_level = self.console_level.unwrap_or(level);
+            root_level = root_level.max(console_level);
+            let target = match self.console_stream {
+                ConsoleStream::Stdout => Target::Stdout,
+                ConsoleStream::Stderr => Target::Stderr,
+            };
+            let stdout = ConsoleAppender::builder()
+                .encoder(encoder())
+                .target(target)
+                .build();
+            config_builder = config_builder.appender(
+                Appender::builder()
+                    .filter(Box::new(ThresholdFilter::new(console_level)))
+                    .build("stdout", Box::new(stdout)),
+            );
+            root_builder = root_builder.appender("stdout");
+        }
+
+        let os_log_target = match (&self.os_log, destination) {
+            (None, LogDestination::OsLog) => Some(OsLogTarget {
+                subsystem: "detach-rs".to_string(),
+                category: "default".to_string(),
+            }),
+            (target, _) => target.clone(),
+        };
+        if let Some(target) = &os_log_target {
+            #[cfg(target_os = "macos")]
+            {
+                let os_log = oslog::OsLogAppender::new(target, message())?;
+                config_builder = config_builder.appender(
+                    Appender::builder()
+                        .filter(Box::new(ThresholdFilter::new(level)))
+                        .build("os_log", Box::new(os_log)),
+                );
+                root_builder = root_builder.appender("os_log");
+            }
+            #[cfg(not(target_os = "macos"))]
+            eprintln!(
+                "Logging to os_log ({}) is only available on macOS; ignoring it.",
+                target.subsystem
+            );
+        }
+
+        Ok(config_builder.build(root_builder.build(root_level))?)
+    }
+}
 
+/// Initializes `log4rs` with a file appender and, optionally, a console appender.
+///
+/// A shorthand for `LoggingConfig::init`; options added since, such as `os_log`, are only
+/// available through `LoggingConfig`.
+///
+/// # Arguments
+/// - `path`: The log file to append to.
+/// - `level`: The minimum level written to the log file.
+/// - `to_console`: Whether to also log to the console.
+/// - `console_level`: The minimum level written to the console; `None` uses `level`.
+/// - `console_stream`: Whether the console appender writes to `stdout` or `stderr`.
+/// - `format`: How both appenders render log lines.
+/// - `redactor`: Secrets both appenders replace with `[REDACTED]` before writing.
 pub fn setup_logging(
-    _path: &PathBuf,
-    _level: log::LevelFilter,
-    _to_console: bool,
+    path: &PathBuf,
+    level: log::LevelFilter,
+    to_console: bool,
+    console_level: Option<log::LevelFilter>,
+    console_stream: ConsoleStream,
+    format: LogFormat,
+    redactor: Option<std::sync::Arc<Redactor>>,
 ) -> Result<(), anyhow::Error> {
-    eprintln!(
-        "File logging with log4rs is not supported on this operating system when daemonizing."
-    );
-    // For non-unix, if daemonize is called (which it won't be if cfg(not(unix)))
-    // then we would rely on main to setup a console logger if not tailing.
-    Ok(())
+    LoggingConfig {
+        to_console,
+        console_level,
+        console_stream,
+        format,
+        redactor,
+        ..LoggingConfig::new(path, level)
+    }
+    .init()
 }
 
 /// A default asynchronous service future that simulates a background task with heartbeats.
@@ -424,11 +2508,31 @@ pub fn setup_logging(
 /// - `Ok(())`: If the service completes its simulated task.
 /// - `Err(anyhow::Error)`: If an error occurs during its execution.
 pub async fn run_service_async() -> anyhow::Result<()> {
+    run_service_on(&SystemClock).await
+}
+
+/// `run_service_async`, with heartbeats timed by `clock` instead of the system clock.
+pub async fn run_service_on(clock: &dyn Clock) -> anyhow::Result<()> {
     use log::debug;
+    let heartbeats = ServiceContext::current().metrics().counter(
+        "detach_heartbeats_total",
+        "Heartbeats of the built-in service",
+    );
+    let mut reloads = ServiceContext::current().subscribe_reload();
     let mut count = 0;
     loop {
         debug!("Service heartbeat #{}", count);
-        tokio::time::sleep(TokioDuration::from_secs(10)).await;
+        heartbeats.inc();
+        let sleep = clock.sleep(TokioDuration::from_secs(10));
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => break,
+                Ok(()) = reloads.changed() => {
+                    info!("Reload requested; the built-in service has nothing to reload.");
+                }
+            }
+        }
         count += 1;
 
         if count > 100 {
@@ -439,3 +2543,114 @@ pub async fn run_service_async() -> anyhow::Result<()> {
     info!("Service shutting down.");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use log::LevelFilter;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("detach-rs").chain(args.iter().copied()))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn daemon_timeout_runs_on_the_clock() {
+        use std::task::{Context, Waker};
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let clock = MockClock::new(chrono::Local::now());
+        let service = std::future::pending();
+        let mut serving = Box::pin(serve_until_timeout(&clock, 60, service));
+        assert!(serving.as_mut().poll(&mut cx).is_pending());
+        clock.advance(TokioDuration::from_secs(59));
+        assert!(serving.as_mut().poll(&mut cx).is_pending());
+        clock.advance(TokioDuration::from_secs(1));
+        assert!(serving.as_mut().poll(&mut cx).is_ready());
+
+        let mut finished = Box::pin(serve_until_timeout(&clock, 60, async { Ok(()) }));
+        assert!(finished.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn each_verbose_raises_the_level_up_to_trace() {
+        assert_eq!(resolve_level(None, 0), LevelFilter::Info);
+        assert_eq!(resolve_level(None, 1), LevelFilter::Debug);
+        assert_eq!(resolve_level(None, 5), LevelFilter::Trace);
+        let given = |logging, verbose| resolve_level(Some(logging), verbose);
+        assert_eq!(given(LevelFilter::Error, 1), LevelFilter::Warn);
+        assert_eq!(given(LevelFilter::Off, 0), LevelFilter::Off);
+        assert_eq!(given(LevelFilter::Trace, 2), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn quiet_only_limits_the_console() {
+        let warn = Some(LevelFilter::Warn);
+        assert_eq!(resolve_console_level(None, false), None);
+        assert_eq!(resolve_console_level(warn, false), warn);
+        assert_eq!(resolve_console_level(None, true), Some(LevelFilter::Error));
+
+        let args = parse(&["-q", "--logging", "debug"]).unwrap();
+        assert_eq!(args.daemon.level(), LevelFilter::Debug);
+        let args = parse(&["-vv", "--logging", "warn"]).unwrap();
+        assert_eq!(args.daemon.level(), LevelFilter::Debug);
+        assert!(parse(&["-q", "-v"]).is_err());
+        assert!(parse(&["-q", "--console-level", "info"]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn modes_are_octal_permission_bits() {
+        assert_eq!(parse_mode("750"), Ok(0o750));
+        assert_eq!(parse_mode("0o2775"), Ok(0o2775));
+        assert!(parse_mode("800").is_err());
+        assert!(parse_mode("17777").is_err());
+        assert!(parse_mode("rwx").is_err());
+    }
+
+    #[test]
+    fn tags_need_a_plain_key() {
+        let tag = |key: &str, value: &str| Ok((key.to_string(), value.to_string()));
+        assert_eq!(parse_tag("env=prod"), tag("env", "prod"));
+        assert_eq!(parse_tag("build.id=a=b"), tag("build.id", "a=b"));
+        assert_eq!(parse_tag("empty="), tag("empty", ""));
+        assert!(parse_tag("=prod").is_err());
+        assert!(parse_tag("my env=prod").is_err());
+        assert!(parse_tag("env").is_err());
+    }
+
+    #[test]
+    fn since_is_a_duration_ago_or_a_time() {
+        let before = chrono::Local::now();
+        let since = parse_since("1h").unwrap();
+        let ago = before - since;
+        assert!(ago >= chrono::Duration::minutes(59) && ago <= chrono::Duration::hours(1));
+        let time = parse_since("2026-06-01T12:00:00Z").unwrap();
+        assert_eq!(time.naive_utc().to_string(), "2026-06-01 12:00:00");
+        assert!(parse_since("yesterday").is_err());
+    }
+
+    #[test]
+    fn relative_paths_are_resolved_before_daemonizing() {
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(parse_absolute("data"), Ok(cwd.join("data")));
+        match parse_seccomp("profile.json") {
+            Ok(SeccompProfile::File(path)) => assert_eq!(path, cwd.join("profile.json")),
+            other => panic!("{:?}", other),
+        }
+        let strict = parse_seccomp("strict");
+        assert!(matches!(strict, Ok(SeccompProfile::Strict)), "{:?}", strict);
+    }
+
+    #[test]
+    fn sanitize_is_off_only_when_both_rules_are() {
+        let rules = parse(&[]).unwrap().sanitize().unwrap();
+        assert_eq!(rules.max_line, Some(64 * 1024));
+        assert_eq!(rules.binary, sanitize::BinaryOutput::Escape);
+        let args = parse(&["--max-line-length", "0"]).unwrap();
+        assert_eq!(args.sanitize().unwrap().max_line, None);
+        let args = parse(&["--max-line-length", "0", "--binary-output", "raw"]).unwrap();
+        assert_eq!(args.sanitize(), None);
+    }
+}
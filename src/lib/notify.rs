@@ -0,0 +1,29 @@
+//! The systemd readiness/status notification protocol.
+//!
+//! A service started with `Type=notify` receives the path of a datagram socket in
+//! `NOTIFY_SOCKET`; messages such as `READY=1` or `STATUS=...` sent there show up in
+//! `systemctl status`. Outside systemd the variable is unset and notifications are no-ops.
+use std::os::unix::net::UnixDatagram;
+
+/// Sends `state` (e.g. `"READY=1"` or `"STATUS=Loading cache"`) to the service manager.
+///
+/// # Returns
+/// - `Ok(true)`: The notification was sent.
+/// - `Ok(false)`: `NOTIFY_SOCKET` is not set, so there is nobody to notify.
+/// - `Err(std::io::Error)`: The socket exists but could not be written to.
+pub fn sd_notify(state: &str) -> std::io::Result<bool> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(true);
+    }
+    socket.send_to(state.as_bytes(), path.as_ref())?;
+    Ok(true)
+}
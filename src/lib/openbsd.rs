@@ -0,0 +1,86 @@
+//! `pledge(2)` and `unveil(2)` for daemons on OpenBSD.
+//!
+//! OpenBSD daemons are expected to drop everything they do not need once they are set up:
+//! `unveil` hides all of the filesystem except the listed paths, `pledge` limits the
+//! process to groups of system calls ("promises"). The `promises` module has sets that
+//! fit the daemons this crate produces, and `compat::Daemonize` applies them in the
+//! daemon child with its `unveil` and `pledge` options.
+//!
+//! ```no_run
+//! use detach::compat::Daemonize;
+//! use detach::openbsd::promises;
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     Daemonize::new()
+//!         .pid_file("/var/run/myservice.pid")
+//!         .unveil("/var/log/myservice", "rwc")
+//!         .unveil("/etc/myservice.conf", "r")
+//!         .pledge(promises::NETWORK_DAEMON)
+//!         .start()?;
+//!     Ok(())
+//! }
+//! ```
+use std::ffi::CString;
+use std::path::Path;
+
+/// Promise sets for common kinds of daemons.
+pub mod promises {
+    /// Writes (and rotates) its own log files, takes file locks, nothing else
+    pub const DAEMON: &str = "stdio rpath wpath cpath flock";
+    /// `DAEMON`, plus serving and making TCP/UDP connections with name resolution
+    pub const NETWORK_DAEMON: &str = "stdio rpath wpath cpath flock inet dns";
+    /// `NETWORK_DAEMON`, plus starting and signalling commands, as `--command` does
+    pub const SUPERVISOR: &str = "stdio rpath wpath cpath flock inet dns proc exec";
+}
+
+/// Restricts the process to `promises`, and programs it executes to `exec_promises`.
+///
+/// `exec_promises` of `None` leaves executed programs unrestricted.
+///
+/// # Returns
+/// - `Ok(())`: The promises are in effect.
+/// - `Err(anyhow::Error)`: A promise is unknown, or widens an earlier pledge.
+pub fn pledge(promises: &str, exec_promises: Option<&str>) -> Result<(), anyhow::Error> {
+    let promises = CString::new(promises)?;
+    let exec_promises = exec_promises.map(CString::new).transpose()?;
+    let exec_ptr = exec_promises
+        .as_ref()
+        .map_or(std::ptr::null(), |promises| promises.as_ptr());
+    if unsafe { libc::pledge(promises.as_ptr(), exec_ptr) } < 0 {
+        return Err(anyhow::anyhow!(
+            "pledge(\"{}\") failed: {}",
+            promises.to_string_lossy(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Makes `path` visible with `permissions` (a combination of `r`, `w`, `x` and `c`).
+///
+/// After the first call everything not unveiled is hidden; `unveil_lock` prevents further
+/// changes.
+pub fn unveil(path: &Path, permissions: &str) -> Result<(), anyhow::Error> {
+    let c_path = CString::new(path.as_os_str().as_encoded_bytes())?;
+    let c_permissions = CString::new(permissions)?;
+    if unsafe { libc::unveil(c_path.as_ptr(), c_permissions.as_ptr()) } < 0 {
+        return Err(anyhow::anyhow!(
+            "unveil({}, \"{}\") failed: {}",
+            path.display(),
+            permissions,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Locks the unveiled paths so they can no longer be changed.
+pub fn unveil_lock() -> Result<(), anyhow::Error> {
+    if unsafe { libc::unveil(std::ptr::null(), std::ptr::null()) } < 0 {
+        return Err(anyhow::anyhow!(
+            "Failed to lock unveil: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
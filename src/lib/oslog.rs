@@ -0,0 +1,141 @@
+//! A log4rs appender for the macOS unified logging system (`os_log`).
+//!
+//! Records written through `OsLogAppender` show up in Console.app and in
+//! `log stream --predicate 'subsystem == "com.example.myservice"'`, next to the logs of
+//! the rest of the system, with log levels mapped to os_log types. The appender renders
+//! each record with its encoder and logs the result as a single public string.
+
+/// The subsystem and category records are filed under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OsLogTarget {
+    /// Reverse-DNS name of the program, e.g. `com.example.myservice`
+    pub subsystem: String,
+    /// Area within the program, e.g. `network`
+    pub category: String,
+}
+
+impl OsLogTarget {
+    /// Parses `SUBSYSTEM` or `SUBSYSTEM:CATEGORY`; the category defaults to `default`.
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let (subsystem, category) = input.split_once(':').unwrap_or((input, "default"));
+        if subsystem.trim().is_empty() || category.trim().is_empty() {
+            return Err(anyhow::anyhow!(
+                "Expected SUBSYSTEM or SUBSYSTEM:CATEGORY, got \"{}\"",
+                input
+            ));
+        }
+        Ok(OsLogTarget {
+            subsystem: subsystem.trim().to_string(),
+            category: category.trim().to_string(),
+        })
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub use appender::OsLogAppender;
+
+#[cfg(target_os = "macos")]
+mod appender {
+    use super::OsLogTarget;
+    use log4rs::append::Append;
+    use log4rs::encode::Encode;
+    use log4rs::encode::writer::simple::SimpleWriter;
+    use std::ffi::{CString, c_char, c_void};
+
+    const OS_LOG_TYPE_DEFAULT: u8 = 0x00;
+    const OS_LOG_TYPE_INFO: u8 = 0x01;
+    const OS_LOG_TYPE_DEBUG: u8 = 0x02;
+    const OS_LOG_TYPE_ERROR: u8 = 0x10;
+
+    unsafe extern "C" {
+        static __dso_handle: u8;
+        fn os_log_create(subsystem: *const c_char, category: *const c_char) -> *mut c_void;
+        fn os_log_type_enabled(log: *mut c_void, kind: u8) -> bool;
+        fn _os_log_impl(
+            dso: *const c_void,
+            log: *mut c_void,
+            kind: u8,
+            format: *const c_char,
+            buf: *const u8,
+            size: u32,
+        );
+    }
+
+    /// The format string `os_log(log, "%{public}s", message)` would use. The logging
+    /// system reads it back from the binary, so it has to live where clang puts it.
+    #[unsafe(link_section = "__TEXT,__oslogstring,cstring_literals")]
+    static FORMAT: [u8; 11] = *b"%{public}s\0";
+
+    /// An appender writing to `os_log`.
+    pub struct OsLogAppender {
+        log: *mut c_void,
+        encoder: Box<dyn Encode>,
+    }
+
+    // SAFETY: os_log objects are immutable and documented as thread-safe.
+    unsafe impl Send for OsLogAppender {}
+    unsafe impl Sync for OsLogAppender {}
+
+    impl std::fmt::Debug for OsLogAppender {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("OsLogAppender").finish_non_exhaustive()
+        }
+    }
+
+    impl OsLogAppender {
+        /// Creates an appender logging to `target`, rendering records with `encoder`.
+        pub fn new(target: &OsLogTarget, encoder: Box<dyn Encode>) -> Result<Self, anyhow::Error> {
+            let subsystem = CString::new(target.subsystem.as_str())?;
+            let category = CString::new(target.category.as_str())?;
+            let log = unsafe { os_log_create(subsystem.as_ptr(), category.as_ptr()) };
+            if log.is_null() {
+                return Err(anyhow::anyhow!("os_log_create failed"));
+            }
+            Ok(OsLogAppender { log, encoder })
+        }
+    }
+
+    impl Append for OsLogAppender {
+        fn append(&self, record: &log::Record) -> anyhow::Result<()> {
+            let kind = match record.level() {
+                log::Level::Error => OS_LOG_TYPE_ERROR,
+                log::Level::Warn => OS_LOG_TYPE_DEFAULT,
+                log::Level::Info => OS_LOG_TYPE_INFO,
+                log::Level::Debug | log::Level::Trace => OS_LOG_TYPE_DEBUG,
+            };
+            if !unsafe { os_log_type_enabled(self.log, kind) } {
+                return Ok(());
+            }
+            let mut writer = SimpleWriter(Vec::new());
+            self.encoder.encode(&mut writer, record)?;
+            let mut text = writer.0;
+            while text.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+                text.pop();
+            }
+            text.retain(|&b| b != 0);
+            let message = CString::new(text)?;
+
+            // The argument buffer for one public string: a header (flags: has non-scalar
+            // items, count: 1) followed by one item (string | public, size, pointer).
+            let mut buf = [0u8; 12];
+            buf[0] = 0x02;
+            buf[1] = 1;
+            buf[2] = 0x22;
+            buf[3] = 8;
+            buf[4..].copy_from_slice(&(message.as_ptr() as u64).to_ne_bytes());
+            unsafe {
+                _os_log_impl(
+                    &__dso_handle as *const u8 as *const c_void,
+                    self.log,
+                    kind,
+                    FORMAT.as_ptr() as *const c_char,
+                    buf.as_ptr(),
+                    buf.len() as u32,
+                );
+            }
+            Ok(())
+        }
+
+        fn flush(&self) {}
+    }
+}
@@ -0,0 +1,131 @@
+//! Handles on processes that stay valid across PID reuse.
+//!
+//! A PID names a process only until it has exited and been reaped; after that the kernel
+//! may give it to an unrelated process, and a `kill(pid, SIGKILL)` meant for the old one
+//! hits the new one. On Linux 5.3 and later a pidfd refers to one process for good:
+//! `pidfd_send_signal` fails with `ESRCH` once that process has exited instead of reaching
+//! a successor, and the descriptor becomes readable when it exits, whether it is a child
+//! of the caller or not. `ProcessHandle` uses a pidfd where the kernel offers one and
+//! falls back to `kill` and polling elsewhere.
+//!
+//! The supervisor keeps a handle on the running command (`ServiceContext::child`) and
+//! sends the forwarded signals and the kill after `--stop-timeout` through it, `adopt`
+//! waits for an adopted command with one, and `detach-rs stop --wait` uses one to wait
+//! for each stopped instance to exit. `status` reports which kind of handle the
+//! supervisor holds as `child_tracking`.
+use std::time::Duration;
+
+/// How often a process without a pidfd is checked for having exited.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A process, held by a pidfd where available and by its PID otherwise.
+#[derive(Debug)]
+pub struct ProcessHandle {
+    pid: u32,
+    #[cfg(target_os = "linux")]
+    fd: Option<std::os::fd::OwnedFd>,
+}
+
+impl ProcessHandle {
+    /// Opens a handle on the process `pid`.
+    ///
+    /// Falls back to the bare PID if the kernel has no `pidfd_open` (before Linux 5.3, or
+    /// outside Linux) or the process is gone already.
+    pub fn open(pid: u32) -> Self {
+        ProcessHandle {
+            pid,
+            #[cfg(target_os = "linux")]
+            fd: pidfd_open(pid),
+        }
+    }
+
+    /// The PID of the process.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Returns `true` if the process is held by a pidfd, so signals and exit
+    /// notifications cannot reach another process that reuses its PID.
+    pub fn has_pidfd(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        return self.fd.is_some();
+        #[cfg(not(target_os = "linux"))]
+        false
+    }
+
+    /// `"pidfd"` or `"pid"`, as `status` reports it.
+    pub fn kind(&self) -> &'static str {
+        if self.has_pidfd() { "pidfd" } else { "pid" }
+    }
+
+    /// Sends `signal` to the process.
+    ///
+    /// # Returns
+    /// - `Ok(())`: The signal was delivered.
+    /// - `Err(io::Error)`: If it was not, e.g. with `ESRCH` because the process has
+    ///   exited; with a pidfd this is also the answer once its PID has been reused.
+    #[cfg(unix)]
+    pub fn signal(&self, signal: i32) -> std::io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some(fd) = &self.fd {
+            use std::os::fd::AsRawFd;
+            // SAFETY: pidfd_send_signal only reads the descriptor, which `fd` keeps open.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_pidfd_send_signal,
+                    fd.as_raw_fd(),
+                    signal,
+                    std::ptr::null::<libc::siginfo_t>(),
+                    0,
+                )
+            };
+            return if ret < 0 {
+                Err(std::io::Error::last_os_error())
+            } else {
+                Ok(())
+            };
+        }
+        crate::signal::send_signal(self.pid, signal)
+    }
+
+    /// Returns `true` while the process exists (as a zombie included).
+    #[cfg(unix)]
+    pub fn is_alive(&self) -> bool {
+        match self.signal(0) {
+            Ok(()) => true,
+            Err(e) => e.raw_os_error() == Some(libc::EPERM),
+        }
+    }
+
+    /// Returns once the process has exited.
+    ///
+    /// With a pidfd this is a notification from the kernel; otherwise the PID is checked
+    /// every `POLL_INTERVAL`, which can miss an exit if the PID is reused in between.
+    #[cfg(unix)]
+    pub async fn exited(&self) {
+        #[cfg(target_os = "linux")]
+        if let Some(fd) = self.fd.as_ref().and_then(|fd| fd.try_clone().ok()) {
+            let interest = tokio::io::Interest::READABLE;
+            if let Ok(fd) = tokio::io::unix::AsyncFd::with_interest(fd, interest) {
+                // A pidfd becomes readable when its process exits, and stays so
+                let _ = fd.readable().await;
+                return;
+            }
+        }
+        while self.is_alive() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pidfd_open(pid: u32) -> Option<std::os::fd::OwnedFd> {
+    use std::os::fd::FromRawFd;
+    // SAFETY: pidfd_open takes a PID and flags and returns a new descriptor or -1.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return None;
+    }
+    // SAFETY: `fd` was just opened and is owned by nothing else.
+    Some(unsafe { std::os::fd::OwnedFd::from_raw_fd(fd as i32) })
+}
@@ -0,0 +1,170 @@
+//! The TCP ports a command listens on.
+//!
+//! A command that cannot bind its port fails right after starting, and under the
+//! supervisor it fails again after every restart. `--port` declares the ports up front,
+//! so they are checked before anything is started, and a port that is taken is reported
+//! with the process holding it, where that can be found out.
+//!
+//! `--port auto` picks a free port instead, which makes it easy to run several copies
+//! of a service side by side. Either way the command learns its ports from `$PORT` (see
+//! `environment`), and the instance reports them in its `status`.
+
+/// Returns `true` if nothing listens on TCP `port`, on any address.
+///
+/// Checked by binding the wildcard addresses, which conflicts with any socket bound to
+/// the port. Errors other than "address in use" (no IPv6, a privileged port) do not
+/// count against the port.
+pub fn is_free(port: u16) -> bool {
+    let in_use = |address: std::net::IpAddr| {
+        matches!(
+            std::net::TcpListener::bind((address, port)),
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse
+        )
+    };
+    !in_use(std::net::Ipv4Addr::UNSPECIFIED.into())
+        && !in_use(std::net::Ipv6Addr::UNSPECIFIED.into())
+}
+
+/// Returns the PID and command line of a process listening on TCP `port`.
+///
+/// Found by matching the socket in `/proc/net/tcp` against the descriptors of every
+/// process, so it is `None` off Linux and for processes of other users unless run as
+/// root.
+#[cfg(target_os = "linux")]
+pub fn holder(port: u16) -> Option<(u32, String)> {
+    let mut inodes = Vec::new();
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(table) = std::fs::read_to_string(table) else {
+            continue;
+        };
+        for line in table.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let local_port = fields
+                .get(1)
+                .and_then(|address| address.rsplit_once(':'))
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            // 0A is LISTEN
+            if local_port == Some(port) && fields.get(3) == Some(&"0A") {
+                inodes.extend(fields.get(9).map(|inode| format!("socket:[{}]", inode)));
+            }
+        }
+    }
+    if inodes.is_empty() {
+        return None;
+    }
+    let processes = std::fs::read_dir("/proc").ok()?;
+    processes
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .find_map(|pid| {
+            let holds = std::fs::read_dir(format!("/proc/{}/fd", pid))
+                .ok()?
+                .filter_map(Result::ok)
+                .filter_map(|fd| std::fs::read_link(fd.path()).ok())
+                .any(|target| {
+                    inodes
+                        .iter()
+                        .any(|inode| target.as_os_str() == inode.as_str())
+                });
+            if !holds {
+                return None;
+            }
+            let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+            let command = cmdline
+                .split(|&byte| byte == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(String::from_utf8_lossy)
+                .collect::<Vec<_>>()
+                .join(" ");
+            Some((pid, command))
+        })
+}
+
+/// Finding the holder of a port needs `/proc`, which only Linux provides.
+#[cfg(not(target_os = "linux"))]
+pub fn holder(port: u16) -> Option<(u32, String)> {
+    let _ = port;
+    None
+}
+
+/// A port given with `--port`: a number, or `auto` for any free one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortSpec {
+    /// Pick a free port when the instance starts
+    Auto,
+    /// This port
+    Port(u16),
+}
+
+impl std::str::FromStr for PortSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(PortSpec::Auto);
+        }
+        match s.parse::<u16>() {
+            Ok(0) | Err(_) => Err(format!(
+                "expected a port number from 1 to 65535, or auto, got \"{}\"",
+                s
+            )),
+            Ok(port) => Ok(PortSpec::Port(port)),
+        }
+    }
+}
+
+/// Turns `specs` into port numbers, picking a free port for every `Auto`.
+///
+/// A picked port is free when it is picked, but nothing reserves it until the command
+/// binds it.
+///
+/// # Returns
+/// - `Ok(Vec<u16>)`: The ports, in the order of `specs`.
+/// - `Err(anyhow::Error)`: If the system would not hand out a free port.
+pub fn resolve(specs: &[PortSpec]) -> Result<Vec<u16>, anyhow::Error> {
+    let mut ports = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let port = match spec {
+            PortSpec::Port(port) => *port,
+            PortSpec::Auto => pick(&ports)?,
+        };
+        ports.push(port);
+    }
+    Ok(ports)
+}
+
+/// Asks the system for an unused port that is free on every address and not in `taken`.
+fn pick(taken: &[u16]) -> Result<u16, anyhow::Error> {
+    for _ in 0..16 {
+        let listener = std::net::TcpListener::bind((std::net::Ipv6Addr::UNSPECIFIED, 0))
+            .or_else(|_| std::net::TcpListener::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)))
+            .map_err(|e| anyhow::anyhow!("Cannot allocate a free port: {}", e))?;
+        let port = listener.local_addr()?.port();
+        drop(listener);
+        if !taken.contains(&port) && is_free(port) {
+            return Ok(port);
+        }
+    }
+    Err(anyhow::anyhow!(
+        "Cannot allocate a free port: none of those offered was free"
+    ))
+}
+
+/// The environment that tells the command its ports: `PORT` is the first, and with
+/// several, `PORT_1`, `PORT_2`, ... are all of them in order.
+pub fn environment(ports: &[u16]) -> Vec<(String, String)> {
+    let mut env: Vec<(String, String)> = ports
+        .first()
+        .map(|port| ("PORT".to_string(), port.to_string()))
+        .into_iter()
+        .collect();
+    if ports.len() > 1 {
+        env.extend(
+            ports
+                .iter()
+                .enumerate()
+                .map(|(i, port)| (format!("PORT_{}", i + 1), port.to_string())),
+        );
+    }
+    env
+}
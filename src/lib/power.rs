@@ -0,0 +1,138 @@
+//! Keeping the machine awake while a background job runs (macOS).
+//!
+//! macOS throttles background processes with App Nap and puts an idle machine to sleep,
+//! which pauses a detached backup or sync halfway through. `with_keep_awake` holds an
+//! IOKit power assertion (the mechanism behind `caffeinate -i`) for as long as the service
+//! future runs; while it is held the system does not idle-sleep and the process is exempt
+//! from App Nap. The display may still sleep.
+#[cfg(target_os = "macos")]
+use log::info;
+use log::warn;
+
+/// A held power assertion; released when dropped.
+#[cfg(target_os = "macos")]
+#[derive(Debug)]
+pub struct PowerAssertion {
+    id: u32,
+}
+
+#[cfg(target_os = "macos")]
+mod ffi {
+    use std::ffi::{c_char, c_void};
+
+    pub type CFStringRef = *const c_void;
+    pub const K_CF_STRING_ENCODING_UTF8: u32 = 0x0800_0100;
+    pub const K_IOPM_ASSERTION_LEVEL_ON: u32 = 255;
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    unsafe extern "C" {
+        pub fn CFStringCreateWithCString(
+            alloc: *const c_void,
+            c_str: *const c_char,
+            encoding: u32,
+        ) -> CFStringRef;
+        pub fn CFRelease(cf: *const c_void);
+    }
+
+    #[link(name = "IOKit", kind = "framework")]
+    unsafe extern "C" {
+        pub fn IOPMAssertionCreateWithName(
+            assertion_type: CFStringRef,
+            level: u32,
+            name: CFStringRef,
+            id: *mut u32,
+        ) -> i32;
+        pub fn IOPMAssertionRelease(id: u32) -> i32;
+    }
+
+    /// Creates a CFString from `s`; the caller releases it with `CFRelease`.
+    pub fn cf_string(s: &str) -> Option<CFStringRef> {
+        let c_str = std::ffi::CString::new(s).ok()?;
+        let cf = unsafe {
+            CFStringCreateWithCString(std::ptr::null(), c_str.as_ptr(), K_CF_STRING_ENCODING_UTF8)
+        };
+        (!cf.is_null()).then_some(cf)
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl PowerAssertion {
+    /// Prevents idle system sleep and App Nap until the assertion is dropped.
+    ///
+    /// `reason` shows up in `pmset -g assertions`.
+    pub fn prevent_idle_sleep(reason: &str) -> Result<Self, anyhow::Error> {
+        let kind = ffi::cf_string("PreventUserIdleSystemSleep")
+            .ok_or_else(|| anyhow::anyhow!("Failed to create power assertion type"))?;
+        let Some(name) = ffi::cf_string(reason) else {
+            unsafe { ffi::CFRelease(kind) };
+            return Err(anyhow::anyhow!("Invalid power assertion reason"));
+        };
+        let mut id = 0;
+        let result = unsafe {
+            let result = ffi::IOPMAssertionCreateWithName(
+                kind,
+                ffi::K_IOPM_ASSERTION_LEVEL_ON,
+                name,
+                &mut id,
+            );
+            ffi::CFRelease(name);
+            ffi::CFRelease(kind);
+            result
+        };
+        if result != 0 {
+            return Err(anyhow::anyhow!(
+                "IOPMAssertionCreateWithName failed with IOReturn {:#x}",
+                result
+            ));
+        }
+        Ok(PowerAssertion { id })
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl Drop for PowerAssertion {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::IOPMAssertionRelease(self.id);
+        }
+    }
+}
+
+/// Runs `future` while keeping the machine awake, if `enabled`.
+///
+/// Failing to take the assertion is logged and the future runs anyway. On platforms
+/// other than macOS a warning is logged and `future` runs as is.
+///
+/// # Arguments
+/// - `enabled`: Whether to keep the machine awake; `false` makes this a plain
+///   `future.await`.
+/// - `reason`: Why the machine is kept awake, as shown by `pmset -g assertions`.
+/// - `future`: The service or command future.
+pub async fn with_keep_awake<F>(enabled: bool, reason: &str, future: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    if !enabled {
+        return future.await;
+    }
+    #[cfg(target_os = "macos")]
+    {
+        let _assertion = match PowerAssertion::prevent_idle_sleep(reason) {
+            Ok(assertion) => {
+                info!("Keeping the system awake while running.");
+                Some(assertion)
+            }
+            Err(e) => {
+                warn!("Could not keep the system awake: {}", e);
+                None
+            }
+        };
+        future.await
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        let _ = reason;
+        warn!("--keep-awake is only supported on macOS; ignoring it.");
+        future.await
+    }
+}
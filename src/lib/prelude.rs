@@ -0,0 +1,36 @@
+//! Everything a binary embedding `detach` usually needs, importable in one line.
+//!
+//! ```no_run
+//! use detach::prelude::*;
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     let args = Args::parse();
+//!     let log_file = resolve_log_path(args.daemon.log_file.as_deref())?;
+//!     let level = args.daemon.level();
+//!     LoggingConfig::new(&log_file, level).init()?;
+//!     daemonize(&log_file, level, args.daemon_timeout(), run_service_async())
+//! }
+//! ```
+pub use crate::{
+    Args, AuditLog, Backend, CommandOutcome, Commands, ConsoleStream, ControlRequest, CoreDumps,
+    CrashReport, DaemonBuilder, DetachArgs, ExitReason, FsSandbox, HealthState, InstanceSelector,
+    Isolation, LifecycleEvent, LogDestination, LogField, LogFormat, LoggingConfig, MemoryStats,
+    Metrics, NetworkMode, OsLogTarget, OutputLine, OutputMode, OutputTail, Redactor, RemoteAction,
+    RestartSchedule, RunLogs, RunOptions, SandboxMode, SeccompProfile, ServiceContext,
+    ServiceManager, ServiceState, SupervisorOptions, daemonize, daemonize_local, print_completions,
+    resolve_console_level, resolve_level, resolve_log_path, run_command, run_command_and_exit,
+    run_service_async, setup_logging, supervise_command, with_alerts, with_crash_report,
+    with_diagnostics_signal, with_history, with_keep_awake, with_memory_stats,
+    with_metrics_endpoint, with_sighup_reload, with_state, with_status_file, with_watchdog,
+};
+
+#[cfg(unix)]
+pub use crate::control::with_control_socket;
+#[cfg(unix)]
+pub use crate::signal::{is_alive, send_signal};
+
+pub use crate::lock::{LockFile, hold_lock};
+pub use crate::profile::Profile;
+pub use crate::schedule::{Jitter, delayed_start, parse_duration};
+
+pub use clap::Parser;
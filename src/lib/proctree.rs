@@ -0,0 +1,137 @@
+//! The tree of processes below an instance.
+//!
+//! A command like `sh -c 'worker | logger'` or a server that forks workers is more than
+//! the one PID `status` reports. `detach-rs tree <NAME>` shows everything below the
+//! instance, read from `/proc`, with the resident memory of each process and its CPU use
+//! over a short sampling interval, so it is only available on Linux.
+use std::time::Duration;
+
+/// One process in a tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Process {
+    /// Its PID
+    pub pid: u32,
+    /// Its parent's PID
+    pub ppid: u32,
+    /// How far below the root of the tree it is; the root is at 0
+    pub depth: usize,
+    /// Its command line, or its name in brackets if it has none (e.g. a zombie)
+    pub command: String,
+    /// Resident memory, in bytes
+    pub rss: u64,
+    /// CPU use over the sampling interval, in percent of one core
+    pub cpu: f64,
+}
+
+/// Returns `root` and all its descendants, depth first and ordered by PID among
+/// siblings.
+///
+/// CPU use is measured by reading the processes twice, `sample` apart.
+///
+/// # Returns
+/// - `Ok(Vec<Process>)`: The tree, starting with `root`.
+/// - `Err(anyhow::Error)`: If `root` does not exist, or `/proc` cannot be read.
+#[cfg(target_os = "linux")]
+pub fn tree(root: u32, sample: Duration) -> Result<Vec<Process>, anyhow::Error> {
+    let before = read_all()?;
+    std::thread::sleep(sample);
+    let after = read_all()?;
+    if !after.iter().any(|stat| stat.pid == root) {
+        return Err(anyhow::anyhow!("Process {} does not exist", root));
+    }
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+
+    let mut tree = Vec::new();
+    let mut pending = vec![(root, 0)];
+    while let Some((pid, depth)) = pending.pop() {
+        let Some(stat) = after.iter().find(|stat| stat.pid == pid) else {
+            continue;
+        };
+        let used = before
+            .iter()
+            .find(|earlier| earlier.pid == pid)
+            .map_or(0, |earlier| {
+                stat.cpu_ticks.saturating_sub(earlier.cpu_ticks)
+            });
+        tree.push(Process {
+            pid,
+            ppid: stat.ppid,
+            depth,
+            command: stat.command.clone(),
+            rss: stat.rss_pages * page_size,
+            cpu: used as f64 / ticks_per_second / sample.as_secs_f64().max(0.001) * 100.0,
+        });
+        // Pushed in reverse so the lowest PID is visited first
+        let mut children: Vec<u32> = after
+            .iter()
+            .filter(|child| child.ppid == pid)
+            .map(|child| child.pid)
+            .collect();
+        children.sort_unstable_by(|a, b| b.cmp(a));
+        pending.extend(children.into_iter().map(|child| (child, depth + 1)));
+    }
+    Ok(tree)
+}
+
+/// Reading the process tree needs `/proc`, which only Linux provides.
+#[cfg(not(target_os = "linux"))]
+pub fn tree(root: u32, sample: Duration) -> Result<Vec<Process>, anyhow::Error> {
+    Err(anyhow::anyhow!(
+        "Reading the processes below {} needs /proc, which this system lacks ({:?})",
+        root,
+        sample
+    ))
+}
+
+#[cfg(target_os = "linux")]
+struct Stat {
+    pid: u32,
+    ppid: u32,
+    command: String,
+    cpu_ticks: u64,
+    rss_pages: u64,
+}
+
+/// Reads every process; those that exit while being read are skipped.
+#[cfg(target_os = "linux")]
+fn read_all() -> Result<Vec<Stat>, anyhow::Error> {
+    let entries =
+        std::fs::read_dir("/proc").map_err(|e| anyhow::anyhow!("Cannot read /proc: {}", e))?;
+    Ok(entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter_map(read_stat)
+        .collect())
+}
+
+#[cfg(target_os = "linux")]
+fn read_stat(pid: u32) -> Option<Stat> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The name is in parentheses and may itself contain spaces and parentheses
+    let open = stat.find('(')?;
+    let close = stat.rfind(')')?;
+    let name = &stat[open + 1..close];
+    // Fields from the state on, which is field 3 in proc(5)
+    let fields: Vec<&str> = stat[close + 1..].split_whitespace().collect();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default();
+    let command = if cmdline.is_empty() {
+        format!("[{}]", name)
+    } else {
+        cmdline
+            .split(|&byte| byte == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(String::from_utf8_lossy)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    Some(Stat {
+        pid,
+        ppid: field(4)? as u32,
+        command,
+        cpu_ticks: field(14)? + field(15)?,
+        rss_pages: field(24)?,
+    })
+}
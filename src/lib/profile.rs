@@ -0,0 +1,73 @@
+//! Trading features for memory on small machines.
+//!
+//! On a router or a single-board computer every megabyte counts, and most of what
+//! `detach-rs` runs there is one command that has to be kept alive. `--profile minimal`
+//! runs it as lean as it goes:
+//!
+//! - the service or command runs on a current-thread tokio runtime instead of one worker
+//!   thread per core (see `Profile::runtime`);
+//! - the lifecycle events kept for `events`, `status` and subscribers are buffered in a
+//!   few slots instead of a few hundred (see `Profile::event_buffers`);
+//! - no control socket is opened and no metrics endpoint is served, so `stop`,
+//!   `restart`, `status` and the other subcommands that talk to an instance cannot
+//!   reach it; `--metrics-listen`, `--grpc` and `--dbus` are refused. Stop it with a
+//!   signal instead.
+//!
+//! The default, `standard`, changes nothing; a build with the `embedded` feature defaults
+//! to `minimal` instead. The profile is process-wide and has to be set
+//! with `set_profile` before `ServiceContext::current()` is first used.
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How many past lifecycle events `ServiceContext::recent_events` keeps normally.
+pub const RECENT_EVENTS: usize = 100;
+
+/// How many lifecycle events a subscriber may fall behind by normally.
+pub const EVENT_CHANNEL: usize = 256;
+
+/// How much of `detach-rs` to run, against how much memory it takes.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// Everything, sized for a server
+    #[cfg_attr(not(feature = "embedded"), default)]
+    Standard,
+    /// A current-thread runtime, small event buffers, no control socket and no metrics
+    #[cfg_attr(feature = "embedded", default)]
+    Minimal,
+}
+
+static PROFILE: AtomicU8 = AtomicU8::new(cfg!(feature = "embedded") as u8);
+
+/// Sets the profile for the whole process.
+pub fn set_profile(profile: Profile) {
+    PROFILE.store(profile as u8, Ordering::Relaxed);
+}
+
+/// The profile set with `set_profile`.
+pub fn profile() -> Profile {
+    match PROFILE.load(Ordering::Relaxed) {
+        1 => Profile::Minimal,
+        _ => Profile::Standard,
+    }
+}
+
+impl Profile {
+    /// A builder for the runtime a service or supervised command runs on: multi-thread,
+    /// or current-thread with `Minimal`. I/O and time drivers are enabled.
+    pub fn runtime(self) -> tokio::runtime::Builder {
+        let mut builder = match self {
+            Profile::Standard => tokio::runtime::Builder::new_multi_thread(),
+            Profile::Minimal => tokio::runtime::Builder::new_current_thread(),
+        };
+        builder.enable_all();
+        builder
+    }
+
+    /// How many lifecycle events a subscriber may fall behind by, and how many past ones
+    /// are kept.
+    pub fn event_buffers(self) -> (usize, usize) {
+        match self {
+            Profile::Standard => (EVENT_CHANNEL, RECENT_EVENTS),
+            Profile::Minimal => (16, 10),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Copying a command's output to a file or a stream, in as few writes as it allows.
+//!
+//! A command that writes a lot (a build, a verbose server, a `yes` gone wrong) can produce
+//! output faster than it is stored when every pipe read turns into a write of its own: a
+//! pipe holds at most 64 KiB and often much less per read, so the copy spends its time in
+//! system calls and the command ends up blocked on a full pipe. `pump` reads into
+//! `BUFFER_SIZE` buffers on a task of its own and hands them to a dedicated writer thread;
+//! whatever piled up while the last write was under way goes out together in one
+//! `write_vectored` call, up to `MAX_BATCH` buffers. At most `MAX_BATCH` buffers are
+//! waiting at any time, so a slow disk slows the command down instead of filling memory.
+//!
+//! On the way, a `Sanitizer` can make the output safe for a log (see `sanitize`).
+//!
+//! Everything written is counted in the `detach_output_bytes_total` and
+//! `detach_output_writes_total` counters of the service's metrics; `throughput` reads them
+//! and `--stats-interval` logs the rate (see `memory::with_memory_stats`).
+use crate::context::ServiceContext;
+use crate::metrics::Counter;
+use crate::sanitize::Sanitizer;
+use std::io::{IoSlice, Write};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc;
+
+/// How much is read from the pipe at a time.
+pub const BUFFER_SIZE: usize = 64 * 1024;
+
+/// How many buffers may be waiting for the writer, and go out in one write at most.
+pub const MAX_BATCH: usize = 16;
+
+/// Output copied by `pump` since the process started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Throughput {
+    /// Bytes written
+    pub bytes: u64,
+    /// Write calls it took
+    pub writes: u64,
+}
+
+impl Throughput {
+    /// Describes the output copied since `earlier`, `elapsed` ago, as
+    /// `"12.5 MiB in 210 writes, 2.1 MiB/s"`.
+    pub fn since(self, earlier: Throughput, elapsed: std::time::Duration) -> String {
+        let mib = self.bytes.saturating_sub(earlier.bytes) as f64 / (1024.0 * 1024.0);
+        format!(
+            "{:.1} MiB in {} writes, {:.1} MiB/s",
+            mib,
+            self.writes.saturating_sub(earlier.writes),
+            mib / elapsed.as_secs_f64().max(0.001)
+        )
+    }
+}
+
+/// The output copied so far, from the counters of the process-wide `ServiceContext`.
+pub fn throughput() -> Throughput {
+    let (bytes, writes) = counters();
+    Throughput {
+        bytes: bytes.get(),
+        writes: writes.get(),
+    }
+}
+
+fn counters() -> (Counter, Counter) {
+    let ctx = ServiceContext::current();
+    let metrics = ctx.metrics();
+    (
+        metrics.counter(
+            "detach_output_bytes_total",
+            "Bytes of command output written to its log or stream",
+        ),
+        metrics.counter(
+            "detach_output_writes_total",
+            "Write calls that command output took",
+        ),
+    )
+}
+
+/// Copies everything `reader` yields to `writer` until end of file, through `sanitizer`
+/// if there is one, returning how many bytes were written.
+///
+/// If writing fails, the rest of the output is still read and discarded, so the command
+/// never blocks on a full pipe, and the error is returned at the end.
+pub async fn pump<R>(
+    mut reader: R,
+    writer: Box<dyn Write + Send>,
+    mut sanitizer: Option<Sanitizer>,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    let (buffers, received) = mpsc::channel(MAX_BATCH);
+    let writer = tokio::task::spawn_blocking(move || write_batches(writer, received));
+    let mut buffers = Some(buffers);
+    loop {
+        let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+        let end = reader.read_buf(&mut buffer).await? == 0;
+        if let Some(sanitizer) = &mut sanitizer {
+            let mut sanitized = Vec::with_capacity(buffer.len() + buffer.len() / 8);
+            match end {
+                true => sanitizer.finish(&mut sanitized),
+                false => sanitizer.push(&buffer, &mut sanitized),
+            }
+            buffer = sanitized;
+        }
+        if !buffer.is_empty()
+            && let Some(sender) = &buffers
+            && sender.send(buffer).await.is_err()
+        {
+            buffers = None;
+        }
+        if end {
+            break;
+        }
+    }
+    drop(buffers);
+    writer.await?
+}
+
+/// Writes the buffers received as they come, those that piled up together.
+fn write_batches(
+    mut writer: Box<dyn Write + Send>,
+    mut buffers: mpsc::Receiver<Vec<u8>>,
+) -> std::io::Result<u64> {
+    let (bytes, writes) = counters();
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    let mut total = 0;
+    while let Some(buffer) = buffers.blocking_recv() {
+        batch.push(buffer);
+        while batch.len() < MAX_BATCH
+            && let Ok(buffer) = buffers.try_recv()
+        {
+            batch.push(buffer);
+        }
+        let mut slices: Vec<IoSlice> = batch.iter().map(|buffer| IoSlice::new(buffer)).collect();
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            let written = match writer.write_vectored(slices) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(written) => written,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            bytes.add(written as u64);
+            writes.inc();
+            total += written as u64;
+            IoSlice::advance_slices(&mut slices, written);
+        }
+        writer.flush()?;
+        batch.clear();
+    }
+    Ok(total)
+}
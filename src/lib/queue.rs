@@ -0,0 +1,452 @@
+//! A local job queue run by a daemonized worker.
+//!
+//! `enqueue` adds a command to the queue in `config::state_dir()/queue` and returns its
+//! job ID. `run_worker`, the service of `detach-rs --queue-worker`, runs queued jobs in ID
+//! order, at most `max_parallel` at a time, each with its output in `logs/<ID>.log`. Jobs
+//! are plain JSON files in `jobs/`, so the queue survives restarts:
+//!
+//! - A worker stopped by SIGTERM or SIGINT passes the signal on to its running jobs and
+//!   puts them back in the queue.
+//! - Jobs left running by a worker that died are queued again when the next worker
+//!   starts.
+//!
+//! A job runs through `sh -c` in the directory it was enqueued from, with the worker's
+//! environment. Only one worker runs per queue. Finished jobs stay listed until
+//! `Queue::prune` removes them, which the worker does after each job when given a
+//! `keep_runs` limit.
+use crate::command::{OutputMode, RunOptions, run_command};
+use crate::lock::LockFile;
+use crate::redact::Redactor;
+use crate::sanitize::Sanitize;
+use crate::signal::{SIGINT, SIGTERM, is_alive};
+use chrono::Local;
+use log::{info, warn};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+
+/// How often the worker looks for new jobs.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where a job is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// Waiting for a free worker slot
+    Queued,
+    /// Being run by the worker
+    Running,
+    /// Exited with status 0
+    Succeeded,
+    /// Exited with another status, or could not be started
+    Failed,
+}
+
+impl JobState {
+    fn as_str(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+        }
+    }
+
+    fn parse(input: &str) -> Option<Self> {
+        match input {
+            "queued" => Some(JobState::Queued),
+            "running" => Some(JobState::Running),
+            "succeeded" => Some(JobState::Succeeded),
+            "failed" => Some(JobState::Failed),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for JobState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+/// A queued command and what became of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    /// Sequence number, unique within the queue
+    pub id: u64,
+    /// The program and its arguments
+    pub command: Vec<String>,
+    /// The directory the job runs in
+    pub cwd: PathBuf,
+    /// Where the job is in its life
+    pub state: JobState,
+    /// When the job was enqueued
+    pub enqueued: chrono::DateTime<Local>,
+    /// When the job last started running
+    pub started: Option<chrono::DateTime<Local>>,
+    /// When the job finished
+    pub finished: Option<chrono::DateTime<Local>>,
+    /// PID of the worker running the job
+    pub worker: Option<u32>,
+    /// Shell exit code of the finished job (128 + signal when killed)
+    pub exit_code: Option<i32>,
+}
+
+impl Job {
+    /// The command as one line of shell words.
+    pub fn command_line(&self) -> String {
+        self.command
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let time = |t: &chrono::DateTime<Local>| t.to_rfc3339();
+        serde_json::json!({
+            "id": self.id,
+            "command": self.command,
+            "cwd": self.cwd,
+            "state": self.state.as_str(),
+            "enqueued": time(&self.enqueued),
+            "started": self.started.as_ref().map(time),
+            "finished": self.finished.as_ref().map(time),
+            "worker": self.worker,
+            "exit_code": self.exit_code,
+        })
+    }
+
+    fn from_json(value: &serde_json::Value) -> Option<Self> {
+        let time = |key: &str| {
+            value[key]
+                .as_str()
+                .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+                .map(|t| t.with_timezone(&Local))
+        };
+        Some(Job {
+            id: value["id"].as_u64()?,
+            command: value["command"]
+                .as_array()?
+                .iter()
+                .map(|arg| arg.as_str().map(str::to_string))
+                .collect::<Option<_>>()?,
+            cwd: PathBuf::from(value["cwd"].as_str()?),
+            state: JobState::parse(value["state"].as_str()?)?,
+            enqueued: time("enqueued")?,
+            started: time("started"),
+            finished: time("finished"),
+            worker: value["worker"].as_u64().map(|pid| pid as u32),
+            exit_code: value["exit_code"].as_i64().map(|code| code as i32),
+        })
+    }
+}
+
+/// The queue directory with its job files and logs.
+#[derive(Debug, Clone)]
+pub struct Queue {
+    dir: PathBuf,
+}
+
+impl Queue {
+    /// Opens the queue in `config::state_dir()/queue`, creating it if needed.
+    pub fn open() -> anyhow::Result<Self> {
+        Self::open_at(&crate::config::state_dir()?.join("queue"))
+    }
+
+    /// Opens the queue in `dir`, creating it if needed.
+    pub fn open_at(dir: &Path) -> anyhow::Result<Self> {
+        for sub in ["jobs", "logs"] {
+            let path = dir.join(sub);
+            std::fs::create_dir_all(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", path.display(), e))?;
+        }
+        Ok(Queue {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// The queue directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The log file of the job `id`.
+    pub fn log_path(&self, id: u64) -> PathBuf {
+        self.dir.join("logs").join(format!("{}.log", id))
+    }
+
+    /// Adds `command`, to be run in `cwd`, and returns the new job.
+    pub fn enqueue(&self, command: &[String], cwd: &Path) -> anyhow::Result<Job> {
+        if command.is_empty() {
+            return Err(anyhow::anyhow!("No command given"));
+        }
+        // Held while the next ID is taken, so concurrent enqueues get different ones
+        let lock = std::fs::File::create(self.dir.join(".enqueue.lock"))?;
+        lock.lock()?;
+        let id = self.jobs()?.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        let job = Job {
+            id,
+            command: command.to_vec(),
+            cwd: cwd.to_path_buf(),
+            state: JobState::Queued,
+            enqueued: Local::now(),
+            started: None,
+            finished: None,
+            worker: None,
+            exit_code: None,
+        };
+        self.save(&job)?;
+        Ok(job)
+    }
+
+    /// Returns all jobs, oldest first. Unreadable job files are skipped with a warning.
+    pub fn jobs(&self) -> anyhow::Result<Vec<Job>> {
+        let dir = self.dir.join("jobs");
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", dir.display(), e))?;
+        let mut jobs: Vec<Job> = entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| {
+                let job = std::fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|text| serde_json::from_str(&text).ok())
+                    .and_then(|value| Job::from_json(&value));
+                if job.is_none() {
+                    warn!("Skipping unreadable job file {}", path.display());
+                }
+                job
+            })
+            .collect();
+        jobs.sort_by_key(|job| job.id);
+        Ok(jobs)
+    }
+
+    /// Writes `job` to its file, replacing the previous version atomically.
+    fn save(&self, job: &Job) -> anyhow::Result<()> {
+        let path = self.dir.join("jobs").join(format!("{}.json", job.id));
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, format!("{:#}\n", job.to_json()))
+            .and_then(|()| std::fs::rename(&tmp, &path))
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
+    }
+
+    /// Removes the oldest finished jobs and their logs, keeping the newest `keep`.
+    pub fn prune(&self, keep: usize) -> anyhow::Result<()> {
+        let finished: Vec<Job> = self
+            .jobs()?
+            .into_iter()
+            .filter(|job| matches!(job.state, JobState::Succeeded | JobState::Failed))
+            .collect();
+        for job in &finished[..finished.len().saturating_sub(keep)] {
+            let path = self.dir.join("jobs").join(format!("{}.json", job.id));
+            std::fs::remove_file(&path)
+                .map_err(|e| anyhow::anyhow!("Failed to remove {}: {}", path.display(), e))?;
+            let _ = std::fs::remove_file(self.log_path(job.id));
+        }
+        Ok(())
+    }
+
+    /// Queues again the jobs left running by a worker that is gone.
+    fn recover(&self) -> anyhow::Result<()> {
+        for mut job in self.jobs()? {
+            let orphaned = job.state == JobState::Running
+                && job
+                    .worker
+                    .is_none_or(|pid| pid == std::process::id() || !is_alive(pid));
+            if orphaned {
+                info!(
+                    "Job {} was interrupted by a worker restart; queuing it again.",
+                    job.id
+                );
+                job.state = JobState::Queued;
+                job.worker = None;
+                self.save(&job)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds `command` to the default queue, to be run in the current directory.
+pub fn enqueue(command: &[String]) -> anyhow::Result<Job> {
+    Queue::open()?.enqueue(command, &std::env::current_dir()?)
+}
+
+/// Runs the jobs of the default queue, at most `max_parallel` at a time, until SIGTERM or
+/// SIGINT. With `keep_runs`, only that many finished jobs and their logs are kept. Job
+/// output is written to the job logs through `sanitize` if given (see `sanitize`), with
+/// the secrets `redactor` knows replaced by `[REDACTED]`.
+///
+/// # Returns
+/// - `Ok(())`: After a stop signal, once the running jobs ended and were queued again.
+/// - `Err(anyhow::Error)`: If another worker serves the queue, or the queue could not be
+///   read or written.
+pub async fn run_worker(
+    max_parallel: usize,
+    keep_runs: Option<usize>,
+    sanitize: Option<Sanitize>,
+    redactor: Option<Arc<Redactor>>,
+) -> anyhow::Result<()> {
+    let queue = Queue::open()?;
+    let lock_path = queue.dir.join("worker.lock");
+    let Some(_lock) = LockFile::try_acquire(&lock_path)? else {
+        return Err(anyhow::anyhow!(
+            "Another queue worker is running (see {})",
+            lock_path.display()
+        ));
+    };
+    queue.recover()?;
+    let max_parallel = max_parallel.max(1);
+    info!(
+        "Queue worker started; running up to {} jobs at a time.",
+        max_parallel
+    );
+
+    let mut term = signal(SignalKind::terminate())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    let (signals, _) = broadcast::channel(4);
+    let mut running = JoinSet::new();
+    let mut stopping = false;
+    loop {
+        if !stopping {
+            let queued = queue
+                .jobs()?
+                .into_iter()
+                .filter(|job| job.state == JobState::Queued);
+            for mut job in queued.take(max_parallel - running.len()) {
+                job.state = JobState::Running;
+                job.started = Some(Local::now());
+                job.worker = Some(std::process::id());
+                queue.save(&job)?;
+                info!("Starting job {}: {}", job.id, job.command_line());
+                let log_path = queue.log_path(job.id);
+                let signals = signals.clone();
+                let redactor = redactor.clone();
+                running.spawn(async move {
+                    let exit_code = run_job(&job, &log_path, signals, sanitize, redactor).await;
+                    (job, exit_code)
+                });
+            }
+        } else if running.is_empty() {
+            info!("Queue worker stopped.");
+            return Ok(());
+        }
+
+        tokio::select! {
+            Some(finished) = running.join_next() => {
+                let Ok((mut job, exit_code)) = finished else {
+                    continue;
+                };
+                if stopping {
+                    info!("Job {} was stopped; it stays queued.", job.id);
+                    job.state = JobState::Queued;
+                    job.worker = None;
+                } else {
+                    job.state = if exit_code == Some(0) {
+                        JobState::Succeeded
+                    } else {
+                        JobState::Failed
+                    };
+                    job.finished = Some(Local::now());
+                    job.exit_code = exit_code;
+                    info!("Job {} {}.", job.id, job.state);
+                }
+                queue.save(&job)?;
+                if let Some(keep) = keep_runs {
+                    queue.prune(keep)?;
+                }
+            }
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            Some(()) = term.recv() => {
+                stopping = true;
+                let _ = signals.send(SIGTERM);
+            }
+            Some(()) = int.recv() => {
+                stopping = true;
+                let _ = signals.send(SIGINT);
+            }
+        }
+    }
+}
+
+/// Runs `job` with its output appended to `log_path` and returns its shell exit code, or
+/// `None` if it could not be started.
+async fn run_job(
+    job: &Job,
+    log_path: &Path,
+    signals: broadcast::Sender<i32>,
+    sanitize: Option<Sanitize>,
+    redactor: Option<Arc<Redactor>>,
+) -> Option<i32> {
+    let mut log = match std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+    {
+        Ok(log) => log,
+        Err(e) => {
+            warn!("Job {}: cannot open {}: {}", job.id, log_path.display(), e);
+            return None;
+        }
+    };
+    let command_line = job.command_line();
+    let _ = writeln!(
+        log,
+        "--- job {} started at {}: {}",
+        job.id,
+        Local::now().to_rfc3339(),
+        match &redactor {
+            Some(redactor) => redactor.redact(&command_line),
+            None => command_line.as_str().into(),
+        }
+    );
+
+    let log = Arc::new(log);
+    let cmd_str = format!(
+        "cd {} && exec {}",
+        shell_quote(&job.cwd.to_string_lossy()),
+        job.command_line()
+    );
+    let opts = RunOptions {
+        output: OutputMode::File(log.clone()),
+        sanitize,
+        redactor,
+        signals: Some(signals),
+        process_group: true,
+        ..RunOptions::default()
+    };
+    let outcome = run_command(&cmd_str, opts).await;
+    let mut log = &*log;
+
+    match outcome {
+        Ok(outcome) => {
+            let reason = outcome.exit_reason();
+            let _ = writeln!(log, "--- job {} {}", job.id, reason);
+            Some(reason.shell_code())
+        }
+        Err(e) => {
+            let _ = writeln!(log, "--- job {} could not be started: {}", job.id, e);
+            warn!("Job {} could not be started: {}", job.id, e);
+            None
+        }
+    }
+}
+
+/// Quotes `arg` for a POSIX shell, leaving plain words as they are.
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
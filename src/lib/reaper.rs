@@ -0,0 +1,230 @@
+//! Keeping track of the processes a command leaves behind.
+//!
+//! A command that double-forks (a classic daemon, or `sh -c 'worker &'`) leaves processes
+//! whose parent has exited. They are normally re-parented to init, out of the
+//! supervisor's sight: `stop` does not stop them and nothing notices when they die. With
+//! `--subreaper` the supervisor marks itself a child subreaper
+//! (`PR_SET_CHILD_SUBREAPER`, Linux only), so those processes are re-parented to
+//! `detach-rs` instead. It then reaps them as they exit, logging how each ended, lists
+//! the ones still running as `orphans` in `status`, and terminates them when it stops
+//! (see `supervisor`), so no part of the service tree outlives it.
+//!
+//! Processes `detach-rs` starts itself are children as well, and must be left for the
+//! code that waits for them. They are started with `spawn` (or `output`), which records
+//! them until `release` is called, so only re-parented processes are ever reaped here.
+use log::{info, warn};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// How often exited orphans are looked for when no SIGCHLD arrives.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether `enable` has made this process a subreaper.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The children `detach-rs` started itself and has not waited for yet.
+static SPAWNED: Mutex<Option<HashSet<u32>>> = Mutex::new(None);
+
+/// Makes this process a child subreaper.
+///
+/// # Returns
+/// - `Ok(())`: Orphaned descendants are re-parented to this process from now on.
+/// - `Err(anyhow::Error)`: If `prctl` fails, or this is not Linux.
+pub fn enable() -> Result<(), anyhow::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        // SAFETY: PR_SET_CHILD_SUBREAPER only sets a flag on the calling process.
+        let ret = unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) };
+        if ret < 0 {
+            return Err(anyhow::anyhow!(
+                "Failed to become a child subreaper: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        ENABLED.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    Err(anyhow::anyhow!(
+        "--subreaper needs PR_SET_CHILD_SUBREAPER, which only Linux has"
+    ))
+}
+
+/// Returns `true` once `enable` has succeeded.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Spawns `command`, recording the child so it is never reaped as an orphan; call
+/// `release` once it has been waited for.
+pub(crate) fn spawn(
+    command: &mut tokio::process::Command,
+) -> std::io::Result<tokio::process::Child> {
+    // Held across the spawn, so a sweep cannot see the child before it is recorded
+    let mut spawned = SPAWNED.lock().unwrap_or_else(|e| e.into_inner());
+    let child = command.spawn()?;
+    if let Some(pid) = child.id() {
+        spawned.get_or_insert_with(HashSet::new).insert(pid);
+    }
+    Ok(child)
+}
+
+/// Runs `command` to completion like `Command::output`, started through `spawn`.
+pub(crate) async fn output(
+    command: &mut tokio::process::Command,
+) -> std::io::Result<std::process::Output> {
+    let child = spawn(command)?;
+    let pid = child.id();
+    let output = child.wait_with_output().await;
+    release(pid);
+    output
+}
+
+/// Forgets a child started with `spawn` after it has been waited for.
+pub(crate) fn release(pid: Option<u32>) {
+    let mut spawned = SPAWNED.lock().unwrap_or_else(|e| e.into_inner());
+    if let (Some(spawned), Some(pid)) = (spawned.as_mut(), pid) {
+        spawned.remove(&pid);
+    }
+}
+
+/// Returns the PIDs of the running orphans re-parented to this process, sorted.
+pub fn orphans() -> Vec<u32> {
+    let spawned = SPAWNED.lock().unwrap_or_else(|e| e.into_inner());
+    let mut orphans: Vec<u32> = children()
+        .into_iter()
+        .filter(|(pid, zombie)| !zombie && !is_spawned(&spawned, *pid))
+        .map(|(pid, _)| pid)
+        .collect();
+    orphans.sort_unstable();
+    orphans
+}
+
+fn is_spawned(spawned: &Option<HashSet<u32>>, pid: u32) -> bool {
+    spawned
+        .as_ref()
+        .is_some_and(|spawned| spawned.contains(&pid))
+}
+
+/// Reaps the orphans that have exited and logs how each ended.
+///
+/// # Returns
+/// The number of orphans reaped.
+pub fn reap() -> usize {
+    let spawned = SPAWNED.lock().unwrap_or_else(|e| e.into_inner());
+    let mut reaped = 0;
+    for (pid, zombie) in children() {
+        if !zombie || is_spawned(&spawned, pid) {
+            continue;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            let mut status = 0;
+            // SAFETY: waitpid writes the status of the given zombie child to `status`.
+            let ret = unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) };
+            if ret == pid as libc::pid_t {
+                let status = std::process::ExitStatus::from_raw(status);
+                let reason = crate::command::ExitReason::from_status(status);
+                info!("Reaped orphaned process {}: {}.", pid, reason);
+                reaped += 1;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = pid;
+    }
+    reaped
+}
+
+/// Reaps orphans whenever a child exits, and every `SWEEP_INTERVAL` in case a SIGCHLD was
+/// merged with another. Does nothing unless `enable` has succeeded.
+pub async fn reap_orphans() {
+    if !is_enabled() {
+        return;
+    }
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut exits = match signal(SignalKind::child()) {
+            Ok(exits) => Some(exits),
+            Err(e) => {
+                warn!(
+                    "Cannot watch for exiting orphans, checking every {:?}: {}",
+                    SWEEP_INTERVAL, e
+                );
+                None
+            }
+        };
+        let mut sweeps = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(()) = async { exits.as_mut()?.recv().await } => {}
+                _ = sweeps.tick() => {}
+            }
+            reap();
+        }
+    }
+}
+
+/// Terminates the orphans that are still running, killing them if they are still there
+/// after `timeout`.
+///
+/// # Returns
+/// - `None`: If there were none.
+/// - `Some(killed)`: If orphans were left, and whether they had to be killed.
+#[cfg(unix)]
+pub async fn clear(timeout: Duration, clock: &dyn crate::clock::Clock) -> Option<bool> {
+    use crate::signal::{SIGKILL, SIGTERM, send_signal};
+    let signal_all = |signal| {
+        for pid in orphans() {
+            if let Err(e) = send_signal(pid, signal) {
+                warn!("Failed to send signal {} to orphan {}: {}", signal, pid, e);
+            }
+        }
+    };
+    if orphans().is_empty() {
+        return None;
+    }
+    warn!("Orphaned processes of the command are still running. Sending them SIGTERM.");
+    signal_all(SIGTERM);
+    let deadline = clock.monotonic() + timeout;
+    while clock.monotonic() < deadline {
+        clock.sleep(Duration::from_millis(100)).await;
+        reap();
+        if orphans().is_empty() {
+            return Some(false);
+        }
+    }
+    warn!("Orphaned processes of the command did not exit. Sending them SIGKILL.");
+    signal_all(SIGKILL);
+    Some(true)
+}
+
+/// Returns the children of this process and whether each has exited (is a zombie).
+#[cfg(target_os = "linux")]
+fn children() -> Vec<(u32, bool)> {
+    let me = std::process::id();
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u32>().ok())
+        .filter_map(|pid| {
+            let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            // The state is field 3 and the parent field 4, right after the name
+            let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+            let zombie = fields.next()? == "Z";
+            let ppid: u32 = fields.next()?.parse().ok()?;
+            (ppid == me).then_some((pid, zombie))
+        })
+        .collect()
+}
+
+/// Without a subreaper there are no orphans to find.
+#[cfg(not(target_os = "linux"))]
+fn children() -> Vec<(u32, bool)> {
+    Vec::new()
+}
@@ -0,0 +1,395 @@
+//! Keeping secrets out of log files.
+//!
+//! A `Redactor` replaces the values of selected environment variables, and anything that
+//! matches a set of regular expressions, with `[REDACTED]`. `RedactingEncoder` applies it to
+//! every record an appender writes, so a token passed in through the environment or printed
+//! by a careless command never lands in a plaintext log file. It redacts the message and the
+//! key-value fields before they are encoded, so a secret is still found when the JSON
+//! encoder would have escaped a `"` or `\` in it. `LineRedactor` does the same for command
+//! output on its way into a run log or a job log (see `sanitize::Sanitizer::redacting`).
+//!
+//! Both kinds of pattern are regular expressions, which need the `redact` feature; without
+//! it `Redactor::new` only accepts an empty set, and nothing is redacted.
+use log::kv::{Error, Key, Source, ToValue, Value, VisitSource};
+use log4rs::encode::{Encode, Write};
+#[cfg(feature = "redact")]
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+/// What redacted text is replaced with.
+pub const REDACTED: &str = "[REDACTED]";
+
+/// The longest incomplete line `LineRedactor` holds back; a longer one is redacted in
+/// pieces of this size, so a line that never ends cannot hold its output back forever.
+pub const MAX_HELD_LINE: usize = 64 * 1024;
+
+/// Environment variable values shorter than this are not redacted; replacing every `1` or
+/// `en` in the log would make it unreadable without protecting anything.
+#[cfg(feature = "redact")]
+const MIN_SECRET_LEN: usize = 4;
+
+/// Replaces secrets in text before it is written anywhere.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    secrets: Vec<String>,
+    #[cfg(feature = "redact")]
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    /// Builds a redactor from environment variable name patterns and free-form regexes.
+    ///
+    /// # Arguments
+    /// - `env_patterns`: Regexes matched against whole variable names (e.g. `AWS_SECRET.*`);
+    ///   the current values of matching variables are redacted.
+    /// - `regexes`: Regexes whose matches are redacted wherever they appear.
+    ///
+    /// # Returns
+    /// - `Ok(Redactor)`: The redactor.
+    /// - `Err(anyhow::Error)`: If one of the patterns is not a valid regex, or there are
+    ///   patterns and the `redact` feature is off.
+    #[cfg(feature = "redact")]
+    pub fn new(env_patterns: &[String], regexes: &[String]) -> Result<Self, anyhow::Error> {
+        let mut secrets = Vec::new();
+        for pattern in env_patterns {
+            let name_re = Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| {
+                anyhow::anyhow!("Invalid --redact-env pattern \"{}\": {}", pattern, e)
+            })?;
+            for (name, value) in std::env::vars_os() {
+                if name_re.is_match(&name.to_string_lossy()) {
+                    let value = value.to_string_lossy().into_owned();
+                    if value.len() >= MIN_SECRET_LEN {
+                        secrets.push(value);
+                    }
+                }
+            }
+        }
+        // Longest first, so a secret containing another one is replaced as a whole.
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        secrets.dedup();
+
+        let patterns = regexes
+            .iter()
+            .map(|re| {
+                Regex::new(re)
+                    .map_err(|e| anyhow::anyhow!("Invalid --redact-regex \"{}\": {}", re, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Redactor { secrets, patterns })
+    }
+
+    /// Without the `redact` feature there is no regex engine to match patterns with.
+    #[cfg(not(feature = "redact"))]
+    pub fn new(env_patterns: &[String], regexes: &[String]) -> Result<Self, anyhow::Error> {
+        if env_patterns.is_empty() && regexes.is_empty() {
+            return Ok(Redactor::default());
+        }
+        Err(anyhow::anyhow!(
+            "--redact-env and --redact-regex need the redact feature, which this build lacks"
+        ))
+    }
+
+    /// Returns `true` if this redactor never changes any text.
+    pub fn is_empty(&self) -> bool {
+        #[cfg(feature = "redact")]
+        if !self.patterns.is_empty() {
+            return false;
+        }
+        self.secrets.is_empty()
+    }
+
+    /// Returns `text` with every secret replaced by `[REDACTED]`.
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for secret in &self.secrets {
+            if text.contains(secret.as_str()) {
+                text = Cow::Owned(text.replace(secret.as_str(), REDACTED));
+            }
+        }
+        #[cfg(feature = "redact")]
+        for pattern in &self.patterns {
+            if let Cow::Owned(replaced) = pattern.replace_all(&text, REDACTED) {
+                text = Cow::Owned(replaced);
+            }
+        }
+        text
+    }
+}
+
+/// Applies a `Redactor` to a stream of output a line at a time, so a secret a read ends in
+/// the middle of is still found.
+#[derive(Debug)]
+pub struct LineRedactor {
+    redactor: Arc<Redactor>,
+    /// The start of a line the last read ended in the middle of
+    held: Vec<u8>,
+}
+
+impl LineRedactor {
+    /// A line redactor at the start of the output.
+    pub fn new(redactor: Arc<Redactor>) -> Self {
+        LineRedactor {
+            redactor,
+            held: Vec::new(),
+        }
+    }
+
+    /// Redacts the lines the next read of output completes, appending them to `out`.
+    pub fn push(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        self.held.extend_from_slice(input);
+        let end = match self.held.iter().rposition(|&byte| byte == b'\n') {
+            Some(newline) => newline + 1,
+            None if self.held.len() > MAX_HELD_LINE => self.held.len(),
+            None => return,
+        };
+        let rest = self.held.split_off(end);
+        let lines = std::mem::replace(&mut self.held, rest);
+        self.redact(&lines, out);
+    }
+
+    /// Redacts what is still held back at the end of the output.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        let held = std::mem::take(&mut self.held);
+        self.redact(&held, out);
+    }
+
+    /// Redacts the text in `bytes`, passing bytes that are not valid UTF-8 on as they are.
+    fn redact(&self, bytes: &[u8], out: &mut Vec<u8>) {
+        for chunk in bytes.utf8_chunks() {
+            out.extend_from_slice(self.redactor.redact(chunk.valid()).as_bytes());
+            out.extend_from_slice(chunk.invalid());
+        }
+    }
+}
+
+/// A log4rs encoder that redacts each record before handing it to another encoder.
+#[derive(Debug)]
+pub struct RedactingEncoder {
+    inner: Box<dyn Encode>,
+    redactor: Arc<Redactor>,
+}
+
+impl RedactingEncoder {
+    /// Wraps `inner` so the message and fields it encodes are redacted by `redactor`.
+    pub fn new(inner: Box<dyn Encode>, redactor: Arc<Redactor>) -> Self {
+        Self { inner, redactor }
+    }
+}
+
+impl Encode for RedactingEncoder {
+    fn encode(&self, w: &mut dyn Write, record: &log::Record) -> anyhow::Result<()> {
+        let message = record.args().to_string();
+        let message = self.redactor.redact(&message);
+        let mut fields = Fields(Vec::new(), &self.redactor);
+        record.key_values().visit(&mut fields)?;
+        self.inner.encode(
+            w,
+            &record
+                .to_builder()
+                .args(format_args!("{}", message))
+                .key_values(&fields)
+                .build(),
+        )
+    }
+}
+
+/// A copy of a record's fields with the text values redacted; numbers and booleans are
+/// kept as they are, so the JSON encoder still writes them typed.
+struct Fields<'r>(Vec<(String, Field)>, &'r Redactor);
+
+enum Field {
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Text(String),
+}
+
+impl ToValue for Field {
+    fn to_value(&self) -> Value<'_> {
+        match self {
+            Field::Bool(b) => Value::from(*b),
+            Field::U64(n) => Value::from(*n),
+            Field::I64(n) => Value::from(*n),
+            Field::F64(n) => Value::from(*n),
+            Field::Text(s) => Value::from(s.as_str()),
+        }
+    }
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let field = if let Some(b) = value.to_bool() {
+            Field::Bool(b)
+        } else if let Some(n) = value.to_u64() {
+            Field::U64(n)
+        } else if let Some(n) = value.to_i64() {
+            Field::I64(n)
+        } else if let Some(n) = value.to_f64() {
+            Field::F64(n)
+        } else {
+            Field::Text(self.1.redact(&value.to_string()).into_owned())
+        };
+        self.0.push((key.to_string(), field));
+        Ok(())
+    }
+}
+
+impl Source for Fields<'_> {
+    fn visit<'kvs>(&'kvs self, visitor: &mut dyn VisitSource<'kvs>) -> Result<(), Error> {
+        for (key, field) in &self.0 {
+            visitor.visit_pair(Key::from_str(key), field.to_value())?;
+        }
+        Ok(())
+    }
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LogFormat;
+    use crate::kv::LineEncoder;
+    use log4rs::encode::writer::simple::SimpleWriter;
+
+    /// A redactor for fixed secrets, without going through the environment.
+    fn secrets(secrets: &[&str]) -> Redactor {
+        let mut secrets: Vec<String> = secrets.iter().map(|s| s.to_string()).collect();
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        Redactor {
+            secrets,
+            #[cfg(feature = "redact")]
+            patterns: Vec::new(),
+        }
+    }
+
+    /// What `encoder` writes for an info record with `message` and `fields`.
+    fn encode(encoder: &dyn Encode, message: &str, fields: &[(&str, Value)]) -> String {
+        let mut out = SimpleWriter(Vec::new());
+        encoder
+            .encode(
+                &mut out,
+                &log::Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(log::Level::Info)
+                    .target("detach")
+                    .key_values(&fields)
+                    .build(),
+            )
+            .unwrap();
+        String::from_utf8(out.0).unwrap()
+    }
+
+    #[cfg(feature = "redact")]
+    #[test]
+    fn env_values_are_redacted() {
+        // Cargo sets these for every test binary; CARGO_PKG_NAME is "detach".
+        let redactor = Redactor::new(&["CARGO_PKG_NAME".into()], &[]).unwrap();
+        assert_eq!(redactor.redact("started detach."), "started [REDACTED].");
+
+        // The name has to match whole, and short values are left alone.
+        let redactor =
+            Redactor::new(&["CARGO_PKG".into(), "CARGO_PKG_VERSION_.*".into()], &[]).unwrap();
+        assert!(redactor.is_empty());
+    }
+
+    #[cfg(feature = "redact")]
+    #[test]
+    fn regex_matches_are_redacted() {
+        let redactor = Redactor::new(&[], &["ghp_[A-Za-z0-9]{8}".into()]).unwrap();
+        assert_eq!(
+            redactor.redact("token ghp_abcd1234 and ghp_short"),
+            "token [REDACTED] and ghp_short"
+        );
+        assert!(Redactor::new(&[], &["(".into()]).is_err());
+        assert!(Redactor::new(&["[".into()], &[]).is_err());
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_secrets_are_redacted() {
+        // A secret containing another one goes as a whole.
+        let redactor = secrets(&["token", "my-token-value"]);
+        assert_eq!(redactor.redact("my-token-value"), "[REDACTED]");
+        assert_eq!(redactor.redact("a token"), "a [REDACTED]");
+
+        let redactor = secrets(&["aaaa", "bbbb"]);
+        assert_eq!(redactor.redact("aaaabbbb"), "[REDACTED][REDACTED]");
+
+        // Overlapping secrets: neither survives in full.
+        let redactor = secrets(&["abcd", "cdef"]);
+        let text = redactor.redact("xabcdefx");
+        assert!(!text.contains("abcd") && !text.contains("cdef"), "{}", text);
+
+        assert!(Redactor::default().is_empty());
+        assert!(matches!(
+            Redactor::default().redact("abcd"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn output_is_redacted_a_line_at_a_time() {
+        let mut lines = LineRedactor::new(Arc::new(secrets(&["hunter22"])));
+        let mut out = Vec::new();
+        lines.push(b"password hun", &mut out);
+        assert!(out.is_empty());
+        lines.push(b"ter22\nnext: hunt", &mut out);
+        assert_eq!(out, b"password [REDACTED]\n");
+        lines.push(b"er22 \xff", &mut out);
+        lines.finish(&mut out);
+        assert_eq!(out, b"password [REDACTED]\nnext: [REDACTED] \xff");
+
+        // A line that never ends is let out once it gets too long
+        let mut out = Vec::new();
+        lines.push(&[b'x'; MAX_HELD_LINE + 1], &mut out);
+        assert_eq!(out.len(), MAX_HELD_LINE + 1);
+    }
+
+    #[test]
+    fn json_output_is_redacted_before_escaping() {
+        let secret = r#"pa"ss\word"#;
+        let encoder = RedactingEncoder::new(
+            Box::new(LineEncoder::new(LogFormat::Json)),
+            Arc::new(secrets(&[secret])),
+        );
+        let line = encode(
+            &encoder,
+            &format!("login with {}", secret),
+            &[
+                ("password", Value::from(secret)),
+                ("port", Value::from(8080u64)),
+            ],
+        );
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["message"], "login with [REDACTED]");
+        assert_eq!(json["password"], "[REDACTED]");
+        assert_eq!(json["port"], 8080);
+        assert!(!line.contains(r#"pa\"ss"#), "{}", line);
+    }
+
+    #[test]
+    fn text_output_is_redacted() {
+        let encoder = RedactingEncoder::new(
+            Box::new(LineEncoder::new(LogFormat::Text)),
+            Arc::new(secrets(&["hunter22"])),
+        );
+        let line = encode(
+            &encoder,
+            "password hunter22",
+            &[
+                ("user", Value::from("bob")),
+                ("pass", Value::from("hunter22")),
+            ],
+        );
+        assert!(
+            line.ends_with(" - INFO - password [REDACTED] user=bob pass=[REDACTED]\n"),
+            "{}",
+            line
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Running commands detached on another host over SSH.
+//!
+//! `start` connects with the system `ssh` client and has the remote shell start the
+//! command in a new session (`setsid`, where the host has it) under `nohup`, with its
+//! input from `/dev/null` and its output in `~/.local/state/detach/remote/<PID>.log`,
+//! then returns the remote PID. Nothing is copied to the host and detach-rs need not be
+//! installed there: a POSIX `sh` is enough. `logs` and `stop` find the run again by PID.
+//!
+//! Authentication, host keys and jump hosts are whatever `ssh` makes of the host
+//! argument and `~/.ssh/config`.
+use std::process::{Command, Stdio};
+
+/// Where the output of remote runs is kept, relative to the remote home directory.
+pub const REMOTE_LOG_DIR: &str = ".local/state/detach/remote";
+
+/// Starts `command` detached on `host` and returns its PID there.
+///
+/// # Arguments
+/// - `host`: The destination as given to `ssh`, e.g. `user@host` or a `Host` alias.
+/// - `command`: The program and its arguments; they are quoted for the remote shell.
+pub fn start(host: &str, command: &[String]) -> anyhow::Result<u32> {
+    if command.is_empty() {
+        return Err(anyhow::anyhow!("No command given for {}", host));
+    }
+    let command = command
+        .iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let script = [
+        "set -e",
+        &format!("dir=\"$HOME/{}\"", REMOTE_LOG_DIR),
+        "mkdir -p \"$dir\"",
+        "log=\"$dir/starting.$$.log\"",
+        "if command -v setsid >/dev/null 2>&1; then session=setsid; else session=; fi",
+        &format!(
+            "nohup $session sh -c {} </dev/null >\"$log\" 2>&1 & pid=$!",
+            shell_quote(&command)
+        ),
+        "mv \"$log\" \"$dir/$pid.log\"",
+        "echo \"$pid\"",
+    ]
+    .join("; ");
+    let output = ssh(host, &script)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow::anyhow!("Failed to run ssh: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Starting the command on {} failed ({})",
+            host,
+            output.status
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{} did not report a PID: {:?}", host, stdout.trim()))
+}
+
+/// Copies the output of the run `pid` on `host` to stdout.
+///
+/// With `follow`, keeps copying new output until interrupted, like `tail -f`.
+pub fn logs(host: &str, pid: u32, follow: bool) -> anyhow::Result<()> {
+    let script = format!(
+        "exec tail -n +1 {follow}\"$HOME/{dir}/{pid}.log\"",
+        follow = if follow { "-f " } else { "" },
+        dir = REMOTE_LOG_DIR,
+        pid = pid,
+    );
+    let status = ssh(host, &script)
+        .status()
+        .map_err(|e| anyhow::anyhow!("Failed to run ssh: {}", e))?;
+    if !status.success() {
+        return Err(anyhow::anyhow!(
+            "Reading the output of {} on {} failed ({})",
+            pid,
+            host,
+            status
+        ));
+    }
+    Ok(())
+}
+
+/// Sends SIGTERM to the run `pid` on `host`, and to the rest of its session if it has
+/// one.
+pub fn stop(host: &str, pid: u32) -> anyhow::Result<()> {
+    let script = format!(
+        "kill -0 {pid} 2>/dev/null || {{ echo \"No process {pid} is running\" >&2; exit 3; }}; \
+         kill -TERM -- -{pid} 2>/dev/null || kill -TERM {pid}",
+        pid = pid,
+    );
+    let status = ssh(host, &script)
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|e| anyhow::anyhow!("Failed to run ssh: {}", e))?;
+    if !status.success() {
+        return Err(anyhow::anyhow!(
+            "Stopping {} on {} failed ({})",
+            pid,
+            host,
+            status
+        ));
+    }
+    Ok(())
+}
+
+/// Returns an `ssh` invocation running the one-line `script` with `sh` on `host`.
+///
+/// `ssh` hands the script to the remote user's login shell, which need not be a POSIX
+/// shell, so it is wrapped in `sh -c '...'`, which most shells parse alike.
+fn ssh(host: &str, script: &str) -> Command {
+    let mut command = Command::new("ssh");
+    // `--` keeps a host starting with `-` from being read as an option.
+    command
+        .arg("--")
+        .arg(host)
+        .arg(format!("sh -c {}", shell_quote(script)));
+    command
+}
+
+/// Quotes `arg` for a POSIX shell.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
@@ -0,0 +1,224 @@
+//! Crash report bundles.
+//!
+//! When a service or command fails for good, `write_crash_report` packs everything needed
+//! to look into it into a single `.tar.gz` under the state directory: the tail of the log,
+//! the last lines of command output kept in memory, the service definition, the command
+//! line, the environment, the failure and some facts about the host. Attaching that one file to a bug report saves a round of questions.
+//!
+//! Writing the bundle needs the `crash-report` feature. Without it `with_crash_report`
+//! logs the failure with a note that no report could be written.
+use crate::command::OutputTail;
+use crate::redact::Redactor;
+#[cfg(feature = "crash-report")]
+use chrono::Local;
+#[cfg(feature = "crash-report")]
+use flate2::{Compression, write::GzEncoder};
+use log::error;
+#[cfg(feature = "crash-report")]
+use log::warn;
+#[cfg(feature = "crash-report")]
+use std::fmt::Write as _;
+#[cfg(feature = "crash-report")]
+use std::fs::File;
+#[cfg(feature = "crash-report")]
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// What to put into a crash report, besides the environment and host information.
+#[derive(Debug, Clone)]
+pub struct CrashReport {
+    /// The log file whose tail is included
+    pub log_file: Option<PathBuf>,
+    /// How many KiB from the end of the log file to include
+    pub log_tail_kb: u64,
+    /// The command output kept in memory, included as `output-tail.txt`
+    pub output: Option<OutputTail>,
+    /// The service definition file to include, if the run came from one
+    pub config_file: Option<PathBuf>,
+    /// Where to write the bundle; defaults to `<state dir>/crash-reports`
+    pub dir: Option<PathBuf>,
+    /// Secrets to remove from the command line and environment snapshot
+    pub redactor: Option<Arc<Redactor>>,
+}
+
+impl CrashReport {
+    /// A report on `log_file` with the default tail size of 64 KiB.
+    pub fn new(log_file: &Path) -> Self {
+        Self {
+            log_file: Some(log_file.to_path_buf()),
+            log_tail_kb: 64,
+            output: None,
+            config_file: None,
+            dir: None,
+            redactor: None,
+        }
+    }
+}
+
+/// Writes a crash report bundle describing `failure`.
+///
+/// # Arguments
+/// - `report`: What to include and where to write it.
+/// - `failure`: The error that ended the run, including its exit status.
+///
+/// # Returns
+/// - `Ok(PathBuf)`: The path of the `.tar.gz` that was written.
+/// - `Err(anyhow::Error)`: If the state directory is unknown or the bundle cannot be written.
+#[cfg(feature = "crash-report")]
+pub fn write_crash_report(report: &CrashReport, failure: &str) -> Result<PathBuf, anyhow::Error> {
+    let dir = match &report.dir {
+        Some(dir) => dir.clone(),
+        None => crate::config::state_dir()?.join("crash-reports"),
+    };
+    std::fs::create_dir_all(&dir)?;
+
+    let now = Local::now();
+    let stem = format!(
+        "crash-{}-{}",
+        now.format("%Y%m%d-%H%M%S"),
+        std::process::id()
+    );
+    let path = dir.join(format!("{}.tar.gz", stem));
+
+    let mut archive =
+        tar::Builder::new(GzEncoder::new(File::create(&path)?, Compression::default()));
+    let mut add = |name: &str, data: &[u8]| -> std::io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(now.timestamp().max(0) as u64);
+        header.set_cksum();
+        archive.append_data(&mut header, format!("{}/{}", stem, name), data)
+    };
+
+    add("failure.txt", format!("{}\n", failure).as_bytes())?;
+    let redact = |text: String| match &report.redactor {
+        Some(redactor) => redactor.redact(&text).into_owned(),
+        None => text,
+    };
+    add("command-line.txt", redact(command_line()).as_bytes())?;
+    add("environment.txt", redact(environment()).as_bytes())?;
+    add("system.txt", system_info().as_bytes())?;
+    if let Some(log_file) = &report.log_file {
+        match read_tail(log_file, report.log_tail_kb * 1024) {
+            Ok(tail) => add("log-tail.txt", &tail)?,
+            Err(e) => warn!("Crash report: cannot read {}: {}", log_file.display(), e),
+        }
+    }
+    if let Some(output) = &report.output {
+        let lines = output.lines().join("\n") + "\n";
+        add("output-tail.txt", redact(lines).as_bytes())?;
+    }
+    if let Some(config_file) = &report.config_file {
+        match std::fs::read(config_file) {
+            Ok(config) => add("service.toml", &config)?,
+            Err(e) => warn!("Crash report: cannot read {}: {}", config_file.display(), e),
+        }
+    }
+
+    archive.into_inner()?.finish()?;
+    Ok(path)
+}
+
+/// Without the `crash-report` feature there is no archive to write.
+#[cfg(not(feature = "crash-report"))]
+pub fn write_crash_report(report: &CrashReport, failure: &str) -> Result<PathBuf, anyhow::Error> {
+    let _ = (report, failure);
+    Err(anyhow::anyhow!(
+        "crash reports need the crash-report feature, which this build lacks"
+    ))
+}
+
+/// Awaits `future` and writes a crash report if it fails.
+///
+/// The error is logged together with the path of the report, then passed on unchanged.
+/// Without a `report` this is a plain `future.await`.
+pub async fn with_crash_report<F>(report: Option<CrashReport>, future: F) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    let result = future.await;
+    if let (Err(e), Some(report)) = (&result, report) {
+        match write_crash_report(&report, &format!("{:#}", e)) {
+            Ok(path) => error!("{:#}. Crash report written to {}", e, path.display()),
+            Err(report_error) => error!("{:#}. Failed to write crash report: {}", e, report_error),
+        }
+    }
+    result
+}
+
+/// Reads at most `max_bytes` from the end of `path`.
+#[cfg(feature = "crash-report")]
+fn read_tail(path: &Path, max_bytes: u64) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(max_bytes)))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(feature = "crash-report")]
+fn command_line() -> String {
+    let mut out = String::new();
+    for arg in std::env::args_os() {
+        let _ = writeln!(out, "{}", arg.to_string_lossy());
+    }
+    out
+}
+
+#[cfg(feature = "crash-report")]
+fn environment() -> String {
+    let mut vars: Vec<_> = std::env::vars_os()
+        .map(|(key, value)| format!("{}={}", key.to_string_lossy(), value.to_string_lossy()))
+        .collect();
+    vars.sort();
+    vars.join("\n") + "\n"
+}
+
+#[cfg(feature = "crash-report")]
+fn system_info() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "detach: {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(out, "time: {}", Local::now().to_rfc3339());
+    let _ = writeln!(out, "pid: {}", std::process::id());
+    let _ = writeln!(out, "run id: {}", crate::context::run_id());
+    let _ = writeln!(
+        out,
+        "os: {} {}",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+    if let Ok(cwd) = std::env::current_dir() {
+        let _ = writeln!(out, "cwd: {}", cwd.display());
+    }
+    #[cfg(unix)]
+    {
+        let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+        if unsafe { libc::uname(&mut uts) } == 0 {
+            let field = |chars: &[libc::c_char]| {
+                let bytes: Vec<u8> = chars
+                    .iter()
+                    .take_while(|&&c| c != 0)
+                    .map(|&c| c as u8)
+                    .collect();
+                String::from_utf8_lossy(&bytes).into_owned()
+            };
+            let _ = writeln!(out, "hostname: {}", field(&uts.nodename));
+            let _ = writeln!(
+                out,
+                "kernel: {} {} {}",
+                field(&uts.sysname),
+                field(&uts.release),
+                field(&uts.version)
+            );
+        }
+    }
+    for (label, file) in [("uptime", "/proc/uptime"), ("loadavg", "/proc/loadavg")] {
+        if let Ok(value) = std::fs::read_to_string(file) {
+            let _ = writeln!(out, "{}: {}", label, value.trim());
+        }
+    }
+    out
+}
@@ -0,0 +1,152 @@
+//! One log file per run of a repeatedly started command.
+//!
+//! A supervised command that is restarted on a schedule or on request would otherwise
+//! interleave all its runs in one growing log. `RunLogs` gives every run its own file,
+//! `<TIMESTAMP>.log` in the instance's directory under `config::state_dir()/runs`, and
+//! appends a JSON line per finished run to `index.jsonl` there:
+//!
+//! ```text
+//! {"run":3,"run_id":"5f0c…","file":"20261016-131320.943.log","started":"…","finished":"…","duration_secs":61.2,"exit":"exited with code 0","exit_code":0}
+//! ```
+//!
+//! Only the newest `keep` runs are kept; older files and their index lines are removed
+//! when a run finishes.
+use crate::command::ExitReason;
+use chrono::Local;
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The name of the summary file in a run log directory.
+pub const INDEX_FILE: &str = "index.jsonl";
+
+/// A directory of per-run log files with a retention limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunLogs {
+    dir: PathBuf,
+    keep: usize,
+}
+
+/// The log file of a run in progress, from `RunLogs::start`.
+#[derive(Debug)]
+pub struct RunLog {
+    /// Where the run's output goes
+    pub path: PathBuf,
+    /// The open file, to pass to `OutputMode::File`
+    pub file: Arc<File>,
+    run: u64,
+    started: chrono::DateTime<Local>,
+}
+
+impl RunLogs {
+    /// Per-run logs of the instance `name` in `config::state_dir()/runs/<name>`, keeping
+    /// the newest `keep` runs.
+    pub fn for_instance(name: &str, keep: usize) -> anyhow::Result<Self> {
+        crate::config::validate_service_name(name)?;
+        Ok(Self::new(
+            &crate::config::state_dir()?.join("runs").join(name),
+            keep,
+        ))
+    }
+
+    /// Per-run logs in `dir`, keeping the newest `keep` runs.
+    pub fn new(dir: &Path, keep: usize) -> Self {
+        RunLogs {
+            dir: dir.to_path_buf(),
+            keep: keep.max(1),
+        }
+    }
+
+    /// The directory holding the run logs and the index.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Creates the log file for run number `run`, starting now.
+    pub fn start(&self, run: u64) -> anyhow::Result<RunLog> {
+        std::fs::create_dir_all(&self.dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", self.dir.display(), e))?;
+        let started = Local::now();
+        let path = self
+            .dir
+            .join(format!("{}.log", started.format("%Y%m%d-%H%M%S%.3f")));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", path.display(), e))?;
+        Ok(RunLog {
+            path,
+            file: Arc::new(file),
+            run,
+            started,
+        })
+    }
+
+    /// Records how the run of `log` ended in the index and removes the runs beyond the
+    /// newest `keep`. Failures are logged, not returned, so they never stop the command.
+    pub fn finish(&self, log: RunLog, duration: Duration, reason: ExitReason) {
+        let entry = serde_json::json!({
+            "run": log.run,
+            "run_id": crate::context::run_id(),
+            "file": log.path.file_name().map(|name| name.to_string_lossy()),
+            "started": log.started.to_rfc3339(),
+            "finished": Local::now().to_rfc3339(),
+            "duration_secs": duration.as_secs_f64(),
+            "exit": reason.to_string(),
+            "exit_code": reason.shell_code(),
+        });
+        let index = self.dir.join(INDEX_FILE);
+        let appended = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index)
+            .and_then(|mut file| writeln!(file, "{}", entry));
+        if let Err(e) = appended {
+            warn!("Failed to update {}: {}", index.display(), e);
+        }
+        if let Err(e) = self.prune() {
+            warn!(
+                "Failed to remove old run logs in {}: {}",
+                self.dir.display(),
+                e
+            );
+        }
+    }
+
+    /// Removes the oldest run logs beyond `keep`, and their lines in the index.
+    fn prune(&self) -> std::io::Result<()> {
+        let mut logs: Vec<PathBuf> = std::fs::read_dir(&self.dir)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+            .collect();
+        if logs.len() <= self.keep {
+            return Ok(());
+        }
+        // The names are timestamps, so they sort oldest first
+        logs.sort();
+        let removed = &logs[..logs.len() - self.keep];
+        for path in removed {
+            std::fs::remove_file(path)?;
+        }
+
+        let index = self.dir.join(INDEX_FILE);
+        let kept: String = std::fs::read_to_string(&index)?
+            .lines()
+            .filter(|line| {
+                let file = serde_json::from_str::<serde_json::Value>(line)
+                    .ok()
+                    .and_then(|entry| entry["file"].as_str().map(str::to_string));
+                file.is_none_or(|file| !removed.iter().any(|path| path.ends_with(&file)))
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let tmp = index.with_extension("jsonl.tmp");
+        std::fs::write(&tmp, kept)?;
+        std::fs::rename(&tmp, &index)
+    }
+}
@@ -0,0 +1,394 @@
+//! Keeping command output from corrupting the logs it is captured in.
+//!
+//! A command that dumps an image, a core or a compressed stream to stdout, or writes
+//! megabytes without a newline, leaves a log nothing can read any more: pagers and `grep`
+//! give up at the first NUL byte, stray control bytes rewrite the terminal the log is
+//! printed on, and log shippers split or drop giant lines. Output captured into a log
+//! file (the `--keep-runs` run logs and the job logs of the queue) is therefore passed
+//! through a `Sanitizer`, which applies the `Sanitize` rules as the output streams by:
+//!
+//! - a line longer than `max_line` bytes is cut there and ends in
+//!   `[... 12345 bytes truncated]`;
+//! - bytes that are not valid UTF-8, and control characters other than tab, newline,
+//!   carriage return and escape (for colors), are written as `\xNN`, so the log stays
+//!   text and no byte is lost;
+//! - with `BinaryOutput::Hexdump`, a read that looks binary (a NUL byte, or more than one
+//!   byte in eight that would have to be escaped) is written as a hexdump instead, like
+//!   `hexdump -C` prints it.
+//!
+//! With `Sanitizer::redacting`, secrets are replaced with `[REDACTED]` first, a line at a
+//! time, before a long line could be cut in the middle of one (see `redact`).
+//!
+//! ```
+//! use detach::sanitize::{BinaryOutput, Sanitize, Sanitizer};
+//!
+//! let mut sanitizer = Sanitizer::new(Sanitize {
+//!     max_line: Some(8),
+//!     binary: BinaryOutput::Escape,
+//! });
+//! let mut log = Vec::new();
+//! sanitizer.push(b"ok\xff\nmuch too long\n", &mut log);
+//! assert_eq!(log, b"ok\\xff\nmuch too [... 5 bytes truncated]\n");
+//! ```
+use crate::redact::{LineRedactor, Redactor};
+use std::io::Write;
+use std::sync::Arc;
+
+/// How output that is not text is written to a log.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BinaryOutput {
+    /// Write invalid UTF-8 and control bytes as \xNN
+    #[default]
+    Escape,
+    /// Write reads that look binary as a hexdump, and escape the rest
+    Hexdump,
+    /// Write everything as it is
+    Raw,
+}
+
+/// What a `Sanitizer` does to output on its way into a log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sanitize {
+    /// The longest line written, in bytes; `None` for no limit
+    pub max_line: Option<usize>,
+    /// What to do with output that is not text
+    pub binary: BinaryOutput,
+}
+
+impl Default for Sanitize {
+    /// Lines of up to 64 KiB, with binary output escaped.
+    fn default() -> Self {
+        Sanitize {
+            max_line: Some(64 * 1024),
+            binary: BinaryOutput::Escape,
+        }
+    }
+}
+
+/// Applies `Sanitize` rules to a stream of output, one read at a time.
+#[derive(Debug)]
+pub struct Sanitizer {
+    rules: Sanitize,
+    /// Bytes written of the current line
+    column: usize,
+    /// Bytes of the current line left out because it is too long
+    dropped: u64,
+    /// The start of a UTF-8 sequence the last read ended in the middle of
+    pending: Vec<u8>,
+    /// Redacts the output before the rules are applied
+    redactor: Option<LineRedactor>,
+}
+
+impl Sanitizer {
+    /// A sanitizer at the start of the output.
+    pub fn new(rules: Sanitize) -> Self {
+        Sanitizer {
+            rules,
+            column: 0,
+            dropped: 0,
+            pending: Vec::new(),
+            redactor: None,
+        }
+    }
+
+    /// Replaces the secrets `redactor` knows with `[REDACTED]` before applying the rules.
+    pub fn redacting(mut self, redactor: Arc<Redactor>) -> Self {
+        self.redactor = Some(LineRedactor::new(redactor));
+        self
+    }
+
+    /// Sanitizes the next read of output, appending what is to be written to `out`.
+    pub fn push(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        match self.redactor.take() {
+            Some(mut redactor) => {
+                let mut redacted = Vec::with_capacity(input.len());
+                redactor.push(input, &mut redacted);
+                self.apply(&redacted, out);
+                self.redactor = Some(redactor);
+            }
+            None => self.apply(input, out),
+        }
+    }
+
+    /// Writes what is still held back at the end of the output.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        if let Some(mut redactor) = self.redactor.take() {
+            let mut redacted = Vec::new();
+            redactor.finish(&mut redacted);
+            self.apply(&redacted, out);
+        }
+        for byte in std::mem::take(&mut self.pending) {
+            self.escape(byte, out);
+        }
+        if self.dropped > 0 {
+            self.newline(out);
+        }
+    }
+
+    /// Applies the rules to `input`.
+    fn apply(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        if self.rules.binary == BinaryOutput::Raw {
+            self.raw(input, out);
+            return;
+        }
+        let joined;
+        let input = if self.pending.is_empty() {
+            input
+        } else {
+            joined = [std::mem::take(&mut self.pending).as_slice(), input].concat();
+            &joined
+        };
+        let (input, held) = input.split_at(input.len() - cut_off(input));
+        if self.rules.binary == BinaryOutput::Hexdump && looks_binary(input) {
+            self.hexdump(input, out);
+        } else {
+            let mut rest = input;
+            while !rest.is_empty() {
+                let (valid, invalid) = match std::str::from_utf8(rest) {
+                    Ok(_) => (rest.len(), 0),
+                    // Nothing incomplete is left at the end, so every error has a length
+                    Err(e) => (e.valid_up_to(), e.error_len().unwrap_or(1)),
+                };
+                self.text(&rest[..valid], out);
+                for &byte in &rest[valid..valid + invalid] {
+                    self.escape(byte, out);
+                }
+                rest = &rest[valid + invalid..];
+            }
+        }
+        self.pending = held.to_vec();
+    }
+
+    fn raw(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        let mut lines = input.split(|&byte| byte == b'\n');
+        if let Some(first) = lines.next() {
+            self.run(first, out);
+        }
+        for line in lines {
+            self.newline(out);
+            self.run(line, out);
+        }
+    }
+
+    /// Writes valid UTF-8, escaping control characters and cutting long lines.
+    fn text(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        let mut start = 0;
+        for (at, &byte) in bytes.iter().enumerate() {
+            // Everything escaped, and the newline, is an ASCII control character
+            if byte >= 0x20 && byte != 0x7f || matches!(byte, b'\t' | b'\r' | 0x1b) {
+                continue;
+            }
+            self.run(&bytes[start..at], out);
+            match byte {
+                b'\n' => self.newline(out),
+                byte => self.escape(byte, out),
+            }
+            start = at + 1;
+        }
+        self.run(&bytes[start..], out);
+    }
+
+    /// Writes as much of a piece of a line as fits, without splitting a character.
+    fn run(&mut self, bytes: &[u8], out: &mut Vec<u8>) {
+        let room = self.room();
+        let mut cut = bytes.len().min(room);
+        while cut > 0 && cut < bytes.len() && bytes[cut] & 0xc0 == 0x80 {
+            cut -= 1;
+        }
+        out.extend_from_slice(&bytes[..cut]);
+        self.column += cut;
+        self.dropped += (bytes.len() - cut) as u64;
+    }
+
+    fn escape(&mut self, byte: u8, out: &mut Vec<u8>) {
+        if self.room() < 4 {
+            self.dropped += 1;
+            return;
+        }
+        let _ = write!(out, "\\x{:02x}", byte);
+        self.column += 4;
+    }
+
+    fn newline(&mut self, out: &mut Vec<u8>) {
+        if self.dropped > 0 {
+            let _ = write!(out, " [... {} bytes truncated]", self.dropped);
+        }
+        out.push(b'\n');
+        self.column = 0;
+        self.dropped = 0;
+    }
+
+    fn room(&self) -> usize {
+        match self.rules.max_line {
+            Some(max) => max.saturating_sub(self.column),
+            None => usize::MAX,
+        }
+    }
+
+    fn hexdump(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        if self.column > 0 || self.dropped > 0 {
+            self.newline(out);
+        }
+        let _ = writeln!(out, "[{} bytes of binary output]", input.len());
+        for (line, bytes) in input.chunks(16).enumerate() {
+            let _ = write!(out, "{:08x} ", line * 16);
+            for i in 0..16 {
+                if i == 8 {
+                    out.push(b' ');
+                }
+                match bytes.get(i) {
+                    Some(byte) => {
+                        let _ = write!(out, " {:02x}", byte);
+                    }
+                    None => out.extend_from_slice(b"   "),
+                }
+            }
+            out.extend_from_slice(b"  |");
+            out.extend(bytes.iter().map(|&byte| match byte {
+                b' '..=b'~' => byte,
+                _ => b'.',
+            }));
+            out.extend_from_slice(b"|\n");
+        }
+    }
+}
+
+/// Control characters that are escaped; tab, newline, carriage return and escape are not.
+fn escaped(byte: u8) -> bool {
+    matches!(byte, 0x00..=0x08 | 0x0b | 0x0c | 0x0e..=0x1a | 0x1c..=0x1f | 0x7f)
+}
+
+/// The length of the start of a UTF-8 sequence that `bytes` ends in, cut off by the read.
+fn cut_off(bytes: &[u8]) -> usize {
+    // An incomplete sequence is a lead byte and at most two continuation bytes
+    let tail = &bytes[bytes.len().saturating_sub(3)..];
+    let Some(lead) = tail.iter().rposition(|&byte| byte & 0xc0 != 0x80) else {
+        return 0;
+    };
+    match std::str::from_utf8(&tail[lead..]) {
+        Err(e) if e.valid_up_to() == 0 && e.error_len().is_none() => tail.len() - lead,
+        _ => 0,
+    }
+}
+
+/// Returns `true` if `bytes` has a NUL, or more than one byte in eight to escape.
+fn looks_binary(bytes: &[u8]) -> bool {
+    if bytes.contains(&0) {
+        return true;
+    }
+    let unprintable: usize = bytes
+        .utf8_chunks()
+        .map(|chunk| {
+            let controls = chunk.valid().bytes().filter(|&byte| escaped(byte)).count();
+            controls + chunk.invalid().len()
+        })
+        .sum();
+    unprintable * 8 > bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sanitizes `reads` one after another with `rules`, through to the end.
+    fn sanitize(max_line: Option<usize>, binary: BinaryOutput, reads: &[&[u8]]) -> Vec<u8> {
+        let mut sanitizer = Sanitizer::new(Sanitize { max_line, binary });
+        let mut out = Vec::new();
+        for read in reads {
+            sanitizer.push(read, &mut out);
+        }
+        sanitizer.finish(&mut out);
+        out
+    }
+
+    fn escape(reads: &[&[u8]]) -> Vec<u8> {
+        sanitize(None, BinaryOutput::Escape, reads)
+    }
+
+    #[test]
+    fn control_bytes_are_escaped_but_not_tabs_returns_and_colors() {
+        assert_eq!(
+            escape(&[b"a\tb\x01c\x1b[1m\x7f\0\r\n"]),
+            b"a\tb\\x01c\x1b[1m\\x7f\\x00\r\n"
+        );
+    }
+
+    #[test]
+    fn characters_split_between_reads_are_kept_whole() {
+        assert_eq!(escape(&[b"caf\xc3", b"\xa9\n"]), "café\n".as_bytes());
+        assert_eq!(escape(&[b"\xe2", b"\x82", b"\xac\n"]), "€\n".as_bytes());
+    }
+
+    #[test]
+    fn invalid_utf8_is_escaped_including_at_the_end() {
+        assert_eq!(escape(&[b"a\xffb\n"]), b"a\\xffb\n");
+        assert_eq!(escape(&[b"ab\xe2\x82"]), b"ab\\xe2\\x82");
+    }
+
+    #[test]
+    fn long_lines_are_cut_without_splitting_a_character() {
+        let out = sanitize(Some(4), BinaryOutput::Escape, &[b"abc\xc3\xa9\nok\n"]);
+        assert_eq!(out, b"abc [... 2 bytes truncated]\nok\n");
+    }
+
+    #[test]
+    fn long_lines_are_cut_across_reads() {
+        let out = sanitize(Some(3), BinaryOutput::Escape, &[b"abcd", b"ef\n"]);
+        assert_eq!(out, b"abc [... 3 bytes truncated]\n");
+        let unterminated = sanitize(Some(2), BinaryOutput::Escape, &[b"abcd"]);
+        assert_eq!(unterminated, b"ab [... 2 bytes truncated]\n");
+    }
+
+    #[test]
+    fn escapes_that_do_not_fit_are_counted_as_truncated() {
+        let out = sanitize(Some(6), BinaryOutput::Escape, &[b"ab\x01\x02\n"]);
+        assert_eq!(out, b"ab\\x01 [... 1 bytes truncated]\n");
+    }
+
+    #[test]
+    fn binary_reads_become_a_hexdump() {
+        let out = sanitize(None, BinaryOutput::Hexdump, &[b"ok\n", b"\0\x01AB"]);
+        let expected = format!(
+            "ok\n[4 bytes of binary output]\n00000000  00 01 41 42{}  |..AB|\n",
+            " ".repeat(12 * 3 + 1)
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        // Text with the odd control byte is escaped as usual
+        let text = sanitize(
+            None,
+            BinaryOutput::Hexdump,
+            &[b"a mostly printable\x01 line\n"],
+        );
+        assert_eq!(text, b"a mostly printable\\x01 line\n");
+    }
+
+    #[cfg(feature = "redact")]
+    #[test]
+    fn secrets_are_redacted_before_long_lines_are_cut() {
+        let redactor = Redactor::new(&[], &["hunter[0-9]+".into()]).unwrap();
+        let mut sanitizer = Sanitizer::new(Sanitize {
+            max_line: Some(10),
+            binary: BinaryOutput::Escape,
+        })
+        .redacting(Arc::new(redactor));
+        let mut out = Vec::new();
+        sanitizer.push(b"pw hunt", &mut out);
+        sanitizer.push(b"er22 ok\nhunter3", &mut out);
+        sanitizer.finish(&mut out);
+        assert_eq!(out, b"pw [REDACT [... 6 bytes truncated]\n[REDACTED]");
+    }
+
+    #[test]
+    fn raw_output_is_only_cut() {
+        let out = sanitize(Some(3), BinaryOutput::Raw, &[b"ab\0cd\nxy\n"]);
+        assert_eq!(out, b"ab\0 [... 2 bytes truncated]\nxy\n");
+    }
+
+    #[test]
+    fn looks_binary_needs_a_nul_or_many_bytes_to_escape() {
+        assert!(looks_binary(b"text\0"));
+        assert!(looks_binary(b"\xff\xfe\xfd abcdef"));
+        assert!(!looks_binary(b"\x01 one control byte in a longer line"));
+        assert!(!looks_binary("ünïcödé".as_bytes()));
+    }
+}
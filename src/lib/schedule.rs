@@ -0,0 +1,740 @@
+//! Wall-clock time parsing for deadlines and schedules.
+//!
+//! A `RestartSchedule` names wall-clock times, which a `Calendar` places on the clock: in
+//! the local timezone or a named one (`Europe/Berlin`, with the `timezone` feature), so a
+//! schedule does not move with the host's timezone setting. Twice a year a DST change
+//! skips an hour, or repeats one; `DstGap` and `DstRepeat` decide whether a time in the
+//! skipped hour runs late or not at all, and whether one in the repeated hour runs once or
+//! twice. A time the host sleeps through is run late, or not at all, as `MissedRuns` says.
+use crate::clock::{Clock, SystemClock};
+use chrono::offset::LocalResult;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeDelta, TimeZone};
+use log::info;
+use std::time::Duration;
+
+/// Date-time layouts accepted by `parse_deadline`, tried in order.
+const DATETIME_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+];
+
+/// Time-of-day layouts accepted by `parse_deadline` and `parse_time_of_day`.
+const TIME_FORMATS: &[&str] = &["%H:%M:%S", "%H:%M"];
+
+/// Parses an absolute wall-clock deadline, interpreted in the local timezone.
+///
+/// Accepted forms:
+/// - RFC 3339 with an offset, e.g. `2024-06-01T00:00:00+02:00`
+/// - A local date and time, e.g. `2024-06-01T00:00` or `2024-06-01 06:30:00`
+/// - A bare date, meaning midnight at its start, e.g. `2024-06-01`
+/// - A time of day, meaning its next occurrence after `now`, e.g. `06:00`
+///
+/// # Arguments
+/// - `input`: The string to parse.
+/// - `now`: The reference time for times of day and the "already passed" check.
+///
+/// # Returns
+/// - `Ok(DateTime<Local>)`: The deadline, which is always later than `now`.
+/// - `Err(anyhow::Error)`: If the string matches none of the forms or lies in the past.
+pub fn parse_deadline(input: &str, now: DateTime<Local>) -> Result<DateTime<Local>, anyhow::Error> {
+    let input = input.trim();
+
+    let deadline = if let Ok(dt) = DateTime::parse_from_rfc3339(input) {
+        dt.with_timezone(&Local)
+    } else if let Some(naive) = DATETIME_FORMATS
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(input, fmt).ok())
+    {
+        local_from_naive(naive)?
+    } else if let Ok(date) = NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        local_from_naive(date.and_time(NaiveTime::MIN))?
+    } else if let Ok(time) = parse_time_of_day(input) {
+        next_time_of_day(time, now)?
+    } else {
+        return Err(anyhow::anyhow!(
+            "Invalid deadline \"{}\": expected e.g. \"2024-06-01T00:00\", \"2024-06-01\" or \"06:00\"",
+            input
+        ));
+    };
+
+    if deadline <= now {
+        return Err(anyhow::anyhow!(
+            "Deadline {} has already passed",
+            deadline.format("%Y-%m-%d %H:%M:%S %Z")
+        ));
+    }
+    Ok(deadline)
+}
+
+/// Parses a time of day such as `03:00` or `03:00:30`.
+pub fn parse_time_of_day(input: &str) -> Result<NaiveTime, anyhow::Error> {
+    TIME_FORMATS
+        .iter()
+        .find_map(|fmt| NaiveTime::parse_from_str(input.trim(), fmt).ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid time of day \"{}\": expected HH:MM", input))
+}
+
+/// Returns the first local instant strictly after `now` whose wall-clock time is `time`.
+///
+/// Days on which `time` does not exist (skipped by a DST change) are passed over; when it
+/// is ambiguous (repeated by a DST change) the earlier instant is used.
+pub fn next_time_of_day(
+    time: NaiveTime,
+    now: DateTime<Local>,
+) -> Result<DateTime<Local>, anyhow::Error> {
+    let mut date = now.date_naive();
+    // Two days always suffice unless the time falls into a DST gap; allow a few more.
+    for _ in 0..4 {
+        if let Some(candidate) = Local.from_local_datetime(&date.and_time(time)).earliest()
+            && candidate > now
+        {
+            return Ok(candidate);
+        }
+        date = date
+            .succ_opt()
+            .ok_or_else(|| anyhow::anyhow!("Date out of range"))?;
+    }
+    Err(anyhow::anyhow!("No upcoming occurrence of {}", time))
+}
+
+/// Parses a human-friendly duration such as `30s`, `5m`, `1h 30m` or `250ms`.
+///
+/// A bare number is taken as seconds, matching the `--timeout` flags.
+pub fn parse_duration(input: &str) -> Result<Duration, anyhow::Error> {
+    let input = input.trim();
+    if let Ok(seconds) = input.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+    humantime::parse_duration(input)
+        .map_err(|e| anyhow::anyhow!("Invalid duration \"{}\": {}", input, e))
+}
+
+/// A random extra delay, drawn uniformly from `min..=max` each time it is sampled.
+///
+/// Spreading the start of many detached pollers over a window keeps them from all hitting
+/// an upstream at the same moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Jitter {
+    /// The smallest delay that may be drawn
+    pub min: Duration,
+    /// The largest delay that may be drawn
+    pub max: Duration,
+}
+
+impl Jitter {
+    /// Parses a jitter range such as `0-60s`, `5s-2m`, or `60s` (meaning `0-60s`).
+    ///
+    /// Each bound is parsed with `parse_duration`, so bare numbers are seconds.
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let (min, max) = match input.trim().split_once('-') {
+            Some((min, max)) => (parse_duration(min)?, parse_duration(max)?),
+            None => (Duration::ZERO, parse_duration(input)?),
+        };
+        if min > max {
+            return Err(anyhow::anyhow!(
+                "Invalid jitter \"{}\": the lower bound is larger than the upper bound",
+                input
+            ));
+        }
+        Ok(Jitter { min, max })
+    }
+
+    /// Draws a random delay from the range.
+    pub fn sample(&self) -> Duration {
+        let min = self.min.as_millis() as u64;
+        let max = self.max.as_millis() as u64;
+        Duration::from_millis(rand::random_range(min..=max))
+    }
+}
+
+/// Waits for `delay` (if any) and then runs `future`.
+///
+/// Used to hold back the start of a service or command after daemonization, e.g. to let
+/// slow dependencies come up after boot. The wait is logged so it does not look like a hang.
+pub async fn delayed_start<F>(delay: Option<Duration>, future: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    delayed_start_on(&SystemClock, delay, future).await
+}
+
+/// `delayed_start`, waiting on `clock` instead of the system clock.
+pub async fn delayed_start_on<F>(clock: &dyn Clock, delay: Option<Duration>, future: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    if let Some(delay) = delay.filter(|delay| !delay.is_zero()) {
+        info!("Delaying start by {}.", humantime::format_duration(delay));
+        clock.sleep(delay).await;
+    }
+    future.await
+}
+
+/// Returns the whole number of seconds from `now` until `deadline`, rounded up.
+pub fn seconds_until(deadline: DateTime<Local>, now: DateTime<Local>) -> u64 {
+    let millis = (deadline - now).num_milliseconds().max(0) as u64;
+    millis.div_ceil(1000)
+}
+
+fn local_from_naive(naive: NaiveDateTime) -> Result<DateTime<Local>, anyhow::Error> {
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| anyhow::anyhow!("{} does not exist in the local timezone", naive))
+}
+
+/// A timezone schedules are read in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Timezone {
+    /// The host's timezone
+    #[default]
+    Local,
+    /// A timezone of the IANA database, e.g. `Europe/Berlin` (feature `timezone`)
+    #[cfg(feature = "timezone")]
+    Named(chrono_tz::Tz),
+}
+
+impl Timezone {
+    /// Parses `local` or an IANA timezone name such as `Europe/Berlin` or `UTC`; without
+    /// the `timezone` feature only `local` is known.
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        let input = input.trim();
+        if input.eq_ignore_ascii_case("local") {
+            return Ok(Timezone::Local);
+        }
+        parse_timezone(input)
+    }
+}
+
+impl std::fmt::Display for Timezone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Timezone::Local => f.write_str("local"),
+            #[cfg(feature = "timezone")]
+            Timezone::Named(tz) => f.write_str(tz.name()),
+        }
+    }
+}
+
+#[cfg(feature = "timezone")]
+fn parse_timezone(input: &str) -> Result<Timezone, anyhow::Error> {
+    input
+        .parse::<chrono_tz::Tz>()
+        .map(Timezone::Named)
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "Unknown timezone \"{}\": expected e.g. \"Europe/Berlin\"",
+                input
+            )
+        })
+}
+
+#[cfg(not(feature = "timezone"))]
+fn parse_timezone(input: &str) -> Result<Timezone, anyhow::Error> {
+    Err(anyhow::anyhow!(
+        "Unknown timezone \"{}\": timezone names need the timezone feature, which this \
+         build lacks",
+        input
+    ))
+}
+
+/// What happens to a scheduled time that a DST change skips, like 02:30 when the clock
+/// jumps from 02:00 to 03:00.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DstGap {
+    /// Run as much later as the clock jumped, e.g. at 03:30
+    #[default]
+    Shift,
+    /// Do not run that day
+    Skip,
+}
+
+/// What happens to a scheduled time that a DST change repeats, like 02:30 when the clock
+/// goes back from 03:00 to 02:00.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DstRepeat {
+    /// Run the first time only
+    #[default]
+    Once,
+    /// Run both times
+    Twice,
+}
+
+/// What happens to scheduled times that passed while the host was asleep, noticed when it
+/// wakes up.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissedRuns {
+    /// Leave them out and wait for the next one
+    Skip,
+    /// Make up for all of them at once
+    #[default]
+    RunOnce,
+    /// Make up for each of them, one after the other
+    RunAll,
+}
+
+/// How the wall-clock times of a schedule are placed on the clock.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Calendar {
+    /// The timezone the times are in
+    pub timezone: Timezone,
+    /// Times skipped by a DST change
+    pub gap: DstGap,
+    /// Times repeated by a DST change
+    pub repeat: DstRepeat,
+}
+
+impl Calendar {
+    /// Formats `instant` in the calendar's timezone, e.g. `2024-06-01 03:00:00 CEST`.
+    pub fn format(&self, instant: DateTime<Local>) -> String {
+        const FORMAT: &str = "%Y-%m-%d %H:%M:%S %Z";
+        match self.timezone {
+            Timezone::Local => instant.format(FORMAT).to_string(),
+            #[cfg(feature = "timezone")]
+            Timezone::Named(tz) => instant.with_timezone(&tz).format(FORMAT).to_string(),
+        }
+    }
+
+    /// Returns the earliest instant strictly after `now` at which one of `times` falls.
+    ///
+    /// `times` must yield wall-clock times in order, starting early enough to cover `now`
+    /// minus `DST_MARGIN`.
+    fn next<Tz: TimeZone>(
+        &self,
+        tz: &Tz,
+        now: &DateTime<Tz>,
+        times: impl Iterator<Item = NaiveDateTime>,
+    ) -> Option<DateTime<Tz>> {
+        let mut next: Option<DateTime<Tz>> = None;
+        for time in times {
+            // Later times fall later, unless a DST change moves the earlier ones past them
+            if let Some(next) = &next
+                && time > next.naive_local() + DST_MARGIN
+            {
+                break;
+            }
+            let instants = match tz.from_local_datetime(&time) {
+                LocalResult::Single(instant) => [Some(instant), None],
+                LocalResult::Ambiguous(first, second) => [
+                    Some(first),
+                    (self.repeat == DstRepeat::Twice).then_some(second),
+                ],
+                LocalResult::None => match self.gap {
+                    DstGap::Shift => [Some(shifted(tz, time)), None],
+                    DstGap::Skip => [None, None],
+                },
+            };
+            for instant in instants.into_iter().flatten() {
+                if instant > *now && next.as_ref().is_none_or(|next| instant < *next) {
+                    next = Some(instant);
+                }
+            }
+        }
+        next
+    }
+}
+
+/// More than any DST change moves the clock.
+const DST_MARGIN: TimeDelta = TimeDelta::hours(3);
+
+/// The instant `time`, skipped by a jump of the clock, would have been without the jump.
+fn shifted<Tz: TimeZone>(tz: &Tz, time: NaiveDateTime) -> DateTime<Tz> {
+    // The offset in effect before the jump; no timezone changes it twice in a day
+    let before = tz
+        .offset_from_utc_datetime(&(time - TimeDelta::days(1)))
+        .fix();
+    tz.from_utc_datetime(&(time - TimeDelta::seconds(before.local_minus_utc().into())))
+}
+
+/// When a supervised command should be restarted on purpose.
+#[derive(Debug, Clone)]
+pub enum RestartSchedule {
+    /// Every day at the given wall-clock time, e.g. `03:00`
+    Daily(NaiveTime),
+    /// On every match of a cron expression, evaluated in wall-clock time (feature `cron`)
+    #[cfg(feature = "cron")]
+    Cron(Box<cron::Schedule>),
+}
+
+impl RestartSchedule {
+    /// Parses a restart schedule.
+    ///
+    /// `HH:MM` or `HH:MM:SS` means daily at that time. Anything else is read as a cron
+    /// expression: the classic five fields (`min hour day month weekday`, e.g.
+    /// `0 3 * * Sun`, with weekdays numbered from 0 or 7 for Sunday to 6 for Saturday) or
+    /// the six/seven-field form with leading seconds, where weekdays are numbered from 1
+    /// for Sunday to 7 for Saturday; without the `cron` feature it is an error.
+    pub fn parse(input: &str) -> Result<Self, anyhow::Error> {
+        if let Ok(time) = parse_time_of_day(input) {
+            return Ok(RestartSchedule::Daily(time));
+        }
+        parse_cron(input.trim())
+    }
+
+    /// Returns the next scheduled instant strictly after `now` in `calendar`, if there is
+    /// one.
+    pub fn next_after(&self, now: DateTime<Local>, calendar: &Calendar) -> Option<DateTime<Local>> {
+        match calendar.timezone {
+            Timezone::Local => self.next_in(calendar, &Local, &now),
+            #[cfg(feature = "timezone")]
+            Timezone::Named(tz) => self
+                .next_in(calendar, &tz, &now.with_timezone(&tz))
+                .map(|next| next.with_timezone(&Local)),
+        }
+    }
+
+    fn next_in<Tz: TimeZone>(
+        &self,
+        calendar: &Calendar,
+        tz: &Tz,
+        now: &DateTime<Tz>,
+    ) -> Option<DateTime<Tz>> {
+        // A time repeated by a DST change may come round again after it has passed
+        let from = now.naive_local() - DST_MARGIN;
+        match self {
+            RestartSchedule::Daily(time) => {
+                let times = from.date().iter_days().map(|date| date.and_time(*time));
+                calendar.next(tz, now, times.filter(|at| *at >= from))
+            }
+            // The wall-clock times of the expression, found as if they were UTC, which has
+            // no DST changes to get in the way
+            #[cfg(feature = "cron")]
+            RestartSchedule::Cron(schedule) => {
+                let times = schedule.after(&from.and_utc()).map(|at| at.naive_utc());
+                calendar.next(tz, now, times)
+            }
+        }
+    }
+}
+#[cfg(feature = "cron")]
+fn parse_cron(input: &str) -> Result<RestartSchedule, anyhow::Error> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        anyhow::anyhow!("Invalid restart schedule \"{}\": {}", input, e)
+    };
+    let fields: Vec<&str> = input.split_whitespace().collect();
+    let expr = match fields[..] {
+        [minute, hour, day, month, weekday] => {
+            let weekday = classic_weekdays(weekday).map_err(|e| invalid(&e))?;
+            format!("0 {} {} {} {} {}", minute, hour, day, month, weekday)
+        }
+        _ => input.to_string(),
+    };
+    let schedule = expr.parse::<cron::Schedule>().map_err(|e| invalid(&e))?;
+    Ok(RestartSchedule::Cron(Box::new(schedule)))
+}
+
+/// Converts the weekday field of a classic five-field expression, where Sunday is 0 or 7,
+/// to the numbering of the `cron` crate, where it is 1 and Saturday is 7. Ranges and steps
+/// are spelled out as lists, so `5-7` (Friday to Sunday) does not wrap around the week.
+/// Day names mean the same in both and are kept as they are.
+#[cfg(feature = "cron")]
+fn classic_weekdays(field: &str) -> Result<String, anyhow::Error> {
+    let named = field.chars().any(|c| c.is_ascii_alphabetic());
+    if named && field.chars().any(|c| c.is_ascii_digit()) {
+        return Err(anyhow::anyhow!(
+            "weekdays \"{}\" mix names and numbers",
+            field
+        ));
+    }
+    if named || field == "*" || field == "?" {
+        return Ok(field.to_string());
+    }
+    let number = |text: &str| {
+        text.parse::<u32>()
+            .ok()
+            .filter(|&day| day <= 7)
+            .ok_or_else(|| anyhow::anyhow!("\"{}\" is not a weekday from 0 to 7", text))
+    };
+    let mut days = Vec::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<usize>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(anyhow::anyhow!("\"{}\" is not a valid step", step)),
+            },
+            None => (item, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((first, last)) => (number(first)?, number(last)?),
+            // `N/STEP` runs from N to the end of the week
+            None if step > 1 => (number(range)?, 6),
+            None => (number(range)?, number(range)?),
+        };
+        if first > last {
+            return Err(anyhow::anyhow!(
+                "weekday range \"{}\" runs backwards",
+                range
+            ));
+        }
+        days.extend((first..=last).step_by(step).map(|day| day % 7 + 1));
+    }
+    days.sort_unstable();
+    days.dedup();
+    Ok(days
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+#[cfg(not(feature = "cron"))]
+fn parse_cron(input: &str) -> Result<RestartSchedule, anyhow::Error> {
+    Err(anyhow::anyhow!(
+        "Invalid restart schedule \"{}\": expected HH:MM; cron expressions need the cron \
+         feature, which this build lacks",
+        input
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The next match of `schedule` in `calendar` after `now`, both in UTC as
+    /// `YYYY-MM-DD HH:MM`.
+    #[cfg(feature = "timezone")]
+    fn next_after(calendar: &Calendar, schedule: &str, now: &str) -> String {
+        let now = NaiveDateTime::parse_from_str(now, "%Y-%m-%d %H:%M")
+            .unwrap()
+            .and_utc()
+            .with_timezone(&Local);
+        let next = RestartSchedule::parse(schedule)
+            .unwrap()
+            .next_after(now, calendar)
+            .unwrap();
+        next.naive_utc().format("%Y-%m-%d %H:%M").to_string()
+    }
+
+    /// Noon on 2026-06-01, local time.
+    fn noon() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2026, 6, 1, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn deadlines_are_dates_times_or_times_of_day() {
+        let now = noon();
+        let at = |hour, minute| Local.with_ymd_and_hms(2026, 6, 2, hour, minute, 0).unwrap();
+        assert_eq!(parse_deadline("2026-06-02T06:30", now).unwrap(), at(6, 30));
+        assert_eq!(
+            parse_deadline(" 2026-06-02 06:30:00 ", now).unwrap(),
+            at(6, 30)
+        );
+        assert_eq!(parse_deadline("2026-06-02", now).unwrap(), at(0, 0));
+        // A time of day that has passed today means tomorrow
+        assert_eq!(parse_deadline("06:30", now).unwrap(), at(6, 30));
+        let later = Local.with_ymd_and_hms(2026, 6, 1, 18, 0, 0).unwrap();
+        assert_eq!(parse_deadline("18:00", now).unwrap(), later);
+        let utc = parse_deadline("2026-06-02T06:30:00Z", now).unwrap();
+        assert_eq!(utc.naive_utc().to_string(), "2026-06-02 06:30:00");
+    }
+
+    #[test]
+    fn deadlines_must_be_valid_and_ahead() {
+        let now = noon();
+        assert!(parse_deadline("2026-06-01T11:00", now).is_err());
+        assert!(parse_deadline("2026-06-01T12:00", now).is_err());
+        assert!(parse_deadline("2026-06-01", now).is_err());
+        assert!(parse_deadline("tomorrow", now).is_err());
+        assert!(parse_deadline("", now).is_err());
+    }
+
+    #[test]
+    fn times_of_day_take_optional_seconds() {
+        let time = |h, m, s| NaiveTime::from_hms_opt(h, m, s).unwrap();
+        assert_eq!(parse_time_of_day("03:00").unwrap(), time(3, 0, 0));
+        assert_eq!(parse_time_of_day(" 03:00:30 ").unwrap(), time(3, 0, 30));
+        assert!(parse_time_of_day("24:00").is_err());
+        assert!(parse_time_of_day("3pm").is_err());
+    }
+
+    #[test]
+    fn seconds_until_rounds_up_and_stops_at_zero() {
+        let now = noon();
+        let later = |millis| now + chrono::Duration::milliseconds(millis);
+        assert_eq!(seconds_until(later(1500), now), 2);
+        assert_eq!(seconds_until(later(2000), now), 2);
+        assert_eq!(seconds_until(now, now), 0);
+        assert_eq!(seconds_until(later(-5000), now), 0);
+    }
+
+    #[test]
+    fn durations_are_seconds_or_human_readable() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration(" 5m ").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h 30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse_duration("-5").is_err());
+        assert!(parse_duration("soon").is_err());
+    }
+
+    #[test]
+    fn jitter_is_a_range_or_an_upper_bound() {
+        let secs = Duration::from_secs;
+        let jitter = |min, max| Jitter { min, max };
+        assert_eq!(Jitter::parse("60s").unwrap(), jitter(secs(0), secs(60)));
+        assert_eq!(Jitter::parse("0-60s").unwrap(), jitter(secs(0), secs(60)));
+        assert_eq!(Jitter::parse("5s-2m").unwrap(), jitter(secs(5), secs(120)));
+        assert_eq!(Jitter::parse("10-10").unwrap(), jitter(secs(10), secs(10)));
+        assert!(Jitter::parse("2m-5s").is_err());
+        assert!(Jitter::parse("5s-").is_err());
+    }
+
+    #[test]
+    fn jitter_samples_stay_in_range() {
+        let jitter = Jitter::parse("1s-2s").unwrap();
+        for _ in 0..100 {
+            let delay = jitter.sample();
+            assert!((jitter.min..=jitter.max).contains(&delay), "{:?}", delay);
+        }
+        assert_eq!(
+            Jitter::parse("3s-3s").unwrap().sample(),
+            Duration::from_secs(3)
+        );
+    }
+
+    /// The next match of `schedule` after `now`, in UTC.
+    #[cfg(all(feature = "cron", feature = "timezone"))]
+    fn next_utc(schedule: &str, now: &str) -> String {
+        let calendar = Calendar {
+            timezone: Timezone::Named(chrono_tz::UTC),
+            ..Calendar::default()
+        };
+        next_after(&calendar, schedule, now)
+    }
+
+    /// A calendar for Berlin, where the clock jumps from 02:00 to 03:00 on 2026-03-29
+    /// (00:59 to 01:00 UTC) and goes back from 03:00 to 02:00 on 2026-10-25 (01:00 UTC).
+    #[cfg(feature = "timezone")]
+    fn berlin(gap: DstGap, repeat: DstRepeat) -> Calendar {
+        Calendar {
+            timezone: Timezone::Named(chrono_tz::Europe::Berlin),
+            gap,
+            repeat,
+        }
+    }
+
+    #[cfg(feature = "timezone")]
+    #[test]
+    fn times_skipped_by_dst_are_shifted_or_skipped() {
+        let shift = berlin(DstGap::Shift, DstRepeat::Once);
+        let skip = berlin(DstGap::Skip, DstRepeat::Once);
+        let before = "2026-03-28 12:00";
+        // 02:30 does not exist that night; shifted, it is 03:30 CEST
+        assert_eq!(next_after(&shift, "02:30", before), "2026-03-29 01:30");
+        assert_eq!(next_after(&skip, "02:30", before), "2026-03-30 00:30");
+        // Times the jump does not touch are placed as usual
+        assert_eq!(next_after(&skip, "03:30", before), "2026-03-29 01:30");
+        assert_eq!(next_after(&skip, "01:30", before), "2026-03-29 00:30");
+    }
+
+    #[cfg(feature = "timezone")]
+    #[test]
+    fn times_repeated_by_dst_run_once_or_twice() {
+        let once = berlin(DstGap::Shift, DstRepeat::Once);
+        let twice = berlin(DstGap::Shift, DstRepeat::Twice);
+        let before = "2026-10-24 12:00";
+        // 02:30 comes first in CEST (00:30 UTC), then again in CET (01:30 UTC)
+        assert_eq!(next_after(&once, "02:30", before), "2026-10-25 00:30");
+        assert_eq!(next_after(&twice, "02:30", before), "2026-10-25 00:30");
+        let between = "2026-10-25 00:45";
+        assert_eq!(next_after(&once, "02:30", between), "2026-10-26 01:30");
+        assert_eq!(next_after(&twice, "02:30", between), "2026-10-25 01:30");
+    }
+
+    #[cfg(all(feature = "cron", feature = "timezone"))]
+    #[test]
+    fn cron_expressions_follow_dst_like_times_of_day() {
+        let shift = berlin(DstGap::Shift, DstRepeat::Once);
+        let twice = berlin(DstGap::Shift, DstRepeat::Twice);
+        assert_eq!(
+            next_after(&shift, "30 2 * * *", "2026-03-28 12:00"),
+            "2026-03-29 01:30"
+        );
+        assert_eq!(
+            next_after(&twice, "30 2 * * *", "2026-10-25 00:45"),
+            "2026-10-25 01:30"
+        );
+        // Every hour: the hour repeated at the change back comes round twice
+        assert_eq!(
+            next_after(&twice, "0 * * * *", "2026-10-25 00:30"),
+            "2026-10-25 01:00"
+        );
+    }
+
+    #[cfg(feature = "timezone")]
+    #[test]
+    fn calendars_format_instants_in_their_timezone() {
+        let calendar = berlin(DstGap::Shift, DstRepeat::Once);
+        let summer = chrono::Utc.with_ymd_and_hms(2026, 6, 1, 1, 0, 0).unwrap();
+        let winter = chrono::Utc.with_ymd_and_hms(2026, 12, 1, 2, 0, 0).unwrap();
+        assert_eq!(
+            calendar.format(summer.with_timezone(&Local)),
+            "2026-06-01 03:00:00 CEST"
+        );
+        assert_eq!(
+            calendar.format(winter.with_timezone(&Local)),
+            "2026-12-01 03:00:00 CET"
+        );
+    }
+
+    #[test]
+    fn timezones_are_local_or_named() {
+        assert_eq!(Timezone::parse(" Local ").unwrap(), Timezone::Local);
+        assert_eq!(
+            Timezone::parse("Europe/Berlin").is_ok(),
+            cfg!(feature = "timezone")
+        );
+        assert!(Timezone::parse("Mars/Olympus_Mons").is_err());
+    }
+
+    #[cfg(all(feature = "cron", feature = "timezone"))]
+    #[test]
+    fn classic_weekdays_count_from_sunday() {
+        // 2026-10-16 is a Friday
+        let friday = "2026-10-16 12:00";
+        assert_eq!(next_utc("0 3 * * 1", friday), "2026-10-19 03:00");
+        assert_eq!(next_utc("0 3 * * 0", friday), "2026-10-18 03:00");
+        assert_eq!(next_utc("0 3 * * 7", friday), "2026-10-18 03:00");
+        assert_eq!(next_utc("0 3 * * 6", friday), "2026-10-17 03:00");
+        assert_eq!(next_utc("0 3 * * Mon", friday), "2026-10-19 03:00");
+        assert_eq!(next_utc("0 3 * * 1-5", friday), "2026-10-19 03:00");
+        assert_eq!(next_utc("0 3 * * 5-7", friday), "2026-10-17 03:00");
+    }
+
+    #[cfg(feature = "cron")]
+    #[test]
+    fn classic_weekdays_map_to_the_crate_numbering() {
+        assert_eq!(classic_weekdays("0").unwrap(), "1");
+        assert_eq!(classic_weekdays("7").unwrap(), "1");
+        assert_eq!(classic_weekdays("1-5").unwrap(), "2,3,4,5,6");
+        assert_eq!(classic_weekdays("5-7").unwrap(), "1,6,7");
+        assert_eq!(classic_weekdays("*/2").unwrap(), "1,3,5,7");
+        assert_eq!(classic_weekdays("0,6").unwrap(), "1,7");
+        assert_eq!(classic_weekdays("*").unwrap(), "*");
+        assert_eq!(classic_weekdays("MON-FRI").unwrap(), "MON-FRI");
+        assert!(classic_weekdays("8").is_err());
+        assert!(classic_weekdays("5-1").is_err());
+        assert!(classic_weekdays("MON,3").is_err());
+    }
+
+    #[test]
+    fn times_of_day_are_daily_schedules() {
+        let schedule = RestartSchedule::parse("03:00").unwrap();
+        let three = NaiveTime::from_hms_opt(3, 0, 0).unwrap();
+        assert!(matches!(schedule, RestartSchedule::Daily(time) if time == three));
+        assert_eq!(
+            RestartSchedule::parse("0 3 * * 1").is_ok(),
+            cfg!(feature = "cron")
+        );
+    }
+
+    #[cfg(feature = "cron")]
+    #[test]
+    fn six_field_expressions_keep_the_crate_numbering() {
+        assert!(RestartSchedule::parse("0 0 3 * * 1").is_ok());
+        assert!(RestartSchedule::parse("0 0 3 * * 0").is_err());
+    }
+}
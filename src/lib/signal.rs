@@ -0,0 +1,105 @@
+//! Small helpers for signalling processes.
+//!
+//! These wrap the raw `libc` calls so callers do not need their own `unsafe` blocks or
+//! `errno` handling. They are only available on Unix.
+use std::io;
+
+pub use libc::{SIGHUP, SIGINT, SIGKILL, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2, SIGWINCH};
+
+/// Signals a supervisor passes on to its command unless told otherwise.
+pub const DEFAULT_FORWARD_SIGNALS: &[i32] = &[SIGTERM, SIGINT, SIGHUP, SIGUSR1, SIGUSR2, SIGWINCH];
+
+/// Returns the conventional name of a signal number (e.g., `SIGSEGV` for 11).
+///
+/// Unknown or platform-specific numbers yield `None`.
+pub fn signal_name(signal: i32) -> Option<&'static str> {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGCHLD => "SIGCHLD",
+        libc::SIGCONT => "SIGCONT",
+        libc::SIGSTOP => "SIGSTOP",
+        libc::SIGTSTP => "SIGTSTP",
+        libc::SIGTTIN => "SIGTTIN",
+        libc::SIGTTOU => "SIGTTOU",
+        libc::SIGURG => "SIGURG",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGVTALRM => "SIGVTALRM",
+        libc::SIGPROF => "SIGPROF",
+        libc::SIGWINCH => "SIGWINCH",
+        libc::SIGSYS => "SIGSYS",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Parses a signal given as `TERM`, `SIGTERM` or `15` (names are case-insensitive).
+pub fn parse_signal(input: &str) -> Result<i32, anyhow::Error> {
+    let input = input.trim();
+    if let Ok(number) = input.parse::<i32>()
+        && signal_name(number).is_some()
+    {
+        return Ok(number);
+    }
+    let upper = input.to_ascii_uppercase();
+    let wanted = upper.strip_prefix("SIG").unwrap_or(&upper);
+    (1..32)
+        .find(|&signal| signal_name(signal).is_some_and(|name| &name[3..] == wanted))
+        .ok_or_else(|| anyhow::anyhow!("Unknown signal \"{}\"", input))
+}
+
+/// Sends `signal` to the process `pid`.
+///
+/// # Returns
+/// - `Ok(())`: The signal was delivered.
+/// - `Err(io::Error)`: `kill(2)` failed, e.g. with `ESRCH` if the process does not exist.
+pub fn send_signal(pid: u32, signal: i32) -> io::Result<()> {
+    let ret = unsafe { libc::kill(pid as libc::pid_t, signal) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Sends `signal` to every process in the process group `pgid`.
+pub fn send_signal_group(pgid: u32, signal: i32) -> io::Result<()> {
+    let ret = unsafe { libc::kill(-(pgid as libc::pid_t), signal) };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns `true` if any process is left in the process group `pgid`.
+pub fn is_group_alive(pgid: u32) -> bool {
+    match send_signal_group(pgid, 0) {
+        Ok(()) => true,
+        Err(e) => e.raw_os_error() == Some(libc::EPERM),
+    }
+}
+
+/// Returns `true` if a process with the given `pid` exists.
+///
+/// Uses the null signal, so a process owned by another user still counts as alive.
+pub fn is_alive(pid: u32) -> bool {
+    match send_signal(pid, 0) {
+        Ok(()) => true,
+        Err(e) => e.raw_os_error() == Some(libc::EPERM),
+    }
+}
@@ -0,0 +1,118 @@
+//! A status file that monitors can read without talking to the instance.
+//!
+//! `detach-rs status` needs the control socket and an instance that answers on it. An
+//! instance started with `--name NAME` therefore also writes its status to
+//! `status/NAME.json` under `config::state_dir()` every `--status-interval` (10 seconds
+//! by default), and once more when it stops:
+//!
+//! ```text
+//! {"name":"web","pid":4242,"run_id":"5f0c…","state":"ready","state_since":"…","health":"healthy","heartbeat":"2026-10-16T03:00:01.204+02:00","interval_secs":10.0,"uptime_secs":3600,"child_pid":4243,"last_event":{…},"memory":{"rss":…},"metrics":{…}}
+//! ```
+//!
+//! The file is replaced by a rename, so a reader always sees a whole snapshot and never
+//! needs a lock. `heartbeat` is when it was written: an instance whose runtime is wedged
+//! stops updating it, so a heartbeat older than a few intervals means the instance is
+//! stuck or gone, even while its process and control socket still exist.
+use crate::context::ServiceContext;
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often the file is written unless `--status-interval` says otherwise.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Returns the status file of the instance `name`.
+pub fn path(name: &str) -> Result<PathBuf, anyhow::Error> {
+    crate::config::validate_service_name(name)?;
+    Ok(crate::config::state_dir()?
+        .join("status")
+        .join(format!("{}.json", name)))
+}
+
+/// Returns the status of the instance `name` as written to its status file.
+pub fn snapshot(name: &str, interval: Duration, ctx: &ServiceContext) -> serde_json::Value {
+    let millis = |time: chrono::DateTime<chrono::Local>| {
+        time.to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+    };
+    let memory = crate::memory::MemoryStats::collect();
+    serde_json::json!({
+        "name": name,
+        "pid": std::process::id(),
+        "run_id": crate::context::run_id(),
+        "state": ctx.state().get().as_str(),
+        "state_since": millis(ctx.state().since()),
+        "health": ctx.health().get().to_string(),
+        "heartbeat": millis(chrono::Local::now()),
+        "interval_secs": interval.as_secs_f64(),
+        "uptime_secs": ctx.uptime().as_secs(),
+        "child_pid": ctx.child_pid(),
+        "last_event": ctx.recent_events().last().map(crate::audit::LifecycleEvent::to_json),
+        "memory": {
+            "rss": memory.rss,
+            "peak_rss": memory.peak_rss,
+        },
+        "metrics": ctx.metrics().to_json(),
+    })
+}
+
+/// Runs `future` while writing the status of `ctx` to the status file of the instance
+/// `name` every `interval`, and a last time after `future` completes.
+///
+/// Without a `name`, or with a zero `interval`, this is a plain `future.await`. Failing
+/// to write the file is logged and never stops the service.
+pub async fn with_status_file<F>(
+    name: Option<String>,
+    interval: Duration,
+    ctx: ServiceContext,
+    future: F,
+) -> anyhow::Result<()>
+where
+    F: std::future::Future<Output = anyhow::Result<()>>,
+{
+    // `future` is awaited in one place only, so it is stored once in this future
+    let file = name
+        .filter(|_| !interval.is_zero())
+        .and_then(|name| match path(&name) {
+            Ok(path) => Some((name, path)),
+            Err(e) => {
+                warn!("Cannot write a status file for {}: {}", name, e);
+                None
+            }
+        });
+    let mut failing = false;
+    let mut update = |ctx: &ServiceContext| {
+        let Some((name, path)) = &file else {
+            return;
+        };
+        match write(path, &snapshot(name, interval, ctx)) {
+            Ok(()) => failing = false,
+            Err(e) if failing => debug!("Failed to write {}: {}", path.display(), e),
+            Err(e) => {
+                warn!("Failed to write {}: {}", path.display(), e);
+                failing = true;
+            }
+        }
+    };
+    let mut ticks = tokio::time::interval(interval.max(Duration::from_millis(1)));
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    tokio::pin!(future);
+    let result = loop {
+        tokio::select! {
+            result = &mut future => break result,
+            _ = ticks.tick(), if file.is_some() => update(&ctx),
+        }
+    };
+    update(&ctx);
+    result
+}
+
+/// Replaces `path` with `status`, through a temporary file so readers never see half of
+/// it.
+fn write(path: &Path, status: &serde_json::Value) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension("json.partial");
+    std::fs::write(&partial, format!("{}\n", status))?;
+    std::fs::rename(&partial, path)
+}
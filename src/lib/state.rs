@@ -0,0 +1,560 @@
+//! The lifecycle state of an instance.
+//!
+//! Whether an instance is up used to be pieced together from several places: a live
+//! PID, a child PID, the health the service reported and the last event. `State` keeps
+//! it in one place instead, as a `ServiceState` that only moves along the allowed
+//! transitions:
+//!
+//! ```text
+//! Defined -> Starting -> Ready <-> Degraded
+//!               |          |          |
+//!               +------> Stopping <---+ -> Starting (restart)
+//!                          |
+//!                          +-> Stopped | Failed
+//! ```
+//!
+//! The supervisor drives it: `Starting` when it starts the command, `Ready` (or
+//! `Degraded` while the service reports itself degraded) once the command has kept
+//! running for a second, `Stopping` when a stop or restart is requested, and `Stopped` or
+//! `Failed` when it is done. Any running state may also end in `Stopped` or `Failed`
+//! directly when the command exits by itself.
+//!
+//! `status` reports the state and every lifecycle event carries the state it left the
+//! instance in. With a control socket, each transition is also written to `NAME.state` in
+//! `config::runtime_dir()`, where it outlives the instance: `detach-rs list` shows
+//! stopped and failed instances from it until `detach-rs clean` removes them. The record
+//! also holds the PID of the running command, which lets a new supervisor take over a
+//! command that outlived its old one (see `adopt`), and the `LastRun` of the command: when
+//! it last started and ended and how it exited, which `list` shows and the
+//! `detach_command_last_*` gauges export. A new instance by the same name keeps the last
+//! run of the one before until it has its own, so a nightly job can be checked on in the
+//! morning.
+use crate::context::ServiceContext;
+use chrono::{DateTime, Local};
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Where an instance is in its lifecycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ServiceState {
+    /// Set up, but nothing has been started yet
+    #[default]
+    Defined,
+    /// The command was started and has not proven itself yet
+    Starting,
+    /// Running and healthy
+    Ready,
+    /// Running, but the service reports itself degraded
+    Degraded,
+    /// Asked to stop, waiting for the command to exit
+    Stopping,
+    /// Done, on request or because the command finished successfully
+    Stopped,
+    /// Done because the command failed, timed out or could not be started
+    Failed,
+}
+
+impl ServiceState {
+    /// Every state, in lifecycle order.
+    pub const ALL: [ServiceState; 7] = [
+        ServiceState::Defined,
+        ServiceState::Starting,
+        ServiceState::Ready,
+        ServiceState::Degraded,
+        ServiceState::Stopping,
+        ServiceState::Stopped,
+        ServiceState::Failed,
+    ];
+
+    /// The lowercase name used in `status`, events and the registry.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ServiceState::Defined => "defined",
+            ServiceState::Starting => "starting",
+            ServiceState::Ready => "ready",
+            ServiceState::Degraded => "degraded",
+            ServiceState::Stopping => "stopping",
+            ServiceState::Stopped => "stopped",
+            ServiceState::Failed => "failed",
+        }
+    }
+
+    /// Returns `true` for `Stopped` and `Failed`, which only a new start leaves.
+    pub fn is_final(self) -> bool {
+        matches!(self, ServiceState::Stopped | ServiceState::Failed)
+    }
+
+    /// Returns `true` for `Ready` and `Degraded`: the command is up.
+    pub fn is_running(self) -> bool {
+        matches!(self, ServiceState::Ready | ServiceState::Degraded)
+    }
+
+    /// Returns `true` if an instance may move from this state to `next`.
+    ///
+    /// ```
+    /// use detach::state::ServiceState;
+    ///
+    /// assert!(ServiceState::Starting.can_become(ServiceState::Ready));
+    /// assert!(ServiceState::Stopping.can_become(ServiceState::Starting));
+    /// assert!(!ServiceState::Stopped.can_become(ServiceState::Ready));
+    /// assert!(!ServiceState::Ready.can_become(ServiceState::Ready));
+    /// ```
+    pub fn can_become(self, next: ServiceState) -> bool {
+        use ServiceState::*;
+        match (self, next) {
+            (Defined | Stopping | Stopped | Failed, Starting) => true,
+            (Starting | Ready | Degraded, Ready | Degraded) => self != next,
+            (Defined | Starting | Ready | Degraded, Stopping) => true,
+            (current, Stopped | Failed) => !current.is_final(),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ServiceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for ServiceState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ServiceState::ALL
+            .into_iter()
+            .find(|state| state.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("unknown state \"{}\"", s))
+    }
+}
+
+/// The last run of the supervised command that has ended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastRun {
+    /// When it was started
+    pub started: DateTime<Local>,
+    /// When it ended
+    pub ended: DateTime<Local>,
+    /// How long it ran
+    pub duration: Duration,
+    /// The exit code a shell would report, 128 + the signal if it was killed; `None` if
+    /// it is not known (an adopted command)
+    pub exit_code: Option<i32>,
+}
+
+impl LastRun {
+    /// The run as the registry records it and `status` reports it:
+    /// `{"started":…,"ended":…,"duration_secs":12.5,"exit_code":0}`.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "started": self.started.to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            "ended": self.ended.to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            "duration_secs": self.duration.as_secs_f64(),
+            "exit_code": self.exit_code,
+        })
+    }
+
+    /// Reads a run written by `to_json`; `None` if `json` is not one.
+    pub fn from_json(json: &serde_json::Value) -> Option<Self> {
+        let time = |key: &str| {
+            DateTime::parse_from_rfc3339(json[key].as_str()?)
+                .ok()
+                .map(|time| time.with_timezone(&Local))
+        };
+        Some(LastRun {
+            started: time("started")?,
+            ended: time("ended")?,
+            duration: Duration::try_from_secs_f64(json["duration_secs"].as_f64()?).ok()?,
+            exit_code: json["exit_code"]
+                .as_i64()
+                .and_then(|code| i32::try_from(code).ok()),
+        })
+    }
+
+    /// Describes the run in a line, e.g. `2026-10-16 03:00:00, 12s, exit 0`.
+    pub fn summary(&self) -> String {
+        let exit = match self.exit_code {
+            Some(code) => format!("exit {}", code),
+            None => "exit unknown".to_string(),
+        };
+        format!(
+            "{}, {}, {}",
+            self.started.format("%Y-%m-%d %H:%M:%S"),
+            humantime::format_duration(Duration::from_secs(self.duration.as_secs())),
+            exit
+        )
+    }
+
+    /// Sets the `detach_command_last_*` gauges of the process-wide metrics to this run.
+    fn export(&self) {
+        let ctx = ServiceContext::current();
+        let metrics = ctx.metrics();
+        let timestamp = |time: DateTime<Local>| time.timestamp_millis() as f64 / 1000.0;
+        metrics
+            .gauge(
+                "detach_command_last_start_timestamp_seconds",
+                "When the last execution started, in seconds since the Unix epoch",
+            )
+            .set(timestamp(self.started));
+        metrics
+            .gauge(
+                "detach_command_last_end_timestamp_seconds",
+                "When the last execution ended, in seconds since the Unix epoch",
+            )
+            .set(timestamp(self.ended));
+        metrics
+            .gauge(
+                "detach_command_last_duration_seconds",
+                "Wall-clock duration of the last execution",
+            )
+            .set(self.duration.as_secs_f64());
+        if let Some(code) = self.exit_code {
+            metrics
+                .gauge(
+                    "detach_command_last_exit_code",
+                    "Shell exit code of the last execution (128 + signal when killed)",
+                )
+                .set(code.into());
+        }
+    }
+}
+
+/// The handle the state of an instance is kept in; obtained from `ServiceContext::state`.
+#[derive(Debug)]
+pub struct State {
+    current: watch::Sender<(ServiceState, DateTime<Local>)>,
+    /// The registry file every transition is written to, once set
+    registry: OnceLock<PathBuf>,
+    /// The PID of the running command and when it started (see `adopt::start_time`)
+    child: Mutex<Option<(u32, Option<u64>)>>,
+    /// The last run of the command that has ended
+    last_run: Mutex<Option<LastRun>>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            current: watch::Sender::new((ServiceState::Defined, Local::now())),
+            registry: OnceLock::new(),
+            child: Mutex::new(None),
+            last_run: Mutex::new(None),
+        }
+    }
+}
+
+impl State {
+    /// The current state.
+    pub fn get(&self) -> ServiceState {
+        self.current.borrow().0
+    }
+
+    /// When the current state was entered.
+    pub fn since(&self) -> DateTime<Local> {
+        self.current.borrow().1
+    }
+
+    /// Returns a receiver that is notified on every transition.
+    pub fn subscribe(&self) -> watch::Receiver<(ServiceState, DateTime<Local>)> {
+        self.current.subscribe()
+    }
+
+    /// Moves to `next`.
+    ///
+    /// # Returns
+    /// - `Ok(())`: If the transition is allowed; it has been made and recorded.
+    /// - `Err(anyhow::Error)`: If it is not (see `ServiceState::can_become`); the state is
+    ///   unchanged.
+    pub fn set(&self, next: ServiceState) -> Result<(), anyhow::Error> {
+        let mut from = next;
+        let changed = self.current.send_if_modified(|current| {
+            from = current.0;
+            if !current.0.can_become(next) {
+                return false;
+            }
+            *current = (next, Local::now());
+            true
+        });
+        if !changed {
+            return Err(anyhow::anyhow!(
+                "Invalid state transition from {} to {}",
+                from,
+                next
+            ));
+        }
+        debug!("State: {} -> {}", from, next);
+        self.record();
+        Ok(())
+    }
+
+    /// Moves to `next` like `set`, logging an invalid transition instead of returning it.
+    pub(crate) fn enter(&self, next: ServiceState) {
+        if let Err(e) = self.set(next) {
+            warn!("{}", e);
+        }
+    }
+
+    /// Moves a starting or running instance to `Ready`, or to `Degraded` if `healthy` is
+    /// `false`; other states are left alone.
+    pub(crate) fn up(&self, healthy: bool) {
+        let next = if healthy {
+            ServiceState::Ready
+        } else {
+            ServiceState::Degraded
+        };
+        let current = self.get();
+        if current == ServiceState::Starting || current.is_running() && current != next {
+            self.enter(next);
+        }
+    }
+
+    /// Moves to `Stopping`, unless a stop is under way or done already.
+    pub(crate) fn stopping(&self) {
+        let current = self.get();
+        if current != ServiceState::Stopping && !current.is_final() {
+            self.enter(ServiceState::Stopping);
+        }
+    }
+
+    /// Moves to `Stopped` after `result` is `Ok`, or to `Failed` after an error, unless
+    /// the state is final already.
+    pub(crate) fn finish<T>(&self, result: &anyhow::Result<T>) {
+        if !self.get().is_final() {
+            self.enter(match result {
+                Ok(_) => ServiceState::Stopped,
+                Err(_) => ServiceState::Failed,
+            });
+        }
+    }
+
+    /// The last run of the command that has ended, or that an earlier instance by the same
+    /// name recorded.
+    pub fn last_run(&self) -> Option<LastRun> {
+        self.last_run
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Notes a run of the command that has just ended, exports it as metrics and records
+    /// it with the state.
+    pub(crate) fn set_last_run(&self, run: LastRun) {
+        run.export();
+        *self.last_run.lock().unwrap_or_else(|e| e.into_inner()) = Some(run);
+        self.record();
+    }
+
+    /// Writes the state to `path` now and after every transition; see `read`.
+    ///
+    /// The last run recorded there by an earlier instance is taken over, unless this one
+    /// has had a run already. Only the first path counts; later calls are ignored.
+    pub fn record_to(&self, path: PathBuf) {
+        let earlier = read_record(&path)
+            .ok()
+            .flatten()
+            .and_then(|record| LastRun::from_json(&record["last_run"]));
+        if self.registry.set(path).is_ok() {
+            let mut last_run = self.last_run.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(earlier) = earlier.filter(|_| last_run.is_none()) {
+                earlier.export();
+                *last_run = Some(earlier);
+            }
+            drop(last_run);
+            self.record();
+        }
+    }
+
+    /// Notes the command that was just started, or `None` once it has exited, and records
+    /// it with the state so a later supervisor can adopt it (see `adopt`).
+    pub(crate) fn set_child(&self, pid: Option<u32>) {
+        let child = pid.map(|pid| (pid, crate::adopt::start_time(pid)));
+        *self.child.lock().unwrap_or_else(|e| e.into_inner()) = child;
+        self.record();
+    }
+
+    fn record(&self) {
+        let Some(path) = self.registry.get() else {
+            return;
+        };
+        let (state, since) = *self.current.borrow();
+        let child = *self.child.lock().unwrap_or_else(|e| e.into_inner());
+        let last_run = self.last_run();
+        let record = serde_json::json!({
+            "state": state.as_str(),
+            "since": since.to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+            "pid": std::process::id(),
+            "run_id": crate::context::run_id(),
+            "child_pid": child.map(|(pid, _)| pid),
+            "child_start_time": child.and_then(|(_, start_time)| start_time),
+            "last_run": last_run.as_ref().map(LastRun::to_json),
+        });
+        if let Err(e) = write(path, &record) {
+            warn!("Cannot record the state in {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Replaces `path` with `record`, through a temporary file so readers never see half of
+/// it.
+fn write(path: &Path, record: &serde_json::Value) -> std::io::Result<()> {
+    let partial = path.with_extension("state.partial");
+    std::fs::write(&partial, format!("{}\n", record))?;
+    std::fs::rename(&partial, path)
+}
+
+/// Returns the registry file the state of the instance `name` is kept in, `NAME.state`
+/// next to its control socket.
+pub fn registry_path(name: &str) -> Result<PathBuf, anyhow::Error> {
+    Ok(crate::control::socket_path(name)?.with_extension("state"))
+}
+
+/// Returns the names of the instances with a recorded state, sorted.
+pub fn recorded() -> Result<Vec<String>, anyhow::Error> {
+    let dir = crate::config::runtime_dir()?;
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow::anyhow!("Failed to read {}: {}", dir.display(), e)),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "state"))
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Reads the last recorded state of the instance `name` and when it was entered.
+///
+/// This is what the instance last wrote: one that was killed before it could record
+/// `Stopped` or `Failed` is still shown in the state it was killed in, so callers
+/// should check whether it is still listening.
+///
+/// # Returns
+/// - `Ok(Some(..))`: The state and the time it was entered.
+/// - `Ok(None)`: If no state was recorded for `name`.
+/// - `Err(anyhow::Error)`: If the record cannot be read or parsed.
+pub fn read(name: &str) -> Result<Option<(ServiceState, String)>, anyhow::Error> {
+    let path = registry_path(name)?;
+    let Some(record) = read_record(&path)? else {
+        return Ok(None);
+    };
+    let state = record["state"]
+        .as_str()
+        .unwrap_or_default()
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid state record {}: {}", path.display(), e))?;
+    let since = record["since"].as_str().unwrap_or_default().to_string();
+    Ok(Some((state, since)))
+}
+
+/// Reads the last run of the command of the instance `name` from the registry, whether it
+/// is still running or not; `None` if no run of it has ended yet.
+pub fn read_last_run(name: &str) -> Result<Option<LastRun>, anyhow::Error> {
+    let record = read_record(&registry_path(name)?)?;
+    Ok(record.and_then(|record| LastRun::from_json(&record["last_run"])))
+}
+
+fn read_record(path: &Path) -> Result<Option<serde_json::Value>, anyhow::Error> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow::anyhow!("Failed to read {}: {}", path.display(), e)),
+    };
+    serde_json::from_str(&text)
+        .map(Some)
+        .map_err(|e| anyhow::anyhow!("Invalid state record {}: {}", path.display(), e))
+}
+
+/// Runs a service `future` that has no supervised command, as `Ready` (or `Degraded`,
+/// following its health) while it runs and `Stopped` or `Failed` after it returns.
+pub async fn with_state<F, T>(ctx: ServiceContext, future: F) -> anyhow::Result<T>
+where
+    F: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let state = ctx.state();
+    state.enter(ServiceState::Starting);
+    state.up(ctx.health().get().is_healthy());
+    tokio::pin!(future);
+    let result = tokio::select! {
+        result = &mut future => result,
+        () = follow_health(&ctx) => future.await,
+    };
+    state.finish(&result);
+    result
+}
+
+/// Moves a running instance between `Ready` and `Degraded` as its health changes.
+///
+/// Only returns if the health handle goes away, which the context prevents.
+pub(crate) async fn follow_health(ctx: &ServiceContext) {
+    let mut health = ctx.health().subscribe();
+    while health.changed().await.is_ok() {
+        let healthy = health.borrow_and_update().is_healthy();
+        if ctx.state().get().is_running() {
+            ctx.state().up(healthy);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ServiceState::*;
+
+    /// Every state and the states it may move to.
+    const TRANSITIONS: [(ServiceState, &[ServiceState]); 7] = [
+        (Defined, &[Starting, Stopping, Stopped, Failed]),
+        (Starting, &[Ready, Degraded, Stopping, Stopped, Failed]),
+        (Ready, &[Degraded, Stopping, Stopped, Failed]),
+        (Degraded, &[Ready, Stopping, Stopped, Failed]),
+        (Stopping, &[Starting, Stopped, Failed]),
+        (Stopped, &[Starting]),
+        (Failed, &[Starting]),
+    ];
+
+    #[test]
+    fn can_become_allows_exactly_the_listed_transitions() {
+        for (from, allowed) in TRANSITIONS {
+            for next in ServiceState::ALL {
+                assert_eq!(
+                    from.can_become(next),
+                    allowed.contains(&next),
+                    "{} -> {}",
+                    from,
+                    next
+                );
+            }
+        }
+        assert!(
+            ServiceState::ALL
+                .iter()
+                .all(|state| TRANSITIONS.iter().any(|(from, _)| from == state))
+        );
+    }
+
+    #[test]
+    fn set_rejects_an_invalid_transition_and_keeps_the_state() {
+        let state = State::default();
+        let since = state.since();
+        let changes = state.subscribe();
+        let e = state.set(Ready).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Invalid state transition from defined to ready"
+        );
+        assert_eq!(state.get(), Defined);
+        assert_eq!(state.since(), since);
+        assert!(!changes.has_changed().unwrap());
+
+        state.set(Starting).unwrap();
+        assert_eq!(state.get(), Starting);
+        assert!(changes.has_changed().unwrap());
+        assert!(state.set(Starting).is_err());
+        assert_eq!(state.get(), Starting);
+    }
+}
@@ -0,0 +1,143 @@
+//! Services that read standard input.
+//!
+//! A detached process has `/dev/null` as its standard input, so the first read returns
+//! end of file. Code written for a terminal (a prompt, "press Enter to continue", a REPL)
+//! takes that as the user being done and quits, or spins on empty reads, and the only
+//! trace is a daemon that ended or went busy for no visible reason. A service that reads
+//! its input through `stdin()` instead of `tokio::io::stdin()` gets a clear log message
+//! the first time it reads end of file, and then what `--on-stdin-eof` selects (see
+//! `OnStdinEof`): the end of file as usual, an error that aborts a service propagating it,
+//! or reads that never complete, so it carries on without input. `is_interactive` and
+//! `closed` let a service choose a non-interactive mode up front or once input is gone:
+//!
+//! ```no_run
+//! use tokio::io::AsyncBufReadExt;
+//!
+//! async fn service() -> anyhow::Result<()> {
+//!     if !detach::stdin::is_interactive() {
+//!         return detach::run_service_async().await;
+//!     }
+//!     let mut lines = tokio::io::BufReader::new(detach::stdin::stdin()).lines();
+//!     while let Some(line) = lines.next_line().await? {
+//!         println!("> {}", line);
+//!     }
+//!     Ok(())
+//! }
+//! ```
+use log::{error, warn};
+use std::io::IsTerminal;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// What a read through `stdin()` returns once standard input is at end of file.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnStdinEof {
+    /// Log a warning and return end of file, as a plain read would
+    #[default]
+    Warn,
+    /// Log an error and fail the read with `UnexpectedEof`, so a service that propagates
+    /// it ends
+    Abort,
+    /// Log a warning and never complete the read, so the service keeps running without
+    /// input
+    Wait,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(0);
+
+/// Sets what reads through `stdin()` do at end of file for the whole process.
+pub fn set_on_stdin_eof(mode: OnStdinEof) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// The mode set with `set_on_stdin_eof`.
+pub fn on_stdin_eof() -> OnStdinEof {
+    match MODE.load(Ordering::Relaxed) {
+        1 => OnStdinEof::Abort,
+        2 => OnStdinEof::Wait,
+        _ => OnStdinEof::Warn,
+    }
+}
+
+/// Returns `true` if standard input is a terminal someone could type into; `false` in a
+/// detached process, or when input comes from a file or a pipe.
+pub fn is_interactive() -> bool {
+    std::io::stdin().is_terminal()
+}
+
+/// Returns `true` once a read through `stdin()` has reached end of file.
+pub fn is_closed() -> bool {
+    *eof().borrow()
+}
+
+/// Returns once a read through `stdin()` has reached end of file, for switching to a
+/// non-interactive mode from another task.
+pub async fn closed() {
+    let _ = eof().subscribe().wait_for(|&closed| closed).await;
+}
+
+/// Standard input, reporting end of file as `on_stdin_eof` says.
+pub fn stdin() -> Stdin {
+    Stdin {
+        inner: tokio::io::stdin(),
+    }
+}
+
+/// The reader returned by `stdin()`.
+#[derive(Debug)]
+pub struct Stdin {
+    inner: tokio::io::Stdin,
+}
+
+impl AsyncRead for Stdin {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        }
+        if !is_closed() {
+            let filled = buf.filled().len();
+            match Pin::new(&mut self.inner).poll_read(cx, buf) {
+                Poll::Ready(Ok(())) if buf.filled().len() == filled => report(),
+                other => return other,
+            }
+        }
+        match on_stdin_eof() {
+            OnStdinEof::Warn => Poll::Ready(Ok(())),
+            OnStdinEof::Abort => Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "standard input is closed, but the service expected to read from it",
+            ))),
+            OnStdinEof::Wait => Poll::Pending,
+        }
+    }
+}
+
+fn eof() -> &'static tokio::sync::watch::Sender<bool> {
+    static EOF: OnceLock<tokio::sync::watch::Sender<bool>> = OnceLock::new();
+    EOF.get_or_init(|| tokio::sync::watch::channel(false).0)
+}
+
+/// Logs the end of file the first time it is read and wakes `closed`.
+fn report() {
+    if eof().send_replace(true) {
+        return;
+    }
+    let why = "Standard input is closed (a detached process reads /dev/null), so nobody \
+               can type into it";
+    match on_stdin_eof() {
+        OnStdinEof::Warn => warn!(
+            "{}. The service read end of file; if it expected a terminal, check \
+             detach::stdin::is_interactive() first.",
+            why
+        ),
+        OnStdinEof::Abort => error!("{}. Aborting the service (--on-stdin-eof abort).", why),
+        OnStdinEof::Wait => warn!("{}. Carrying on without input (--on-stdin-eof wait).", why),
+    }
+}
@@ -0,0 +1,1018 @@
+//! Keeping a command running for the lifetime of the daemon.
+//!
+//! `supervise_command` runs a command with `run_command` and restarts it at the times
+//! given by a `RestartSchedule`, in the timezone of `SupervisorOptions::calendar`, which
+//! works around upstream services that leak memory or handles over long uptimes.
+//! Scheduled restarts use the same graceful stop as timeouts: SIGINT, then SIGKILL once
+//! the grace period is over.
+//!
+//! Like an init process in a container, the supervisor acts as a signal proxy: the signals
+//! in `SupervisorOptions::forward_signals` are caught and passed on to the running command
+//! (or its whole process group) instead of stopping the supervisor. SIGHUP travels as a
+//! reload request on the process-wide `ServiceContext`, so reloads requested by other means
+//! reach the command too. After a forwarded SIGTERM or SIGINT the command is not restarted,
+//! and its exit counts as a stop rather than a failure.
+//!
+//! Management interfaces stop or restart the command through
+//! `ServiceContext::request`; the supervisor sends it SIGTERM and, for a restart, starts it
+//! again right away.
+//!
+//! However a stop or restart is requested, the command gets
+//! `SupervisorOptions::stop_timeout` to exit before it is sent SIGKILL. Signals reach the
+//! command through its `pidfd::ProcessHandle`, so on Linux neither they nor the kill can
+//! hit another process that was given its PID after it exited. When the
+//! supervisor itself is stopped, it also waits for the rest of the command's process group
+//! (with `SupervisorOptions::process_group`) and for the processes the command left behind
+//! (with `SupervisorOptions::subreaper`, see `reaper`) to exit, terminating and then
+//! killing what is left, and logs a summary of how the shutdown went before it returns.
+//!
+//! With `SupervisorOptions::container` each run is a container started by podman or
+//! docker instead of a `sh -c` child; see `container`.
+//!
+//! With `SupervisorOptions::adopt` the first run is a command an earlier supervisor left
+//! running, watched until it exits instead of started; see `adopt`.
+//!
+//! With `SupervisorOptions::detached` the processes below the supervisor are checked for
+//! terminals they should not have, which a detached command would block on; see `tty`.
+//!
+//! Every start, stop, restart, exit, forwarded signal and reload, and the command
+//! becoming ready, is published as a `LifecycleEvent` on the process-wide
+//! `ServiceContext` and, with `SupervisorOptions::audit`, also recorded in an
+//! `AuditLog`. The supervisor also moves
+//! the `ServiceContext::state` of the instance along: `Starting` for each run, `Ready` or
+//! `Degraded` once the command has kept running for `SETTLE`, `Stopping` on a stop or
+//! restart, and `Stopped` or `Failed` when it returns.
+//!
+//! The listeners, timers and watchers the supervisor starts run in a `TaskGroup` (see
+//! `tasks`), which is cancelled and awaited before `supervise_command` returns.
+use crate::adopt::Orphan;
+use crate::audit::{self, AuditAction, AuditLog, AuditTrigger};
+use crate::clock::{self, Clock};
+use crate::command::{CommandOutcome, ExitReason, OutputMode, RunOptions, run_command};
+use crate::container::Container;
+use crate::context::{ControlRequest, ServiceContext};
+use crate::cores::CoreDumps;
+use crate::isolation::Isolation;
+use crate::landlock::FsSandbox;
+use crate::redact::Redactor;
+use crate::runlog::RunLogs;
+use crate::sanitize::Sanitize;
+use crate::schedule::{Calendar, Jitter, MissedRuns, RestartSchedule, delayed_start_on};
+use crate::seccomp::SeccompProfile;
+use crate::state::{LastRun, ServiceState};
+use crate::tasks::TaskGroup;
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Notify, broadcast, watch};
+
+/// How long a started command has to keep running before the instance counts as ready.
+pub const SETTLE: Duration = Duration::from_secs(1);
+
+/// How long the command may take to exit after a stop request unless
+/// `SupervisorOptions::stop_timeout` says otherwise.
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Options controlling `supervise_command`.
+#[derive(Debug, Clone)]
+pub struct SupervisorOptions {
+    /// Maximum time a single execution of the command may run
+    pub run_timeout: Option<Duration>,
+    /// Maximum time the supervisor runs in total, across all restarts
+    pub lifetime: Option<Duration>,
+    /// When to restart the command on purpose
+    pub restart_at: Option<RestartSchedule>,
+    /// The timezone `restart_at` is in, and what it does on DST changes
+    pub calendar: Calendar,
+    /// What happens to restarts the host slept through
+    pub missed: MissedRuns,
+    /// Random delay applied before starting the command again after a scheduled restart
+    pub start_jitter: Option<Jitter>,
+    /// Core dump handling for every execution of the command
+    pub cores: Option<CoreDumps>,
+    /// Namespaces and privilege restrictions for every execution of the command (Linux only)
+    pub isolation: Option<Isolation>,
+    /// Landlock filesystem sandbox for every execution of the command (Linux only)
+    pub sandbox: Option<FsSandbox>,
+    /// Seccomp filter for every execution of the command (Linux only)
+    pub seccomp: Option<SeccompProfile>,
+    /// Signals passed on to the running command (Unix only); defaults to
+    /// `signal::DEFAULT_FORWARD_SIGNALS`
+    pub forward_signals: Vec<i32>,
+    /// Run the command in its own process group and signal the whole group
+    pub process_group: bool,
+    /// Make the supervisor a child subreaper, so processes the command leaves behind are
+    /// re-parented to it, reaped and stopped with it (Linux only; see `reaper`)
+    pub subreaper: bool,
+    /// How long the command, and on shutdown the rest of its process group, may take to
+    /// exit after a stop or restart request before it is killed
+    pub stop_timeout: Duration,
+    /// CPU time each execution of the command may use (Unix only)
+    pub cpu_limit: Option<Duration>,
+    /// Bytes of output each execution of the command may write before it is killed
+    pub max_output: Option<u64>,
+    /// Where supervisor actions are recorded, if anywhere
+    pub audit: Option<AuditLog>,
+    /// Run the command string as an image in this container instead of through `sh -c`
+    pub container: Option<Container>,
+    /// Write the output of each run to its own file here instead of inheriting stdout
+    pub run_logs: Option<RunLogs>,
+    /// What is done to output written to `run_logs`; `None` writes it as it is
+    pub sanitize: Option<Sanitize>,
+    /// Secrets replaced with `[REDACTED]` in the output written to `run_logs`
+    pub redactor: Option<Arc<Redactor>>,
+    /// Variables set for every execution of the command on top of the inherited environment
+    pub env: Vec<(String, String)>,
+    /// The clock timeouts, restarts and start delays are measured on
+    pub clock: Arc<dyn Clock>,
+    /// A command an earlier supervisor left running, watched as the first run instead of
+    /// starting the command (see `adopt::find`)
+    pub adopt: Option<Orphan>,
+    /// The supervisor runs detached from any terminal, so processes of the command that
+    /// take one are warned about (Linux only; see `tty`)
+    pub detached: bool,
+}
+
+impl Default for SupervisorOptions {
+    fn default() -> Self {
+        Self {
+            run_timeout: None,
+            lifetime: None,
+            restart_at: None,
+            calendar: Calendar::default(),
+            missed: MissedRuns::default(),
+            start_jitter: None,
+            cores: None,
+            isolation: None,
+            sandbox: None,
+            seccomp: None,
+            #[cfg(unix)]
+            forward_signals: crate::signal::DEFAULT_FORWARD_SIGNALS.to_vec(),
+            #[cfg(not(unix))]
+            forward_signals: Vec::new(),
+            process_group: false,
+            subreaper: false,
+            stop_timeout: DEFAULT_STOP_TIMEOUT,
+            cpu_limit: None,
+            max_output: None,
+            audit: None,
+            container: None,
+            run_logs: None,
+            sanitize: Some(Sanitize::default()),
+            redactor: None,
+            env: Vec::new(),
+            clock: clock::system(),
+            adopt: None,
+            detached: false,
+        }
+    }
+}
+
+/// How an execution of the supervised command ended.
+struct Exited {
+    pid: Option<u32>,
+    duration: Duration,
+    timed_out: bool,
+    output_exceeded: bool,
+    /// `None` for an adopted command, whose exit status cannot be collected
+    reason: Option<ExitReason>,
+}
+
+impl Exited {
+    fn success(&self) -> bool {
+        self.reason.is_some_and(|reason| reason.success())
+    }
+}
+
+impl From<CommandOutcome> for Exited {
+    fn from(outcome: CommandOutcome) -> Self {
+        Exited {
+            pid: outcome.pid,
+            duration: outcome.duration,
+            timed_out: outcome.timed_out,
+            output_exceeded: outcome.output_exceeded,
+            reason: Some(outcome.exit_reason()),
+        }
+    }
+}
+
+impl std::fmt::Display for Exited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason {
+            Some(reason) => reason.fmt(f),
+            None => f.write_str("exited, exit status unknown"),
+        }
+    }
+}
+
+/// Why an execution of the supervised command was cut short.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Limit {
+    Run,
+    Lifetime,
+}
+
+/// Runs `cmd_str` through `sh -c` and restarts it on the configured schedule.
+///
+/// The command runs until it exits by itself, its run timeout expires, or the supervisor
+/// lifetime ends. When a scheduled restart comes due, the command is stopped gracefully
+/// and started again after a random `start_jitter` delay; the next planned restart is
+/// logged each time. Restarts that came due while the host was asleep are handled as
+/// `SupervisorOptions::missed` says. Any delay before the first run is up to the caller.
+///
+/// Handlers for the forwarded signals are installed when the supervisor starts and stay
+/// installed after it returns, so the caller should exit soon afterwards.
+///
+/// # Arguments
+/// - `cmd_str`: The command string to be executed, or the image and its arguments with
+///   `SupervisorOptions::container`.
+/// - `opts`: Timeouts and restart schedule.
+///
+/// # Returns
+/// - `Ok(())`: The command exited successfully, the supervisor lifetime ended, a
+///   forwarded stop signal arrived between runs, or a stop was requested.
+/// - `Err(anyhow::Error)`: The command failed, hit its run timeout, or could not be started.
+pub async fn supervise_command(cmd_str: String, opts: SupervisorOptions) -> anyhow::Result<()> {
+    let tasks = Arc::new(TaskGroup::new());
+    let result = supervise(cmd_str, opts, &tasks).await;
+    tasks.shutdown().await;
+    ServiceContext::current().state().finish(&result);
+    result
+}
+
+async fn supervise(
+    cmd_str: String,
+    opts: SupervisorOptions,
+    tasks: &Arc<TaskGroup>,
+) -> anyhow::Result<()> {
+    let ctx = ServiceContext::current();
+    let state = ctx.state();
+    let clock = &*opts.clock;
+    let started = clock.monotonic();
+    let mut run = 0u64;
+    let metrics = ctx.metrics().clone();
+    let runs = metrics.counter(
+        "detach_command_runs_total",
+        "Executions of the supervised command",
+    );
+    let durations = metrics.histogram(
+        "detach_command_duration_seconds",
+        "Wall-clock duration of each execution",
+        &[1.0, 10.0, 60.0, 600.0, 3600.0, 86400.0],
+    );
+
+    let (signals, _) = broadcast::channel(16);
+    // Set to what stopped the supervisor: a signal or a control request
+    let (stop, mut stopped) = watch::channel(None);
+    let audit = opts.audit.as_ref();
+    let shutdown = Arc::new(Shutdown {
+        timeout: opts.stop_timeout,
+        process_group: opts.process_group,
+        subreaper: opts.subreaper,
+        clock: opts.clock.clone(),
+        requested: Mutex::new(None),
+        forced: AtomicBool::new(false),
+        tasks: tasks.clone(),
+    });
+    listen_for_signals(
+        &opts.forward_signals,
+        &signals,
+        stop.clone(),
+        &shutdown,
+        audit,
+        tasks,
+    );
+    let restart = Arc::new(AtomicBool::new(false));
+    let start_now = Arc::new(Notify::new());
+    listen_for_requests(
+        &signals,
+        stop,
+        restart.clone(),
+        start_now.clone(),
+        shutdown.clone(),
+        tasks,
+    );
+    tasks.spawn("health follower", async {
+        crate::state::follow_health(&ServiceContext::current()).await
+    });
+    if opts.subreaper {
+        crate::reaper::enable()?;
+        tasks.spawn("orphan reaper", crate::reaper::reap_orphans());
+    }
+    if opts.detached {
+        tasks.spawn("terminal watcher", crate::tty::watch_terminals());
+    }
+    let reload = opts
+        .forward_signals
+        .iter()
+        .any(|&signal| is_sighup(signal))
+        .then(|| ServiceContext::current().subscribe_reload());
+    if let Some(mut reloads) = reload.clone() {
+        let audit = audit.cloned();
+        tasks.spawn("reload recorder", async move {
+            while reloads.changed().await.is_ok() {
+                audit::record(
+                    audit.as_ref(),
+                    AuditAction::Reload,
+                    AuditTrigger::Signal,
+                    "forwarded to the command as SIGHUP",
+                );
+            }
+        });
+    }
+
+    // Restarts the host slept through that are still to be made up for
+    let catch_up = Arc::new(AtomicU64::new(0));
+    // A requested restart skips the start jitter and is attributed to the request
+    let mut immediate = false;
+    let mut next_trigger = AuditTrigger::Schedule;
+    loop {
+        let stop_trigger = *stopped.borrow();
+        if let Some(trigger) = stop_trigger {
+            info!("Stop requested; not restarting the command.");
+            state.enter(ServiceState::Stopped);
+            audit::record(
+                audit,
+                AuditAction::Stop,
+                trigger,
+                "not restarting after a stop request",
+            );
+            return Ok(());
+        }
+        if run > 0 && !std::mem::take(&mut immediate) {
+            let jitter = opts.start_jitter.map(|jitter| jitter.sample());
+            tokio::select! {
+                _ = delayed_start_on(clock, jitter, async {}) => {}
+                _ = start_now.notified() => info!("Start requested; skipping the start delay."),
+                _ = stopped.wait_for(Option::is_some) => continue,
+            }
+        }
+        run += 1;
+        let mut limits = Vec::new();
+        if let Some(run_timeout) = opts.run_timeout {
+            limits.push((run_timeout, Limit::Run));
+        }
+        if let Some(lifetime) = opts.lifetime {
+            let elapsed = clock.monotonic().saturating_sub(started);
+            limits.push((lifetime.saturating_sub(elapsed), Limit::Lifetime));
+        }
+        let limit = limits.into_iter().min_by_key(|(duration, _)| *duration);
+
+        let adopted = opts.adopt.filter(|_| run == 1);
+        let trigger = if run == 1 {
+            AuditTrigger::Cli
+        } else {
+            next_trigger
+        };
+        state.enter(ServiceState::Starting);
+        if let Some(orphan) = &adopted {
+            info!(
+                "Adopting command (run #1), left running by supervisor {}: PID {}",
+                orphan.supervisor, orphan.pid
+            );
+            let detail = format!("run #1: PID {}: {}", orphan.pid, cmd_str);
+            audit::record(audit, AuditAction::Adopt, trigger, &detail);
+        } else {
+            info!("Starting command (run #{}): \"{}\"", run, cmd_str);
+            let detail = format!("run #{}: {}", run, cmd_str);
+            audit::record(audit, AuditAction::Start, trigger, &detail);
+        }
+        let run_log = match &opts.run_logs {
+            Some(_) if adopted.is_some() => None,
+            Some(logs) => {
+                let log = logs.start(run)?;
+                info!("Output of run #{} goes to {}.", run, log.path.display());
+                Some(log)
+            }
+            None => None,
+        };
+        let (interrupt, restart_due) = watch::channel(false);
+        let run_opts = RunOptions {
+            timeout: limit.map(|(duration, _)| duration),
+            interrupt: Some(restart_due.clone()),
+            cores: opts.cores.clone(),
+            isolation: opts.isolation,
+            sandbox: opts.sandbox.clone(),
+            seccomp: opts.seccomp.clone(),
+            reload: reload.clone(),
+            signals: Some(signals.clone()),
+            process_group: opts.process_group,
+            cpu_limit: opts.cpu_limit,
+            max_output: opts.max_output,
+            tail: ServiceContext::current().output_tail(),
+            sanitize: opts.sanitize,
+            redactor: opts.redactor.clone(),
+            env: opts.env.clone(),
+            output: match &run_log {
+                Some(log) => OutputMode::File(log.file.clone()),
+                None => OutputMode::Inherit,
+            },
+            clock: opts.clock.clone(),
+            ..RunOptions::default()
+        };
+        let settle = opts.clock.sleep(SETTLE);
+        let settle_audit = audit.cloned();
+        let run_tasks = TaskGroup::new();
+        if let Some(schedule) = &opts.restart_at {
+            let restart = Restart {
+                clock: opts.clock.clone(),
+                schedule: schedule.clone(),
+                calendar: opts.calendar,
+                missed: opts.missed,
+                catch_up: catch_up.clone(),
+            };
+            run_tasks.spawn("restart schedule", restart.when_due(interrupt));
+        }
+        run_tasks.spawn("readiness check", async move {
+            settle.await;
+            let ctx = ServiceContext::current();
+            if ctx.state().get() == ServiceState::Starting {
+                ctx.state().up(ctx.health().get().is_healthy());
+                let detail = format!("run #{}", run);
+                let audit = settle_audit.as_ref();
+                audit::record(audit, AuditAction::Ready, AuditTrigger::Command, &detail);
+            }
+        });
+        let run_started = clock.now();
+        let outcome: Exited = match (&adopted, &opts.container) {
+            (Some(orphan), _) => {
+                let watched = crate::adopt::watch(orphan, run_opts).await;
+                Exited {
+                    pid: Some(orphan.pid),
+                    duration: watched.duration,
+                    timed_out: watched.timed_out,
+                    output_exceeded: false,
+                    reason: None,
+                }
+            }
+            (None, Some(container)) => {
+                container.remove().await;
+                let outcome = run_command(&container.run_command(&cmd_str), run_opts).await;
+                container.remove().await;
+                outcome?.into()
+            }
+            (None, None) => run_command(&cmd_str, run_opts).await?.into(),
+        };
+        run_tasks.shutdown().await;
+        if stopped.borrow().is_some() {
+            shutdown.finish(run, &outcome).await;
+        }
+        runs.inc();
+        state.set_last_run(LastRun {
+            started: run_started,
+            ended: clock.now(),
+            duration: outcome.duration,
+            exit_code: outcome.reason.map(|reason| reason.shell_code()),
+        });
+        durations.observe(outcome.duration.as_secs_f64());
+        if let (Some(logs), Some(log), Some(reason)) = (&opts.run_logs, run_log, outcome.reason) {
+            logs.finish(log, outcome.duration, reason);
+        }
+
+        if restart.swap(false, Ordering::SeqCst) {
+            info!("Restart requested: command stopped, starting it again.");
+            let detail = format!("run #{}: {}", run, outcome);
+            audit::record(audit, AuditAction::Restart, AuditTrigger::Control, &detail);
+            immediate = true;
+            next_trigger = AuditTrigger::Control;
+            continue;
+        }
+
+        if outcome.output_exceeded {
+            let detail = format!("run #{}: output limit exceeded", run);
+            state.enter(ServiceState::Failed);
+            audit::record(audit, AuditAction::Stop, AuditTrigger::Limit, &detail);
+            return Err(anyhow::anyhow!("Command exceeded its output limit."));
+        }
+        if outcome.timed_out && *restart_due.borrow() {
+            info!("Scheduled restart: command stopped, starting it again.");
+            let detail = format!("run #{}", run);
+            state.stopping();
+            audit::record(audit, AuditAction::Restart, AuditTrigger::Schedule, &detail);
+            next_trigger = AuditTrigger::Schedule;
+            continue;
+        }
+        if outcome.timed_out {
+            match limit.map(|(_, kind)| kind) {
+                Some(Limit::Lifetime) => {
+                    info!("Supervisor lifetime reached. Command stopped.");
+                    let detail = format!("run #{}", run);
+                    state.enter(ServiceState::Stopped);
+                    audit::record(audit, AuditAction::Stop, AuditTrigger::Lifetime, &detail);
+                    return Ok(());
+                }
+                _ => {
+                    let detail = format!("run #{}", run);
+                    state.enter(ServiceState::Failed);
+                    audit::record(audit, AuditAction::Stop, AuditTrigger::Timeout, &detail);
+                    return Err(anyhow::anyhow!("Command timed out."));
+                }
+            }
+        }
+        let detail = format!("run #{}: {}", run, outcome);
+        // A command ended by a forwarded SIGTERM or a stop request was stopped, not failed
+        let stop_trigger = *stopped.borrow();
+        state.enter(if stop_trigger.is_some() || outcome.success() {
+            ServiceState::Stopped
+        } else {
+            ServiceState::Failed
+        });
+        audit::record(audit, AuditAction::Exit, AuditTrigger::Command, &detail);
+        if let Some(trigger) = stop_trigger {
+            info!("Command stopped on request.");
+            audit::record(audit, AuditAction::Stop, trigger, &detail);
+            return Ok(());
+        }
+        if outcome.success() {
+            info!("Command executed successfully.");
+            return Ok(());
+        }
+        return Err(anyhow::anyhow!("Command failed: {}", outcome));
+    }
+}
+
+/// How late a scheduled restart may be noticed before it counts as missed; `sleep_until`
+/// is never that late while the host is awake.
+const MISSED_AFTER: Duration = Duration::from_secs(60);
+
+/// The most missed restarts `MissedRuns::RunAll` makes up for.
+const MAX_CATCH_UP: u64 = 100;
+
+/// The restarts of `SupervisorOptions::restart_at`, as seen by one run of the command.
+struct Restart {
+    clock: Arc<dyn Clock>,
+    schedule: RestartSchedule,
+    calendar: Calendar,
+    missed: MissedRuns,
+    /// Missed restarts still to be made up for, kept across runs
+    catch_up: Arc<AtomicU64>,
+}
+
+impl Restart {
+    /// Waits until the next restart is due by the wall clock, then sets `due`.
+    ///
+    /// Restarts that passed while the host was asleep are left out, made up for with one
+    /// restart, or each made up for by a restart once the command has settled, as
+    /// `missed` says; the decision is logged.
+    async fn when_due(self, due: watch::Sender<bool>) {
+        let clock = &*self.clock;
+        if self.catch_up.load(Ordering::SeqCst) > 0 {
+            clock.sleep(SETTLE).await;
+            let left = self.catch_up.fetch_sub(1, Ordering::SeqCst) - 1;
+            info!(
+                "Making up for a missed scheduled restart ({} more to go).",
+                left
+            );
+            due.send_replace(true);
+            return;
+        }
+        let mut now = clock.now();
+        while let Some(next) = self.schedule.next_after(now, &self.calendar) {
+            info!("Next scheduled restart at {}.", self.calendar.format(next));
+            crate::clock::sleep_until(clock, next).await;
+            now = clock.now();
+            if (now - next).to_std().unwrap_or_default() <= MISSED_AFTER {
+                due.send_replace(true);
+                return;
+            }
+            let mut missed = 1;
+            let mut last = next;
+            while missed < MAX_CATCH_UP
+                && let Some(time) = self
+                    .schedule
+                    .next_after(last, &self.calendar)
+                    .filter(|time| *time <= now)
+            {
+                missed += 1;
+                last = time;
+            }
+            let (what, decision) = match missed {
+                1 => (
+                    format!("the scheduled restart at {}", self.calendar.format(next)),
+                    match self.missed {
+                        MissedRuns::Skip => "skipping it",
+                        MissedRuns::RunOnce | MissedRuns::RunAll => "restarting now",
+                    },
+                ),
+                _ => (
+                    format!(
+                        "{} scheduled restarts, the first at {},",
+                        missed,
+                        self.calendar.format(next)
+                    ),
+                    match self.missed {
+                        MissedRuns::Skip => "skipping them",
+                        MissedRuns::RunOnce => "restarting once now",
+                        MissedRuns::RunAll => "restarting once for each of them",
+                    },
+                ),
+            };
+            warn!(
+                "Missed {} while the host was asleep or its clock was set forward; {}.",
+                what, decision
+            );
+            match self.missed {
+                MissedRuns::Skip => continue,
+                MissedRuns::RunOnce => {}
+                MissedRuns::RunAll => self.catch_up.store(missed - 1, Ordering::SeqCst),
+            }
+            due.send_replace(true);
+            return;
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_sighup(signal: i32) -> bool {
+    signal == crate::signal::SIGHUP
+}
+
+#[cfg(not(unix))]
+fn is_sighup(_signal: i32) -> bool {
+    false
+}
+
+/// Catches each of `forward` (except SIGHUP, which arrives as a reload request) and sends
+/// it on `signals`; SIGTERM and SIGINT also set `stop`. Each forwarded signal is recorded
+/// in `audit`. The listeners run in `tasks`.
+#[cfg(unix)]
+fn listen_for_signals(
+    forward: &[i32],
+    signals: &broadcast::Sender<i32>,
+    stop: watch::Sender<Option<AuditTrigger>>,
+    shutdown: &Arc<Shutdown>,
+    audit: Option<&AuditLog>,
+    tasks: &TaskGroup,
+) {
+    use crate::signal::{SIGINT, SIGTERM, signal_name};
+    use tokio::signal::unix::{SignalKind, signal};
+
+    for &number in forward {
+        if is_sighup(number) {
+            continue;
+        }
+        let name = signal_name(number).unwrap_or("signal");
+        let mut stream = match signal(SignalKind::from_raw(number)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Cannot forward {}: {}", name, e);
+                continue;
+            }
+        };
+        let signals = signals.clone();
+        let stop = stop.clone();
+        let shutdown = shutdown.clone();
+        let audit = audit.cloned();
+        tasks.spawn("signal listener", async move {
+            while stream.recv().await.is_some() {
+                info!("Received {}, forwarding it to the command.", name);
+                let detail = format!("{} forwarded to the command", name);
+                audit::record(
+                    audit.as_ref(),
+                    AuditAction::Signal,
+                    AuditTrigger::Signal,
+                    &detail,
+                );
+                let _ = signals.send(number);
+                if number == SIGTERM || number == SIGINT {
+                    ServiceContext::current().state().stopping();
+                    stop.send_replace(Some(AuditTrigger::Signal));
+                    shutdown.begin();
+                }
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn listen_for_signals(
+    _forward: &[i32],
+    _signals: &broadcast::Sender<i32>,
+    _stop: watch::Sender<Option<AuditTrigger>>,
+    _shutdown: &Arc<Shutdown>,
+    _audit: Option<&AuditLog>,
+    _tasks: &TaskGroup,
+) {
+}
+
+/// Acts on `ServiceContext::request`s: a stop sets `stop` and a restart sets `restart`,
+/// and both send SIGTERM to the command and begin the `shutdown` of the running command;
+/// a start wakes up a pending start delay. The listener runs in `tasks`.
+fn listen_for_requests(
+    signals: &broadcast::Sender<i32>,
+    stop: watch::Sender<Option<AuditTrigger>>,
+    restart: Arc<AtomicBool>,
+    start_now: Arc<Notify>,
+    shutdown: Arc<Shutdown>,
+    tasks: &TaskGroup,
+) {
+    let mut requests = ServiceContext::current().subscribe_requests();
+    let signals = signals.clone();
+    tasks.spawn("request listener", async move {
+        loop {
+            let request = match requests.recv().await {
+                Ok(request) => request,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            info!("{:?} requested.", request);
+            match request {
+                ControlRequest::Start => {
+                    start_now.notify_waiters();
+                    continue;
+                }
+                ControlRequest::Stop => {
+                    stop.send_replace(Some(AuditTrigger::Control));
+                }
+                ControlRequest::Restart => restart.store(true, Ordering::SeqCst),
+            }
+            ServiceContext::current().state().stopping();
+            #[cfg(unix)]
+            let _ = signals.send(crate::signal::SIGTERM);
+            #[cfg(not(unix))]
+            let _ = &signals;
+            shutdown.begin();
+        }
+    });
+}
+
+/// How the running command is stopped, shared by the listeners that request a stop and the
+/// supervisor that reports on it.
+struct Shutdown {
+    timeout: Duration,
+    process_group: bool,
+    subreaper: bool,
+    clock: Arc<dyn Clock>,
+    /// When the last stop was requested, on `clock`'s monotonic reading
+    requested: Mutex<Option<Duration>>,
+    /// Whether the command had to be killed after the last request
+    forced: AtomicBool,
+    /// Where the kill timers run, so they end with the supervisor
+    tasks: Arc<TaskGroup>,
+}
+
+impl Shutdown {
+    /// Notes a stop request, and kills the running command if it is still the one running
+    /// once `timeout` has passed.
+    fn begin(self: &Arc<Self>) {
+        let Some(child) = ServiceContext::current().child() else {
+            return;
+        };
+        *self.requested.lock().unwrap_or_else(|e| e.into_inner()) = Some(self.clock.monotonic());
+        self.forced.store(false, Ordering::SeqCst);
+        #[cfg(unix)]
+        {
+            let expired = self.clock.sleep(self.timeout);
+            let shutdown = self.clone();
+            self.tasks.spawn("kill timer", async move {
+                expired.await;
+                let current = ServiceContext::current().child();
+                if !current.is_some_and(|current| Arc::ptr_eq(&current, &child)) {
+                    return;
+                }
+                warn!(
+                    "Command did not exit within {:?} of the stop request. Sending SIGKILL.",
+                    shutdown.timeout
+                );
+                shutdown.forced.store(true, Ordering::SeqCst);
+                shutdown.kill(&child, crate::signal::SIGKILL);
+            });
+        }
+        #[cfg(not(unix))]
+        let _ = child;
+    }
+
+    /// Sends `signal` to the command, or to its process group.
+    #[cfg(unix)]
+    fn kill(&self, child: &crate::pidfd::ProcessHandle, signal: i32) {
+        let result = if self.process_group {
+            crate::signal::send_signal_group(child.pid(), signal)
+        } else {
+            child.signal(signal)
+        };
+        if let Err(e) = result {
+            warn!("Failed to send signal {} to the command: {}", signal, e);
+        }
+    }
+
+    /// Waits for what is left of the process group of the stopped command, then logs how
+    /// the shutdown of `run` went.
+    async fn finish(&self, run: u64, outcome: &Exited) {
+        let requested = *self.requested.lock().unwrap_or_else(|e| e.into_inner());
+        let took = requested.map(|requested| self.clock.monotonic().saturating_sub(requested));
+        let summary = match (self.forced.load(Ordering::SeqCst), took) {
+            (true, _) => format!(
+                "run #{} was killed after the stop timeout of {:?}",
+                run, self.timeout
+            ),
+            (false, Some(took)) => format!(
+                "run #{} exited {:.3}s after the stop request ({})",
+                run,
+                took.as_secs_f64(),
+                outcome
+            ),
+            (false, None) => format!("run #{} exited ({})", run, outcome),
+        };
+        #[cfg(unix)]
+        let group = match (self.process_group, outcome.pid) {
+            (true, Some(pgid)) => match self.clear_group(pgid).await {
+                None => "; no processes were left in its group",
+                Some(false) => "; the processes left in its group exited after SIGTERM",
+                Some(true) => "; the processes left in its group were killed",
+            },
+            _ => "",
+        };
+        #[cfg(unix)]
+        let orphans = match self.subreaper {
+            true => match crate::reaper::clear(self.timeout, &*self.clock).await {
+                None => "",
+                Some(false) => "; its orphaned processes exited after SIGTERM",
+                Some(true) => "; its orphaned processes were killed",
+            },
+            false => "",
+        };
+        #[cfg(not(unix))]
+        let (group, orphans) = ("", "");
+        info!("Shutdown: {}{}{}.", summary, group, orphans);
+    }
+
+    /// Terminates the processes left in the process group `pgid`, killing them if they are
+    /// still there after `timeout`.
+    ///
+    /// # Returns
+    /// - `None`: If the group was empty.
+    /// - `Some(killed)`: If processes were left, and whether they had to be killed.
+    #[cfg(unix)]
+    async fn clear_group(&self, pgid: u32) -> Option<bool> {
+        use crate::signal::{SIGKILL, SIGTERM, is_group_alive, send_signal_group};
+        if !is_group_alive(pgid) {
+            return None;
+        }
+        let kill = |signal| {
+            if let Err(e) = send_signal_group(pgid, signal) {
+                warn!("Failed to send signal {} to the command: {}", signal, e);
+            }
+        };
+        warn!("Processes of the command are still running. Sending SIGTERM to its group.");
+        kill(SIGTERM);
+        let deadline = self.clock.monotonic() + self.timeout;
+        while self.clock.monotonic() < deadline {
+            self.clock.sleep(Duration::from_millis(100)).await;
+            if !is_group_alive(pgid) {
+                return Some(false);
+            }
+        }
+        warn!("Processes of the command did not exit. Sending SIGKILL to its group.");
+        kill(SIGKILL);
+        Some(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::pin::Pin;
+    use std::task::{Context, Waker};
+
+    #[cfg(feature = "timezone")]
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    /// A daily restart at 03:00 UTC, seen from 02:00 UTC on the returned mock clock.
+    #[cfg(feature = "timezone")]
+    fn daily_restart(missed: MissedRuns) -> (MockClock, Restart) {
+        use chrono::{Local, TimeZone, Utc};
+        let start = Utc.with_ymd_and_hms(2026, 10, 16, 2, 0, 0).unwrap();
+        let clock = MockClock::new(start.with_timezone(&Local));
+        let restart = Restart {
+            clock: Arc::new(clock.clone()),
+            schedule: RestartSchedule::parse("03:00").unwrap(),
+            calendar: Calendar {
+                timezone: crate::schedule::Timezone::Named(chrono_tz::UTC),
+                ..Calendar::default()
+            },
+            missed,
+            catch_up: Arc::new(AtomicU64::new(0)),
+        };
+        (clock, restart)
+    }
+
+    /// Starts `restart` waiting, moves `clock` forward by `by`, and returns whether the
+    /// restart is then due, along with the missed restarts left to make up for.
+    fn due_after(clock: &MockClock, restart: Restart, by: Duration) -> (bool, u64) {
+        let catch_up = restart.catch_up.clone();
+        let (due, seen) = watch::channel(false);
+        let mut waiting = Box::pin(restart.when_due(due));
+        let mut poll = || {
+            Pin::new(&mut waiting)
+                .poll(&mut Context::from_waker(Waker::noop()))
+                .is_ready()
+        };
+        assert!(!poll());
+        clock.advance(by);
+        let done = poll();
+        assert_eq!(done, *seen.borrow());
+        (done, catch_up.load(Ordering::SeqCst))
+    }
+
+    #[cfg(feature = "timezone")]
+    #[test]
+    fn scheduled_restart_is_due_at_its_time() {
+        let (clock, restart) = daily_restart(MissedRuns::Skip);
+        let almost = HOUR - Duration::from_secs(1);
+        assert_eq!(due_after(&clock, restart, almost), (false, 0));
+        let (clock, restart) = daily_restart(MissedRuns::Skip);
+        assert_eq!(due_after(&clock, restart, HOUR), (true, 0));
+    }
+
+    #[cfg(feature = "timezone")]
+    #[test]
+    fn restarts_missed_while_asleep_follow_missed_runs() {
+        // Waking up at 03:30 on the 20th, five restarts (16th to 20th) have been missed
+        let asleep = 4 * 24 * HOUR + HOUR + HOUR / 2;
+        let (clock, restart) = daily_restart(MissedRuns::Skip);
+        assert_eq!(due_after(&clock, restart, asleep), (false, 0));
+        let (clock, restart) = daily_restart(MissedRuns::RunOnce);
+        assert_eq!(due_after(&clock, restart, asleep), (true, 0));
+        let (clock, restart) = daily_restart(MissedRuns::RunAll);
+        assert_eq!(due_after(&clock, restart, asleep), (true, 4));
+    }
+
+    #[cfg(feature = "timezone")]
+    #[test]
+    fn restarts_noticed_a_little_late_are_not_missed() {
+        let (clock, restart) = daily_restart(MissedRuns::Skip);
+        let late = HOUR + MISSED_AFTER;
+        assert_eq!(due_after(&clock, restart, late), (true, 0));
+        let (clock, restart) = daily_restart(MissedRuns::Skip);
+        let missed = late + Duration::from_secs(1);
+        assert_eq!(due_after(&clock, restart, missed), (false, 0));
+    }
+
+    #[cfg(feature = "timezone")]
+    #[test]
+    fn making_up_for_missed_restarts_is_capped() {
+        let (clock, restart) = daily_restart(MissedRuns::RunAll);
+        let asleep = 365 * 24 * HOUR;
+        assert_eq!(due_after(&clock, restart, asleep), (true, MAX_CATCH_UP - 1));
+    }
+
+    #[test]
+    fn missed_restarts_are_made_up_for_after_settling() {
+        let clock = MockClock::new(chrono::Local::now());
+        let restart = Restart {
+            clock: Arc::new(clock.clone()),
+            schedule: RestartSchedule::parse("03:00").unwrap(),
+            calendar: Calendar::default(),
+            missed: MissedRuns::RunAll,
+            catch_up: Arc::new(AtomicU64::new(2)),
+        };
+        assert_eq!(due_after(&clock, restart, SETTLE), (true, 1));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_forwarded_sigterm_stops_the_command_without_failing() {
+        use crate::signal::SIGTERM;
+        use tokio::signal::unix::{SignalKind, signal};
+
+        // Once tokio handles SIGTERM, it no longer ends the test process
+        let _handled = signal(SignalKind::terminate()).unwrap();
+        let mut events = ServiceContext::current().subscribe_events();
+        let opts = SupervisorOptions {
+            forward_signals: vec![SIGTERM],
+            ..SupervisorOptions::default()
+        };
+        let supervising = tokio::spawn(supervise_command("sleep 30".to_string(), opts));
+        let wait = Duration::from_secs(10);
+        loop {
+            let event = tokio::time::timeout(wait, events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if event.action == AuditAction::Start {
+                break;
+            }
+        }
+        unsafe { libc::kill(libc::getpid(), SIGTERM) };
+
+        let result = tokio::time::timeout(wait, supervising)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.is_ok(), "{:?}", result);
+        let mut stop = None;
+        while let Ok(event) = events.try_recv() {
+            if event.action == AuditAction::Exit {
+                assert_eq!(event.state, ServiceState::Stopped);
+            }
+            if event.action == AuditAction::Stop {
+                stop = Some(event);
+            }
+        }
+        let stop = stop.expect("no stop event");
+        assert_eq!(stop.trigger, AuditTrigger::Signal);
+        assert_eq!(stop.state, ServiceState::Stopped);
+    }
+}
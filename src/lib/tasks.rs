@@ -0,0 +1,98 @@
+//! Internal tasks that end with the code that started them.
+//!
+//! The supervisor's signal and request listeners, the kill timer of a stop, the clients of
+//! the control socket and of the HTTP endpoints, and alert deliveries all run as tasks of
+//! their own. Spawned with a bare `tokio::spawn`, they would outlive the service they
+//! belong to, and a panic in one of them would end it without a trace. A `TaskGroup`
+//! spawns them instead: a panic is logged as soon as it happens, and `shutdown` cancels
+//! every task still running and waits until all of them are gone, so nothing they own
+//! (a socket, a child handle, a half-written file) is left behind when the service
+//! returns. `join` waits for tasks that should finish their work, such as an alert on its
+//! way out, and dropping the group cancels its tasks without waiting.
+use log::error;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+use std::task::Poll;
+use tokio::task::JoinSet;
+
+/// A set of internal tasks that are cancelled and awaited together.
+#[derive(Debug, Default)]
+pub struct TaskGroup {
+    tasks: Mutex<JoinSet<()>>,
+}
+
+impl TaskGroup {
+    /// An empty group.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawns `task` on the current runtime as part of this group. `what` names it in the
+    /// log if it panics.
+    pub fn spawn<F>(&self, what: &'static str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget the tasks that are done, so a long-lived group does not grow
+        while tasks.try_join_next().is_some() {}
+        tasks.spawn(async move {
+            if let Err(panic) = catch_unwind(task).await {
+                error!(
+                    "Internal task {} panicked: {}",
+                    what,
+                    panic_message(&*panic)
+                );
+            }
+        });
+    }
+
+    /// Waits until all tasks have ended by themselves. If this is cancelled, the tasks
+    /// still running are cancelled with it.
+    ///
+    /// The group is empty afterwards and can be used again.
+    pub async fn join(&self) {
+        let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap_or_else(|e| e.into_inner()));
+        while tasks.join_next().await.is_some() {}
+    }
+
+    /// Cancels the tasks that are still running and waits until all of them have ended.
+    ///
+    /// The group is empty afterwards and can be used again.
+    pub async fn shutdown(&self) {
+        self.tasks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .abort_all();
+        self.join().await;
+    }
+}
+
+/// Runs `task` to completion, returning the payload if it panics.
+async fn catch_unwind<F>(task: F) -> Result<(), Box<dyn Any + Send>>
+where
+    F: Future<Output = ()>,
+{
+    let mut task = std::pin::pin!(task);
+    std::future::poll_fn(|cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| task.as_mut().poll(cx))) {
+            Ok(Poll::Ready(())) => Poll::Ready(Ok(())),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    })
+    .await
+}
+
+/// The message a panic was raised with, if it has one.
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "(no message)"
+    }
+}
@@ -0,0 +1,193 @@
+//! `{{variable}}` substitution in command strings.
+//!
+//! Several instances of one service differ in little more than their name, port and log
+//! directory. Instead of templating the command line outside, `--command` may refer to
+//! them, and they are filled in when the instance starts:
+//!
+//! - `{{name}}`: the `--name` of the instance
+//! - `{{instance}}`: its index among copies of the same service, `1` for a single one
+//! - `{{port}}`: its first `--port`, and `{{port_1}}`, `{{port_2}}`, ... all of them
+//! - `{{log_dir}}`: the directory of its log file
+//!
+//! Whitespace inside the braces is ignored. `{{name}}` and `{{log_dir}}` are quoted for
+//! the shell when they are more than a plain word, so they must not be put in quotes
+//! again. A variable that has no value for this instance (`{{port}}` without `--port`) is
+//! an error rather than an empty string, so the command cannot start with a hole in it.
+//!
+//! Anything else in double braces is left as it is, so the command can still hold a Go
+//! template (`docker inspect --format '{{.Name}}'`) or a jq filter. That includes a
+//! misspelled variable, which then reaches the command unexpanded.
+
+/// The values the variables of a template are replaced with.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Vars {
+    /// The instance name, if there is one
+    pub name: Option<String>,
+    /// The index of the instance among copies of the same service, from 1
+    pub instance: usize,
+    /// The ports of the instance, in order
+    pub ports: Vec<u16>,
+    /// The directory of the log file, if logging to a file
+    pub log_dir: Option<String>,
+}
+
+impl Vars {
+    /// The value of `variable`, or `None` if it is not one of ours.
+    fn get(&self, variable: &str) -> Option<Result<String, String>> {
+        let missing = |what: &str| Err(format!("{{{{{}}}}} needs {}", variable, what));
+        let value = match variable {
+            "name" => self
+                .name
+                .as_deref()
+                .map_or_else(|| missing("--name"), |name| Ok(shell_quote(name))),
+            "instance" => Ok(self.instance.max(1).to_string()),
+            "port" => self
+                .ports
+                .first()
+                .map_or_else(|| missing("--port"), |port| Ok(port.to_string())),
+            "log_dir" => self
+                .log_dir
+                .as_deref()
+                .map_or_else(|| missing("a log file"), |dir| Ok(shell_quote(dir))),
+            _ => {
+                let n = variable
+                    .strip_prefix("port_")
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .filter(|&n| n >= 1)?;
+                self.ports.get(n - 1).map_or_else(
+                    || missing(&format!("at least {} --port options", n)),
+                    |port| Ok(port.to_string()),
+                )
+            }
+        };
+        Some(value)
+    }
+}
+
+/// Replaces every `{{variable}}` in `template` with its value from `vars`, leaving other
+/// text in double braces as it is.
+///
+/// ```
+/// use detach::template::{Vars, expand};
+///
+/// let vars = Vars {
+///     name: Some("web-2".into()),
+///     ports: vec![8082],
+///     ..Vars::default()
+/// };
+/// let command = expand("./server --id {{ name }} --listen :{{port}}", &vars).unwrap();
+/// assert_eq!(command, "./server --id web-2 --listen :8082");
+/// let command = expand("docker inspect --format '{{.Name}}' {{name}}", &vars).unwrap();
+/// assert_eq!(command, "docker inspect --format '{{.Name}}' web-2");
+/// assert!(expand("./server {{port_2}}", &vars).is_err());
+/// ```
+///
+/// # Returns
+/// - `Ok(String)`: The expanded string; one without variables is returned as it is.
+/// - `Err(anyhow::Error)`: If a variable has no value for this instance.
+pub fn expand(template: &str, vars: &Vars) -> Result<String, anyhow::Error> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            break;
+        };
+        match vars.get(after[..end].trim()) {
+            Some(value) => {
+                let value =
+                    value.map_err(|e| anyhow::anyhow!("Cannot expand the command: {}", e))?;
+                out.push_str(&rest[..start]);
+                out.push_str(&value);
+                rest = &after[end + 2..];
+            }
+            // Not ours; the braces may still open a variable further on
+            None => {
+                out.push_str(&rest[..start + 2]);
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Quotes `arg` for a POSIX shell, leaving plain words as they are.
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        Vars {
+            name: Some("web".into()),
+            instance: 2,
+            ports: vec![8080, 9090],
+            log_dir: Some("/var/log/my services".into()),
+        }
+    }
+
+    #[test]
+    fn known_variables_are_replaced() {
+        let command = "./server {{name}} {{ instance }} {{port}} {{port_1}} {{port_2}}";
+        assert_eq!(
+            expand(command, &vars()).unwrap(),
+            "./server web 2 8080 8080 9090"
+        );
+        assert_eq!(expand("{{instance}}", &Vars::default()).unwrap(), "1");
+    }
+
+    #[test]
+    fn other_braces_are_left_alone() {
+        for command in [
+            "docker inspect --format '{{.Names}}' web",
+            "docker ps --format '{{ json . }}'",
+            "echo {{prot}} {{port_0}} {{port_x}}",
+            "echo '{{' unclosed",
+            "jq '{a: {b: 1}}'",
+        ] {
+            assert_eq!(expand(command, &Vars::default()).unwrap(), command);
+        }
+        // A variable after braces that are not one is still expanded
+        assert_eq!(
+            expand("echo {{.Name}}{{port}} {{{{name}}", &vars()).unwrap(),
+            "echo {{.Name}}8080 {{web"
+        );
+    }
+
+    #[test]
+    fn missing_values_are_errors() {
+        for command in ["{{name}}", "{{port}}", "{{port_1}}", "{{log_dir}}"] {
+            assert!(expand(command, &Vars::default()).is_err(), "{}", command);
+        }
+        assert!(expand("{{port_3}}", &vars()).is_err());
+    }
+
+    #[test]
+    fn name_and_log_dir_are_quoted_for_the_shell() {
+        assert_eq!(
+            expand("ls {{log_dir}}", &vars()).unwrap(),
+            "ls '/var/log/my services'"
+        );
+        let vars = Vars {
+            name: Some("it's; rm -rf ~".into()),
+            log_dir: Some("/var/log/detach".into()),
+            ..Vars::default()
+        };
+        assert_eq!(
+            expand("echo {{name}} > {{log_dir}}/out", &vars).unwrap(),
+            r"echo 'it'\''s; rm -rf ~' > /var/log/detach/out"
+        );
+    }
+}
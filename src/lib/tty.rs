@@ -0,0 +1,166 @@
+//! Terminals in the service tree.
+//!
+//! A detached instance has no terminal: `daemonize` starts a new session and points the
+//! standard streams at `/dev/null` and the log file. A command that wants one anyway, to
+//! ask for a password, confirm a prompt or page its output, fails to open `/dev/tty`, or
+//! opens a terminal device by path and waits for input nobody will type, stopped by
+//! SIGTTIN or blocked in `read`. From the outside such a daemon just hangs. When the
+//! supervisor runs detached it checks the processes below it every `CHECK_INTERVAL` and
+//! warns once about each one that has a controlling terminal or a terminal device open;
+//! `detach-rs doctor <NAME>` runs the same check on demand. Only terminals the instance
+//! itself does not hold count, so the one an instance in the foreground shares with its
+//! command is not reported. Both are read from `/proc`, so this is only available on
+//! Linux.
+use log::warn;
+use std::time::Duration;
+
+/// How often a detached supervisor checks the processes below it for terminals.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What to do about a command that uses a terminal, for logs and `doctor`.
+pub const ADVICE: &str = "A detached command has nobody to answer it on a terminal and may \
+    block waiting for input. Make it run non-interactively (e.g. with a --batch, --yes or \
+    --no-pager flag, PAGER=cat, or credentials from a file or the environment), or give it \
+    its input on stdin (--command \"... < input\").";
+
+/// A process that holds a terminal its instance does not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TerminalUse {
+    /// Its PID
+    pub pid: u32,
+    /// Its command line
+    pub command: String,
+    /// Its controlling terminal, if it acquired one
+    pub controlling: Option<String>,
+    /// The descriptors it has open on terminal devices, with their paths
+    pub open: Vec<(u32, String)>,
+}
+
+impl std::fmt::Display for TerminalUse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Process {} ({})", self.pid, self.command)?;
+        if let Some(terminal) = &self.controlling {
+            write!(f, " has the controlling terminal {}", terminal)?;
+            if !self.open.is_empty() {
+                write!(f, " and")?;
+            }
+        }
+        if !self.open.is_empty() {
+            let open: Vec<String> = self
+                .open
+                .iter()
+                .map(|(fd, path)| format!("FD {} on {}", fd, path))
+                .collect();
+            write!(f, " has {} open", open.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the processes below `root` that hold a terminal `root` does not hold itself.
+///
+/// # Returns
+/// - `Ok(Vec<TerminalUse>)`: Those processes, depth first; empty if there are none.
+/// - `Err(anyhow::Error)`: If `root` does not exist, or `/proc` cannot be read.
+#[cfg(target_os = "linux")]
+pub fn find(root: u32) -> Result<Vec<TerminalUse>, anyhow::Error> {
+    let tree = crate::proctree::tree(root, Duration::ZERO)?;
+    let (own_terminal, own_open) = terminals(root);
+    Ok(tree
+        .into_iter()
+        .filter(|process| process.pid != root)
+        .filter_map(|process| {
+            let (controlling, open) = terminals(process.pid);
+            let found = TerminalUse {
+                pid: process.pid,
+                command: process.command,
+                controlling: controlling.filter(|terminal| own_terminal.as_ref() != Some(terminal)),
+                open: open
+                    .into_iter()
+                    .filter(|(_, path)| !own_open.iter().any(|(_, own)| own == path))
+                    .collect(),
+            };
+            (found.controlling.is_some() || !found.open.is_empty()).then_some(found)
+        })
+        .collect())
+}
+
+/// Reading terminals needs `/proc`, which only Linux provides.
+#[cfg(not(target_os = "linux"))]
+pub fn find(root: u32) -> Result<Vec<TerminalUse>, anyhow::Error> {
+    Err(anyhow::anyhow!(
+        "Finding the terminals below {} needs /proc, which this system lacks",
+        root
+    ))
+}
+
+/// Warns about every process below this one that takes a terminal, once for as long as
+/// it holds it. Runs until dropped; does nothing where `find` is not available.
+pub async fn watch_terminals() {
+    let mut warned: Vec<u32> = Vec::new();
+    let mut checks = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        checks.tick().await;
+        let Ok(found) = find(std::process::id()) else {
+            return;
+        };
+        for process in &found {
+            if !warned.contains(&process.pid) {
+                warn!("{}. {}", process, ADVICE);
+            }
+        }
+        warned = found.iter().map(|process| process.pid).collect();
+    }
+}
+
+/// Returns the controlling terminal of `pid` and the descriptors it has open on
+/// terminal devices.
+#[cfg(target_os = "linux")]
+fn terminals(pid: u32) -> (Option<String>, Vec<(u32, String)>) {
+    let controlling = std::fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| {
+            // The terminal is field 7; the fields after the name start with field 3
+            let fields = &stat[stat.rfind(')')? + 1..];
+            fields.split_whitespace().nth(7 - 3)?.parse::<u64>().ok()
+        })
+        .filter(|&tty_nr| tty_nr != 0)
+        .map(device_name);
+    let mut open: Vec<(u32, String)> = std::fs::read_dir(format!("/proc/{}/fd", pid))
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let fd = entry.file_name().to_str()?.parse().ok()?;
+            let target = std::fs::read_link(entry.path()).ok()?;
+            let target = target.to_str()?;
+            is_terminal(target).then(|| (fd, target.to_string()))
+        })
+        .collect();
+    open.sort();
+    (controlling, open)
+}
+
+/// Whether `path` is a terminal a process reads from or writes to. The master side of
+/// a pseudo-terminal (`/dev/ptmx`) is not: a process holding it provides a terminal, as
+/// `script` or `tmux` do.
+#[cfg(target_os = "linux")]
+fn is_terminal(path: &str) -> bool {
+    path == "/dev/console"
+        || path.starts_with("/dev/tty")
+        || (path.starts_with("/dev/pts/") && path != "/dev/pts/ptmx")
+}
+
+/// Names the terminal device number `tty_nr` from `/proc/PID/stat`.
+#[cfg(target_os = "linux")]
+fn device_name(tty_nr: u64) -> String {
+    let major = (tty_nr >> 8) & 0xfff;
+    let minor = (tty_nr & 0xff) | ((tty_nr >> 12) & 0xfff00);
+    match (major, minor) {
+        (136..=143, _) => format!("/dev/pts/{}", (major - 136) * 256 + minor),
+        (4, 0..=63) => format!("/dev/tty{}", minor),
+        (4, _) => format!("/dev/ttyS{}", minor - 64),
+        (5, 1) => "/dev/console".to_string(),
+        _ => format!("device {}:{}", major, minor),
+    }
+}
@@ -0,0 +1,402 @@
+//! Checking the options of a run before anything is started.
+//!
+//! Most mistakes in the arguments surface late: an unwritable log directory or an unknown
+//! user is only noticed by the forked daemon, whose errors end up nowhere, and the rest
+//! stop the run one at a time. `check` looks at the whole configuration up front, with
+//! everything that can only be known at run time (directories, users, paths, installed
+//! tools), and `validate` reports all problems in one error, each with a suggestion:
+//!
+//! ```text
+//! Error: Found 2 problems with the options:
+//!   - The log directory /var/log/detach is not writable.
+//!     Hint: choose another file with --log-file, or use a user that may write there.
+//!   - --keep-runs keeps the runs of a command under its --name, and none was given.
+//!     Hint: add --name NAME.
+//! ```
+use crate::{Args, Backend, FsSandbox, Isolation, LogDestination, resolve_log_path};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// One thing wrong with the options, and what to do about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// What is wrong
+    pub message: String,
+    /// How to fix it, if there is an obvious way
+    pub hint: Option<String>,
+}
+
+impl Problem {
+    fn new(message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Problem {
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n    Hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns every problem found with `args`; empty if there are none.
+pub fn check(args: &Args) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    if args.subcommand.is_some() || args.completions.is_some() {
+        return problems;
+    }
+
+    if args.log_to.resolve() != LogDestination::Journald {
+        match resolve_log_path(args.daemon.log_file.as_deref()) {
+            Ok(path) => check_log_file(&path, &mut problems),
+            Err(e) => problems.push(Problem {
+                message: format!("Cannot resolve the log file path: {}", e),
+                hint: None,
+            }),
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(owner) = &args.log_dir_owner {
+        if let Err(e) = crate::compat::resolve_user(&owner.user) {
+            problems.push(Problem::new(
+                format!("{} in --log-dir-owner.", e),
+                "create the user first, or give a numeric id.",
+            ));
+        }
+        if let Some(Err(e)) = owner.group.as_ref().map(crate::compat::resolve_group) {
+            problems.push(Problem::new(
+                format!("{} in --log-dir-owner.", e),
+                "create the group first, or give a numeric id.",
+            ));
+        }
+    }
+
+    if let Some(path) = &args.exclusive_lock {
+        check_parent(path, "--exclusive-lock", &mut problems);
+    }
+    if let Some(path) = &args.audit_log {
+        check_parent(path, "--audit-log", &mut problems);
+    }
+    #[cfg(all(unix, feature = "grpc"))]
+    if let Some(path) = &args.grpc_token_file
+        && let Err(e) = std::fs::File::open(path)
+    {
+        problems.push(Problem::new(
+            format!("Cannot read --grpc-token-file {}: {}.", path.display(), e),
+            "create it with a random token, readable by the user running the service.",
+        ));
+    }
+    for path in args.allow_read.iter().chain(&args.allow_write) {
+        if !path.exists() {
+            problems.push(Problem::new(
+                format!("The sandbox path {} does not exist.", path.display()),
+                "create it before starting, or remove it from --allow-read/--allow-write.",
+            ));
+        }
+    }
+
+    let sandbox = FsSandbox {
+        read: args.allow_read.clone(),
+        write: args.allow_write.clone(),
+        mode: args.sandbox,
+    };
+    if let Err(e) = sandbox.check() {
+        problems.push(Problem::new(
+            e.to_string(),
+            "use --sandbox best-effort to run without it where it is unavailable.",
+        ));
+    }
+
+    if args.backend != Backend::Process {
+        let isolation = Isolation {
+            private_tmp: args.private_tmp,
+            no_new_privileges: args.no_new_privileges,
+            network: args.network,
+        };
+        if args.cores.is_some()
+            || args.seccomp.is_some()
+            || !sandbox.is_empty()
+            || !isolation.is_default()
+        {
+            problems.push(Problem::new(
+                "A container backend runs the command in a container; --cores, --seccomp, the \
+                 sandbox and the isolation options only apply to the process backend.",
+                "configure the container instead, or use --backend process.",
+            ));
+        }
+        let engine = if args.backend == Backend::Podman {
+            "podman"
+        } else {
+            "docker"
+        };
+        if find_program(engine).is_none() {
+            problems.push(Problem::new(
+                format!(
+                    "--backend {} needs `{}`, which is not on the PATH.",
+                    engine, engine
+                ),
+                format!("install {}, or extend PATH to include it.", engine),
+            ));
+        }
+    }
+
+    if args.profile == crate::profile::Profile::Minimal {
+        let mut served = Vec::new();
+        if args.metrics_listen.is_some() {
+            served.push("--metrics-listen");
+        }
+        #[cfg(all(unix, feature = "grpc"))]
+        if args.grpc.is_some() {
+            served.push("--grpc");
+        }
+        #[cfg(all(unix, feature = "dbus"))]
+        if args.dbus.is_some() {
+            served.push("--dbus");
+        }
+        if !served.is_empty() {
+            problems.push(Problem::new(
+                format!(
+                    "--profile minimal serves no management interface, and {} asks for one.",
+                    served.join(" and ")
+                ),
+                "leave out --profile minimal, or the interface.",
+            ));
+        }
+    }
+
+    let redacting = !args.redact_env.is_empty() || !args.redact_regex.is_empty();
+    for (used, flag, feature, built) in [
+        (
+            args.crash_report.is_some(),
+            "--crash-report",
+            "crash-report",
+            cfg!(feature = "crash-report"),
+        ),
+        (
+            args.metrics_listen.is_some(),
+            "--metrics-listen",
+            "http",
+            cfg!(feature = "http"),
+        ),
+        (
+            !args.alert_webhook.is_empty(),
+            "--alert-webhook",
+            "http",
+            cfg!(feature = "http"),
+        ),
+        (
+            redacting,
+            "--redact-env and --redact-regex",
+            "redact",
+            cfg!(feature = "redact"),
+        ),
+    ] {
+        if used && !built {
+            problems.push(Problem::new(
+                format!(
+                    "{} needs the {} feature, which this build of detach-rs lacks.",
+                    flag, feature
+                ),
+                format!(
+                    "rebuild with --features {}, or leave out {}.",
+                    feature, flag
+                ),
+            ));
+        }
+    }
+
+    if args.keep_runs.is_some() && args.command.is_some() && args.name.is_none() {
+        problems.push(Problem::new(
+            "--keep-runs keeps the runs of a command under its --name, and none was given.",
+            "add --name NAME.",
+        ));
+    }
+
+    if args.instances.is_some_and(|count| count > 1)
+        && args
+            .ports
+            .iter()
+            .any(|spec| *spec != crate::ports::PortSpec::Auto)
+    {
+        problems.push(Problem::new(
+            "--instances starts several copies of the command, which cannot all listen on \
+             the same --port.",
+            "use --port auto, and {{port}} in the command.",
+        ));
+    }
+
+    for spec in &args.ports {
+        let &crate::ports::PortSpec::Port(port) = spec else {
+            continue;
+        };
+        if crate::ports::is_free(port) {
+            continue;
+        }
+        problems.push(match crate::ports::holder(port) {
+            Some((pid, command)) => Problem::new(
+                format!(
+                    "Port {} is already in use by process {} ({}).",
+                    port, pid, command
+                ),
+                "stop that process, or have the command listen on another port.",
+            ),
+            None => Problem::new(
+                format!("Port {} is already in use.", port),
+                format!(
+                    "find out by whom with `ss -ltnp 'sport = :{}'`, as root if it is \
+                     not your process.",
+                    port
+                ),
+            ),
+        });
+    }
+
+    #[cfg(unix)]
+    if let Some(name) = &args.name {
+        let names: Vec<String> = match (args.instances, args.instance) {
+            (Some(count), None) => (1..=count)
+                .map(|index| crate::instances::instance_name(name, index))
+                .collect(),
+            _ => vec![name.clone()],
+        };
+        for name in names {
+            if let Ok(path) = crate::control::socket_path(&name)
+                && crate::control::is_listening(&path)
+            {
+                problems.push(Problem::new(
+                    format!("An instance named \"{}\" is already running.", name),
+                    format!(
+                        "check it with `detach-rs status --name {}`, or choose another --name.",
+                        name
+                    ),
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Runs `check` and turns the problems, if any, into a single error listing them all.
+pub fn validate(args: &Args) -> Result<(), anyhow::Error> {
+    let problems = check(args);
+    match problems.len() {
+        0 => Ok(()),
+        1 => Err(anyhow::anyhow!("{}", problems[0])),
+        n => {
+            let list: Vec<String> = problems
+                .iter()
+                .map(|problem| format!("  - {}", problem))
+                .collect();
+            Err(anyhow::anyhow!(
+                "Found {} problems with the options:\n{}",
+                n,
+                list.join("\n")
+            ))
+        }
+    }
+}
+
+/// The log file has to be writable, or creatable: its directory, or the closest existing
+/// ancestor it would be created in, has to be writable.
+fn check_log_file(path: &Path, problems: &mut Vec<Problem>) {
+    if path.exists() {
+        if !writable(path) {
+            problems.push(Problem::new(
+                format!("The log file {} is not writable.", path.display()),
+                "choose another file with --log-file, or fix its permissions.",
+            ));
+        }
+        return;
+    }
+    let Some(dir) = path.parent() else {
+        return;
+    };
+    let Some(existing) = dir.ancestors().find(|ancestor| ancestor.exists()) else {
+        return;
+    };
+    if !existing.is_dir() {
+        problems.push(Problem::new(
+            format!(
+                "The log directory {} cannot be created: {} is not a directory.",
+                dir.display(),
+                existing.display()
+            ),
+            "choose another file with --log-file.",
+        ));
+    } else if !writable(existing) {
+        let what = if existing == dir {
+            format!("The log directory {} is not writable.", dir.display())
+        } else {
+            format!(
+                "The log directory {} does not exist and cannot be created in {}.",
+                dir.display(),
+                existing.display()
+            )
+        };
+        problems.push(Problem::new(
+            what,
+            "choose another file with --log-file, or use a user that may write there.",
+        ));
+    }
+}
+
+/// `path` itself is created when needed, but its directory has to exist and be writable.
+fn check_parent(path: &Path, flag: &str, problems: &mut Vec<Problem>) {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !dir.is_dir() {
+        problems.push(Problem::new(
+            format!(
+                "The directory of {} {} does not exist.",
+                flag,
+                path.display()
+            ),
+            format!("create {} first.", dir.display()),
+        ));
+    } else if !writable(if path.exists() { path } else { dir }) {
+        problems.push(Problem::new(
+            format!("{} {} is not writable.", flag, path.display()),
+            "choose another path, or fix the permissions.",
+        ));
+    }
+}
+
+#[cfg(unix)]
+fn writable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn writable(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|metadata| !metadata.permissions().readonly())
+        .unwrap_or(false)
+}
+
+/// Looks `program` up on the `PATH`.
+fn find_program(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path).find_map(|dir| {
+        let candidate = dir.join(program);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        let exe = candidate.with_extension(std::env::consts::EXE_EXTENSION);
+        exe.is_file().then_some(exe)
+    })
+}
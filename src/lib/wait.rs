@@ -0,0 +1,357 @@
+//! Watching a detached instance from the terminal that launched it.
+//!
+//! `--detach` returns as soon as the daemon is forked off, so a service that fails on its
+//! first start (a typo in its configuration, a port that is taken) is only noticed later,
+//! in its log. With `--wait`, the process that launched the daemon stays after the fork
+//! instead of exiting: it prints what is written to the log from then on, and returns once
+//! `control::wait_ready` sees the instance ready, leaving the daemon to run on its own.
+//! Any key, or Ctrl-C, stops watching early without touching the instance.
+//!
+//! `--tail` follows the log the same way, with no end but the service's: the service runs
+//! as a daemon and the terminal only views its log, so Ctrl-C stops the viewing and
+//! leaves the service running, with a hint on how to follow or stop it later.
+//!
+//! The launcher exits with status 1 if the instance ends before it is ready, or is not
+//! ready within the timeout (the instance is left running then). When `--tail` follows
+//! the service to its end, it exits with the service's status, and with 1 if the service
+//! ended without reporting one (killed by a signal, say). Otherwise it exits with 0. It
+//! learns the daemon's PID, its exit status and that the daemon ended from a pipe the
+//! daemon holds open for as long as it runs, so even a daemon that fails before its
+//! control socket answers is noticed at once.
+use crate::control;
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+/// How often the log is checked for new output.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What the launching process waits for after forking off the daemon.
+#[derive(Debug, Clone)]
+pub struct Wait {
+    /// The instance name, if it has one
+    name: Option<String>,
+    log: PathBuf,
+    /// Where the output of this start begins in the log
+    offset: u64,
+    /// The control socket to wait on for readiness, and for how long at most; `None`
+    /// follows the log until interrupted
+    ready: Option<(PathBuf, Duration)>,
+}
+
+static WAIT: Mutex<Option<Wait>> = Mutex::new(None);
+
+/// The write end of the exit pipe in the daemon, or -1 without one.
+static RUNNING: AtomicI32 = AtomicI32::new(-1);
+
+/// Sets what the launching process of `daemonize` waits for; with `None` it exits right
+/// after the fork.
+pub fn set_wait(wait: Option<Wait>) {
+    *WAIT.lock().unwrap_or_else(|e| e.into_inner()) = wait;
+}
+
+/// Takes the wait set with `set_wait`, for the fork about to happen.
+pub(crate) fn take_wait() -> Option<Wait> {
+    WAIT.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+/// Opens the pipe through which the launching process learns the daemon's PID and notices
+/// it exit: the daemon writes its PID and keeps the write end open (see `hold`), and the
+/// read end sees end of file once it is gone. Neither end is inherited by commands the
+/// daemon runs.
+pub(crate) fn exit_pipe() -> Result<(OwnedFd, OwnedFd), anyhow::Error> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(anyhow::anyhow!(
+            "Failed to create a pipe: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    // SAFETY: pipe just opened both descriptors, and nothing else owns them.
+    let (reader, writer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    for fd in [&reader, &writer] {
+        unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    Ok((reader, writer))
+}
+
+/// Writes the daemon's PID to the write end of the exit pipe, which stays open until the
+/// daemon exits. Only makes async-signal-safe system calls.
+pub(crate) fn hold(running: OwnedFd) {
+    let mut running = File::from(running);
+    let _ = running.write_all(&std::process::id().to_ne_bytes());
+    RUNNING.store(running.into_raw_fd(), Ordering::SeqCst);
+}
+
+/// Tells the launching process, if it is watching, the status the daemon is about to exit
+/// with.
+pub(crate) fn report_exit(code: i32) {
+    let fd = RUNNING.swap(-1, Ordering::SeqCst);
+    if fd >= 0 {
+        // SAFETY: `hold` left the descriptor open for this, and nothing else uses it.
+        let mut running = unsafe { File::from_raw_fd(fd) };
+        let _ = running.write_all(&code.to_ne_bytes());
+    }
+}
+
+/// How watching ended.
+enum Outcome {
+    Ready(serde_json::Value),
+    Detached,
+    /// The service exited with the status it reported, if it did
+    Exited(Option<i32>),
+    Failed(anyhow::Error),
+}
+
+impl Wait {
+    /// Waits up to `timeout` for the instance called `name` to be ready, showing what is
+    /// appended to `log` from now on.
+    pub fn new(name: &str, log: &Path, timeout: Duration) -> Result<Self, anyhow::Error> {
+        Ok(Wait {
+            ready: Some((control::socket_path(name)?, timeout)),
+            ..Wait::follow(Some(name), log)
+        })
+    }
+
+    /// Shows what is appended to `log` from now on until interrupted, or until the service
+    /// (the instance called `name`, if it has one) exits.
+    pub fn follow(name: Option<&str>, log: &Path) -> Self {
+        Wait {
+            name: name.map(str::to_string),
+            log: log.to_path_buf(),
+            offset: std::fs::metadata(log).map(|meta| meta.len()).unwrap_or(0),
+            ready: None,
+        }
+    }
+
+    /// Watches the daemon forked off through `child`, which holds the write end of the
+    /// `exit_pipe` read by `exited`, and returns the exit status of the launching process.
+    pub(crate) fn run(self, child: libc::pid_t, exited: OwnedFd) -> i32 {
+        // The first child exits as soon as it has forked the daemon
+        unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+        let mut exited = File::from(exited);
+        let mut pid = [0; 4];
+        let daemon = exited
+            .read_exact(&mut pid)
+            .ok()
+            .map(|()| u32::from_ne_bytes(pid));
+        let rt = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(rt) => rt,
+            Err(e) => {
+                eprintln!("detach-rs: Cannot watch {}: {}", self.service(), e);
+                return 0;
+            }
+        };
+        let terminal = match self.ready {
+            Some(_) => {
+                eprintln!(
+                    "detach-rs: Showing the log of {} until it is ready; press any key to stop \
+                     watching.",
+                    self.service()
+                );
+                Terminal::keys()
+            }
+            None => {
+                eprintln!(
+                    "detach-rs: Following the log of {}; press Ctrl-C to stop following and \
+                     leave it running.",
+                    self.service()
+                );
+                None
+            }
+        };
+        let outcome = rt.block_on(self.watch(terminal.is_some(), exited));
+        drop(terminal);
+        match outcome {
+            Outcome::Ready(status) => {
+                eprintln!(
+                    "detach-rs: {} is ready (command PID {}) and keeps running in the background.",
+                    self.service(),
+                    status["child_pid"]
+                );
+                0
+            }
+            Outcome::Detached => {
+                // SIGTERM stops any instance, with or without a command to supervise
+                let stop = match (daemon, &self.name) {
+                    (Some(pid), _) => format!("kill {}", pid),
+                    (None, Some(name)) => format!("detach-rs stop --name {}", name),
+                    (None, None) => "kill".to_string(),
+                };
+                eprintln!(
+                    "detach-rs: Stopped watching; {} keeps running in the background{}. Follow \
+                     its log with `detach-rs logs -f {}`, or stop it with `{}`.",
+                    self.service(),
+                    daemon
+                        .map(|pid| format!(" as PID {}", pid))
+                        .unwrap_or_default(),
+                    self.log.display(),
+                    stop
+                );
+                0
+            }
+            Outcome::Exited(Some(0)) => {
+                eprintln!("detach-rs: {} exited.", self.subject());
+                0
+            }
+            Outcome::Exited(Some(code)) => {
+                eprintln!(
+                    "detach-rs: {} exited with status {} (log: {}).",
+                    self.subject(),
+                    code,
+                    self.log.display()
+                );
+                code
+            }
+            Outcome::Exited(None) => {
+                eprintln!(
+                    "detach-rs: {} ended without reporting an exit status (log: {}).",
+                    self.subject(),
+                    self.log.display()
+                );
+                1
+            }
+            Outcome::Failed(e) => {
+                eprintln!("detach-rs: {} (log: {}).", e, self.log.display());
+                1
+            }
+        }
+    }
+
+    /// The instance's name, or "the service" for one without.
+    fn service(&self) -> &str {
+        self.name.as_deref().unwrap_or("the service")
+    }
+
+    /// `service`, to start a sentence with.
+    fn subject(&self) -> &str {
+        self.name.as_deref().unwrap_or("The service")
+    }
+
+    /// Copies the log to stdout until the instance is ready, gone, or watching is stopped.
+    async fn watch(&self, keys: bool, exited: File) -> Outcome {
+        let mut out = std::io::stdout();
+        let mut pos = self.offset;
+        let ready = async {
+            let Some((socket, timeout)) = &self.ready else {
+                return std::future::pending().await;
+            };
+            match control::wait_ready(socket, None, *timeout).await {
+                Ok(status) => Outcome::Ready(status),
+                Err(e) => {
+                    Outcome::Failed(anyhow::anyhow!("{}; {} is left running", e, self.service()))
+                }
+            }
+        };
+        let gone = async {
+            let status = read_status(exited).await;
+            match self.ready {
+                Some(_) => Outcome::Failed(anyhow::anyhow!(
+                    "{} exited before it was ready",
+                    self.service()
+                )),
+                None => Outcome::Exited(status),
+            }
+        };
+        let key = key_pressed(keys);
+        let interrupted = tokio::signal::ctrl_c();
+        tokio::pin!(ready, gone, key, interrupted);
+        let mut ticks = tokio::time::interval(POLL_INTERVAL);
+        let outcome = loop {
+            tokio::select! {
+                outcome = &mut ready => break outcome,
+                outcome = &mut gone => break outcome,
+                _ = &mut key => break Outcome::Detached,
+                _ = &mut interrupted => break Outcome::Detached,
+                _ = ticks.tick() => {
+                    pos = crate::logs::copy_appended(&self.log, pos, &mut out).unwrap_or(pos);
+                }
+            }
+        };
+        let _ = crate::logs::copy_appended(&self.log, pos, &mut out);
+        outcome
+    }
+}
+
+/// Returns once a key is pressed on the terminal, or never without one.
+async fn key_pressed(keys: bool) {
+    if !keys {
+        return std::future::pending().await;
+    }
+    if !read_one(std::io::stdin()).await {
+        std::future::pending().await
+    }
+}
+
+/// Reads one byte from `input`, returning `true` if there was one and `false` at end of
+/// file or on an error.
+async fn read_one<R: Read + Send + 'static>(mut input: R) -> bool {
+    in_thread(move || input.read(&mut [0]).is_ok_and(|read| read > 0))
+        .await
+        .unwrap_or(false)
+}
+
+/// Reads the exit pipe until the daemon is gone, returning the exit status it reported
+/// with `report_exit`, if any.
+async fn read_status(mut exited: File) -> Option<i32> {
+    in_thread(move || {
+        let mut status = [0; 4];
+        let reported = exited.read_exact(&mut status).is_ok();
+        // The status comes right before the daemon exits; wait for that too
+        let _ = exited.read(&mut [0]);
+        reported.then(|| i32::from_ne_bytes(status))
+    })
+    .await
+    .flatten()
+}
+
+/// Runs the blocking `read` in a thread of its own, as nothing would wait for a blocking
+/// task at exit.
+async fn in_thread<T: Send + 'static>(read: impl FnOnce() -> T + Send + 'static) -> Option<T> {
+    let (done, result) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = done.send(read());
+    });
+    result.await.ok()
+}
+
+/// The terminal on stdin, switched to pass on every key as it is pressed, without echo,
+/// until dropped.
+struct Terminal {
+    saved: libc::termios,
+}
+
+impl Terminal {
+    /// Switches the terminal on stdin, if there is one, to take single keys.
+    fn keys() -> Option<Terminal> {
+        if !std::io::stdin().is_terminal() {
+            return None;
+        }
+        // SAFETY: tcgetattr fills in the termios it is given, which is plain data.
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } < 0 {
+            return None;
+        }
+        let mut keys = saved;
+        keys.c_lflag &= !(libc::ICANON | libc::ECHO);
+        keys.c_cc[libc::VMIN] = 1;
+        keys.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &keys) } < 0 {
+            return None;
+        }
+        Some(Terminal { saved })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved) };
+    }
+}
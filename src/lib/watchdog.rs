@@ -0,0 +1,155 @@
+//! Detecting a wedged tokio runtime from outside of it.
+//!
+//! A daemon whose runtime is stuck (every worker blocked in a synchronous call, a
+//! deadlock, a busy loop that never yields) keeps its PID and its log file and looks
+//! alive, while nothing happens for days. `with_watchdog` spawns a canary task that
+//! records a heartbeat and a plain OS thread that checks it: once the canary has not run
+//! for `Watchdog::stall`, the thread logs a diagnostic dump (worker and task counts, and
+//! on Linux the state of every thread) and, with `Watchdog::abort`, aborts the process so
+//! a supervisor or service manager can restart it.
+use log::{error, info};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Settings for `with_watchdog`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchdog {
+    /// How long the canary task may go without running before the runtime counts as stalled
+    pub stall: Duration,
+    /// Abort the process after logging the dump, instead of waiting for it to recover
+    pub abort: bool,
+}
+
+/// Runs `future` while watching the current tokio runtime for stalls; without a
+/// `watchdog` this is a plain `future.await`.
+pub async fn with_watchdog<F>(watchdog: Option<Watchdog>, future: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    let Some(watchdog) = watchdog else {
+        return future.await;
+    };
+    let origin = Instant::now();
+    let beat = Arc::new(AtomicU64::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    let interval = (watchdog.stall / 4).max(Duration::from_millis(100));
+
+    let canary = crate::tasks::TaskGroup::new();
+    {
+        let beat = beat.clone();
+        canary.spawn("watchdog canary", async move {
+            loop {
+                beat.store(origin.elapsed().as_millis() as u64, Ordering::Relaxed);
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+    let handle = tokio::runtime::Handle::current();
+    let thread = {
+        let done = done.clone();
+        std::thread::Builder::new()
+            .name("detach-watchdog".to_string())
+            .spawn(move || watch(watchdog, interval, origin, &beat, &done, &handle))
+    };
+    if let Err(e) = &thread {
+        log::warn!("Failed to start the runtime watchdog: {}", e);
+    }
+    info!(
+        "Runtime watchdog active: stall after {}{}.",
+        humantime::format_duration(watchdog.stall),
+        if watchdog.abort { ", then abort" } else { "" }
+    );
+
+    let output = future.await;
+    done.store(true, Ordering::Relaxed);
+    canary.shutdown().await;
+    output
+}
+
+/// The watchdog thread: checks the canary's heartbeat every `interval` until `done`.
+fn watch(
+    watchdog: Watchdog,
+    interval: Duration,
+    origin: Instant,
+    beat: &AtomicU64,
+    done: &AtomicBool,
+    handle: &tokio::runtime::Handle,
+) {
+    let mut stalled_since = None;
+    while !done.load(Ordering::Relaxed) {
+        std::thread::sleep(interval);
+        let last = Duration::from_millis(beat.load(Ordering::Relaxed));
+        let silent = origin.elapsed().saturating_sub(last);
+        if silent < watchdog.stall {
+            if let Some(since) = stalled_since.take() {
+                info!(
+                    "Runtime recovered after stalling for {}.",
+                    humantime::format_duration(round(Instant::now() - since))
+                );
+            }
+            continue;
+        }
+        if stalled_since.is_some() {
+            continue;
+        }
+        stalled_since = Some(Instant::now() - silent);
+        error!(
+            "Runtime stalled: the watchdog canary task has not run for {}.\n{}",
+            humantime::format_duration(round(silent)),
+            dump(handle)
+        );
+        if watchdog.abort {
+            error!("Aborting the stalled process.");
+            log::logger().flush();
+            std::process::abort();
+        }
+    }
+}
+
+/// Describes the runtime and, on Linux, every thread of the process.
+fn dump(handle: &tokio::runtime::Handle) -> String {
+    use std::fmt::Write as _;
+
+    let metrics = handle.metrics();
+    let mut out = format!(
+        "  runtime: {} workers, {} alive tasks, {} tasks in the global queue",
+        metrics.num_workers(),
+        metrics.num_alive_tasks(),
+        metrics.global_queue_depth()
+    );
+    #[cfg(target_os = "linux")]
+    if let Ok(tasks) = std::fs::read_dir("/proc/self/task") {
+        for task in tasks.filter_map(Result::ok) {
+            let dir = task.path();
+            let read = |file: &str| {
+                std::fs::read_to_string(dir.join(file))
+                    .map(|s| s.trim().to_string())
+                    .unwrap_or_default()
+            };
+            // The state follows the parenthesized command name in `stat`
+            let stat = read("stat");
+            let state = stat
+                .rsplit_once(") ")
+                .and_then(|(_, rest)| rest.split(' ').next())
+                .unwrap_or("?")
+                .to_string();
+            let _ = write!(
+                out,
+                "\n  thread {} ({}): state {}, waiting in {}",
+                task.file_name().to_string_lossy(),
+                read("comm"),
+                state,
+                Some(read("wchan"))
+                    .filter(|w| !w.is_empty() && w != "0")
+                    .unwrap_or("-".into())
+            );
+        }
+    }
+    out
+}
+
+/// Rounds to whole milliseconds so logged durations stay readable.
+fn round(duration: Duration) -> Duration {
+    Duration::from_millis(duration.as_millis() as u64)
+}